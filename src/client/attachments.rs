@@ -0,0 +1,373 @@
+/*
+ * This module implements encrypted file attachments.
+ *
+ * A file is never put in a message. Instead:
+ *  1. it is encrypted under its own random key with secretstream
+ *     (XChaCha20-Poly1305), in chunks of `CHUNK_LEN` bytes, the last
+ *     chunk tagged `Final`
+ *  2. the encrypted chunks, back to back, form a blob that is uploaded
+ *     to the relay's content-addressed blob store (see `net::blobs`)
+ *  3. the message only carries an `Attachment` (`Payload::Attachment`):
+ *     the blob id, the key and header of the stream, and the SHA-256 of
+ *     the file
+ *
+ * The receiver downloads the blob piece by piece and decrypts it as it
+ * arrives (`Decryptor`). A chunk that was altered, reordered or cut off
+ * (no `Final` tag), or a digest or size mismatch, rejects the file:
+ * nothing is written to disk before it is fully checked.
+ *
+ * The relay only ever sees the encrypted blob; whoever learns a blob id
+ * without the message still cannot read the file.
+ */
+
+use crate::net::blobs::{self, BLOB_CHUNK};
+use crate::net::transport::Transport;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::secretstream::{self, Header, Key, Pull, Stream, Tag};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/* Size of the plaintext carried by one chunk of the stream, in bytes */
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/* Largest file that can be attached, in bytes */
+pub const MAX_ATTACHMENT: usize = 24 * 1024 * 1024;
+
+/*
+ * What a message carries for an attached file.
+ *
+ * Fields:
+ *  - `name`    : File name, as chosen by the sender (sanitized on save)
+ *  - `size`    : Size of the file, in bytes
+ *  - `blob_id` : Id of the encrypted blob on the relay
+ *  - `key`     : The file's secretstream key
+ *  - `header`  : The secretstream header
+ *  - `digest`  : SHA-256 of the file
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    pub name: String,
+    pub size: u64,
+    pub blob_id: String,
+    pub key: Vec<u8>,
+    pub header: Vec<u8>,
+    pub digest: Vec<u8>,
+}
+
+/*
+ * Reasons why an attachment cannot be sent or opened.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachmentError {
+    /* The file is larger than `MAX_ATTACHMENT` */
+    TooLarge,
+    /* The relay does not have the blob */
+    NotFound,
+    /* A chunk does not decrypt in its place in the stream */
+    BadChunk(u64),
+    /* The stream ends before its `Final` chunk, or goes on after it */
+    Truncated,
+    /* The file does not match the digest or size of the message */
+    DigestMismatch,
+    /* The attachment is malformed */
+    Malformed,
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachmentError::TooLarge => write!(f, "the file is too large"),
+            AttachmentError::NotFound => write!(f, "the relay does not have this file"),
+            AttachmentError::BadChunk(index) => write!(f, "chunk {index} of the file is invalid"),
+            AttachmentError::Truncated => write!(f, "the file was cut off"),
+            AttachmentError::DigestMismatch => write!(f, "the file does not match its digest"),
+            AttachmentError::Malformed => write!(f, "the attachment is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for AttachmentError {}
+
+/*
+ * Encrypts the file `data` named `name` under a fresh key.
+ *
+ * Returns the attachment to send and the blob to upload.
+ */
+pub fn encrypt(name: &str, data: &[u8]) -> Result<(Attachment, Vec<u8>), AttachmentError> {
+    if data.len() > MAX_ATTACHMENT {
+        return Err(AttachmentError::TooLarge);
+    }
+    let key = secretstream::gen_key();
+    let (mut stream, header) = Stream::init_push(&key).map_err(|_| AttachmentError::Malformed)?;
+
+    /* An empty file still gets its (empty) `Final` chunk */
+    let pieces: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(CHUNK_LEN).collect() };
+    let mut blob = Vec::with_capacity(data.len() + pieces.len() * secretstream::ABYTES);
+    for (index, piece) in pieces.iter().enumerate() {
+        let tag = if index + 1 == pieces.len() { Tag::Final } else { Tag::Message };
+        let chunk = stream.push(piece, None, tag).map_err(|_| AttachmentError::Malformed)?;
+        blob.extend_from_slice(&chunk);
+    }
+
+    let attachment = Attachment {
+        name: name.to_string(),
+        size: data.len() as u64,
+        blob_id: blobs::blob_id(&blob),
+        key: key.0.to_vec(),
+        header: header.0.to_vec(),
+        digest: sha256::hash(data).0.to_vec(),
+    };
+    Ok((attachment, blob))
+}
+
+/*
+ * Decrypts an attachment's blob as it is downloaded.
+ *
+ * Bytes are fed in any split (`push`); every complete chunk is
+ * decrypted right away, and `finish` checks the end of the stream,
+ * the size and the digest.
+ */
+pub struct Decryptor {
+    stream: Stream<Pull>,
+    pending: Vec<u8>,
+    plaintext: Vec<u8>,
+    hash: sha256::State,
+    index: u64,
+    size: u64,
+    digest: Vec<u8>,
+}
+
+impl Decryptor {
+    /* Starts decrypting the blob of `attachment` */
+    pub fn new(attachment: &Attachment) -> Result<Self, AttachmentError> {
+        if attachment.size > MAX_ATTACHMENT as u64 {
+            return Err(AttachmentError::TooLarge);
+        }
+        let key = Key::from_slice(&attachment.key).ok_or(AttachmentError::Malformed)?;
+        let header = Header::from_slice(&attachment.header).ok_or(AttachmentError::Malformed)?;
+        Ok(Self {
+            stream: Stream::init_pull(&header, &key).map_err(|_| AttachmentError::Malformed)?,
+            pending: Vec::new(),
+            plaintext: Vec::new(),
+            hash: sha256::State::new(),
+            index: 0,
+            size: attachment.size,
+            digest: attachment.digest.clone(),
+        })
+    }
+
+    /* Feeds the next bytes of the blob */
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), AttachmentError> {
+        self.pending.extend_from_slice(bytes);
+        let chunk_len = CHUNK_LEN + secretstream::ABYTES;
+        while self.pending.len() >= chunk_len {
+            let rest = self.pending.split_off(chunk_len);
+            let chunk = std::mem::replace(&mut self.pending, rest);
+            self.pull(&chunk)?;
+        }
+        Ok(())
+    }
+
+    fn pull(&mut self, chunk: &[u8]) -> Result<(), AttachmentError> {
+        if self.stream.is_finalized() {
+            return Err(AttachmentError::Truncated);
+        }
+        let (piece, _tag) = self.stream.pull(chunk, None).map_err(|_| AttachmentError::BadChunk(self.index))?;
+        if self.plaintext.len() + piece.len() > self.size as usize {
+            return Err(AttachmentError::DigestMismatch);
+        }
+        self.hash.update(&piece);
+        self.plaintext.extend_from_slice(&piece);
+        self.index += 1;
+        Ok(())
+    }
+
+    /* Decrypts the last (short) chunk and checks the whole file */
+    pub fn finish(mut self) -> Result<Vec<u8>, AttachmentError> {
+        if !self.pending.is_empty() {
+            let chunk = std::mem::take(&mut self.pending);
+            self.pull(&chunk)?;
+        }
+        if !self.stream.is_finalized() {
+            return Err(AttachmentError::Truncated);
+        }
+        if self.plaintext.len() as u64 != self.size || self.hash.finalize().0.as_slice() != self.digest.as_slice() {
+            return Err(AttachmentError::DigestMismatch);
+        }
+        Ok(self.plaintext)
+    }
+}
+
+/*
+ * Uploads `blob` to the relay behind `transport`, piece by piece.
+ *
+ * Stops early if the relay already has it (same blob id).
+ */
+pub async fn upload(transport: &dyn Transport, blob_id: &str, blob: &[u8]) -> io::Result<()> {
+    let pieces: Vec<&[u8]> = blob.chunks(BLOB_CHUNK).collect();
+    let mut offset = 0u64;
+    for (index, piece) in pieces.iter().enumerate() {
+        let last = index + 1 == pieces.len();
+        let received = transport.blob_put(blob_id, offset, piece.to_vec(), last).await?;
+        if received >= blob.len() as u64 {
+            return Ok(());
+        }
+        offset += piece.len() as u64;
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "the relay did not store the blob"))
+}
+
+/*
+ * Downloads the blob of `attachment` from the relay behind `transport`
+ * and decrypts it as it arrives.
+ *
+ * Returns the checked file.
+ */
+pub async fn download(transport: &dyn Transport, attachment: &Attachment) -> Result<Vec<u8>, String> {
+    let mut decryptor = Decryptor::new(attachment).map_err(|e| e.to_string())?;
+    /* The blob is at most one tag per chunk larger than the file */
+    let chunks = attachment.size / CHUNK_LEN as u64 + 1;
+    let limit = attachment.size + chunks * secretstream::ABYTES as u64;
+    let mut offset = 0u64;
+    loop {
+        let (total, data) = transport
+            .blob_get(&attachment.blob_id, offset)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| AttachmentError::NotFound.to_string())?;
+        if total > limit {
+            return Err(AttachmentError::DigestMismatch.to_string());
+        }
+        if data.is_empty() && offset < total {
+            return Err(AttachmentError::Truncated.to_string());
+        }
+        offset += data.len() as u64;
+        decryptor.push(&data).map_err(|e| e.to_string())?;
+        if offset >= total {
+            break;
+        }
+    }
+    decryptor.finish().map_err(|e| e.to_string())
+}
+
+/*
+ * Writes a checked file into `dir`, under the last component of its
+ * name (stripped of anything that could leave `dir`).
+ *
+ * Returns the path written.
+ */
+pub fn save(dir: &str, attachment: &Attachment, data: &[u8]) -> io::Result<PathBuf> {
+    let name: String = attachment
+        .name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let name = match name.trim_start_matches('.') {
+        "" => attachment.blob_id.chars().filter(char::is_ascii_hexdigit).take(16).collect(),
+        name => name.to_string(),
+    };
+    std::fs::create_dir_all(dir)?;
+    let path = Path::new(dir).join(name);
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = CHUNK_LEN + secretstream::ABYTES;
+
+    fn decrypt(attachment: &Attachment, blob: &[u8], split: usize) -> Result<Vec<u8>, AttachmentError> {
+        let mut decryptor = Decryptor::new(attachment)?;
+        for piece in blob.chunks(split) {
+            decryptor.push(piece)?;
+        }
+        decryptor.finish()
+    }
+
+    /* A file of two full chunks and a short one */
+    fn file() -> Vec<u8> {
+        (0..2 * CHUNK_LEN + 100).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn files_round_trip() {
+        for data in [Vec::new(), b"hello".to_vec(), vec![7; CHUNK_LEN], file()] {
+            let (attachment, blob) = encrypt("file.bin", &data).unwrap();
+            assert_eq!(attachment.blob_id, blobs::blob_id(&blob));
+            for split in [1000, CHUNK, blob.len().max(1)] {
+                assert_eq!(decrypt(&attachment, &blob, split).as_ref(), Ok(&data));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_or_extended_streams_are_refused() {
+        let (attachment, blob) = encrypt("file.bin", &file()).unwrap();
+        assert_eq!(decrypt(&attachment, &blob[..2 * CHUNK], CHUNK), Err(AttachmentError::Truncated), "last chunk dropped");
+        assert_eq!(decrypt(&attachment, &blob[..CHUNK + 10], CHUNK), Err(AttachmentError::BadChunk(1)), "cut in a chunk");
+        assert_eq!(decrypt(&attachment, &blob[..0], CHUNK), Err(AttachmentError::Truncated), "nothing at all");
+
+        /* Chunks after the `Final` one (the file ends on a chunk boundary) */
+        let (whole, blob) = encrypt("file.bin", &vec![7; 2 * CHUNK_LEN]).unwrap();
+        let mut extended = blob.clone();
+        extended.extend_from_slice(&blob[..CHUNK]);
+        assert_eq!(decrypt(&whole, &extended, CHUNK), Err(AttachmentError::Truncated), "data after the end");
+        assert_eq!(decrypt(&attachment, &blob, CHUNK), Err(AttachmentError::BadChunk(0)), "another file");
+
+        /* A short file whose final chunk is cut */
+        let (attachment, blob) = encrypt("note.txt", b"hello").unwrap();
+        assert_eq!(decrypt(&attachment, &blob[..blob.len() - 1], 4), Err(AttachmentError::BadChunk(0)));
+    }
+
+    #[test]
+    fn altered_streams_are_refused() {
+        let data = file();
+        let (attachment, blob) = encrypt("file.bin", &data).unwrap();
+
+        let mut swapped = blob[CHUNK..2 * CHUNK].to_vec();
+        swapped.extend_from_slice(&blob[..CHUNK]);
+        swapped.extend_from_slice(&blob[2 * CHUNK..]);
+        assert_eq!(decrypt(&attachment, &swapped, CHUNK), Err(AttachmentError::BadChunk(0)), "chunks reordered");
+
+        let mut flipped = blob.clone();
+        flipped[CHUNK + 5] ^= 1;
+        assert_eq!(decrypt(&attachment, &flipped, CHUNK), Err(AttachmentError::BadChunk(1)));
+
+        let (other, _) = encrypt("file.bin", &data).unwrap();
+        let wrong_key = Attachment { key: other.key, ..attachment.clone() };
+        assert_eq!(decrypt(&wrong_key, &blob, CHUNK), Err(AttachmentError::BadChunk(0)));
+
+        let wrong_digest = Attachment { digest: vec![0; 32], ..attachment.clone() };
+        assert_eq!(decrypt(&wrong_digest, &blob, CHUNK), Err(AttachmentError::DigestMismatch));
+        let smaller = Attachment { size: 10, ..attachment.clone() };
+        assert_eq!(decrypt(&smaller, &blob, CHUNK), Err(AttachmentError::DigestMismatch));
+        let malformed = Attachment { header: vec![1, 2, 3], ..attachment.clone() };
+        assert_eq!(decrypt(&malformed, &blob, CHUNK).err(), Some(AttachmentError::Malformed));
+        let huge = Attachment { size: MAX_ATTACHMENT as u64 + 1, ..attachment };
+        assert_eq!(decrypt(&huge, &blob, CHUNK).err(), Some(AttachmentError::TooLarge));
+        assert_eq!(encrypt("big", &vec![0; MAX_ATTACHMENT + 1]).err(), Some(AttachmentError::TooLarge));
+    }
+
+    #[test]
+    fn saved_names_stay_in_the_directory() {
+        let dir = std::env::temp_dir().join(format!("blackipher-downloads-{}", std::process::id()));
+        let dir = dir.to_string_lossy().into_owned();
+        let (mut attachment, _) = encrypt("x", b"data").unwrap();
+        for (name, saved) in [("../../etc/passwd", "passwd"), ("..\\evil.txt", "evil.txt"), ("..", ""), ("a\nb", "ab")] {
+            attachment.name = name.to_string();
+            let path = save(&dir, &attachment, b"data").unwrap();
+            assert_eq!(path.parent(), Some(Path::new(&dir)), "{name:?}");
+            if !saved.is_empty() {
+                assert_eq!(path.file_name().unwrap().to_string_lossy(), saved);
+            }
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,85 @@
+/*
+ * This module implements contact cards, to introduce a contact to
+ * someone else from a conversation.
+ *
+ * A card (`Payload::ContactCard`) carries the contact's public bundle
+ * and their signed username claim (see `net::directory`). Importing it
+ * checks that the two agree: the claim is signed by the bundle's
+ * identity key for the bundle's username, and the bundle's pre-key and
+ * device list are signed by that key too.
+ *
+ * This only shows that the owner of that identity key claimed the
+ * username. Whether it is the right person is vouched for by the sender
+ * of the card alone: the receiver has not verified it (e.g. with the
+ * directory, or in person).
+ */
+
+use crate::client::contacts::{check_claim, ContactError};
+use crate::client::user::{PublicBundle, User};
+use crate::net::directory::UsernameClaim;
+use serde::{Deserialize, Serialize};
+
+/*
+ * A contact, as introduced by someone.
+ *
+ * Fields:
+ *  - `bundle` : The contact's public keys
+ *  - `claim`  : Their signed username claim
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ContactCard {
+    pub bundle: PublicBundle,
+    pub claim: UsernameClaim,
+}
+
+impl ContactCard {
+    /* The card of `user`, given their username claim */
+    pub fn new(user: &User, claim: UsernameClaim) -> Self {
+        Self {
+            bundle: user.public_bundle(),
+            claim,
+        }
+    }
+
+    /* The username the card introduces */
+    pub fn username(&self) -> &str {
+        &self.bundle.username
+    }
+
+    /*
+     * Checks the card and returns the contact it introduces.
+     *
+     * Refused if the bundle is malformed or not self-consistent, or if
+     * the claim does not bind its username to its identity key.
+     */
+    pub fn contact(&self) -> Result<User, ContactError> {
+        let user = User::from_bundle(&self.bundle).ok_or(ContactError::InvalidBundle)?;
+        check_claim(&user, &self.claim)?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cards_must_agree_with_their_claim() {
+        let bob = User::new("bob", 1);
+        let card = ContactCard::new(&bob, bob.username_claim().unwrap());
+        assert_eq!(card.username(), "bob");
+        assert_eq!(card.contact().unwrap().identity_pk, bob.identity_pk);
+
+        let mallory = User::new("bob", 1);
+        let swapped = ContactCard::new(&mallory, bob.username_claim().unwrap());
+        assert_eq!(swapped.contact().err(), Some(ContactError::ClaimMismatch));
+
+        let mut forged = card.clone();
+        forged.claim.signature[0] ^= 1;
+        assert_eq!(forged.contact().err(), Some(ContactError::InvalidClaim));
+
+        let mut broken = card;
+        broken.bundle.identity_pk = mallory.identity_pk.as_ref().to_vec();
+        assert_eq!(broken.contact().err(), Some(ContactError::InvalidBundle));
+    }
+}
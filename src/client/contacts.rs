@@ -3,51 +3,228 @@
  * which acts as an in-memory address book for managing `User` objects.
  *
  * Features:
- *  - Add or remove users
+ *  - Add or remove users (usernames are unique)
+ *  - Add users only after checking their directory claim
  *  - Search for users by username
  *  - List all stored users
- *
- * Note: This implementation is intentionally simple
- * and does not enforce uniqueness of usernames.
+ *  - Local nicknames, shown instead of usernames and never sent
+ *  - Block users (their messages are dropped)
+ *  - Save and load the list (public keys only) as JSON
  */
 
-use crate::client::user::User;
+use crate::client::user::{PublicBundle, User};
+use crate::net::directory::UsernameClaim;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/* Longest nickname, in characters */
+pub const MAX_NICKNAME_LEN: usize = 32;
+
+/* Reasons a contact can be refused */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContactError {
+    /* A contact with this username is already in the list */
+    Duplicate,
+    /* The directory claim is malformed or its signature does not verify */
+    InvalidClaim,
+    /* The directory claim is for another username or another identity key */
+    ClaimMismatch,
+    /* The public keys are malformed, or not signed by the identity key */
+    InvalidBundle,
+    /* The nickname is too long or holds control characters */
+    InvalidNickname,
+    /* No contact has this username */
+    Unknown,
+}
+
+impl fmt::Display for ContactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ContactError::Duplicate => "contact already exists",
+            ContactError::InvalidClaim => "invalid directory claim",
+            ContactError::ClaimMismatch => "directory claim does not match the contact's identity key",
+            ContactError::InvalidBundle => "invalid public keys",
+            ContactError::InvalidNickname => "invalid nickname",
+            ContactError::Unknown => "no such contact",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for ContactError {}
+
+/*
+ * Checks that `claim` is a valid directory claim for `user`:
+ * properly signed, for the same username and the same identity key.
+ */
+pub fn check_claim(user: &User, claim: &UsernameClaim) -> Result<(), ContactError> {
+    if !claim.verify() {
+        return Err(ContactError::InvalidClaim);
+    }
+    if claim.username != user.username || claim.identity_key() != Some(user.identity_pk) {
+        return Err(ContactError::ClaimMismatch);
+    }
+    Ok(())
+}
+
+/* The contact list as stored on disk */
+#[derive(Serialize, Deserialize, Default)]
+struct StoredContacts {
+    contacts: Vec<PublicBundle>,
+    #[serde(default)]
+    nicknames: HashMap<String, String>,
+    #[serde(default)]
+    blocked: BTreeSet<String>,
+}
 
 /*
  * Represents a collection of contacts (users).
  *
  * Internally, this is a wrapper around a `Vec<User>`,
  * but it provides convenience methods for common operations.
+ *
+ * Fields:
+ *  - `users`     : The list of users currently stored in this contact list
+ *  - `nicknames` : Local nickname of a user, by username
+ *  - `blocked`   : Usernames whose messages are dropped (contacts or not)
  */
 #[derive(Default)]
 pub struct Contacts {
-    /* The list of users currently stored in this contact list */
     pub users: Vec<User>,
+    nicknames: HashMap<String, String>,
+    blocked: BTreeSet<String>,
 }
 
 impl Contacts {
     /* Creates a new, empty `Contacts` list */
     pub fn new() -> Self {
-        Self { users: Vec::new() }
+        Self::default()
+    }
+
+    /*
+     * Loads a contact list from a JSON file located at `path`.
+     *
+     * Returns `None` if the file is missing or unreadable. Contacts whose
+     * public keys no longer verify are dropped.
+     */
+    pub fn load(path: &str) -> Option<Self> {
+        if !Path::new(path).exists() {
+            return None;
+        }
+        let data = fs::read_to_string(path).ok()?;
+        let stored: StoredContacts = serde_json::from_str(&data).ok()?;
+        let mut contacts = Contacts {
+            users: Vec::new(),
+            nicknames: stored.nicknames,
+            blocked: stored.blocked,
+        };
+        for user in stored.contacts.iter().filter_map(User::from_bundle) {
+            let _ = contacts.add(user);
+        }
+        contacts.nicknames.retain(|username, _| contacts.users.iter().any(|u| &u.username == username));
+        Some(contacts)
+    }
+
+    /* Saves the contact list (public keys, nicknames, blocked users) to a JSON file at `path` */
+    pub fn save(&self, path: &str) {
+        let stored = StoredContacts {
+            contacts: self.users.iter().map(User::public_bundle).collect(),
+            nicknames: self.nicknames.clone(),
+            blocked: self.blocked.clone(),
+        };
+        if let Ok(json) = serde_json::to_string_pretty(&stored) {
+            let _ = fs::write(path, json);
+        }
     }
 
     /*
      * Adds a new user to the contact list.
      *
-     * Warning: this does not prevent duplicate usernames.
+     * Refused if a contact with the same username already exists.
      */
-    pub fn add(&mut self, user: User) {
+    pub fn add(&mut self, user: User) -> Result<(), ContactError> {
+        if self.find(&user.username).is_some() {
+            return Err(ContactError::Duplicate);
+        }
         self.users.push(user);
+        Ok(())
     }
 
     /*
-     * Removes a user from the contact list by username.
-     *
-     * If multiple users share the same username,
-     * all of them will be removed.
+     * Adds a user found in the directory, after checking that `claim`
+     * (as returned by the lookup) binds their username to their identity key.
      */
+    pub fn add_verified(&mut self, user: User, claim: &UsernameClaim) -> Result<(), ContactError> {
+        check_claim(&user, claim)?;
+        self.add(user)
+    }
+
+    /* Removes a user from the contact list by username (and their nickname) */
     pub fn remove(&mut self, username: &str) {
         self.users.retain(|u| u.username != username);
+        self.nicknames.remove(username);
+    }
+
+    /*
+     * Sets the local nickname of contact `username`; an empty one
+     * clears it.
+     *
+     * Refused if they are not a contact, or if the nickname is longer
+     * than `MAX_NICKNAME_LEN` or holds control characters.
+     */
+    pub fn set_nickname(&mut self, username: &str, nickname: &str) -> Result<(), ContactError> {
+        let nickname = nickname.trim();
+        if nickname.chars().count() > MAX_NICKNAME_LEN || nickname.chars().any(char::is_control) {
+            return Err(ContactError::InvalidNickname);
+        }
+        if self.find(username).is_none() {
+            return Err(ContactError::Unknown);
+        }
+        if nickname.is_empty() {
+            self.nicknames.remove(username);
+        } else {
+            self.nicknames.insert(username.to_string(), nickname.to_string());
+        }
+        Ok(())
+    }
+
+    /* The local nickname of `username`, if any */
+    pub fn nickname(&self, username: &str) -> Option<&str> {
+        self.nicknames.get(username).map(String::as_str)
+    }
+
+    /*
+     * How to show `username`: "nickname (username)" when they have a
+     * nickname, so the username is never hidden.
+     */
+    pub fn display_name(&self, username: &str) -> String {
+        match self.nickname(username) {
+            Some(nickname) => format!("{nickname} ({username})"),
+            None => username.to_string(),
+        }
+    }
+
+    /* Blocks `username`: their messages are dropped from now on */
+    pub fn block(&mut self, username: &str) {
+        self.blocked.insert(username.to_string());
+    }
+
+    /* Unblocks `username` */
+    pub fn unblock(&mut self, username: &str) {
+        self.blocked.remove(username);
+    }
+
+    /* Whether `username` is blocked */
+    pub fn is_blocked(&self, username: &str) -> bool {
+        self.blocked.contains(username)
+    }
+
+    /* The blocked usernames, in order */
+    pub fn blocked(&self) -> impl Iterator<Item = &str> {
+        self.blocked.iter().map(String::as_str)
     }
 
     /*
@@ -81,4 +258,47 @@ impl Contacts {
     pub fn get(&self, username: &str) -> Option<&User> {
         self.users.iter().find(|u| u.username() == username)
     }
+
+    /* Gets a mutable reference to a user by username (e.g. to update their device list) */
+    pub fn get_mut(&mut self, username: &str) -> Option<&mut User> {
+        self.users.iter_mut().find(|u| u.username() == username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_must_match_the_contact() {
+        let bob = User::new("bob", 1);
+        let claim = bob.username_claim().unwrap();
+        assert_eq!(check_claim(&bob, &claim), Ok(()));
+
+        /* Someone else's key under bob's name */
+        let mallory = User::new("bob", 1);
+        assert_eq!(check_claim(&mallory, &claim), Err(ContactError::ClaimMismatch));
+        assert_eq!(check_claim(&bob, &mallory.username_claim().unwrap()), Err(ContactError::ClaimMismatch));
+
+        /* Bob's key under another name */
+        let carol = User::new("carol", 1);
+        let mut renamed = claim.clone();
+        renamed.username = "carol".to_string();
+        assert_eq!(check_claim(&carol, &renamed), Err(ContactError::InvalidClaim));
+
+        let mut forged = claim;
+        forged.signature[0] ^= 1;
+        assert_eq!(check_claim(&bob, &forged), Err(ContactError::InvalidClaim));
+    }
+
+    #[test]
+    fn verified_add_refuses_a_mismatch() {
+        let mut contacts = Contacts::default();
+        let bob = User::new("bob", 1);
+        let mallory = User::new("bob", 1);
+        assert!(contacts.add_verified(mallory, &bob.username_claim().unwrap()).is_err());
+        assert!(contacts.get("bob").is_none());
+        assert!(contacts.add_verified(bob.clone(), &bob.username_claim().unwrap()).is_ok());
+        assert_eq!(contacts.add_verified(bob.clone(), &bob.username_claim().unwrap()), Err(ContactError::Duplicate));
+    }
 }
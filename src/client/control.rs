@@ -0,0 +1,80 @@
+/*
+ * This module holds the helpers shared by control messages
+ * (receipts, typing indicators, ...).
+ *
+ * A control message is a small serializable value, carried in its
+ * variant of `Payload` and encrypted end to end exactly like a chat
+ * message (`User::encrypt_message_with_logs`). The envelope's `kind`
+ * tells the receiver which payload to expect once decrypted.
+ *
+ * `seal` addresses the device the peer's keys belong to (for a contact,
+ * their primary device); `seal_for_device` addresses any other device of
+ * the peer, listed in their signed device list (see `net::devices`).
+ */
+
+use crate::client::payload::Payload;
+use crate::client::sessions::new_message_id;
+use crate::client::user::User;
+use crate::net::devices::DeviceInfo;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use sodiumoxide::crypto::box_;
+
+/*
+ * Encrypts `value` from `sender` to `peer` and wraps it
+ * in a fresh envelope of the given `kind`.
+ */
+pub fn seal<T: Clone + Into<Payload>>(kind: EnvelopeKind, value: &T, sender: &User, peer: &User) -> Option<Envelope> {
+    let (epk, nonce, ciphertext, _log) = sender.encrypt_message_with_logs(peer, &value.clone().into());
+    Some(Envelope {
+        id: new_message_id(),
+        kind,
+        sender: sender.username().to_string(),
+        sender_device: sender.device_id.clone(),
+        recipient: peer.username().to_string(),
+        device: peer.device_id.clone(),
+        ephemeral_pk: epk.as_ref().to_vec(),
+        nonce: nonce.0.to_vec(),
+        ciphertext,
+    })
+}
+
+/*
+ * Encrypts `value` from `sender` to device `device` of `peer` and
+ * wraps it in a fresh envelope of the given `kind`.
+ */
+pub fn seal_for_device<T: Clone + Into<Payload>>(
+    kind: EnvelopeKind,
+    value: &T,
+    sender: &User,
+    peer: &User,
+    device: &DeviceInfo,
+) -> Option<Envelope> {
+    let (epk, nonce, ciphertext, _log) = sender.encrypt_for_device_with_logs(peer, device, &value.clone().into())?;
+    Some(Envelope {
+        id: new_message_id(),
+        kind,
+        sender: sender.username().to_string(),
+        sender_device: sender.device_id.clone(),
+        recipient: peer.username().to_string(),
+        device: device.device_id.clone(),
+        ephemeral_pk: epk.as_ref().to_vec(),
+        nonce: nonce.0.to_vec(),
+        ciphertext,
+    })
+}
+
+/*
+ * Decrypts a control message of the given `kind` addressed to `me`.
+ *
+ * Returns `None` if the envelope has another kind, cannot be
+ * decrypted, or does not contain a `T`.
+ */
+pub fn open<T: TryFrom<Payload>>(kind: EnvelopeKind, envelope: &Envelope, me: &User) -> Option<T> {
+    if envelope.kind != kind {
+        return None;
+    }
+    let epk = box_::PublicKey::from_slice(&envelope.ephemeral_pk)?;
+    let nonce = box_::Nonce::from_slice(&envelope.nonce)?;
+    let (payload, _log) = me.decrypt_message_with_logs(&epk, &nonce, &envelope.ciphertext, &envelope.sender)?;
+    T::try_from(payload).ok()
+}
@@ -0,0 +1,120 @@
+/*
+ * This module implements the client side of linked devices
+ * (see `net::devices` for the signed device list).
+ *
+ * A one-to-one message is encrypted separately for each device of the
+ * recipient, listed in their device list, and sent to that device's
+ * mailbox. Our own other devices get a `SyncedMessage` instead, so that
+ * they show the message in the same conversation: they re-encrypt it
+ * for their own store, like a message they sent themselves.
+ *
+ * A device list is only used once it verifies against the account's
+ * identity key; until then, only the device whose keys we hold (a
+ * contact's primary device) is reached.
+ *
+ * Group conversations stay between primary devices: group updates and
+ * TreeKEM commits are signed with the account's identity key, which
+ * linked devices do not hold.
+ */
+
+use crate::client::control;
+use crate::client::payload::Payload;
+use crate::client::user::User;
+use crate::net::devices::DeviceInfo;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+
+/*
+ * A message we sent from another of our devices.
+ *
+ * Fields:
+ *  - `peer`       : The conversation (recipient of the message)
+ *  - `message_id` : Id of the message, the same on every device
+ *  - `payload`    : The message
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncedMessage {
+    pub peer: String,
+    pub message_id: String,
+    pub payload: Box<Payload>,
+}
+
+impl SyncedMessage {
+    /* Encrypts the message from `me` to our own device `device` (envelope kind `Sync`) */
+    pub fn seal(&self, me: &User, device: &DeviceInfo) -> Option<Envelope> {
+        control::seal_for_device(EnvelopeKind::Sync, self, me, me, device)
+    }
+
+    /* Decrypts a synced message addressed to `me`, if the envelope holds one */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::Sync, envelope, me)
+    }
+}
+
+/*
+ * The devices of `user` to encrypt to: those of their device list if it
+ * verifies against their identity key, else the device of their keys.
+ */
+pub fn devices_of(user: &User) -> Vec<DeviceInfo> {
+    if user.devices.verify(&user.identity_pk) && !user.devices.devices.is_empty() {
+        user.devices.devices.clone()
+    } else {
+        vec![user.device_info()]
+    }
+}
+
+/* Our devices other than this one */
+pub fn other_devices(me: &User) -> Vec<DeviceInfo> {
+    devices_of(me).into_iter().filter(|d| d.device_id != me.device_id).collect()
+}
+
+/*
+ * Encrypts a control message of the given `kind` for each of `devices`
+ * of `peer`, one envelope per device.
+ */
+pub fn seal_for_devices<T: Clone + Into<Payload>>(
+    kind: EnvelopeKind,
+    value: &T,
+    me: &User,
+    peer: &User,
+    devices: &[DeviceInfo],
+) -> Vec<Envelope> {
+    devices
+        .iter()
+        .filter_map(|device| control::seal_for_device(kind, value, me, peer, device))
+        .collect()
+}
+
+/*
+ * The extra copies of message `message_id` (`payload`, sent to `peer`),
+ * besides the one for the device of `peer`'s keys:
+ *  - one per other device of `peer`, with the envelope id "message_id.device"
+ *  - one `SyncedMessage` per other device of ours
+ */
+pub fn fan_out(me: &User, peer: &User, message_id: &str, payload: &Payload) -> Vec<Envelope> {
+    let mut envelopes: Vec<Envelope> = devices_of(peer)
+        .iter()
+        .filter(|device| device.device_id != peer.device_id)
+        .filter_map(|device| {
+            let (epk, nonce, ciphertext, _log) = me.encrypt_for_device_with_logs(peer, device, payload)?;
+            Some(Envelope {
+                id: format!("{message_id}.{}", device.device_id),
+                kind: EnvelopeKind::Message,
+                sender: me.username().to_string(),
+                sender_device: me.device_id.clone(),
+                recipient: peer.username().to_string(),
+                device: device.device_id.clone(),
+                ephemeral_pk: epk.as_ref().to_vec(),
+                nonce: nonce.0.to_vec(),
+                ciphertext,
+            })
+        })
+        .collect();
+    let synced = SyncedMessage {
+        peer: peer.username().to_string(),
+        message_id: message_id.to_string(),
+        payload: Box::new(payload.clone()),
+    };
+    envelopes.extend(other_devices(me).iter().filter_map(|device| synced.seal(me, device)));
+    envelopes
+}
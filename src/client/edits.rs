@@ -0,0 +1,312 @@
+/*
+ * This module implements message edits and deletes for everyone.
+ *
+ * An edit (`Payload::Edit`) or a delete (`Payload::Delete`) is sent as
+ * a message of its own, in the same conversation, naming the id of the
+ * message it changes. It is stored like any message, so the earlier
+ * versions of an edited message stay in the local history, encrypted.
+ *
+ * Each device decides for itself whether to honour a request: it must
+ * come from the author of the message, and be made within the edit
+ * window of the original. Only text can be edited; a deleted message
+ * stays deleted.
+ *
+ * The window is measured with the `SentTimes` the request carries: when
+ * it was made and when the original was sent, both by the sender's
+ * clock, so a device that was offline, or a request that was retried,
+ * is judged as if it had arrived at once. Requests from clients that
+ * did not send these times fall back to this device's own
+ * `StoredMessage::sent_at` of both. The times are the sender's word:
+ * the window keeps honest clients from rewriting old messages, it does
+ * not stop a modified one.
+ *
+ * Likewise, "the author" is the sender the request arrived from. Group
+ * messages are signed with the sender's key, but a one-to-one message
+ * is only as authenticated as the relay that delivered it (it checks
+ * the login of the sending device); the ciphertext does not prove who
+ * wrote it.
+ *
+ * `Revisions` folds the requests of a conversation into what the chat
+ * shows: the current text and earlier versions of edited messages, the
+ * deleted ones, and the requests that were refused (and why).
+ */
+
+use crate::client::payload::Payload;
+use crate::client::sessions::StoredMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/* Default window to edit or delete a message after it was sent, in seconds */
+pub const DEFAULT_EDIT_WINDOW: u64 = 15 * 60;
+
+/*
+ * When an edit or delete was made, and when the message it changes was
+ * sent, by the clock of its sender (milliseconds since the Unix epoch).
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SentTimes {
+    pub sent_at: u64,
+    pub original_sent_at: u64,
+}
+
+/*
+ * Reasons why an edit or a delete is refused.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    /* No message with that id in the conversation */
+    UnknownMessage,
+    /* The request does not come from the author of the message */
+    NotAuthor,
+    /* The request came after the edit window */
+    TooLate,
+    /* Only text messages can be edited */
+    NotText,
+    /* The message was deleted */
+    Deleted,
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::UnknownMessage => write!(f, "unknown message"),
+            EditError::NotAuthor => write!(f, "not from the author of the message"),
+            EditError::TooLate => write!(f, "after the edit window"),
+            EditError::NotText => write!(f, "only text can be edited"),
+            EditError::Deleted => write!(f, "the message was deleted"),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+/*
+ * Checks an edit (`edit` is `true`) or delete stored as `request`,
+ * carrying `times`, against the message it names, `target` (with its
+ * payload).
+ *
+ * `window_ms` is the edit window, in milliseconds.
+ */
+pub fn check(
+    request: &StoredMessage,
+    times: Option<SentTimes>,
+    target: Option<(&StoredMessage, &Payload)>,
+    edit: bool,
+    deleted: bool,
+    window_ms: u64,
+) -> Result<(), EditError> {
+    let (message, payload) = target.ok_or(EditError::UnknownMessage)?;
+    if message.sender.is_empty() || request.sender != message.sender {
+        return Err(EditError::NotAuthor);
+    }
+    if deleted {
+        return Err(EditError::Deleted);
+    }
+    let (sent_at, original_sent_at) = match times {
+        Some(times) => (times.sent_at, times.original_sent_at),
+        None => (request.sent_at, message.sent_at),
+    };
+    if message.sent_at == 0 || original_sent_at == 0 || sent_at.saturating_sub(original_sent_at) > window_ms {
+        return Err(EditError::TooLate);
+    }
+    if edit && !matches!(payload, Payload::Text { .. }) {
+        return Err(EditError::NotText);
+    }
+    Ok(())
+}
+
+/* Whether `me` can still edit or delete `message` at `now_ms` */
+pub fn can_change(message: &StoredMessage, me: &str, now_ms: u64, window_ms: u64) -> bool {
+    message.sender == me && message.sent_at != 0 && now_ms.saturating_sub(message.sent_at) <= window_ms
+}
+
+/*
+ * The edits and deletes of a conversation, applied.
+ *
+ * Fields:
+ *  - `versions` : For each edited message, its texts from the original
+ *                 to the current one
+ *  - `deleted`  : The deleted messages
+ *  - `refused`  : The refused requests (by id), with the reason
+ */
+#[derive(Default)]
+pub struct Revisions {
+    versions: HashMap<String, Vec<String>>,
+    deleted: HashSet<String>,
+    refused: HashMap<String, EditError>,
+}
+
+impl Revisions {
+    /*
+     * Applies the requests among `messages` (a conversation, in order,
+     * with their payloads) to the messages they name.
+     */
+    pub fn apply<'a>(messages: &[(&'a StoredMessage, &'a Payload)], window_ms: u64) -> Self {
+        let by_id: HashMap<&str, (&StoredMessage, &Payload)> = messages
+            .iter()
+            .filter(|(message, _)| !message.id.is_empty())
+            .map(|(message, payload)| (message.id.as_str(), (*message, *payload)))
+            .collect();
+        let mut revisions = Revisions::default();
+        for (request, payload) in messages {
+            let (target, edit, times) = match payload {
+                Payload::Edit { target, text, times } => (target, Some(text), *times),
+                Payload::Delete { target, times } => (target, None, *times),
+                _ => continue,
+            };
+            let original = by_id.get(target.as_str()).copied();
+            let deleted = revisions.deleted.contains(target);
+            if let Err(e) = check(request, times, original, edit.is_some(), deleted, window_ms) {
+                revisions.refused.insert(request.id.clone(), e);
+                continue;
+            }
+            let Some(text) = edit else {
+                revisions.deleted.insert(target.clone());
+                continue;
+            };
+            if let Some((_, Payload::Text { text: first, .. })) = original {
+                revisions
+                    .versions
+                    .entry(target.clone())
+                    .or_insert_with(|| vec![first.clone()])
+                    .push(text.clone());
+            }
+        }
+        revisions
+    }
+
+    /* The texts of message `id`, from the original to the current one, if it was edited */
+    pub fn versions(&self, id: &str) -> Option<&[String]> {
+        self.versions.get(id).map(Vec::as_slice)
+    }
+
+    /* Whether message `id` was deleted */
+    pub fn is_deleted(&self, id: &str) -> bool {
+        self.deleted.contains(id)
+    }
+
+    /* Why the request `id` was refused, if it was */
+    pub fn refused(&self, id: &str) -> Option<&EditError> {
+        self.refused.get(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: u64 = DEFAULT_EDIT_WINDOW * 1000;
+
+    fn stored(id: &str, sender: &str, sent_at: u64) -> StoredMessage {
+        StoredMessage {
+            id: id.to_string(),
+            sender: sender.to_string(),
+            sent_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_the_author_changes_text_in_time() {
+        let original = stored("m1", "alice", 1_000);
+        let text = Payload::text("hi");
+        let target = Some((&original, &text));
+        let request = |sender: &str, at: u64| stored("r1", sender, at);
+
+        assert_eq!(check(&request("alice", 1_000 + WINDOW), None, target, true, false, WINDOW), Ok(()));
+        assert_eq!(check(&request("alice", 2_000), None, target, false, false, WINDOW), Ok(()));
+        assert_eq!(check(&request("bob", 2_000), None, target, true, false, WINDOW), Err(EditError::NotAuthor));
+        assert_eq!(check(&request("", 2_000), None, target, true, false, WINDOW), Err(EditError::NotAuthor));
+        assert_eq!(check(&request("alice", 1_001 + WINDOW), None, target, true, false, WINDOW), Err(EditError::TooLate));
+        assert_eq!(check(&request("alice", 2_000), None, None, true, false, WINDOW), Err(EditError::UnknownMessage));
+        assert_eq!(check(&request("alice", 2_000), None, target, true, true, WINDOW), Err(EditError::Deleted));
+        assert_eq!(check(&request("alice", 2_000), None, target, false, true, WINDOW), Err(EditError::Deleted));
+
+        /* A request stored before the original (clock skew) is in time */
+        assert_eq!(check(&request("alice", 0), None, target, true, false, WINDOW), Ok(()));
+
+        /* Messages without an author or a time cannot be changed */
+        let anonymous = stored("m2", "", 1_000);
+        assert_eq!(check(&request("", 2_000), None, Some((&anonymous, &text)), true, false, WINDOW), Err(EditError::NotAuthor));
+        let undated = stored("m3", "alice", 0);
+        assert_eq!(check(&request("alice", 2_000), None, Some((&undated, &text)), true, false, WINDOW), Err(EditError::TooLate));
+
+        let reaction = Payload::Reaction {
+            target: "m0".to_string(),
+            emoji: "👍".to_string(),
+            remove: false,
+        };
+        assert_eq!(check(&request("alice", 2_000), None, Some((&original, &reaction)), true, false, WINDOW), Err(EditError::NotText));
+        assert_eq!(check(&request("alice", 2_000), None, Some((&original, &reaction)), false, false, WINDOW), Ok(()));
+    }
+
+    #[test]
+    fn revisions_fold_the_requests() {
+        let edit = |target: &str, text: &str| Payload::Edit {
+            target: target.to_string(),
+            text: text.to_string(),
+            times: None,
+        };
+        let delete = |target: &str| Payload::Delete {
+            target: target.to_string(),
+            times: None,
+        };
+        let messages = [
+            (stored("m1", "alice", 1_000), Payload::text("one")),
+            (stored("m2", "bob", 1_000), Payload::text("two")),
+            (stored("e1", "alice", 2_000), edit("m1", "one!")),
+            (stored("e2", "alice", 3_000), edit("m1", "one!!")),
+            (stored("e3", "alice", 3_000), edit("m2", "hijacked")),
+            (stored("d1", "bob", 4_000), delete("m2")),
+            (stored("e4", "bob", 5_000), edit("m2", "too late")),
+            (stored("e5", "alice", 2_000 + WINDOW), edit("m1", "late")),
+        ];
+        let refs: Vec<(&StoredMessage, &Payload)> = messages.iter().map(|(m, p)| (m, p)).collect();
+        let revisions = Revisions::apply(&refs, WINDOW);
+
+        assert_eq!(revisions.versions("m1"), Some(&["one".to_string(), "one!".to_string(), "one!!".to_string()][..]));
+        assert!(revisions.is_deleted("m2"));
+        assert!(!revisions.is_deleted("m1"));
+        assert_eq!(revisions.refused("e3"), Some(&EditError::NotAuthor));
+        assert_eq!(revisions.refused("e4"), Some(&EditError::Deleted));
+        assert_eq!(revisions.refused("e5"), Some(&EditError::TooLate));
+        assert_eq!(revisions.refused("e1"), None);
+    }
+
+    #[test]
+    fn the_window_is_the_senders() {
+        let original = stored("m1", "alice", 1_000);
+        let text = Payload::text("hi");
+        let target = Some((&original, &text));
+        let times = |sent_at: u64, original_sent_at: u64| Some(SentTimes { sent_at, original_sent_at });
+
+        /* Reaching this device long after the window (offline, retried), but made in time */
+        let late = stored("r1", "alice", 10 * WINDOW);
+        assert_eq!(check(&late, times(500 + WINDOW, 500), target, true, false, WINDOW), Ok(()));
+
+        /* Reaching this device at once, but made after the window */
+        let prompt = stored("r2", "alice", 2_000);
+        assert_eq!(check(&prompt, times(501 + WINDOW, 500), target, true, false, WINDOW), Err(EditError::TooLate));
+
+        /* A message sent without a time cannot be changed, whatever the request says */
+        assert_eq!(check(&prompt, times(2_000, 0), target, true, false, WINDOW), Err(EditError::TooLate));
+        let undated = stored("m2", "alice", 0);
+        assert_eq!(check(&prompt, times(2_000, 1_000), Some((&undated, &text)), true, false, WINDOW), Err(EditError::TooLate));
+    }
+
+    #[test]
+    fn times_are_optional_on_the_wire() {
+        let old = r#"{"kind": "edit", "version": 1, "body": {"target": "m1", "text": "one!"}}"#;
+        assert!(matches!(Payload::decode(old), Some(Payload::Edit { times: None, .. })));
+
+        let delete = Payload::Delete {
+            target: "m1".to_string(),
+            times: Some(SentTimes { sent_at: 2, original_sent_at: 1 }),
+        };
+        let Some(Payload::Delete { times, .. }) = Payload::decode(&delete.encode()) else {
+            panic!("not a delete");
+        };
+        assert_eq!(times, Some(SentTimes { sent_at: 2, original_sent_at: 1 }));
+    }
+}
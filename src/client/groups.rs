@@ -0,0 +1,664 @@
+/*
+ * This module implements group conversations with Sender Keys
+ * (the scheme Signal uses for groups).
+ *
+ * Each member owns a sender key for the group:
+ *  - a chain key, ratcheted forward once per message:
+ *        message_key = HMAC-SHA256(chain_key, 0x01)
+ *        chain_key'  = HMAC-SHA256(chain_key, 0x02)
+ *  - an Ed25519 signing key pair, so members cannot forge each other's messages
+ *
+ * The public part of the sender key (chain key, iteration, signing public
+ * key) is distributed to every other member over the existing pairwise
+ * channel (`SenderKeyDistribution`, a control message, see `client::control`).
+ *
+ * A group message is then encrypted once with the current message key
+ * (XSalsa20-Poly1305), signed, and the same ciphertext is fanned out in
+ * one envelope per member. Receivers ratchet the sender's chain to the
+ * message's iteration, keeping the keys of skipped iterations so messages
+ * delivered out of order can still be read.
+ *
+ * Members and admins are managed with signed updates (see
+ * `client::membership`); when someone leaves, the remaining members
+ * rotate their sender keys.
+ *
+ * A group can instead be created in TreeKEM mode (see `client::treekem`):
+ * its messages are then encrypted with the epoch secrets of a ratchet
+ * tree, and no sender keys are exchanged.
+ *
+ * Group state (members, own and received sender keys) is stored in the
+ * `Session`, next to the conversations. Group conversations are keyed by
+ * the group id, which starts with `#` and thus never collides with a
+ * username.
+ *
+ * Note: like the rest of this educational client, stored messages keep
+ * what is needed to decrypt them again (here, the message key), so the
+ * history can be displayed with its logs.
+ */
+
+use crate::client::control;
+use crate::client::membership::{GroupOp, GroupUpdate};
+use crate::client::payload::Payload;
+use crate::client::treekem::TreeKem;
+use crate::client::user::User;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::{secretbox, sign};
+use sodiumoxide::randombytes;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/* How far ahead of the known iteration a message may be (bounds the work per message) */
+pub const MAX_SKIP: u32 = 1000;
+
+/* How many keys of skipped iterations a chain keeps (the oldest are dropped first) */
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+/* Reasons a group message can be rejected */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupError {
+    /* The sender is not a member of the group */
+    NotAMember,
+    /* No sender key received (yet) from the sender for this chain */
+    MissingSenderKey,
+    /* The message uses a sender key that was replaced since */
+    StaleSenderKey,
+    /* The message was not signed by the sender's signing key */
+    BadSignature,
+    /* The message is more than `MAX_SKIP` iterations ahead */
+    TooFarAhead,
+    /* The message key was already used (or discarded) */
+    Replayed,
+    /* The chain reached its last iteration (a new sender key is needed) */
+    ChainExhausted,
+    /* The ciphertext does not decrypt */
+    DecryptionFailed,
+    /* The payload is of a kind or version this client does not read */
+    Unreadable,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            GroupError::NotAMember => "sender is not a member of the group",
+            GroupError::MissingSenderKey => "no sender key for this sender",
+            GroupError::StaleSenderKey => "sender key was rotated",
+            GroupError::BadSignature => "bad sender signature",
+            GroupError::TooFarAhead => "message too far ahead in the chain",
+            GroupError::Replayed => "message key already used",
+            GroupError::ChainExhausted => "sender key chain exhausted",
+            GroupError::DecryptionFailed => "decryption failed",
+            GroupError::Unreadable => "payload this client does not read",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for GroupError {}
+
+/*
+ * A sender key chain.
+ *
+ * Fields:
+ *  - `key_id`     : Random identifier of the chain (a new chain gets a new id)
+ *  - `chain_key`  : Current chain key (for iteration `iteration`)
+ *  - `iteration`  : Index of the next message key
+ *  - `signing_pk` : Ed25519 public key verifying the chain's messages
+ *  - `signing_sk` : Matching secret key, only in our own chain
+ *  - `skipped`    : Message keys of iterations skipped over (received chains only)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SenderKey {
+    pub key_id: String,
+    pub chain_key: [u8; 32],
+    pub iteration: u32,
+    pub signing_pk: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_sk: Option<Vec<u8>>,
+    #[serde(default)]
+    pub skipped: HashMap<u32, [u8; 32]>,
+}
+
+/* HMAC-SHA256(key, [byte]): one step of the chain */
+fn chain_step(chain_key: &[u8; 32], byte: u8) -> [u8; 32] {
+    let key = hmacsha256::Key(*chain_key);
+    hmacsha256::authenticate(&[byte], &key).0
+}
+
+impl SenderKey {
+    /* Generates a fresh chain (with its signing key pair) for ourselves */
+    pub fn generate() -> Self {
+        let (signing_pk, signing_sk) = sign::gen_keypair();
+        let mut chain_key = [0u8; 32];
+        chain_key.copy_from_slice(&randombytes::randombytes(32));
+        Self {
+            key_id: hex::encode(randombytes::randombytes(8)),
+            chain_key,
+            iteration: 0,
+            signing_pk: signing_pk.as_ref().to_vec(),
+            signing_sk: Some(signing_sk.as_ref().to_vec()),
+            skipped: HashMap::new(),
+        }
+    }
+
+    /* The part of the chain that is sent to other members */
+    pub fn public(&self) -> Self {
+        Self {
+            signing_sk: None,
+            skipped: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /* Derives the message key of the current iteration and ratchets forward */
+    fn advance(&mut self) -> Result<(u32, [u8; 32]), GroupError> {
+        let iteration = self.iteration;
+        let next = iteration.checked_add(1).ok_or(GroupError::ChainExhausted)?;
+        let message_key = chain_step(&self.chain_key, 0x01);
+        self.chain_key = chain_step(&self.chain_key, 0x02);
+        self.iteration = next;
+        Ok((iteration, message_key))
+    }
+
+    /*
+     * Returns the message key for `iteration`, ratcheting forward if needed.
+     *
+     * Keys of skipped iterations are kept, up to `MAX_SKIPPED_KEYS`
+     * (those of the oldest iterations go first); every key is handed out once.
+     */
+    fn message_key(&mut self, iteration: u32) -> Result<[u8; 32], GroupError> {
+        if iteration < self.iteration {
+            return self.skipped.remove(&iteration).ok_or(GroupError::Replayed);
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return Err(GroupError::TooFarAhead);
+        }
+        loop {
+            let (i, key) = self.advance()?;
+            if i == iteration {
+                return Ok(key);
+            }
+            self.skipped.insert(i, key);
+            if self.skipped.len() > MAX_SKIPPED_KEYS {
+                if let Some(oldest) = self.skipped.keys().min().copied() {
+                    self.skipped.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+/*
+ * A message encrypted with a sender key.
+ *
+ * Fields:
+ *  - `group_id`   : Group the message belongs to
+ *  - `message_id` : Identifier shared by all copies (`StoredMessage::id`)
+ *  - `key_id`     : Sender key chain used
+ *  - `iteration`  : Chain iteration of the message key
+ *  - `nonce`      : XSalsa20-Poly1305 nonce
+ *  - `ciphertext` : Encrypted text
+ *  - `signature`  : Signature of all the above by the chain's signing key
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupMessage {
+    pub group_id: String,
+    pub message_id: String,
+    pub key_id: String,
+    pub iteration: u32,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl GroupMessage {
+    /* The bytes covered by the signature */
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [&self.group_id, &self.message_id, &self.key_id] {
+            data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            data.extend_from_slice(field.as_bytes());
+        }
+        data.extend_from_slice(&self.iteration.to_be_bytes());
+        data.extend_from_slice(&self.nonce);
+        data.extend_from_slice(&self.ciphertext);
+        data
+    }
+
+    /*
+     * Wraps the message from `sender` in an envelope for one member.
+     * Every member gets the same ciphertext, under a distinct envelope id.
+     * Group traffic goes between primary devices (the group state and our
+     * sender key live there, see `ui::app`).
+     */
+    pub fn envelope(&self, sender: &User, recipient: &str) -> Option<Envelope> {
+        Some(Envelope {
+            id: format!("{}.{}", self.message_id, recipient),
+            kind: EnvelopeKind::Group,
+            sender: sender.username().to_string(),
+            sender_device: sender.device_id.clone(),
+            recipient: recipient.to_string(),
+            device: String::new(),
+            ephemeral_pk: Vec::new(),
+            nonce: self.nonce.clone(),
+            ciphertext: serde_json::to_vec(self).ok()?,
+        })
+    }
+
+    /* Extracts a group message from an envelope of kind `Group` */
+    pub fn from_envelope(envelope: &Envelope) -> Option<Self> {
+        if envelope.kind != EnvelopeKind::Group {
+            return None;
+        }
+        serde_json::from_slice(&envelope.ciphertext).ok()
+    }
+}
+
+/*
+ * Decrypts a stored group message with its message key
+ * (see `StoredMessage::message_key`).
+ */
+pub fn open_stored(message_key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Option<Payload> {
+    Payload::decode(&open_plaintext(message_key, nonce, ciphertext)?)
+}
+
+fn open_plaintext(message_key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Option<String> {
+    let key = secretbox::Key::from_slice(message_key)?;
+    let nonce = secretbox::Nonce::from_slice(nonce)?;
+    let plaintext = secretbox::open(ciphertext, &nonce, &key).ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
+/* The public sender key of a member for a group */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SenderKeyDistribution {
+    pub group_id: String,
+    pub key: SenderKey,
+}
+
+impl SenderKeyDistribution {
+    /* Encrypts the distribution from `sender` to `peer` (envelope kind `SenderKey`) */
+    pub fn seal(&self, sender: &User, peer: &User) -> Option<Envelope> {
+        control::seal(EnvelopeKind::SenderKey, self, sender, peer)
+    }
+
+    /* Decrypts a distribution addressed to `me`, if the envelope holds one */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::SenderKey, envelope, me)
+    }
+}
+
+/*
+ * A group conversation.
+ *
+ * Fields:
+ *  - `id`           : Random identifier, starts with `#`
+ *  - `name`         : Display name
+ *  - `members`      : Usernames of all members, including ourselves
+ *  - `admins`       : Members allowed to change the group
+ *  - `version`      : Version of the last applied update
+ *  - `history`      : Every applied update, from the creation
+ *  - `pending`      : Updates received ahead of their turn
+ *  - `own`          : Our sender key chain
+ *  - `peers`        : Sender key chains received from the other members
+ *  - `retired_keys` : Ids of replaced sender key chains
+ *  - `tree`         : Our ratchet tree, in TreeKEM mode once welcomed
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<String>,
+    pub admins: Vec<String>,
+    pub version: u64,
+    pub history: Vec<GroupUpdate>,
+    #[serde(default)]
+    pub pending: Vec<GroupUpdate>,
+    pub own: SenderKey,
+    #[serde(default)]
+    pub peers: HashMap<String, SenderKey>,
+    #[serde(default)]
+    pub retired_keys: HashSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<TreeKem>,
+}
+
+/* Generates a fresh group identifier */
+pub fn new_group_id() -> String {
+    format!("#{}", hex::encode(randombytes::randombytes(8)))
+}
+
+/* Whether a conversation key designates a group */
+pub fn is_group(conversation: &str) -> bool {
+    conversation.starts_with('#')
+}
+
+impl Group {
+    /* Whether the group was created in TreeKEM mode */
+    pub fn uses_treekem(&self) -> bool {
+        matches!(self.history.first().map(|u| &u.op), Some(GroupOp::Create { treekem: true, .. }))
+    }
+
+    /*
+     * The member making the TreeKEM commits for membership changes:
+     * the first admin, or the first member if no admin is left.
+     */
+    pub fn committer(&self) -> Option<&String> {
+        self.admins.first().or(self.members.first())
+    }
+
+    /* Our sender key distribution message for this group */
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            group_id: self.id.clone(),
+            key: self.own.public(),
+        }
+    }
+
+    /*
+     * Replaces our sender key with a fresh chain, e.g. after a member
+     * left: they know the old chain, not the new one. The new key must
+     * then be distributed to the remaining members.
+     */
+    pub fn rotate_sender_key(&mut self) {
+        self.own = SenderKey::generate();
+    }
+
+    /* The other members of the group */
+    pub fn others<'a>(&'a self, me: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.members.iter().filter(move |m| m.as_str() != me)
+    }
+
+    /*
+     * Stores the sender key `sender` distributed to us, retiring the
+     * chain it replaces.
+     *
+     * A distribution of the chain we already hold only moves it forward
+     * (the keys skipped so far are kept): one for an iteration we are
+     * past would let old messages be read again.
+     *
+     * Returns `false` if `sender` is not a member of the group, if the
+     * key was already retired, or if it does not move the chain forward.
+     */
+    pub fn accept_distribution(&mut self, sender: &str, key: SenderKey) -> bool {
+        if !self.members.iter().any(|m| m == sender) || self.retired_keys.contains(&key.key_id) {
+            return false;
+        }
+        match self.peers.get_mut(sender) {
+            Some(chain) if chain.key_id == key.key_id => {
+                if key.iteration <= chain.iteration || key.signing_pk != chain.signing_pk {
+                    return false;
+                }
+                chain.chain_key = key.chain_key;
+                chain.iteration = key.iteration;
+            }
+            _ => {
+                let key = SenderKey {
+                    signing_sk: None,
+                    skipped: HashMap::new(),
+                    ..key
+                };
+                if let Some(old) = self.peers.insert(sender.to_string(), key) {
+                    self.retired_keys.insert(old.key_id);
+                }
+            }
+        }
+        true
+    }
+
+    /*
+     * Encrypts `payload` once with our sender key.
+     *
+     * Returns the message, the message key (kept for the local history)
+     * and a step-by-step log.
+     */
+    pub fn encrypt(&mut self, message_id: String, payload: &Payload) -> Result<(GroupMessage, [u8; 32], String), GroupError> {
+        let chain_before = self.own.chain_key;
+        let (iteration, message_key) = self.own.advance()?;
+        let nonce = secretbox::gen_nonce();
+        let ciphertext = secretbox::seal(payload.encode().as_bytes(), &nonce, &secretbox::Key(message_key));
+
+        let mut message = GroupMessage {
+            group_id: self.id.clone(),
+            message_id,
+            key_id: self.own.key_id.clone(),
+            iteration,
+            nonce: nonce.0.to_vec(),
+            ciphertext,
+            signature: Vec::new(),
+        };
+        let signing_sk = self
+            .own
+            .signing_sk
+            .as_deref()
+            .and_then(sign::SecretKey::from_slice)
+            .expect("own sender key has a signing key");
+        message.signature = sign::sign_detached(&message.signed_bytes(), &signing_sk).as_ref().to_vec();
+
+        let log = format!(
+            concat!(
+                "== log (group send) ==\n",
+                "Group: {} ({} members)\n",
+                "Sender key: {} iteration {}\n",
+                "Chain key: {}\n",
+                "Message key = HMAC(chain key, 0x01): {}\n",
+                "Next chain key = HMAC(chain key, 0x02): {}\n",
+                "Nonce: {}\n",
+                "Ciphertext: {}\n",
+                "Signed with the sender signing key, fanned out to {} members\n"
+            ),
+            self.name,
+            self.members.len(),
+            self.own.key_id,
+            iteration,
+            hex::encode(chain_before),
+            hex::encode(message_key),
+            hex::encode(self.own.chain_key),
+            hex::encode(nonce.0),
+            hex::encode(&message.ciphertext),
+            self.members.len().saturating_sub(1),
+        );
+
+        Ok((message, message_key, log))
+    }
+
+    /*
+     * Decrypts a message from `sender` with their sender key.
+     *
+     * Returns the payload, the message key and a step-by-step log.
+     */
+    pub fn decrypt(&mut self, sender: &str, message: &GroupMessage) -> Result<(Payload, [u8; 32], String), GroupError> {
+        if !self.members.iter().any(|m| m == sender) {
+            return Err(GroupError::NotAMember);
+        }
+        if self.retired_keys.contains(&message.key_id) {
+            return Err(GroupError::StaleSenderKey);
+        }
+        let chain = self.peers.get_mut(sender).ok_or(GroupError::MissingSenderKey)?;
+        if chain.key_id != message.key_id {
+            return Err(GroupError::MissingSenderKey);
+        }
+        let signing_pk = sign::PublicKey::from_slice(&chain.signing_pk).ok_or(GroupError::BadSignature)?;
+        let signature =
+            sign::Signature::try_from(message.signature.as_slice()).map_err(|_| GroupError::BadSignature)?;
+        if !sign::verify_detached(&signature, &message.signed_bytes(), &signing_pk) {
+            return Err(GroupError::BadSignature);
+        }
+
+        let known = chain.iteration;
+        let message_key = chain.message_key(message.iteration)?;
+        let plaintext =
+            open_plaintext(&message_key, &message.nonce, &message.ciphertext).ok_or(GroupError::DecryptionFailed)?;
+        let payload = Payload::decode(&plaintext).ok_or(GroupError::Unreadable)?;
+
+        let log = format!(
+            concat!(
+                "== log (group recv) ==\n",
+                "Group: {}\nSender: {}\n",
+                "Signature by sender signing key: valid\n",
+                "Sender key: {} iteration {} (chain was at {}, {} skipped keys kept)\n",
+                "Message key: {}\n",
+                "Payload: {} v{}\n",
+                "Plaintext: {}\n"
+            ),
+            self.name,
+            sender,
+            message.key_id,
+            message.iteration,
+            known,
+            chain.skipped.len(),
+            hex::encode(message_key),
+            payload.kind(),
+            payload.version(),
+            payload.encode(),
+        );
+
+        Ok((payload, message_key, log))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A group of alice and bob, as alice created it and as bob holds it with alice's sender key */
+    fn pair() -> (Group, Group) {
+        let alice = User::new("alice", 1);
+        let (sent, update) = Group::create(new_group_id(), "test", vec!["bob".to_string()], false, &alice).unwrap();
+        let mut received = Group::from_create(&update, &alice.identity_pk).unwrap();
+        assert!(received.accept_distribution("alice", sent.distribution().key));
+        (sent, received)
+    }
+
+    fn send(group: &mut Group, text: &str) -> GroupMessage {
+        group.encrypt(text.to_string(), &Payload::text(text)).unwrap().0
+    }
+
+    fn read(group: &mut Group, message: &GroupMessage) -> Result<String, GroupError> {
+        group.decrypt("alice", message).map(|(payload, _, _)| payload.encode())
+    }
+
+    #[test]
+    fn out_of_order_messages_are_read_once() {
+        let (mut alice, mut bob) = pair();
+        let first = send(&mut alice, "one");
+        let second = send(&mut alice, "two");
+
+        assert_eq!(read(&mut bob, &second), Ok(Payload::text("two").encode()));
+        assert_eq!(read(&mut bob, &first), Ok(Payload::text("one").encode()));
+        assert_eq!(read(&mut bob, &first), Err(GroupError::Replayed));
+        assert_eq!(read(&mut bob, &second), Err(GroupError::Replayed));
+    }
+
+    #[test]
+    fn replayed_distribution_does_not_rewind_the_chain() {
+        let (mut alice, mut bob) = pair();
+        let old = alice.distribution().key;
+        let message = send(&mut alice, "one");
+        read(&mut bob, &message).unwrap();
+
+        assert!(!bob.accept_distribution("alice", old));
+        assert_eq!(read(&mut bob, &message), Err(GroupError::Replayed));
+    }
+
+    #[test]
+    fn later_distribution_moves_the_chain_forward() {
+        let (mut alice, mut bob) = pair();
+        let skipped = send(&mut alice, "one");
+        let later = alice.distribution().key;
+        assert!(bob.accept_distribution("alice", later.clone()));
+        assert!(!bob.accept_distribution("alice", later));
+
+        let message = send(&mut alice, "two");
+        assert_eq!(read(&mut bob, &message), Ok(Payload::text("two").encode()));
+        assert_eq!(read(&mut bob, &skipped), Err(GroupError::Replayed));
+    }
+
+    #[test]
+    fn distribution_with_another_signing_key_is_ignored() {
+        let (alice, mut bob) = pair();
+        let mut forged = alice.distribution().key;
+        forged.iteration += 1;
+        forged.signing_pk = SenderKey::generate().signing_pk;
+        assert!(!bob.accept_distribution("alice", forged));
+    }
+
+    #[test]
+    fn distributed_skipped_keys_are_not_trusted() {
+        let (mut alice, mut bob) = pair();
+        let mut rotated = SenderKey::generate();
+        rotated.skipped.insert(0, [7; 32]);
+        assert!(bob.accept_distribution("alice", rotated.clone()));
+        assert!(bob.peers["alice"].skipped.is_empty() && bob.peers["alice"].signing_sk.is_none());
+
+        /* The old chain is retired */
+        let message = send(&mut alice, "one");
+        assert_eq!(read(&mut bob, &message), Err(GroupError::StaleSenderKey));
+        assert!(!bob.accept_distribution("alice", alice.distribution().key));
+    }
+
+    #[test]
+    fn skipped_keys_are_capped() {
+        let mut chain = SenderKey::generate();
+        for _ in 0..3 {
+            let ahead = chain.iteration + MAX_SKIP;
+            chain.message_key(ahead).unwrap();
+        }
+        assert_eq!(chain.skipped.len(), MAX_SKIPPED_KEYS);
+        /* The oldest ones were dropped */
+        assert_eq!(chain.message_key(0), Err(GroupError::Replayed));
+        assert!(chain.message_key(chain.iteration - 2).is_ok());
+    }
+
+    #[test]
+    fn message_too_far_ahead_is_refused() {
+        let (mut alice, mut bob) = pair();
+        let mut message = send(&mut alice, "one");
+        message.iteration = MAX_SKIP + 1;
+        assert_eq!(bob.peers["alice"].clone().message_key(message.iteration), Err(GroupError::TooFarAhead));
+        assert_eq!(read(&mut bob, &message), Err(GroupError::BadSignature));
+    }
+
+    #[test]
+    fn unreadable_payloads_are_told_apart() {
+        let (mut alice, mut bob) = pair();
+        let (mut message, message_key, _) = alice.encrypt("m1".to_string(), &Payload::text("one")).unwrap();
+        let nonce = secretbox::Nonce::from_slice(&message.nonce).unwrap();
+        let poll = r#"{"kind": "poll", "version": 1, "body": {}}"#;
+        message.ciphertext = secretbox::seal(poll.as_bytes(), &nonce, &secretbox::Key(message_key));
+        let signing_sk = sign::SecretKey::from_slice(alice.own.signing_sk.as_deref().unwrap()).unwrap();
+        message.signature = sign::sign_detached(&message.signed_bytes(), &signing_sk).as_ref().to_vec();
+
+        assert_eq!(read(&mut bob, &message), Err(GroupError::Unreadable));
+        assert!(open_stored(&message_key, &message.nonce, &message.ciphertext).is_none());
+    }
+
+    #[test]
+    fn envelope_names_the_sending_device() {
+        let (mut alice, _) = pair();
+        let message = send(&mut alice, "one");
+        let sender = User::from_seed_on_device("alice", &[1; 32], 1, "laptop");
+        let envelope = message.envelope(&sender, "bob").unwrap();
+        assert_eq!((envelope.sender.as_str(), envelope.sender_device.as_str()), ("alice", "laptop"));
+        assert_eq!(GroupMessage::from_envelope(&envelope).unwrap().signature, message.signature);
+    }
+
+    #[test]
+    fn exhausted_chain_stops() {
+        let mut chain = SenderKey::generate();
+        chain.iteration = u32::MAX;
+        assert_eq!(chain.advance(), Err(GroupError::ChainExhausted));
+        assert_eq!(chain.iteration, u32::MAX);
+    }
+
+    #[test]
+    fn message_from_another_signer_is_refused() {
+        let (mut alice, mut bob) = pair();
+        let mut message = send(&mut alice, "one");
+        let (_, other) = sign::gen_keypair();
+        message.signature = sign::sign_detached(&message.signed_bytes(), &other).as_ref().to_vec();
+        assert_eq!(read(&mut bob, &message), Err(GroupError::BadSignature));
+        assert_eq!(bob.decrypt("mallory", &message).err(), Some(GroupError::NotAMember));
+    }
+}
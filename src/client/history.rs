@@ -0,0 +1,445 @@
+/*
+ * This module implements history transfer: sending the one-to-one
+ * conversations of a `Session` to a newly linked device of the same
+ * account, on the user's request.
+ *
+ * The sending device decrypts its history into `HistoryRecord`s and
+ * encrypts them again under a fresh one-time transfer key with
+ * secretstream (XChaCha20-Poly1305), in chunks of `CHUNK_LEN` bytes:
+ *
+ *   Offer  { transfer_id, key, header, chunks, digest = SHA256(records) }
+ *   Chunk  { transfer_id, index, data }   (the last one tagged `Final`)
+ *
+ * Each of these travels as an encrypted control message (envelope kind
+ * `History`) to the new device only, through the relay like any other
+ * envelope. The receiving device collects the chunks in any order, then
+ * decrypts them in stream order: a chunk that was dropped, reordered,
+ * altered or cut off (no `Final` tag), or a digest mismatch, rejects
+ * the whole transfer.
+ *
+ * Once checked, the records are encrypted for the new device's own store
+ * (like synced messages, see `client::devices`) and merged into its
+ * `Session`: messages it already has, by id, are not stored twice.
+ *
+ * Group conversations are not transferred: they stay on the primary
+ * device (see `client::devices`).
+ */
+
+use crate::client::contacts::Contacts;
+use crate::client::control;
+use crate::client::groups;
+use crate::client::payload::Payload;
+use crate::client::sessions::{new_message_id, Session, StoredMessage};
+use crate::client::user::User;
+use crate::net::devices::DeviceInfo;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::secretstream::{self, Header, Key, Stream, Tag};
+use sodiumoxide::crypto::box_;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/* Size of the plaintext carried by one chunk, in bytes */
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/*
+ * Largest transfer a device accepts, in chunks. The receiving device
+ * acknowledges the chunks only once the transfer is complete, so they
+ * all wait in its relay mailbox together, well within its limits.
+ */
+pub const MAX_CHUNKS: u32 = 512;
+
+/*
+ * One message of the history, in clear.
+ *
+ * Fields:
+ *  - `peer`    : The conversation (contact username)
+ *  - `id`      : Id of the message (`StoredMessage::id`)
+ *  - `sender`  : Author of the message
+ *  - `payload` : The message
+ *  - `read`    : Whether it was read
+ *  - `sent_at` : When it was stored on the sending device (see `StoredMessage::sent_at`)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryRecord {
+    pub peer: String,
+    pub id: String,
+    pub sender: String,
+    pub payload: Payload,
+    pub read: bool,
+    #[serde(default)]
+    pub sent_at: u64,
+}
+
+/*
+ * Announces a transfer and carries its one-time key.
+ *
+ * Fields:
+ *  - `transfer_id` : Random id of the transfer
+ *  - `key`         : The one-time secretstream key
+ *  - `header`      : The secretstream header
+ *  - `chunks`      : Number of chunks
+ *  - `digest`      : SHA-256 of the serialized records
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryOffer {
+    pub transfer_id: String,
+    pub key: Vec<u8>,
+    pub header: Vec<u8>,
+    pub chunks: u32,
+    pub digest: Vec<u8>,
+}
+
+/* One encrypted piece of the serialized records */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HistoryChunk {
+    pub transfer_id: String,
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+/* What a `History` envelope carries */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HistoryMessage {
+    Offer(HistoryOffer),
+    Chunk(HistoryChunk),
+}
+
+impl HistoryMessage {
+    /* The transfer this message belongs to */
+    pub fn transfer_id(&self) -> &str {
+        match self {
+            HistoryMessage::Offer(offer) => &offer.transfer_id,
+            HistoryMessage::Chunk(chunk) => &chunk.transfer_id,
+        }
+    }
+
+    /* Encrypts the message from `me` to our own device `device` (envelope kind `History`) */
+    pub fn seal(&self, me: &User, device: &DeviceInfo) -> Option<Envelope> {
+        control::seal_for_device(EnvelopeKind::History, self, me, me, device)
+    }
+
+    /* Decrypts a history message addressed to `me`, if the envelope holds one */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::History, envelope, me)
+    }
+}
+
+/*
+ * Reasons why a received transfer is rejected.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryError {
+    /* The offer announces more than `MAX_CHUNKS` chunks */
+    TooLarge,
+    /* A chunk does not decrypt in its place in the stream */
+    BadChunk(u32),
+    /* The stream ends before its `Final` chunk, or goes on after it */
+    Truncated,
+    /* The records do not match the digest of the offer */
+    DigestMismatch,
+    /* The offer or the records are malformed */
+    Malformed,
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::TooLarge => write!(f, "the history transfer is too large"),
+            HistoryError::BadChunk(index) => write!(f, "chunk {index} of the history transfer is invalid"),
+            HistoryError::Truncated => write!(f, "the history transfer was cut off"),
+            HistoryError::DigestMismatch => write!(f, "the history transfer does not match its digest"),
+            HistoryError::Malformed => write!(f, "the history transfer is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+/*
+ * Decrypts the one-to-one history of `session` on device `me`.
+ *
+ * Messages are stored encrypted to our own pre-key; sent ones from
+ * before that were stored as sent, encrypted to the contact's, which
+ * demo contacts carry (as when displaying them).
+ * Messages from before message ids existed get an id derived from their
+ * ciphertext, so transferring twice does not duplicate them.
+ */
+pub fn export(me: &User, contacts: &Contacts, session: &Session) -> Vec<HistoryRecord> {
+    let mut peers: Vec<&String> = session.conversations.keys().filter(|peer| !groups::is_group(peer)).collect();
+    peers.sort();
+
+    let mut records = Vec::new();
+    for peer in peers {
+        for stored in &session.conversations[peer] {
+            let incoming = !stored.sender.is_empty() && stored.sender != me.username();
+            let author = if incoming { peer.as_str() } else { me.username() };
+            let contact = contacts.get(peer).filter(|_| !incoming);
+            let (Some(epk), Some(nonce)) = (
+                box_::PublicKey::from_slice(&stored.ephemeral_pk),
+                box_::Nonce::from_slice(&stored.nonce),
+            ) else {
+                continue;
+            };
+            let decrypted = std::iter::once(me)
+                .chain(contact)
+                .find_map(|reader| reader.decrypt_message_with_logs(&epk, &nonce, &stored.ciphertext, author));
+            let Some((payload, _log)) = decrypted else {
+                continue;
+            };
+            let id = if stored.id.is_empty() {
+                hex::encode(&sha256::hash(&stored.ciphertext).0[..16])
+            } else {
+                stored.id.clone()
+            };
+            records.push(HistoryRecord {
+                peer: peer.clone(),
+                id,
+                sender: author.to_string(),
+                payload,
+                read: stored.read,
+                sent_at: stored.sent_at,
+            });
+        }
+    }
+    records
+}
+
+/*
+ * Encrypts `records` under a fresh one-time transfer key.
+ *
+ * Returns the offer (holding the key) and the chunks, in order.
+ */
+pub fn seal(records: &[HistoryRecord]) -> Option<(HistoryOffer, Vec<HistoryChunk>)> {
+    let transfer_id = new_message_id();
+    let plaintext = serde_json::to_vec(records).ok()?;
+    let key = secretstream::gen_key();
+    let (mut stream, header) = Stream::init_push(&key).ok()?;
+
+    /* The JSON array is never empty, so there is at least one chunk */
+    let pieces: Vec<&[u8]> = plaintext.chunks(CHUNK_LEN).collect();
+    let mut chunks = Vec::with_capacity(pieces.len());
+    for (index, piece) in pieces.iter().enumerate() {
+        let tag = if index + 1 == pieces.len() { Tag::Final } else { Tag::Message };
+        chunks.push(HistoryChunk {
+            transfer_id: transfer_id.clone(),
+            index: index as u32,
+            data: stream.push(piece, Some(transfer_id.as_bytes()), tag).ok()?,
+        });
+    }
+
+    let offer = HistoryOffer {
+        transfer_id,
+        key: key.0.to_vec(),
+        header: header.0.to_vec(),
+        chunks: chunks.len() as u32,
+        digest: sha256::hash(&plaintext).0.to_vec(),
+    };
+    Some((offer, chunks))
+}
+
+/*
+ * A transfer being received from another of our devices.
+ *
+ * Chunks may arrive before the offer, and in any order.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct IncomingTransfer {
+    pub offer: Option<HistoryOffer>,
+    pub chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+impl IncomingTransfer {
+    /*
+     * Adds a message of the transfer. Chunks beyond `MAX_CHUNKS`, or
+     * beyond the count announced by the offer, are dropped.
+     */
+    pub fn add(&mut self, message: HistoryMessage) {
+        match message {
+            HistoryMessage::Offer(offer) => self.offer = Some(offer),
+            HistoryMessage::Chunk(chunk) => {
+                let limit = self.offer.as_ref().map_or(MAX_CHUNKS, |o| o.chunks.min(MAX_CHUNKS));
+                if chunk.index < limit {
+                    self.chunks.insert(chunk.index, chunk.data);
+                }
+            }
+        }
+    }
+
+    /* Whether the offer and every chunk it announces have arrived */
+    pub fn is_complete(&self) -> bool {
+        self.offer.as_ref().is_some_and(|o| o.chunks > MAX_CHUNKS || self.chunks.len() as u32 >= o.chunks)
+    }
+
+    /* Decrypts and checks the complete transfer */
+    pub fn open(&self) -> Result<Vec<HistoryRecord>, HistoryError> {
+        let offer = self.offer.as_ref().ok_or(HistoryError::Malformed)?;
+        if offer.chunks > MAX_CHUNKS {
+            return Err(HistoryError::TooLarge);
+        }
+        let key = Key::from_slice(&offer.key).ok_or(HistoryError::Malformed)?;
+        let header = Header::from_slice(&offer.header).ok_or(HistoryError::Malformed)?;
+        let mut stream = Stream::init_pull(&header, &key).map_err(|_| HistoryError::Malformed)?;
+
+        let mut plaintext = Vec::new();
+        for index in 0..offer.chunks {
+            if stream.is_finalized() {
+                return Err(HistoryError::Truncated);
+            }
+            let data = self.chunks.get(&index).ok_or(HistoryError::Truncated)?;
+            let (piece, _tag) = stream
+                .pull(data, Some(offer.transfer_id.as_bytes()))
+                .map_err(|_| HistoryError::BadChunk(index))?;
+            plaintext.extend_from_slice(&piece);
+        }
+        if !stream.is_finalized() {
+            return Err(HistoryError::Truncated);
+        }
+        if sha256::hash(&plaintext).0.as_slice() != offer.digest.as_slice() {
+            return Err(HistoryError::DigestMismatch);
+        }
+        serde_json::from_slice(&plaintext).map_err(|_| HistoryError::Malformed)
+    }
+}
+
+/*
+ * Stores `records`, received from our device `from_device`, in the
+ * session of `me`: each message is encrypted to our own pre-key for
+ * this device's store.
+ * Conversations with someone who is not a contact are skipped.
+ *
+ * Returns the number of messages that were new.
+ */
+pub fn merge(session: &mut Session, me: &User, contacts: &Contacts, records: Vec<HistoryRecord>, from_device: &str) -> usize {
+    let mut by_peer: BTreeMap<String, Vec<StoredMessage>> = BTreeMap::new();
+    for record in records {
+        if contacts.get(&record.peer).is_none() {
+            continue;
+        }
+        let incoming = record.sender != me.username();
+        if incoming && record.sender != record.peer {
+            continue;
+        }
+        let (epk, nonce, ciphertext, log) = me.encrypt_message_with_logs(me, &record.payload);
+        by_peer.entry(record.peer).or_default().push(StoredMessage {
+            id: record.id,
+            sender: record.sender,
+            ciphertext,
+            ephemeral_pk: epk.as_ref().to_vec(),
+            nonce: nonce.0.to_vec(),
+            log: format!("== log (history) ==\nTransferred from our device {from_device}\n{log}"),
+            read: record.read,
+            message_key: Vec::new(),
+            sent_at: record.sent_at,
+        });
+    }
+    by_peer
+        .into_iter()
+        .map(|(peer, history)| session.merge_history(&peer, history))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Enough records for three chunks */
+    fn records() -> Vec<HistoryRecord> {
+        (0..3)
+            .map(|i| HistoryRecord {
+                peer: "bob".to_string(),
+                id: format!("m{i}"),
+                sender: "alice".to_string(),
+                payload: Payload::text(&"x".repeat(CHUNK_LEN * 2 / 3)),
+                read: true,
+                sent_at: i,
+            })
+            .collect()
+    }
+
+    fn transfer(offer: &HistoryOffer, chunks: &[HistoryChunk]) -> IncomingTransfer {
+        let mut transfer = IncomingTransfer::default();
+        for chunk in chunks {
+            transfer.add(HistoryMessage::Chunk(chunk.clone()));
+        }
+        transfer.add(HistoryMessage::Offer(offer.clone()));
+        transfer
+    }
+
+    #[test]
+    fn transfers_round_trip_in_any_order() {
+        let (offer, mut chunks) = seal(&records()).unwrap();
+        assert_eq!(offer.chunks, 3);
+        chunks.reverse();
+        let transfer = transfer(&offer, &chunks);
+        assert!(transfer.is_complete());
+        let received = transfer.open().unwrap();
+        assert_eq!(received.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), ["m0", "m1", "m2"]);
+    }
+
+    #[test]
+    fn cut_transfers_are_refused() {
+        let (offer, chunks) = seal(&records()).unwrap();
+
+        let missing = transfer(&offer, &chunks[..2]);
+        assert!(!missing.is_complete());
+        assert_eq!(missing.open().err(), Some(HistoryError::Truncated));
+
+        /* An offer that stops before the `Final` chunk */
+        let shorter = HistoryOffer { chunks: 2, ..offer.clone() };
+        let cut = transfer(&shorter, &chunks);
+        assert!(cut.is_complete());
+        assert_eq!(cut.open().err(), Some(HistoryError::Truncated));
+
+        /* An offer that goes on after it */
+        let longer = HistoryOffer { chunks: 4, ..offer.clone() };
+        let mut extra = chunks.clone();
+        extra.push(HistoryChunk { index: 3, ..chunks[0].clone() });
+        assert_eq!(transfer(&longer, &extra).open().err(), Some(HistoryError::Truncated));
+
+        let huge = HistoryOffer { chunks: MAX_CHUNKS + 1, ..offer };
+        let huge = transfer(&huge, &[]);
+        assert!(huge.is_complete());
+        assert_eq!(huge.open().err(), Some(HistoryError::TooLarge));
+    }
+
+    #[test]
+    fn altered_transfers_are_refused() {
+        let (offer, chunks) = seal(&records()).unwrap();
+
+        let mut swapped = chunks.clone();
+        swapped.swap(0, 1);
+        for (index, chunk) in swapped.iter_mut().enumerate() {
+            chunk.index = index as u32;
+        }
+        assert_eq!(transfer(&offer, &swapped).open().err(), Some(HistoryError::BadChunk(0)));
+
+        let mut flipped = chunks.clone();
+        flipped[1].data[3] ^= 1;
+        assert_eq!(transfer(&offer, &flipped).open().err(), Some(HistoryError::BadChunk(1)));
+
+        /* The chunk of another transfer, even under the same key, is refused */
+        let renamed = HistoryOffer { transfer_id: "other".to_string(), ..offer.clone() };
+        assert_eq!(transfer(&renamed, &chunks).open().err(), Some(HistoryError::BadChunk(0)));
+
+        let wrong_digest = HistoryOffer { digest: vec![0; 32], ..offer.clone() };
+        assert_eq!(transfer(&wrong_digest, &chunks).open().err(), Some(HistoryError::DigestMismatch));
+        let wrong_key = HistoryOffer { key: vec![0; 3], ..offer };
+        assert_eq!(transfer(&wrong_key, &chunks).open().err(), Some(HistoryError::Malformed));
+        assert_eq!(IncomingTransfer::default().open().err(), Some(HistoryError::Malformed));
+    }
+
+    #[test]
+    fn merging_keeps_every_message_without_an_id() {
+        let stored = |id: &str, byte: u8| StoredMessage { id: id.to_string(), ciphertext: vec![byte], ..Default::default() };
+        let mut session = Session::default();
+        session.conversations.insert("bob".to_string(), vec![stored("", 9), stored("m1", 1)]);
+
+        let history = vec![stored("", 7), stored("", 8), stored("m1", 2), stored("m1", 3), stored("m2", 4)];
+        assert_eq!(session.merge_history("bob", history), 3);
+        let bytes: Vec<u8> = session.conversations["bob"].iter().map(|m| m.ciphertext[0]).collect();
+        assert_eq!(bytes, [7, 8, 1, 4, 9]);
+    }
+}
@@ -0,0 +1,206 @@
+/*
+ * This module implements device provisioning: linking a new device to
+ * an account with a one-time code (see `net::link` for the wire format).
+ *
+ *  - The primary device shows a `LinkCode` and waits on its channel.
+ *  - The new device generates its own device key and pre-key, and sends
+ *    a `LinkRequest` (`PendingLink::new`).
+ *  - The primary device checks the request, adds the device to the
+ *    signed device list, endorses its pre-key with the identity key and
+ *    seals that in a `LinkResponse` (`accept`), then publishes the list.
+ *  - The new device opens the response and becomes a linked `User` of
+ *    the account (`PendingLink::complete`).
+ *
+ * No secret key leaves the device it was made on, and the identity
+ * secret key stays on the primary device. A lost device is revoked by
+ * removing it from the device list (`User::remove_device`).
+ */
+
+use crate::client::user::User;
+use crate::net::devices::{DeviceInfo, DeviceList};
+use crate::net::link::{self, LinkCode, LinkGrant, LinkRequest, LinkResponse};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::{box_, secretbox, sign};
+use std::fmt;
+
+/* Number of one-time pre-keys generated for a new device */
+const NEW_DEVICE_PREKEYS: usize = 4;
+
+/*
+ * Reasons why linking a device fails.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /* Only the primary device can add devices */
+    NotPrimary,
+    /* The request was not made with the code, or is malformed */
+    BadRequest,
+    /* The response cannot be opened with the code */
+    BadResponse,
+    /* The grant does not match the account or the new device */
+    BadGrant,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::NotPrimary => write!(f, "only the primary device can link devices"),
+            LinkError::BadRequest => write!(f, "the link request was not made with this code"),
+            LinkError::BadResponse => write!(f, "the link response cannot be opened with this code"),
+            LinkError::BadGrant => write!(f, "the link response does not match this account or device"),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/*
+ * The id for the next device of `list`: one more than the highest
+ * numeric id (the primary device is "1").
+ */
+pub fn next_device_id(list: &DeviceList) -> String {
+    let highest = list
+        .devices
+        .iter()
+        .filter_map(|d| d.device_id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(1);
+    (highest + 1).to_string()
+}
+
+/*
+ * A new device waiting for the primary device to answer.
+ *
+ * Fields:
+ *  - `code`      : The linking code typed or scanned on this device
+ *  - `request`   : The request sent to the primary device
+ *  - the new device's keys, and the ephemeral key of the exchange
+ */
+#[derive(Clone)]
+pub struct PendingLink {
+    pub code: LinkCode,
+    pub request: LinkRequest,
+    device: (sign::PublicKey, sign::SecretKey),
+    prekey: (box_::PublicKey, box_::SecretKey),
+    ephemeral_sk: box_::SecretKey,
+}
+
+impl PendingLink {
+    /* Generates the new device's keys and the request for `code` */
+    pub fn new(code: LinkCode) -> Self {
+        let device = sign::gen_keypair();
+        let prekey = box_::gen_keypair();
+        let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+
+        let mut request = LinkRequest {
+            signing_pk: device.0.as_ref().to_vec(),
+            prekey: prekey.0.as_ref().to_vec(),
+            ephemeral_pk: ephemeral_pk.as_ref().to_vec(),
+            proof: Vec::new(),
+            mac: Vec::new(),
+        };
+        let signed = request.signed_bytes(&code.username);
+        request.proof = sign::sign_detached(&signed, &device.1).as_ref().to_vec();
+        request.mac = hmacsha256::authenticate(&signed, &hmacsha256::Key(code.key())).as_ref().to_vec();
+
+        Self {
+            code,
+            request,
+            device,
+            prekey,
+            ephemeral_sk,
+        }
+    }
+
+    /*
+     * Opens the primary device's response and checks the grant: the
+     * device list must be signed by the account's identity key and list
+     * this device with its keys, and the pre-key must be endorsed.
+     *
+     * Returns the new device, ready to log in.
+     */
+    pub fn complete(self, response: &LinkResponse) -> Result<User, LinkError> {
+        let peer_ephemeral = box_::PublicKey::from_slice(&response.ephemeral_pk).ok_or(LinkError::BadResponse)?;
+        let nonce = secretbox::Nonce::from_slice(&response.nonce).ok_or(LinkError::BadResponse)?;
+        let shared = box_::precompute(&peer_ephemeral, &self.ephemeral_sk);
+        let key = link::session_key(&self.code.key(), &shared.0, &self.request.ephemeral_pk, &response.ephemeral_pk);
+        let plaintext = secretbox::open(&response.sealed, &nonce, &secretbox::Key(key)).map_err(|_| LinkError::BadResponse)?;
+        let grant: LinkGrant = serde_json::from_slice(&plaintext).map_err(|_| LinkError::BadResponse)?;
+
+        let identity_pk = sign::PublicKey::from_slice(&grant.identity_pk).ok_or(LinkError::BadGrant)?;
+        let prekey_sig = sign::Signature::try_from(grant.prekey_sig.as_slice()).map_err(|_| LinkError::BadGrant)?;
+        let listed = grant.list.device(&grant.device_id).is_some_and(|d| {
+            d.signing_pk == self.request.signing_pk && d.prekey == self.request.prekey
+        });
+        if grant.list.username != self.code.username
+            || !grant.list.verify(&identity_pk)
+            || !listed
+            || !sign::verify_detached(&prekey_sig, self.prekey.0.as_ref(), &identity_pk)
+        {
+            return Err(LinkError::BadGrant);
+        }
+
+        let one_time_prekeys = (0..NEW_DEVICE_PREKEYS).map(|_| box_::gen_keypair()).collect();
+        let (prekey_pk, prekey_sk) = self.prekey;
+        Ok(User::linked(
+            identity_pk,
+            &grant.device_id,
+            self.device,
+            (prekey_pk, prekey_sk, prekey_sig),
+            one_time_prekeys,
+            grant.list,
+        ))
+    }
+}
+
+/*
+ * Accepts a request made with `code` on the primary device `me`: checks
+ * its MAC and proof, adds the new device to the device list (signing the
+ * new version) and seals the grant for it.
+ *
+ * Returns the response to send and the new device. The caller publishes
+ * `me.devices` afterwards.
+ */
+pub fn accept(me: &mut User, code: &LinkCode, request: &LinkRequest) -> Result<(LinkResponse, DeviceInfo), LinkError> {
+    if me.identity_sk.is_none() || code.username != me.username() {
+        return Err(LinkError::NotPrimary);
+    }
+    let signed = request.signed_bytes(&code.username);
+    let mac = hmacsha256::Tag::from_slice(&request.mac).ok_or(LinkError::BadRequest)?;
+    if !hmacsha256::verify(&mac, &signed, &hmacsha256::Key(code.key())) {
+        return Err(LinkError::BadRequest);
+    }
+    let signing_pk = sign::PublicKey::from_slice(&request.signing_pk).ok_or(LinkError::BadRequest)?;
+    let proof = sign::Signature::try_from(request.proof.as_slice()).map_err(|_| LinkError::BadRequest)?;
+    let peer_ephemeral = box_::PublicKey::from_slice(&request.ephemeral_pk).ok_or(LinkError::BadRequest)?;
+    if !sign::verify_detached(&proof, &signed, &signing_pk) || box_::PublicKey::from_slice(&request.prekey).is_none() {
+        return Err(LinkError::BadRequest);
+    }
+
+    let device = DeviceInfo {
+        device_id: next_device_id(&me.devices),
+        signing_pk: request.signing_pk.clone(),
+        prekey: request.prekey.clone(),
+        /* Direct links reach primary devices only */
+        noise_pk: Vec::new(),
+    };
+    let prekey_sig = me.add_device(device.clone()).ok_or(LinkError::NotPrimary)?;
+    let grant = LinkGrant {
+        identity_pk: me.identity_pk.as_ref().to_vec(),
+        device_id: device.device_id.clone(),
+        prekey_sig: prekey_sig.as_ref().to_vec(),
+        list: me.devices.clone(),
+    };
+
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let shared = box_::precompute(&peer_ephemeral, &ephemeral_sk);
+    let key = link::session_key(&code.key(), &shared.0, &request.ephemeral_pk, ephemeral_pk.as_ref());
+    let nonce = secretbox::gen_nonce();
+    let plaintext = serde_json::to_vec(&grant).map_err(|_| LinkError::BadGrant)?;
+    let response = LinkResponse {
+        ephemeral_pk: ephemeral_pk.as_ref().to_vec(),
+        nonce: nonce.0.to_vec(),
+        sealed: secretbox::seal(&plaintext, &nonce, &secretbox::Key(key)),
+    };
+    Ok((response, device))
+}
@@ -0,0 +1,561 @@
+/*
+ * This module defines group membership management.
+ *
+ * The state of a group (name, members, admins) only changes through
+ * `GroupUpdate`s: numbered operations signed with the identity key of
+ * their author. Every member applies the same updates in the same order
+ * (version 0, 1, 2, ...), checking for each one that:
+ *  - the signature is valid for the author's identity key
+ *  - the author was allowed to make the change: admins can invite,
+ *    remove, rename and promote; any member can leave
+ *  - the change makes sense (e.g. no removing a non-member)
+ *
+ * Updates that arrive early are kept until the missing ones arrive.
+ * When two admins act at once, two updates share a version: the one
+ * whose signature has the lowest SHA-256 hash wins, whatever the order
+ * they arrive in. A member who applied the other one rolls back to the
+ * version before and applies the winner (then the later updates again),
+ * so every member ends up with the same history. The loser is reported
+ * as a conflict.
+ *
+ * Updates travel as pairwise control messages (`GroupUpdates`). A newly
+ * invited member receives the whole history, so they can check every
+ * step from the creation of the group.
+ *
+ * When a member is removed (or leaves), every remaining member replaces
+ * its sender key (see `Group::rotate_sender_key`), so the former member
+ * cannot read later messages.
+ */
+
+use crate::client::control;
+use crate::client::groups::{Group, SenderKey};
+use crate::client::user::User;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::sign;
+use std::fmt;
+
+/* Domain separation tag for group update signatures */
+const GROUP_UPDATE_CONTEXT: &[u8] = b"blackipher-group-v1";
+
+/* A change to the state of a group */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum GroupOp {
+    /* Creates the group; the author becomes its first admin */
+    Create {
+        name: String,
+        members: Vec<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        treekem: bool,
+    },
+    /* Adds a member */
+    Invite { member: String },
+    /* Removes a member */
+    Remove { member: String },
+    /* The author leaves the group */
+    Leave,
+    /* Changes the group name */
+    Rename { name: String },
+    /* Makes a member admin */
+    Promote { member: String },
+}
+
+impl fmt::Display for GroupOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupOp::Create { name, members, treekem } => {
+                write!(f, "created \"{}\" with {}", name, members.join(", "))?;
+                if *treekem {
+                    f.write_str(" (TreeKEM)")?;
+                }
+                Ok(())
+            }
+            GroupOp::Invite { member } => write!(f, "invited {member}"),
+            GroupOp::Remove { member } => write!(f, "removed {member}"),
+            GroupOp::Leave => f.write_str("left"),
+            GroupOp::Rename { name } => write!(f, "renamed the group to \"{name}\""),
+            GroupOp::Promote { member } => write!(f, "made {member} admin"),
+        }
+    }
+}
+
+/*
+ * A signed group state update.
+ *
+ * Fields:
+ *  - `group_id`  : Group the update applies to
+ *  - `version`   : Position in the group history (0 = creation)
+ *  - `author`    : Username of the member making the change
+ *  - `op`        : The change
+ *  - `signature` : Signature by the author's identity key
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GroupUpdate {
+    pub group_id: String,
+    pub version: u64,
+    pub author: String,
+    pub op: GroupOp,
+    pub signature: Vec<u8>,
+}
+
+/* Reasons an update can be refused */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipError {
+    /* The author's identity key is unknown */
+    UnknownAuthor,
+    /* The signature does not verify */
+    BadSignature,
+    /* The author is not an admin of the group */
+    NotAdmin,
+    /* The author or the target is not a member */
+    NotAMember,
+    /* The invited member is already in the group */
+    AlreadyMember,
+    /* The first update is not a valid creation */
+    InvalidCreate,
+    /* Another update won for this version */
+    Conflict(u64),
+    /* This device does not hold the account's identity key (linked device) */
+    NoIdentityKey,
+}
+
+impl fmt::Display for MembershipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MembershipError::UnknownAuthor => f.write_str("unknown author"),
+            MembershipError::BadSignature => f.write_str("bad signature"),
+            MembershipError::NotAdmin => f.write_str("only admins can do this"),
+            MembershipError::NotAMember => f.write_str("not a member of the group"),
+            MembershipError::AlreadyMember => f.write_str("already a member"),
+            MembershipError::InvalidCreate => f.write_str("invalid group creation"),
+            MembershipError::Conflict(version) => {
+                write!(f, "conflicting update for version {version}, another one won")
+            }
+            MembershipError::NoIdentityKey => f.write_str("group changes can only be signed on the primary device"),
+        }
+    }
+}
+
+impl std::error::Error for MembershipError {}
+
+/* Members added and removed by the updates applied in one go */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Applied {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl GroupUpdate {
+    /* The bytes covered by the signature */
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut data = GROUP_UPDATE_CONTEXT.to_vec();
+        let body = (&self.group_id, self.version, &self.author, &self.op);
+        data.extend_from_slice(&serde_json::to_vec(&body).unwrap_or_default());
+        data
+    }
+
+    /* Builds and signs an update as `author` (on their primary device) */
+    pub fn sign(group_id: &str, version: u64, op: GroupOp, author: &User) -> Result<Self, MembershipError> {
+        let identity_sk = author.identity_sk.as_ref().ok_or(MembershipError::NoIdentityKey)?;
+        let mut update = Self {
+            group_id: group_id.to_string(),
+            version,
+            author: author.username().to_string(),
+            op,
+            signature: Vec::new(),
+        };
+        update.signature = sign::sign_detached(&update.signed_bytes(), identity_sk)
+            .as_ref()
+            .to_vec();
+        Ok(update)
+    }
+
+    /*
+     * Whether this update wins over `other`, for the same version:
+     * the lowest hash of the signature wins (every member agrees).
+     */
+    pub fn wins_over(&self, other: &GroupUpdate) -> bool {
+        sha256::hash(&self.signature).0 < sha256::hash(&other.signature).0
+    }
+
+    /* Checks the signature against the author's identity key */
+    pub fn verify(&self, author_pk: &sign::PublicKey) -> bool {
+        let Ok(signature) = sign::Signature::try_from(self.signature.as_slice()) else {
+            return false;
+        };
+        sign::verify_detached(&signature, &self.signed_bytes(), author_pk)
+    }
+}
+
+/*
+ * One or more updates sent to a member: the latest update for current
+ * members, the whole history for a newly invited one.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupUpdates {
+    pub updates: Vec<GroupUpdate>,
+}
+
+impl GroupUpdates {
+    /* Encrypts the updates from `sender` to `peer` (envelope kind `GroupUpdate`) */
+    pub fn seal(&self, sender: &User, peer: &User) -> Option<Envelope> {
+        control::seal(EnvelopeKind::GroupUpdate, self, sender, peer)
+    }
+
+    /* Decrypts updates addressed to `me`, if the envelope holds some */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::GroupUpdate, envelope, me)
+    }
+}
+
+impl Group {
+    /*
+     * Creates a group as `creator` and returns it with its signed
+     * creation update (version 0), to send to the other members.
+     * In TreeKEM mode, the tree must then be set up (see `client::treekem`).
+     */
+    pub fn create(
+        id: String,
+        name: &str,
+        members: Vec<String>,
+        treekem: bool,
+        creator: &User,
+    ) -> Result<(Self, GroupUpdate), MembershipError> {
+        let me = creator.username().to_string();
+        let mut all = vec![me.clone()];
+        for member in members {
+            if !all.contains(&member) {
+                all.push(member);
+            }
+        }
+        let op = GroupOp::Create {
+            name: name.to_string(),
+            members: all.clone(),
+            treekem,
+        };
+        let update = GroupUpdate::sign(&id, 0, op, creator)?;
+        let group = Self {
+            id,
+            name: name.to_string(),
+            members: all,
+            admins: vec![me],
+            version: 0,
+            history: vec![update.clone()],
+            pending: Vec::new(),
+            own: SenderKey::generate(),
+            peers: Default::default(),
+            retired_keys: Default::default(),
+            tree: None,
+        };
+        Ok((group, update))
+    }
+
+    /*
+     * Builds a group from its creation update, received from another member.
+     * Later updates are applied with `receive`.
+     */
+    pub fn from_create(update: &GroupUpdate, author_pk: &sign::PublicKey) -> Result<Self, MembershipError> {
+        let GroupOp::Create { name, members, .. } = &update.op else {
+            return Err(MembershipError::InvalidCreate);
+        };
+        if update.version != 0 || !members.contains(&update.author) {
+            return Err(MembershipError::InvalidCreate);
+        }
+        if !update.verify(author_pk) {
+            return Err(MembershipError::BadSignature);
+        }
+        Ok(Self {
+            id: update.group_id.clone(),
+            name: name.clone(),
+            members: members.clone(),
+            admins: vec![update.author.clone()],
+            version: 0,
+            history: vec![update.clone()],
+            pending: Vec::new(),
+            own: SenderKey::generate(),
+            peers: Default::default(),
+            retired_keys: Default::default(),
+            tree: None,
+        })
+    }
+
+    /* Whether `name` is a member */
+    pub fn is_member(&self, name: &str) -> bool {
+        self.members.iter().any(|m| m == name)
+    }
+
+    /* Whether `name` is an admin (and still a member) */
+    pub fn is_admin(&self, name: &str) -> bool {
+        self.is_member(name) && self.admins.iter().any(|a| a == name)
+    }
+
+    /* Signs `op` as the next update of the group (not applied yet) */
+    pub fn propose(&self, op: GroupOp, author: &User) -> Result<GroupUpdate, MembershipError> {
+        GroupUpdate::sign(&self.id, self.version + 1, op, author)
+    }
+
+    /* Checks that `update` is allowed in the current state, then applies it */
+    fn apply(&mut self, update: &GroupUpdate, author_pk: &sign::PublicKey, applied: &mut Applied) -> Result<(), MembershipError> {
+        if !update.verify(author_pk) {
+            return Err(MembershipError::BadSignature);
+        }
+        let author = update.author.as_str();
+        if !self.is_member(author) {
+            return Err(MembershipError::NotAMember);
+        }
+        let admin_only = !matches!(update.op, GroupOp::Leave);
+        if admin_only && !self.is_admin(author) {
+            return Err(MembershipError::NotAdmin);
+        }
+
+        match &update.op {
+            GroupOp::Create { .. } => return Err(MembershipError::InvalidCreate),
+            GroupOp::Invite { member } => {
+                if self.is_member(member) {
+                    return Err(MembershipError::AlreadyMember);
+                }
+                self.members.push(member.clone());
+                applied.removed.retain(|m| m != member);
+                applied.added.push(member.clone());
+            }
+            GroupOp::Remove { member } => {
+                if !self.is_member(member) {
+                    return Err(MembershipError::NotAMember);
+                }
+                self.remove_member(member, applied);
+            }
+            GroupOp::Leave => self.remove_member(author, applied),
+            GroupOp::Rename { name } => self.name = name.clone(),
+            GroupOp::Promote { member } => {
+                if !self.is_member(member) {
+                    return Err(MembershipError::NotAMember);
+                }
+                if !self.admins.contains(member) {
+                    self.admins.push(member.clone());
+                }
+            }
+        }
+        self.version = update.version;
+        self.history.push(update.clone());
+        Ok(())
+    }
+
+    fn remove_member(&mut self, member: &str, applied: &mut Applied) {
+        self.members.retain(|m| m != member);
+        self.admins.retain(|a| a != member);
+        if let Some(key) = self.peers.remove(member) {
+            self.retired_keys.insert(key.key_id);
+        }
+        applied.added.retain(|m| m != member);
+        applied.removed.push(member.to_string());
+    }
+
+    /*
+     * Rebuilds the state from the creation update and the updates kept
+     * in `history` (after the later ones were taken out).
+     */
+    fn replay(&mut self, identity_of: &impl Fn(&str) -> Option<sign::PublicKey>) {
+        let history = std::mem::take(&mut self.history);
+        let Some((create, updates)) = history.split_first() else {
+            return;
+        };
+        if let GroupOp::Create { name, members, .. } = &create.op {
+            self.name = name.clone();
+            self.members = members.clone();
+            self.admins = vec![create.author.clone()];
+        }
+        self.version = 0;
+        self.history = vec![create.clone()];
+        let mut ignored = Applied::default();
+        for update in updates {
+            let Some(author_pk) = identity_of(&update.author) else {
+                break;
+            };
+            if self.apply(update, &author_pk, &mut ignored).is_err() {
+                break;
+            }
+        }
+    }
+
+    /*
+     * Applies received updates, in version order.
+     *
+     * `identity_of` returns the identity key of a username. Updates
+     * ahead of the next expected version are kept for later. An update
+     * that wins over the one applied for its version rolls the group
+     * back (see the top of this module). Returns the membership changes
+     * and the errors of refused updates.
+     */
+    pub fn receive(
+        &mut self,
+        updates: Vec<GroupUpdate>,
+        identity_of: impl Fn(&str) -> Option<sign::PublicKey>,
+    ) -> (Applied, Vec<MembershipError>) {
+        let mut applied = Applied::default();
+        let mut errors = Vec::new();
+        let members_before = self.members.clone();
+        let mut rolled_back = false;
+
+        let group_id = self.id.clone();
+        for update in updates.into_iter().filter(|u| u.group_id == group_id) {
+            match self.history.iter().position(|h| h.version == update.version) {
+                Some(index) if self.history[index] == update => {}
+                Some(index)
+                    if index > 0
+                        && update.wins_over(&self.history[index])
+                        && identity_of(&update.author).is_some_and(|pk| update.verify(&pk)) =>
+                {
+                    let undone = self.history.split_off(index);
+                    self.replay(&identity_of);
+                    for later in undone.into_iter().skip(1) {
+                        if !self.pending.contains(&later) {
+                            self.pending.push(later);
+                        }
+                    }
+                    self.pending.push(update);
+                    rolled_back = true;
+                }
+                Some(_) => errors.push(MembershipError::Conflict(update.version)),
+                None if !self.pending.contains(&update) => self.pending.push(update),
+                None => {}
+            }
+        }
+
+        loop {
+            /* Of the updates for the next version, the winner goes first */
+            let next = self.version + 1;
+            let Some(index) = (0..self.pending.len())
+                .filter(|&i| self.pending[i].version == next)
+                .reduce(|best, i| if self.pending[i].wins_over(&self.pending[best]) { i } else { best })
+            else {
+                break;
+            };
+            let update = self.pending.remove(index);
+            let result = match identity_of(&update.author) {
+                Some(author_pk) => self.apply(&update, &author_pk, &mut applied),
+                None => Err(MembershipError::UnknownAuthor),
+            };
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        }
+        self.pending.retain(|u| u.version > self.version);
+
+        /* After a roll back, the changes are counted from where we were */
+        if rolled_back {
+            applied = Applied {
+                added: self.members.iter().filter(|m| !members_before.contains(m)).cloned().collect(),
+                removed: members_before.into_iter().filter(|m| !self.members.contains(m)).collect(),
+            };
+        }
+        (applied, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::groups::new_group_id;
+
+    /* Alice's group with bob and carol, both admins, as each of them holds it */
+    fn group() -> ([User; 3], [Group; 3]) {
+        let users = ["alice", "bob", "carol"].map(|name| User::new(name, 1));
+        let members = vec!["bob".to_string(), "carol".to_string()];
+        let (mut alice, create) = Group::create(new_group_id(), "test", members, false, &users[0]).unwrap();
+        let mut promotions = Vec::new();
+        for (version, member) in [(1, "bob"), (2, "carol")] {
+            let op = GroupOp::Promote { member: member.to_string() };
+            promotions.push(GroupUpdate::sign(&alice.id, version, op, &users[0]).unwrap());
+        }
+        assert!(alice.receive(promotions.clone(), identities(&users)).1.is_empty());
+        let [bob, carol] = [(); 2].map(|_| {
+            let mut group = Group::from_create(&create, &users[0].identity_pk).unwrap();
+            assert!(group.receive(promotions.clone(), identities(&users)).1.is_empty());
+            group
+        });
+        (users, [alice, bob, carol])
+    }
+
+    fn identities(users: &[User; 3]) -> impl Fn(&str) -> Option<sign::PublicKey> + '_ {
+        |name| users.iter().find(|u| u.username() == name).map(|u| u.identity_pk)
+    }
+
+    #[test]
+    fn concurrent_updates_converge() {
+        let (users, [mut alice, mut bob, mut carol]) = group();
+        let renamed = bob.propose(GroupOp::Rename { name: "bob's".to_string() }, &users[1]).unwrap();
+        let removed = carol.propose(GroupOp::Remove { member: "alice".to_string() }, &users[2]).unwrap();
+        let (winner, loser) = if renamed.wins_over(&removed) { (&renamed, &removed) } else { (&removed, &renamed) };
+
+        /* Each author applies their own first, alice gets them in both orders */
+        bob.receive(vec![renamed.clone()], identities(&users));
+        carol.receive(vec![removed.clone()], identities(&users));
+        let (_, errors) = alice.receive(vec![loser.clone(), winner.clone()], identities(&users));
+        assert!(errors.is_empty());
+        bob.receive(vec![removed.clone()], identities(&users));
+        carol.receive(vec![renamed.clone()], identities(&users));
+        let (_, errors) = alice.receive(vec![loser.clone()], identities(&users));
+        assert_eq!(errors, vec![MembershipError::Conflict(3)]);
+
+        for group in [&alice, &bob, &carol] {
+            assert_eq!(group.history.last(), Some(winner));
+            assert_eq!(group.members, bob.members);
+            assert_eq!(group.name, bob.name);
+        }
+    }
+
+    #[test]
+    fn roll_back_reports_the_changes() {
+        let (users, [_, mut bob, _]) = group();
+        let removed = bob.propose(GroupOp::Remove { member: "carol".to_string() }, &users[1]).unwrap();
+        /* A rename by alice at the same version, that wins */
+        let renamed = (0..)
+            .map(|i| bob.propose(GroupOp::Rename { name: format!("name {i}") }, &users[0]).unwrap())
+            .find(|candidate| candidate.wins_over(&removed))
+            .unwrap();
+        let (applied, _) = bob.receive(vec![removed], identities(&users));
+        assert_eq!(applied.removed, vec!["carol".to_string()]);
+
+        let (applied, errors) = bob.receive(vec![renamed], identities(&users));
+        assert!(errors.is_empty());
+        assert_eq!(applied.added, vec!["carol".to_string()]);
+        assert!(bob.is_member("carol"));
+    }
+
+    #[test]
+    fn forged_winner_does_not_roll_back() {
+        let (users, [_, mut bob, _]) = group();
+        let renamed = bob.propose(GroupOp::Rename { name: "kept".to_string() }, &users[1]).unwrap();
+        bob.receive(vec![renamed.clone()], identities(&users));
+        let mut forged = renamed.clone();
+        forged.op = GroupOp::Rename { name: "forged".to_string() };
+        for byte in 0..=255u8 {
+            forged.signature = vec![byte; 64];
+            if forged.wins_over(&renamed) {
+                break;
+            }
+        }
+        assert!(forged.wins_over(&renamed));
+
+        let (_, errors) = bob.receive(vec![forged], identities(&users));
+        assert_eq!(errors, vec![MembershipError::Conflict(3)]);
+        assert_eq!(bob.name, "kept");
+    }
+
+    #[test]
+    fn refused_updates() {
+        let (users, [_, mut bob, _]) = group();
+        let stranger = User::new("mallory", 1);
+        let mut update = bob.propose(GroupOp::Rename { name: "x".to_string() }, &stranger).unwrap();
+        let known = |name: &str| {
+            (name == "mallory").then_some(stranger.identity_pk).or_else(|| identities(&users)(name))
+        };
+        assert_eq!(bob.receive(vec![update.clone()], known).1, vec![MembershipError::NotAMember]);
+
+        update.author = "alice".to_string();
+        assert_eq!(bob.receive(vec![update], identities(&users)).1, vec![MembershipError::BadSignature]);
+        assert_eq!(bob.name, "test");
+    }
+}
@@ -1,3 +1,21 @@
+pub mod attachments;
+pub mod cards;
 pub mod contacts;
+pub mod control;
+pub mod devices;
+pub mod edits;
+pub mod groups;
+pub mod history;
+pub mod link;
+pub mod membership;
+pub mod outbox;
+pub mod payload;
+pub mod reactions;
+pub mod replies;
+pub mod receipts;
 pub mod sessions;
+pub mod settings;
+pub mod transparency;
+pub mod treekem;
+pub mod typing;
 pub mod user;
@@ -0,0 +1,305 @@
+/*
+ * This module defines the `Outbox`, a persistent queue of outgoing
+ * envelopes that tracks whether each message actually left the client.
+ *
+ * Every message goes through the following states:
+ *
+ *   Pending ──send ok──> Sent ──receipt──> Delivered ──receipt──> Read
+ *      │  ▲
+ *      │  └── send failed, retry after backoff
+ *      └───── too many failures ──> Failed ──manual retry──> Pending
+ *
+ * While no transport is available, messages simply stay `Pending`
+ * (offline queueing) and do not consume retry attempts.
+ *
+ * Retries use exponential backoff with "equal jitter": the delay
+ * doubles with each attempt (capped at `MAX_DELAY_MS`) and a random
+ * half of it is added, so many clients coming back online at once do
+ * not all retry at the same instant.
+ *
+ * The outbox is serialized to JSON, like `Session`, so queued
+ * messages survive a restart.
+ */
+
+use crate::net::transport::Envelope;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::randombytes;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* Delay before the first retry */
+pub const BASE_DELAY_MS: u64 = 1_000;
+
+/* Upper bound for a single retry delay */
+pub const MAX_DELAY_MS: u64 = 60_000;
+
+/* Number of failed sends after which a message is marked `Failed` */
+pub const MAX_ATTEMPTS: u32 = 6;
+
+/* Delivery state of an outgoing message */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryState {
+    /* Queued, waiting to be handed to the transport */
+    Pending,
+    /* Accepted by the transport (relay or peer) */
+    Sent,
+    /* Confirmed as received by the recipient (delivery receipt) */
+    Delivered,
+    /* Confirmed as displayed by the recipient (read receipt) */
+    Read,
+    /* Gave up after `MAX_ATTEMPTS`; can be retried manually */
+    Failed,
+}
+
+/*
+ * A single outgoing message.
+ *
+ * Fields:
+ *  - `envelope`       : What is sent over the transport
+ *  - `state`          : Current `DeliveryState`
+ *  - `attempts`       : Number of failed send attempts so far
+ *  - `next_attempt_ms`: Earliest time (Unix ms) for the next attempt
+ *  - `last_error`     : Error returned by the last failed attempt
+ *  - `in_flight`      : A send is currently running (not persisted)
+ */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutboxEntry {
+    pub envelope: Envelope,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub next_attempt_ms: u64,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub in_flight: bool,
+}
+
+/* Persistent list of outgoing messages */
+#[derive(Serialize, Deserialize, Default)]
+pub struct Outbox {
+    pub entries: Vec<OutboxEntry>,
+}
+
+/* Current time in milliseconds since the Unix epoch */
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/*
+ * Computes the delay before retry number `attempts` (1-based).
+ *
+ * delay = min(BASE * 2^(attempts-1), MAX), then a uniform jitter
+ * in [delay/2, delay] is applied.
+ */
+pub fn backoff_delay_ms(attempts: u32) -> u64 {
+    let exp = attempts.saturating_sub(1).min(16);
+    let delay = BASE_DELAY_MS.saturating_mul(1 << exp).min(MAX_DELAY_MS);
+    let half = delay / 2;
+    half + randombytes::randombytes_uniform(half as u32 + 1) as u64
+}
+
+impl Outbox {
+    /*
+     * Loads the outbox from a JSON file located at `path`.
+     *
+     * If the file does not exist, or if deserialization fails,
+     * an empty outbox is returned instead.
+     */
+    pub fn load(path: &str) -> Self {
+        if Path::new(path).exists() {
+            let data = fs::read_to_string(path).unwrap_or_default();
+            serde_json::from_str(&data).unwrap_or_default()
+        } else {
+            Outbox::default()
+        }
+    }
+
+    /* Saves the outbox to a JSON file at `path` */
+    pub fn save(&self, path: &str) {
+        if let Ok(json) = serde_json::to_string_pretty(self) {
+            let _ = fs::write(path, json);
+        }
+    }
+
+    /* Queues an envelope for sending as soon as possible */
+    pub fn enqueue(&mut self, envelope: Envelope) {
+        self.entries.push(OutboxEntry {
+            envelope,
+            state: DeliveryState::Pending,
+            attempts: 0,
+            next_attempt_ms: 0,
+            last_error: None,
+            in_flight: false,
+        });
+    }
+
+    /* Returns the delivery state of message `id`, if it is in the outbox */
+    pub fn state(&self, id: &str) -> Option<DeliveryState> {
+        self.entry(id).map(|e| e.state)
+    }
+
+    /* Returns the entry for message `id` */
+    pub fn entry(&self, id: &str) -> Option<&OutboxEntry> {
+        self.entries.iter().find(|e| e.envelope.id == id)
+    }
+
+    fn entry_mut(&mut self, id: &str) -> Option<&mut OutboxEntry> {
+        self.entries.iter_mut().find(|e| e.envelope.id == id)
+    }
+
+    /*
+     * Returns the envelopes that should be sent now and marks them
+     * as in flight, so they are not handed out twice.
+     *
+     * Only envelopes accepted by `routable` (i.e. for which a transport
+     * is available) are returned; the others stay queued.
+     */
+    pub fn take_due(&mut self, now: u64, routable: impl Fn(&Envelope) -> bool) -> Vec<Envelope> {
+        self.entries
+            .iter_mut()
+            .filter(|e| e.state == DeliveryState::Pending && !e.in_flight && e.next_attempt_ms <= now)
+            .filter(|e| routable(&e.envelope))
+            .map(|e| {
+                e.in_flight = true;
+                e.envelope.clone()
+            })
+            .collect()
+    }
+
+    /* Records that the transport accepted message `id` */
+    pub fn mark_sent(&mut self, id: &str) {
+        if let Some(entry) = self.entry_mut(id) {
+            entry.in_flight = false;
+            entry.last_error = None;
+            if entry.state == DeliveryState::Pending {
+                entry.state = DeliveryState::Sent;
+            }
+        }
+    }
+
+    /*
+     * Records a failed send of message `id`.
+     *
+     * The message is rescheduled with backoff, or marked `Failed`
+     * once `MAX_ATTEMPTS` is reached.
+     */
+    pub fn mark_failed_attempt(&mut self, id: &str, error: String, now: u64) {
+        if let Some(entry) = self.entry_mut(id) {
+            entry.in_flight = false;
+            entry.attempts += 1;
+            entry.last_error = Some(error);
+            if entry.attempts >= MAX_ATTEMPTS {
+                entry.state = DeliveryState::Failed;
+            } else {
+                entry.next_attempt_ms = now + backoff_delay_ms(entry.attempts);
+            }
+        }
+    }
+
+    /*
+     * Records that the recipient confirmed message `id`.
+     *
+     * A late delivery receipt never downgrades a message already `Read`.
+     */
+    pub fn mark_delivered(&mut self, id: &str) {
+        if let Some(entry) = self.entry_mut(id) {
+            if entry.state != DeliveryState::Read {
+                entry.state = DeliveryState::Delivered;
+            }
+        }
+    }
+
+    /* Records that the recipient read message `id` */
+    pub fn mark_read(&mut self, id: &str) {
+        if let Some(entry) = self.entry_mut(id) {
+            entry.state = DeliveryState::Read;
+        }
+    }
+
+    /* Puts a `Failed` message back in the queue (manual retry) */
+    pub fn retry(&mut self, id: &str) {
+        if let Some(entry) = self.entry_mut(id) {
+            if entry.state == DeliveryState::Failed {
+                entry.state = DeliveryState::Pending;
+                entry.attempts = 0;
+                entry.next_attempt_ms = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(ids: &[&str]) -> Outbox {
+        let mut outbox = Outbox::default();
+        for id in ids {
+            outbox.enqueue(Envelope {
+                id: id.to_string(),
+                sender: "alice".to_string(),
+                recipient: "bob".to_string(),
+                ..Default::default()
+            });
+        }
+        outbox
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_cap() {
+        for attempts in 1..=40 {
+            let full = (BASE_DELAY_MS << (attempts - 1).min(16)).min(MAX_DELAY_MS);
+            for _ in 0..20 {
+                let delay = backoff_delay_ms(attempts);
+                assert!((full / 2..=full).contains(&delay), "attempt {attempts}: {delay} ms");
+            }
+        }
+        assert!(backoff_delay_ms(0) <= BASE_DELAY_MS);
+        assert!(backoff_delay_ms(u32::MAX) <= MAX_DELAY_MS);
+    }
+
+    #[test]
+    fn failed_sends_are_retried_then_given_up() {
+        let mut outbox = outbox(&["m1"]);
+        assert!(outbox.take_due(0, |_| false).is_empty(), "no transport: stays queued");
+        assert_eq!(outbox.entry("m1").unwrap().attempts, 0);
+
+        let mut now = 1_000;
+        for attempt in 1..MAX_ATTEMPTS {
+            assert_eq!(outbox.take_due(now, |_| true).len(), 1);
+            assert!(outbox.take_due(now, |_| true).is_empty(), "already in flight");
+            outbox.mark_failed_attempt("m1", "down".to_string(), now);
+            let entry = outbox.entry("m1").unwrap();
+            assert_eq!((entry.state, entry.attempts), (DeliveryState::Pending, attempt));
+            let due = entry.next_attempt_ms;
+            assert!(outbox.take_due(due - 1, |_| true).is_empty(), "not due yet");
+            now = due;
+        }
+        assert_eq!(outbox.take_due(now, |_| true).len(), 1);
+        outbox.mark_failed_attempt("m1", "down".to_string(), now);
+        assert_eq!(outbox.state("m1"), Some(DeliveryState::Failed));
+        assert!(outbox.take_due(u64::MAX, |_| true).is_empty());
+
+        outbox.retry("m1");
+        assert_eq!(outbox.take_due(now, |_| true).len(), 1);
+        outbox.mark_sent("m1");
+        assert_eq!(outbox.state("m1"), Some(DeliveryState::Sent));
+    }
+
+    #[test]
+    fn receipts_only_move_forward() {
+        let mut outbox = outbox(&["m1"]);
+        outbox.retry("m1");
+        assert_eq!(outbox.state("m1"), Some(DeliveryState::Pending), "only failed messages are retried");
+        outbox.mark_sent("m1");
+        outbox.mark_read("m1");
+        outbox.mark_delivered("m1");
+        assert_eq!(outbox.state("m1"), Some(DeliveryState::Read));
+        outbox.mark_sent("m1");
+        assert_eq!(outbox.state("m1"), Some(DeliveryState::Read));
+        assert_eq!(outbox.state("unknown"), None);
+    }
+}
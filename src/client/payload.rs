@@ -0,0 +1,279 @@
+/*
+ * This module defines the `Payload`, what every end-to-end encrypted
+ * plaintext holds: chat content (text, attachment, reaction, edit,
+ * delete, contact card) as well as control messages (receipts, typing indicators,
+ * group control, device sync and history transfer).
+ *
+ * A payload is serialized as JSON, with its kind and the version of
+ * that kind's format next to its body:
+ *
+ *   {"kind": "text", "version": 1, "body": {"text": "hey"}}
+ *
+ * `Payload::decode` reads any kind this client knows, up to the version
+ * listed in `KINDS`. A kind it does not know, a newer version of one it
+ * knows, or a body that does not parse, decodes to nothing: the message
+ * is dropped, neither stored nor shown, so an older client skips what a
+ * newer one sends. A change to the format of a kind bumps its version,
+ * unless older clients can ignore it (an optional field, like the quote
+ * of a reply or the times of an edit); a new feature adds a variant.
+ *
+ * Plaintexts from before payloads existed are bare text; they still
+ * decode, as `Text`.
+ */
+
+use crate::client::attachments::Attachment;
+use crate::client::cards::ContactCard;
+use crate::client::devices::SyncedMessage;
+use crate::client::edits::SentTimes;
+use crate::client::groups::SenderKeyDistribution;
+use crate::client::history::HistoryMessage;
+use crate::client::membership::GroupUpdates;
+use crate::client::receipts::Receipt;
+use crate::client::replies::Quote;
+use crate::client::treekem::TreeKemMessage;
+use crate::client::typing::TypingSignal;
+use serde::{Deserialize, Serialize};
+
+/* Every kind this client reads, with the newest version of its format */
+const KINDS: &[(&str, u32)] = &[
+    ("text", 1),
+    ("attachment", 1),
+    ("reaction", 1),
+    ("edit", 1),
+    ("delete", 1),
+    ("contact_card", 1),
+    ("receipt", 1),
+    ("typing", 1),
+    ("sender_key", 1),
+    ("group_update", 1),
+    ("tree_kem", 1),
+    ("sync", 1),
+    ("history", 1),
+];
+
+/*
+ * The content of an encrypted message.
+ *
+ * Chat content:
+ *  - `Text`       : A text message, quoting the message it replies to if any
+ *  - `Attachment` : A file, uploaded as an encrypted blob (see `client::attachments`)
+ *  - `Reaction`   : An emoji reaction to message `target` (`remove` withdraws it)
+ *  - `Edit`       : A new text for our message `target`, with the `SentTimes`
+ *                   its edit window is checked against (see `client::edits`)
+ *  - `Delete`     : Deletes our message `target` for everyone (likewise)
+ *  - `ContactCard`: Introduces a contact (see `client::cards`)
+ *
+ * Control messages (each sent in an envelope of the matching kind):
+ *  - `Receipt`, `Typing`             : see `client::receipts`, `client::typing`
+ *  - `SenderKey`, `GroupUpdate`, `TreeKem`: group control, see `client::groups`,
+ *                                      `client::membership`, `client::treekem`
+ *  - `Sync`, `History`               : between our devices, see `client::devices`,
+ *                                      `client::history`
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", content = "body", rename_all = "snake_case")]
+pub enum Payload {
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quote: Option<Quote>,
+    },
+    Attachment(Attachment),
+    Reaction { target: String, emoji: String, remove: bool },
+    Edit {
+        target: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<SentTimes>,
+    },
+    Delete {
+        target: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<SentTimes>,
+    },
+    ContactCard(ContactCard),
+    Receipt(Receipt),
+    Typing(TypingSignal),
+    SenderKey(SenderKeyDistribution),
+    GroupUpdate(GroupUpdates),
+    TreeKem(TreeKemMessage),
+    Sync(SyncedMessage),
+    History(HistoryMessage),
+}
+
+impl Payload {
+    /* A text message */
+    pub fn text(text: &str) -> Self {
+        Payload::Text {
+            text: text.to_string(),
+            quote: None,
+        }
+    }
+
+    /* The kind of the payload, as on the wire */
+    pub fn kind(&self) -> &str {
+        match self {
+            Payload::Text { .. } => "text",
+            Payload::Attachment(_) => "attachment",
+            Payload::Reaction { .. } => "reaction",
+            Payload::Edit { .. } => "edit",
+            Payload::Delete { .. } => "delete",
+            Payload::ContactCard(_) => "contact_card",
+            Payload::Receipt(_) => "receipt",
+            Payload::Typing(_) => "typing",
+            Payload::SenderKey(_) => "sender_key",
+            Payload::GroupUpdate(_) => "group_update",
+            Payload::TreeKem(_) => "tree_kem",
+            Payload::Sync(_) => "sync",
+            Payload::History(_) => "history",
+        }
+    }
+
+    /* The version of the payload's format */
+    pub fn version(&self) -> u32 {
+        supported_version(self.kind()).unwrap_or(1)
+    }
+
+    /* Serializes the payload, with its version */
+    pub fn encode(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(object) = value.as_object_mut() {
+            object.insert("version".to_string(), self.version().into());
+        }
+        value.to_string()
+    }
+
+    /*
+     * Reads a plaintext: a payload, or a bare text from before payloads.
+     *
+     * Returns `None` for a payload of an unknown kind or a newer version
+     * than this client reads, and for a known one whose body does not
+     * parse: the caller drops the message.
+     */
+    pub fn decode(plaintext: &str) -> Option<Self> {
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(plaintext) else {
+            return Some(Payload::text(plaintext));
+        };
+        let kind = value.get("kind").and_then(|k| k.as_str());
+        let version = value.get("version").and_then(|v| v.as_u64());
+        let (Some(kind), Some(version)) = (kind, version) else {
+            return Some(Payload::text(plaintext));
+        };
+        let version = u32::try_from(version).ok()?;
+        if version > supported_version(kind)? {
+            return None;
+        }
+        serde_json::from_value(value).ok()
+    }
+
+    /* What the chat shows for the payload, in one line */
+    pub fn summary(&self) -> String {
+        match self {
+            Payload::Text { text, .. } => text.clone(),
+            Payload::Attachment(attachment) => format!("📎 {}", attachment.name),
+            Payload::Reaction { emoji, remove: false, .. } => format!("reacted {emoji}"),
+            Payload::Reaction { emoji, remove: true, .. } => format!("removed reaction {emoji}"),
+            Payload::Edit { text, .. } => format!("edited: {text}"),
+            Payload::Delete { .. } => "deleted a message".to_string(),
+            Payload::ContactCard(card) => format!("📇 {}", card.username()),
+            payload => format!("({})", payload.kind()),
+        }
+    }
+}
+
+/* The newest version of `kind` this client reads, if it knows the kind */
+fn supported_version(kind: &str) -> Option<u32> {
+    KINDS.iter().find(|(known, _)| *known == kind).map(|(_, version)| *version)
+}
+
+/*
+ * Conversions between a control message and its payload variant, so
+ * `client::control` can seal and open any of them.
+ */
+macro_rules! carried {
+    ($($variant:ident($ty:ty)),* $(,)?) => {
+        $(
+            impl From<$ty> for Payload {
+                fn from(value: $ty) -> Self {
+                    Payload::$variant(value)
+                }
+            }
+
+            impl TryFrom<Payload> for $ty {
+                type Error = Payload;
+
+                fn try_from(payload: Payload) -> Result<Self, Payload> {
+                    match payload {
+                        Payload::$variant(value) => Ok(value),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+carried!(
+    Receipt(Receipt),
+    Typing(TypingSignal),
+    SenderKey(SenderKeyDistribution),
+    GroupUpdate(GroupUpdates),
+    TreeKem(TreeKemMessage),
+    Sync(SyncedMessage),
+    History(HistoryMessage),
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_of(payload: Payload) -> Option<String> {
+        match payload {
+            Payload::Text { text, quote: None } => Some(text),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let encoded = Payload::text("hey").encode();
+        assert_eq!(Payload::decode(&encoded).and_then(text_of), Some("hey".to_string()));
+
+        let reaction = Payload::Reaction {
+            target: "m1".to_string(),
+            emoji: "👍".to_string(),
+            remove: false,
+        };
+        let decoded = Payload::decode(&reaction.encode()).unwrap();
+        assert!(matches!(decoded, Payload::Reaction { ref target, remove: false, .. } if target == "m1"));
+        assert_eq!(decoded.version(), 1);
+    }
+
+    #[test]
+    fn unreadable_payloads_are_dropped() {
+        for plaintext in [
+            r#"{"kind": "poll", "version": 1, "body": {}}"#,
+            r#"{"kind": "text", "version": 2, "body": {"text": "hey"}}"#,
+            r#"{"kind": "text", "version": 4294967297, "body": {"text": "wraps to 1"}}"#,
+            r#"{"kind": "reaction", "version": 1, "body": {"target": 3}}"#,
+            r#"{"kind": "text", "version": 1}"#,
+        ] {
+            assert!(Payload::decode(plaintext).is_none(), "{plaintext:?}");
+        }
+    }
+
+    #[test]
+    fn anything_else_is_text() {
+        for plaintext in [
+            "hello",
+            "",
+            "{not json",
+            r#"{"kind": "text", "body": {"text": "no version"}}"#,
+            r#"{"kind": "text", "version": -1, "body": {"text": "no version"}}"#,
+            r#"["kind", "text"]"#,
+            "\u{0}attachment:{\"id\": \"x\"}",
+        ] {
+            assert_eq!(Payload::decode(plaintext).and_then(text_of), Some(plaintext.to_string()), "{plaintext:?}");
+        }
+    }
+}
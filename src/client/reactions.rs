@@ -0,0 +1,164 @@
+/*
+ * This module implements emoji reactions to messages.
+ *
+ * A reaction (`Payload::Reaction`) is sent as a message of its own, in
+ * the same conversation (one-to-one or group), naming the id of the
+ * message it reacts to. Sending the same emoji again with `remove` set
+ * withdraws it, so each person has at most one reaction of each emoji
+ * on a message: the last one they sent counts.
+ *
+ * The reactor is the sender the reaction was authenticated as, never a
+ * name carried in the payload.
+ */
+
+use crate::client::payload::Payload;
+use crate::client::sessions::StoredMessage;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/* Emojis offered by the reaction picker */
+pub const QUICK_REACTIONS: &[&str] = &["👍", "❤", "😂", "😮", "😢", "🙏"];
+
+/* Longest reaction accepted, in bytes (an emoji with its modifiers) */
+const MAX_REACTION_LEN: usize = 32;
+
+/* Whether `emoji` can be sent as a reaction: short, one symbol, no text */
+pub fn valid_reaction(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= MAX_REACTION_LEN
+        && !emoji.chars().any(|c| c.is_whitespace() || c.is_control() || c.is_ascii_alphanumeric())
+}
+
+/*
+ * The reactions of a conversation, by message.
+ *
+ * For each message id, each emoji maps to who reacted with it.
+ */
+#[derive(Default)]
+pub struct Reactions {
+    by_message: HashMap<String, BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl Reactions {
+    /*
+     * Collects the reactions among `messages` (a conversation, in order,
+     * with their payloads).
+     */
+    pub fn apply(messages: &[(&StoredMessage, &Payload)]) -> Self {
+        let mut reactions = Reactions::default();
+        for (message, payload) in messages {
+            let Payload::Reaction { target, emoji, remove } = payload else {
+                continue;
+            };
+            if message.sender.is_empty() || !valid_reaction(emoji) {
+                continue;
+            }
+            let by_emoji = reactions.by_message.entry(target.clone()).or_default();
+            let reactors = by_emoji.entry(emoji.clone()).or_default();
+            if *remove {
+                reactors.remove(&message.sender);
+                if reactors.is_empty() {
+                    by_emoji.remove(emoji);
+                }
+            } else {
+                reactors.insert(message.sender.clone());
+            }
+        }
+        reactions
+    }
+
+    /*
+     * The reactions to message `id`, grouped by emoji: each emoji with
+     * its count, and whether `me` is among those who reacted with it.
+     */
+    pub fn summary(&self, id: &str, me: &str) -> Vec<(String, usize, bool)> {
+        self.by_message
+            .get(id)
+            .map(|by_emoji| {
+                by_emoji
+                    .iter()
+                    .map(|(emoji, reactors)| (emoji.clone(), reactors.len(), reactors.contains(me)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /* Whether `me` reacted to message `id` with `emoji` */
+    pub fn has_reacted(&self, id: &str, emoji: &str, me: &str) -> bool {
+        self.by_message
+            .get(id)
+            .and_then(|by_emoji| by_emoji.get(emoji))
+            .is_some_and(|reactors| reactors.contains(me))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reaction(sender: &str, target: &str, emoji: &str, remove: bool) -> (StoredMessage, Payload) {
+        let message = StoredMessage {
+            sender: sender.to_string(),
+            ..Default::default()
+        };
+        let payload = Payload::Reaction {
+            target: target.to_string(),
+            emoji: emoji.to_string(),
+            remove,
+        };
+        (message, payload)
+    }
+
+    fn collect(reactions: &[(StoredMessage, Payload)]) -> Reactions {
+        Reactions::apply(&reactions.iter().map(|(message, payload)| (message, payload)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn reactions_are_grouped_by_emoji() {
+        let reactions = collect(&[
+            reaction("alice", "m1", "👍", false),
+            reaction("bob", "m1", "👍", false),
+            reaction("bob", "m1", "👍", false),
+            reaction("bob", "m1", "❤", false),
+            reaction("carol", "m2", "😂", false),
+        ]);
+        assert_eq!(
+            reactions.summary("m1", "alice"),
+            vec![("❤".to_string(), 1, false), ("👍".to_string(), 2, true)]
+        );
+        assert!(reactions.has_reacted("m1", "❤", "bob"));
+        assert!(!reactions.has_reacted("m2", "😂", "bob"));
+        assert!(reactions.summary("m3", "alice").is_empty());
+    }
+
+    #[test]
+    fn the_last_reaction_counts() {
+        let reactions = collect(&[
+            reaction("alice", "m1", "👍", false),
+            reaction("bob", "m1", "👍", false),
+            reaction("alice", "m1", "👍", true),
+            reaction("bob", "m1", "❤", true),
+        ]);
+        assert_eq!(reactions.summary("m1", "alice"), vec![("👍".to_string(), 1, false)]);
+
+        let reactions = collect(&[reaction("alice", "m1", "👍", true), reaction("alice", "m1", "👍", false)]);
+        assert!(reactions.has_reacted("m1", "👍", "alice"));
+
+        let reactions = collect(&[reaction("alice", "m1", "👍", false), reaction("alice", "m1", "👍", true)]);
+        assert!(reactions.summary("m1", "alice").is_empty());
+    }
+
+    #[test]
+    fn invalid_reactions_are_ignored() {
+        for emoji in ["", "ok", "👍 👍", "\u{7}", &"👍".repeat(10)] {
+            assert!(!valid_reaction(emoji), "{emoji:?}");
+        }
+        assert!(QUICK_REACTIONS.iter().all(|emoji| valid_reaction(emoji)));
+
+        let reactions = collect(&[
+            reaction("", "m1", "👍", false),
+            reaction("alice", "m1", "ok", false),
+            (StoredMessage::default(), Payload::text("👍")),
+        ]);
+        assert!(reactions.summary("m1", "alice").is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ * This module defines delivery and read receipts.
+ *
+ * A receipt is a small control message telling the sender that some of
+ * their messages were received (`Delivered`) or displayed (`Read`).
+ * It references the acknowledged messages by id and is encrypted end to
+ * end exactly like a normal message (see `client::control`), so the
+ * relay only sees an opaque envelope.
+ *
+ * Receipts are never stored in the `Session`: they only update the
+ * delivery state of the matching `Outbox` entries.
+ */
+
+use crate::client::control;
+use crate::client::user::User;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+
+/* What a receipt acknowledges */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+/*
+ * A receipt for one or more messages.
+ *
+ * Fields:
+ *  - `kind`        : Delivered or Read
+ *  - `message_ids` : Ids of the acknowledged messages (`StoredMessage::id`)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Receipt {
+    pub kind: ReceiptKind,
+    pub message_ids: Vec<String>,
+}
+
+impl Receipt {
+    /* Encrypts the receipt from `sender` to `peer` (envelope kind `Receipt`) */
+    pub fn seal(&self, sender: &User, peer: &User) -> Option<Envelope> {
+        control::seal(EnvelopeKind::Receipt, self, sender, peer)
+    }
+
+    /* Decrypts a receipt addressed to `me`, if the envelope holds one */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::Receipt, envelope, me)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::outbox::{DeliveryState, Outbox};
+    use crate::client::typing::TypingSignal;
+
+    #[test]
+    fn receipts_reach_only_their_recipient() {
+        let alice = User::new("alice", 1);
+        let bob = User::new("bob", 1);
+        let receipt = Receipt {
+            kind: ReceiptKind::Read,
+            message_ids: vec!["m1".to_string(), "m2".to_string()],
+        };
+        let envelope = receipt.seal(&bob, &alice).unwrap();
+        assert_eq!((envelope.kind, envelope.recipient.as_str()), (EnvelopeKind::Receipt, "alice"));
+
+        let opened = Receipt::open(&envelope, &alice).unwrap();
+        assert_eq!((opened.kind, opened.message_ids), (receipt.kind, receipt.message_ids));
+        assert!(Receipt::open(&envelope, &User::new("alice", 1)).is_none());
+        assert!(TypingSignal::open(&envelope, &alice).is_none());
+
+        let mut typing = envelope.clone();
+        typing.kind = EnvelopeKind::Typing;
+        assert!(Receipt::open(&typing, &alice).is_none());
+    }
+
+    #[test]
+    fn receipts_move_the_outbox_forward() {
+        let mut outbox = Outbox::default();
+        for id in ["m1", "m2"] {
+            outbox.enqueue(Envelope {
+                id: id.to_string(),
+                ..Default::default()
+            });
+            outbox.mark_sent(id);
+        }
+        let receipts = [(ReceiptKind::Delivered, "m1"), (ReceiptKind::Read, "m2"), (ReceiptKind::Delivered, "m2")];
+        for (kind, id) in receipts {
+            match kind {
+                ReceiptKind::Delivered => outbox.mark_delivered(id),
+                ReceiptKind::Read => outbox.mark_read(id),
+            }
+        }
+        assert_eq!(outbox.state("m1"), Some(DeliveryState::Delivered));
+        assert_eq!(outbox.state("m2"), Some(DeliveryState::Read), "a late delivery receipt does not undo a read");
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * This module implements quoted replies.
+ *
+ * A reply is a text message (`Payload::Text`) carrying a `Quote`: the
+ * id of the message it answers, its author and a short excerpt of it.
+ * Older clients ignore the quote and read the reply as plain text.
+ *
+ * The excerpt is what the sender saw; the chat shows the original as
+ * stored on this device when it has it, and only falls back to the
+ * excerpt when the original never arrived.
+ */
+
+use crate::client::payload::Payload;
+use serde::{Deserialize, Serialize};
+
+/* Longest excerpt of a quoted message, in characters */
+pub const EXCERPT_LEN: usize = 80;
+
+/*
+ * The message a reply answers.
+ *
+ * Fields:
+ *  - `id`      : Id of the quoted message (`StoredMessage::id`)
+ *  - `author`  : Who wrote it
+ *  - `excerpt` : The start of it, at most `EXCERPT_LEN` characters
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Quote {
+    pub id: String,
+    pub author: String,
+    pub excerpt: String,
+}
+
+impl Quote {
+    /* A quote of message `id` by `author`, carrying `payload` */
+    pub fn of(id: &str, author: &str, payload: &Payload) -> Self {
+        Self {
+            id: id.to_string(),
+            author: author.to_string(),
+            excerpt: excerpt(&payload.summary()),
+        }
+    }
+}
+
+/*
+ * Shortens `text` to one line of at most `EXCERPT_LEN` characters,
+ * marking the cut with an ellipsis.
+ */
+pub fn excerpt(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= EXCERPT_LEN {
+        return line;
+    }
+    let mut short: String = line.chars().take(EXCERPT_LEN - 1).collect();
+    short.push('…');
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /* A text message as read by a client from before quoted replies */
+    #[derive(Deserialize)]
+    struct OlderText {
+        text: String,
+    }
+
+    #[test]
+    fn older_clients_read_replies_as_text() {
+        let reply = Payload::Text {
+            text: "sure".to_string(),
+            quote: Some(Quote::of("m1", "alice", &Payload::text("lunch?"))),
+        };
+        let value: Value = serde_json::from_str(&reply.encode()).unwrap();
+        assert_eq!(value["kind"], "text");
+        assert_eq!(value["version"], 1);
+        let older: OlderText = serde_json::from_value(value["body"].clone()).unwrap();
+        assert_eq!(older.text, "sure");
+
+        /* And replies read messages without a quote */
+        let Some(Payload::Text { text, quote }) = Payload::decode(&Payload::text("hey").encode()) else {
+            panic!("not a text");
+        };
+        assert_eq!((text.as_str(), quote), ("hey", None));
+    }
+
+    #[test]
+    fn quotes_carry_a_short_excerpt() {
+        let quote = Quote::of("m1", "alice", &Payload::text("see\nyou   there"));
+        assert_eq!(quote.excerpt, "see you there");
+
+        let long = "x".repeat(EXCERPT_LEN + 1);
+        let short = excerpt(&long);
+        assert_eq!(short.chars().count(), EXCERPT_LEN);
+        assert!(short.ends_with('…'));
+        assert_eq!(excerpt(&long[1..]), long[1..]);
+    }
+}
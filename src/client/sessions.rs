@@ -3,15 +3,19 @@
  * for persisting encrypted conversations with contacts.
  *
  * Each conversation is identified by the recipient's username
- * and contains a list of `StoredMessage` entries.
+ * (or a group id, see `client::groups`) and contains a list of
+ * `StoredMessage` entries. Group state is stored alongside.
  *
  * Messages are serialized to and from JSON, enabling persistence
  * across application runs.
  */
 
+use crate::client::groups::Group;
+use crate::client::outbox::now_ms;
 use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::box_;
-use std::collections::HashMap;
+use sodiumoxide::randombytes;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 
@@ -19,17 +23,33 @@ use std::path::Path;
  * Represents a single encrypted message that is stored in a session.
  *
  * Fields:
+ *  - `id`           : Unique message identifier (hex), also used on the wire
+ *  - `sender`       : Username of the author of the message
  *  - `ciphertext`   : The encrypted payload of the message
  *  - `ephemeral_pk` : The sender's ephemeral public key (as raw bytes)
  *  - `nonce`        : The nonce used during encryption
  *  - `log`          : A human-readable log of the encryption/decryption process
+ *  - `read`         : For received messages, whether they were displayed
+ *  - `message_key`  : For group messages, the sender-key message key
+ *  - `sent_at`      : When the message was stored on this device (ms since the
+ *                     Unix epoch; 0 for messages from before timestamps)
  */
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct StoredMessage {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub sender: String,
     pub ciphertext: Vec<u8>,
     pub ephemeral_pk: Vec<u8>,
     pub nonce: Vec<u8>,
     pub log: String,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_key: Vec<u8>,
+    #[serde(default)]
+    pub sent_at: u64,
 }
 
 /*
@@ -40,8 +60,21 @@ pub struct StoredMessage {
  */
 #[derive(Serialize, Deserialize, Default)]
 pub struct Session {
-    /* Key = recipient username, Value = list of messages with that recipient */
+    /* Key = recipient username (or group id), Value = list of messages with that recipient */
     pub conversations: HashMap<String, Vec<StoredMessage>>,
+    /* Key = group id, Value = group members and sender keys */
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+    /* Key = recipient username (or group id), Value = changes to its messages since loaded */
+    #[serde(skip)]
+    changes: HashMap<String, u64>,
+}
+
+/*
+ * Generates a fresh random message identifier (16 bytes, hex-encoded).
+ */
+pub fn new_message_id() -> String {
+    hex::encode(randombytes::randombytes(16))
 }
 
 impl Session {
@@ -76,6 +109,7 @@ impl Session {
      *
      * Parameters:
      *  - `recipient`   : The username of the message recipient
+     *  - `sender`      : The username of the author
      *  - `ciphertext`  : The encrypted message payload
      *  - `ephemeral_pk`: The sender's ephemeral public key
      *  - `nonce`       : The encryption nonce
@@ -83,22 +117,119 @@ impl Session {
      *
      * If the recipient does not already exist in the session,
      * a new conversation entry will be created automatically.
+     *
+     * Returns the identifier generated for the message.
      */
     pub fn add_message(
         &mut self,
         recipient: &str,
+        sender: &str,
         ciphertext: Vec<u8>,
         ephemeral_pk: box_::PublicKey,
         nonce: box_::Nonce,
         log: String,
-    ) {
-        let entry = self.conversations.entry(recipient.to_string()).or_default();
-        entry.push(StoredMessage {
-            ciphertext,
-            ephemeral_pk: ephemeral_pk.as_ref().to_vec(),
-            nonce: nonce.0.to_vec(),
-            log,
-        });
+    ) -> String {
+        let id = new_message_id();
+        self.insert_message(
+            recipient,
+            StoredMessage {
+                id: id.clone(),
+                sender: sender.to_string(),
+                ciphertext,
+                ephemeral_pk: ephemeral_pk.as_ref().to_vec(),
+                nonce: nonce.0.to_vec(),
+                log,
+                read: true,
+                message_key: Vec::new(),
+                sent_at: now_ms(),
+            },
+        );
+        id
+    }
+
+    /*
+     * Inserts an already built message into the conversation `peer`.
+     *
+     * Messages whose `id` is already present are ignored, so a message
+     * delivered twice (e.g. after a reconnect) is only stored once.
+     *
+     * Returns `true` if the message was inserted.
+     */
+    pub fn insert_message(&mut self, peer: &str, message: StoredMessage) -> bool {
+        let entry = self.conversations.entry(peer.to_string()).or_default();
+        if !message.id.is_empty() && entry.iter().any(|m| m.id == message.id) {
+            return false;
+        }
+        entry.push(message);
+        *self.changes.entry(peer.to_string()).or_default() += 1;
+        true
+    }
+
+    /*
+     * Merges `history`, an earlier copy of the conversation `peer` (e.g.
+     * transferred from another device), into it.
+     *
+     * The history comes first, in its order, followed by the messages
+     * it does not hold; a message present on both sides (same `id`) is
+     * kept once, as stored here. Messages without an id (from before
+     * ids existed) are all kept.
+     *
+     * Returns the number of messages that were not stored yet.
+     */
+    pub fn merge_history(&mut self, peer: &str, history: Vec<StoredMessage>) -> usize {
+        let existing = self.conversations.remove(peer).unwrap_or_default();
+        let history_ids: HashSet<String> = history.iter().map(|m| m.id.clone()).collect();
+        let (shared, rest): (Vec<StoredMessage>, Vec<StoredMessage>) = existing
+            .into_iter()
+            .partition(|m| !m.id.is_empty() && history_ids.contains(&m.id));
+        let mut shared: HashMap<String, StoredMessage> = shared.into_iter().map(|m| (m.id.clone(), m)).collect();
+
+        let mut merged = Vec::with_capacity(history.len() + rest.len());
+        let mut seen = HashSet::new();
+        let mut added = 0;
+        for message in history {
+            if !message.id.is_empty() && !seen.insert(message.id.clone()) {
+                continue;
+            }
+            match shared.remove(&message.id) {
+                Some(local) => merged.push(local),
+                None => {
+                    added += 1;
+                    merged.push(message);
+                }
+            }
+        }
+        merged.extend(rest);
+        self.conversations.insert(peer.to_string(), merged);
+        *self.changes.entry(peer.to_string()).or_default() += 1;
+        added
+    }
+
+    /*
+     * Marks every unread message received from `peer` as read
+     * and returns their ids.
+     */
+    pub fn mark_read(&mut self, peer: &str, me: &str) -> Vec<String> {
+        let Some(messages) = self.conversations.get_mut(peer) else {
+            return Vec::new();
+        };
+        messages
+            .iter_mut()
+            .filter(|m| !m.read && m.sender != me)
+            .map(|m| {
+                m.read = true;
+                m.id.clone()
+            })
+            .collect()
+    }
+
+    /*
+     * How many times messages were added to (or reordered in) the
+     * conversation `peer` since the session was loaded, so that what is
+     * derived from them (e.g. their decrypted payloads) can be cached.
+     */
+    pub fn changes(&self, peer: &str) -> u64 {
+        self.changes.get(peer).copied().unwrap_or(0)
     }
 
     /*
@@ -0,0 +1,62 @@
+/*
+ * This module defines the local user's `Settings`,
+ * persisted as JSON next to the session.
+ *
+ * Settings are privacy choices that only affect what this client
+ * sends; they are never shared with contacts or the relay.
+ */
+
+use crate::client::edits::DEFAULT_EDIT_WINDOW;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/*
+ * Per-user preferences.
+ *
+ * Fields:
+ *  - `send_read_receipts` : Tell contacts when their messages were read
+ *  - `typing_indicators`  : Send typing indicators, and show the contacts' ones
+ *  - `edit_window`        : How long after a message edits and deletes of it are
+ *                           accepted, in seconds (see `client::edits`)
+ */
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Settings {
+    pub send_read_receipts: bool,
+    pub typing_indicators: bool,
+    pub edit_window: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            send_read_receipts: true,
+            typing_indicators: true,
+            edit_window: DEFAULT_EDIT_WINDOW,
+        }
+    }
+}
+
+impl Settings {
+    /*
+     * Loads settings from a JSON file located at `path`.
+     *
+     * Missing files or fields fall back to the defaults.
+     */
+    pub fn load(path: &str) -> Self {
+        if Path::new(path).exists() {
+            let data = fs::read_to_string(path).unwrap_or_default();
+            serde_json::from_str(&data).unwrap_or_default()
+        } else {
+            Settings::default()
+        }
+    }
+
+    /* Saves the settings to a JSON file at `path` */
+    pub fn save(&self, path: &str) {
+        if let Ok(json) = serde_json::to_string_pretty(self) {
+            let _ = fs::write(path, json);
+        }
+    }
+}
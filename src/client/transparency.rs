@@ -0,0 +1,283 @@
+/*
+ * This module defines the `Monitor`, the client-side half of key
+ * transparency (see `net::transparency`).
+ *
+ * Every directory lookup goes through the monitor, which checks:
+ *  1. the tree head is signed by the relay's log key
+ *     (pinned the first time it is seen)
+ *  2. the tree head is consistent with the last one seen: the log only
+ *     grew, nothing was rewritten and the size never went back
+ *  3. the returned claim is included in that tree, at the given index
+ *
+ * Failures of 1 and 2 mean the relay showed us a different history than
+ * before (a fork, or a rollback): they are reported as divergence.
+ *
+ * Lookups are serialized, so each one is checked against the tree head
+ * left by the previous one. A monitor created with `load` keeps the log
+ * key and the last tree head in a JSON file: the first lookup of the
+ * next run is checked against them, so a relay cannot hand out another
+ * log after a restart of the app.
+ */
+
+use crate::net::directory::UsernameClaim;
+use crate::net::transparency::{leaf_hash, verify_consistency, verify_inclusion, DirectoryLookup, SignedTreeHead};
+use crate::net::transport::Transport;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tokio::sync::Mutex;
+
+/* Reasons a lookup can be rejected */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransparencyError {
+    /* The lookup itself failed */
+    Transport(String),
+    /* The relay signs tree heads with another key than before */
+    LogKeyChanged,
+    /* The tree head signature does not verify */
+    BadTreeHead,
+    /* The tree is smaller than one seen before */
+    Rollback { seen: u64, got: u64 },
+    /* The tree does not extend the one seen before */
+    Inconsistent { seen: u64, got: u64 },
+    /* The claim is for another username than the one looked up */
+    WrongUsername,
+    /* The claim is not in the signed tree */
+    NotIncluded,
+}
+
+impl TransparencyError {
+    /* Whether the relay showed us two incompatible versions of its log */
+    pub fn is_divergence(&self) -> bool {
+        matches!(
+            self,
+            TransparencyError::LogKeyChanged
+                | TransparencyError::Rollback { .. }
+                | TransparencyError::Inconsistent { .. }
+        )
+    }
+}
+
+impl fmt::Display for TransparencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransparencyError::Transport(e) => write!(f, "lookup failed: {e}"),
+            TransparencyError::LogKeyChanged => f.write_str("the relay's log key changed"),
+            TransparencyError::BadTreeHead => f.write_str("tree head signature does not verify"),
+            TransparencyError::Rollback { seen, got } => {
+                write!(f, "log went back from {seen} to {got} entries")
+            }
+            TransparencyError::Inconsistent { seen, got } => {
+                write!(f, "log of {got} entries does not extend the one of {seen} entries seen before")
+            }
+            TransparencyError::WrongUsername => f.write_str("the relay returned a claim for another username"),
+            TransparencyError::NotIncluded => f.write_str("claim is not included in the signed log"),
+        }
+    }
+}
+
+impl std::error::Error for TransparencyError {}
+
+/*
+ * What the monitor remembers of the log.
+ *
+ * Fields:
+ *  - `log_key` : The pinned log key
+ *  - `head`    : The last tree head that passed every check
+ */
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct MonitorState {
+    log_key: Option<sign::PublicKey>,
+    head: Option<SignedTreeHead>,
+}
+
+/*
+ * Verifies directory lookups against the history of the log.
+ *
+ * Fields:
+ *  - `state` : What was seen of the log so far
+ *  - `path`  : JSON file the state is saved to after each lookup, if any
+ */
+#[derive(Debug, Default)]
+pub struct Monitor {
+    state: Mutex<MonitorState>,
+    path: Option<String>,
+}
+
+impl Monitor {
+    /* Creates a monitor that has not seen any tree head yet (kept in memory only) */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+     * Creates a monitor that remembers the log in a JSON file at `path`,
+     * starting from what it holds (nothing if it is missing or invalid).
+     */
+    pub fn load(path: &str) -> Self {
+        let state = if Path::new(path).exists() {
+            let data = fs::read_to_string(path).unwrap_or_default();
+            serde_json::from_str(&data).unwrap_or_default()
+        } else {
+            MonitorState::default()
+        };
+        Self {
+            state: Mutex::new(state),
+            path: Some(path.to_string()),
+        }
+    }
+
+    /*
+     * Looks `username` up through `transport` and verifies the answer.
+     *
+     * Returns the claim (if the username is registered) only when every
+     * check passes.
+     */
+    pub async fn lookup(&self, transport: &dyn Transport, username: &str) -> Result<Option<UsernameClaim>, TransparencyError> {
+        let mut state = self.state.lock().await;
+        let known_size = state.head.as_ref().map_or(0, |h| h.size);
+        let lookup = transport
+            .lookup(username, known_size)
+            .await
+            .map_err(|e| TransparencyError::Transport(e.to_string()))?;
+        let result = state.check(username, lookup);
+        if let Some(path) = &self.path {
+            state.save(path);
+        }
+        result
+    }
+}
+
+impl MonitorState {
+    /* Saves the state to a JSON file at `path` */
+    fn save(&self, path: &str) {
+        if let Ok(json) = serde_json::to_string_pretty(self) {
+            let _ = fs::write(path, json);
+        }
+    }
+
+    fn check(&mut self, username: &str, lookup: DirectoryLookup) -> Result<Option<UsernameClaim>, TransparencyError> {
+        /* 1) Signed by the pinned log key */
+        let log_key = sign::PublicKey::from_slice(&lookup.log_key).ok_or(TransparencyError::BadTreeHead)?;
+        if self.log_key.is_some_and(|pinned| pinned != log_key) {
+            return Err(TransparencyError::LogKeyChanged);
+        }
+        if !lookup.head.verify(&log_key) {
+            return Err(TransparencyError::BadTreeHead);
+        }
+        self.log_key = Some(log_key);
+
+        /* 2) Consistent with the last tree head seen */
+        let head = lookup.head;
+        if let Some(seen) = &self.head {
+            if head.size < seen.size {
+                return Err(TransparencyError::Rollback {
+                    seen: seen.size,
+                    got: head.size,
+                });
+            }
+            if !verify_consistency(seen.size, head.size, &seen.root, &head.root, &lookup.consistency) {
+                return Err(TransparencyError::Inconsistent {
+                    seen: seen.size,
+                    got: head.size,
+                });
+            }
+        }
+
+        /* 3) The claim is in the tree */
+        let result = match lookup.claim {
+            Some(claim) if claim.username != username => Err(TransparencyError::WrongUsername),
+            Some(claim) if !verify_inclusion(&leaf_hash(&claim), lookup.index, head.size, &lookup.inclusion, &head.root) => {
+                Err(TransparencyError::NotIncluded)
+            }
+            claim => Ok(claim),
+        };
+        self.head = Some(head);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::user::User;
+    use crate::server::directory::Directory;
+
+    fn claim(name: &str) -> UsernameClaim {
+        User::new(name, 1).username_claim().unwrap()
+    }
+
+    fn path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("blackipher-{name}-{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn lookups_are_checked() {
+        let mut directory = Directory::new();
+        directory.register(claim("alice")).unwrap();
+        let mut state = MonitorState::default();
+        assert_eq!(state.check("alice", directory.lookup("alice", 0)).unwrap().unwrap().username, "alice");
+        assert_eq!(state.check("bob", directory.lookup("bob", 1)), Ok(None));
+
+        let mut lookup = directory.lookup("alice", 1);
+        lookup.claim = Some(claim("alice"));
+        assert_eq!(state.check("alice", lookup), Err(TransparencyError::NotIncluded));
+        let lookup = directory.lookup("alice", 1);
+        assert_eq!(state.check("bob", lookup), Err(TransparencyError::WrongUsername));
+        let mut lookup = directory.lookup("alice", 1);
+        lookup.head.signature[0] ^= 1;
+        assert_eq!(state.check("alice", lookup), Err(TransparencyError::BadTreeHead));
+    }
+
+    #[test]
+    fn saved_head_catches_divergence_after_a_restart() {
+        let (relay_file, monitor_file) = (path("directory"), path("monitor"));
+        let mut directory = Directory::new();
+        directory.register(claim("alice")).unwrap();
+        directory.save(&relay_file);
+        directory.register(claim("bob")).unwrap();
+
+        let mut state = MonitorState::default();
+        state.check("bob", directory.lookup("bob", 0)).unwrap();
+        state.save(&monitor_file);
+        let reloaded = || {
+            let monitor = Monitor::load(&monitor_file);
+            monitor.state.into_inner()
+        };
+
+        /* The relay restarted with the log it saved before adding bob */
+        let restored = Directory::load(&relay_file).unwrap();
+        assert_eq!(
+            reloaded().check("alice", restored.lookup("alice", 2)),
+            Err(TransparencyError::Rollback { seen: 2, got: 1 })
+        );
+
+        /* ... or with a new log of the same size */
+        let mut other = Directory::new();
+        other.register(claim("alice")).unwrap();
+        other.register(claim("bob")).unwrap();
+        assert_eq!(reloaded().check("alice", other.lookup("alice", 2)), Err(TransparencyError::LogKeyChanged));
+
+        /* ... or with a different log under the same key */
+        let mut forked = Directory::load(&relay_file).unwrap();
+        forked.register(claim("carol")).unwrap();
+        assert_eq!(
+            reloaded().check("alice", forked.lookup("alice", 2)),
+            Err(TransparencyError::Inconsistent { seen: 2, got: 2 })
+        );
+
+        /* The same log, grown, is fine */
+        directory.save(&relay_file);
+        let mut grown = Directory::load(&relay_file).unwrap();
+        grown.register(claim("carol")).unwrap();
+        assert!(reloaded().check("carol", grown.lookup("carol", 2)).unwrap().is_some());
+
+        let _ = fs::remove_file(relay_file);
+        let _ = fs::remove_file(monitor_file);
+    }
+}
@@ -0,0 +1,1163 @@
+/*
+ * This module implements an optional group mode based on TreeKEM, the
+ * ratchet tree of MLS (RFC 9420), as an educational counterpart to
+ * Sender Keys (see `client::groups`).
+ *
+ * Members sit at the leaves of a binary tree. Every node holds an X25519
+ * key pair (or is blank); a member knows the secret keys of the nodes on
+ * its direct path, from its leaf up to the root. The tree is stored as an
+ * array (leaf `i` at node `2i`, parents in between), with a power of two
+ * leaves.
+ *
+ * The group moves from epoch to epoch with commits. A commit applies
+ * proposals (add or remove members; none for a plain key update), then
+ * refreshes the committer's direct path:
+ *
+ *     path_secret[0]   = random                     (leaf)
+ *     path_secret[i+1] = HMAC(path_secret[i], "path")
+ *     node key pair    = X25519 from HMAC(path_secret[i], "node")
+ *     commit_secret    = HMAC(path_secret[root], "path")
+ *
+ * Each path secret is sealed (`sealedbox`) to the resolution of the
+ * copath node: the non-blank nodes covering the other half of the
+ * subtree, so each member can decrypt exactly one of them and derive the
+ * rest of the path up to the root. The key schedule then gives:
+ *
+ *     joiner_secret     = HMAC(init_secret, commit_secret)
+ *     epoch_secret      = HMAC(joiner_secret, "epoch" || group || epoch)
+ *     init_secret'      = HMAC(epoch_secret, "init")
+ *     encryption_secret = HMAC(epoch_secret, "encryption")
+ *
+ * New members get a `Welcome` sealed to their signed pre-key, with the
+ * joiner secret and the path secret of their lowest common ancestor with
+ * the committer. Removing a member blanks its leaf and direct path, so
+ * the next path secrets are never sealed to a key it knows.
+ *
+ * Group messages are encrypted with a key derived from the encryption
+ * secret, the sender and a per-epoch counter, and signed with the
+ * sender's identity key.
+ *
+ * Simplifications (this is a teaching client): no proposals by reference,
+ * no unmerged leaves (the direct path of a new member is blanked instead),
+ * a single committer at a time, and message keys are not deleted after
+ * use within an epoch.
+ */
+
+use crate::client::control;
+use crate::client::groups::GroupMessage;
+use crate::client::payload::Payload;
+use crate::client::user::User;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::{box_, sealedbox, secretbox, sign};
+use sodiumoxide::randombytes;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/* Domain separation tag for commit and welcome signatures */
+const TREEKEM_CONTEXT: &[u8] = b"blackipher-treekem-v1";
+
+/* Encryption secrets of previous epochs kept for late messages */
+pub const MAX_PAST_EPOCHS: usize = 3;
+
+/* Commit logs kept for display */
+const MAX_LOGS: usize = 20;
+
+/* Reasons a commit, welcome or message can be rejected */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeKemError {
+    /* The message is for another group */
+    WrongGroup,
+    /* The commit or message is not for our current epoch */
+    WrongEpoch { ours: u64, got: u64 },
+    /* The message is for an epoch whose secrets were discarded */
+    UnknownEpoch(u64),
+    /* The signature does not verify */
+    BadSignature,
+    /* The member is not in the tree */
+    UnknownMember(String),
+    /* The added member is already in the tree */
+    AlreadyMember(String),
+    /* The commit removes us from the group */
+    Removed,
+    /* None of the sealed path secrets is for a key we hold */
+    NoPathSecret,
+    /* The update path does not match the tree */
+    PathMismatch,
+    /* A sealed secret or the ciphertext does not decrypt */
+    DecryptionFailed,
+    /* The message was already received */
+    Replayed,
+    /* This device does not hold the account's identity key (linked device) */
+    NoIdentityKey,
+    /* The payload is of a kind or version this client does not read */
+    Unreadable,
+}
+
+impl fmt::Display for TreeKemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeKemError::WrongGroup => f.write_str("message for another group"),
+            TreeKemError::WrongEpoch { ours, got } => write!(f, "epoch {got} while the group is at epoch {ours}"),
+            TreeKemError::UnknownEpoch(epoch) => write!(f, "secrets of epoch {epoch} were discarded"),
+            TreeKemError::BadSignature => f.write_str("bad signature"),
+            TreeKemError::UnknownMember(name) => write!(f, "{name} is not in the tree"),
+            TreeKemError::AlreadyMember(name) => write!(f, "{name} is already in the tree"),
+            TreeKemError::Removed => f.write_str("we were removed from the group"),
+            TreeKemError::NoPathSecret => f.write_str("no path secret sealed to a key we hold"),
+            TreeKemError::PathMismatch => f.write_str("update path does not match the tree"),
+            TreeKemError::DecryptionFailed => f.write_str("decryption failed"),
+            TreeKemError::Replayed => f.write_str("message already received"),
+            TreeKemError::NoIdentityKey => f.write_str("only the primary device can sign for the account"),
+            TreeKemError::Unreadable => f.write_str("payload this client does not read"),
+        }
+    }
+}
+
+impl std::error::Error for TreeKemError {}
+
+/* Level of node `x` in the array tree (RFC 9420, appendix C): 0 for leaves */
+fn level(x: usize) -> u32 {
+    x.trailing_ones()
+}
+
+fn left(x: usize) -> usize {
+    x ^ (1 << (level(x) - 1))
+}
+
+fn right(x: usize) -> usize {
+    x ^ (3 << (level(x) - 1))
+}
+
+fn parent(x: usize) -> usize {
+    let k = level(x);
+    (x | (1 << k)) & !(1 << (k + 1))
+}
+
+fn sibling(x: usize) -> usize {
+    let p = parent(x);
+    if x < p { right(p) } else { left(p) }
+}
+
+/* Root of a tree of `leaves` leaves (a power of two) */
+fn root(leaves: usize) -> usize {
+    leaves - 1
+}
+
+/* Ancestors of node `x`, from its parent up to the root */
+fn direct_path(x: usize, leaves: usize) -> Vec<usize> {
+    let mut path = Vec::new();
+    let mut node = x;
+    while node != root(leaves) {
+        node = parent(node);
+        path.push(node);
+    }
+    path
+}
+
+/* Whether node `x` is in the subtree of node `p` */
+fn covers(p: usize, x: usize) -> bool {
+    let span = (1usize << level(p)) - 1;
+    x + span >= p && x <= p + span
+}
+
+fn hmac(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    hmacsha256::authenticate(data, &hmacsha256::Key(*key)).0
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&randombytes::randombytes(32));
+    secret
+}
+
+fn next_path_secret(path_secret: &[u8; 32]) -> [u8; 32] {
+    hmac(path_secret, b"path")
+}
+
+/* The key pair of a node, derived from its path secret */
+fn node_keypair(path_secret: &[u8; 32]) -> (box_::PublicKey, box_::SecretKey) {
+    box_::keypair_from_seed(&box_::Seed(hmac(path_secret, b"node")))
+}
+
+fn to_secret(bytes: &[u8]) -> Option<[u8; 32]> {
+    bytes.try_into().ok()
+}
+
+/* Message key for `sender`'s message number `generation` in an epoch */
+fn message_key(encryption_secret: &[u8; 32], sender: &str, generation: u32) -> [u8; 32] {
+    let mut data = b"message".to_vec();
+    data.extend_from_slice(&(sender.len() as u32).to_be_bytes());
+    data.extend_from_slice(sender.as_bytes());
+    data.extend_from_slice(&generation.to_be_bytes());
+    hmac(encryption_secret, &data)
+}
+
+/* Key id of the messages of `epoch` (`GroupMessage::key_id`) */
+fn epoch_key_id(epoch: u64) -> String {
+    format!("epoch-{epoch}")
+}
+
+fn signature_of(data: &[u8], identity_sk: &sign::SecretKey) -> Vec<u8> {
+    let mut message = TREEKEM_CONTEXT.to_vec();
+    message.extend_from_slice(data);
+    sign::sign_detached(&message, identity_sk).as_ref().to_vec()
+}
+
+fn verify_signature(data: &[u8], signature: &[u8], signer_pk: &sign::PublicKey) -> bool {
+    let Ok(signature) = sign::Signature::try_from(signature) else {
+        return false;
+    };
+    let mut message = TREEKEM_CONTEXT.to_vec();
+    message.extend_from_slice(data);
+    sign::verify_detached(&signature, &message, signer_pk)
+}
+
+/* A change of membership carried by a commit */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "proposal", rename_all = "snake_case")]
+pub enum Proposal {
+    /* Adds `member`, whose leaf key is its signed pre-key */
+    Add { member: String, init_key: Vec<u8> },
+    /* Removes `member` */
+    Remove { member: String },
+}
+
+impl fmt::Display for Proposal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Proposal::Add { member, .. } => write!(f, "add {member}"),
+            Proposal::Remove { member } => write!(f, "remove {member}"),
+        }
+    }
+}
+
+/*
+ * A node of an update path.
+ *
+ * Fields:
+ *  - `node`    : Node index
+ *  - `public`  : New public key of the node
+ *  - `secrets` : Its path secret, sealed to each node of the resolution
+ *                of the copath node (in resolution order)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PathNode {
+    pub node: usize,
+    pub public: Vec<u8>,
+    pub secrets: Vec<Vec<u8>>,
+}
+
+/*
+ * A commit, moving the group from `epoch` to `epoch + 1`.
+ *
+ * Fields:
+ *  - `group_id`    : Group the commit applies to
+ *  - `epoch`       : Epoch the commit starts from
+ *  - `committer`   : Username of the member making the commit
+ *  - `proposals`   : Membership changes, applied before the path
+ *  - `leaf_public` : New leaf key of the committer
+ *  - `path`        : New keys of the committer's direct path
+ *  - `signature`   : Signature by the committer's identity key
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Commit {
+    pub group_id: String,
+    pub epoch: u64,
+    pub committer: String,
+    pub proposals: Vec<Proposal>,
+    pub leaf_public: Vec<u8>,
+    pub path: Vec<PathNode>,
+    pub signature: Vec<u8>,
+}
+
+impl Commit {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let body = (&self.group_id, self.epoch, &self.committer, &self.proposals, &self.leaf_public, &self.path);
+        serde_json::to_vec(&body).unwrap_or_default()
+    }
+
+    /* Checks the signature against the committer's identity key */
+    pub fn verify(&self, committer_pk: &sign::PublicKey) -> bool {
+        verify_signature(&self.signed_bytes(), &self.signature, committer_pk)
+    }
+}
+
+/*
+ * Lets a new member join the group at `epoch`.
+ *
+ * Fields:
+ *  - `group_id`  : Group joined
+ *  - `epoch`     : Epoch the commit adding the member led to
+ *  - `committer` : Username of the member who added them
+ *  - `leaves`    : Members of the tree, by leaf
+ *  - `nodes`     : Public keys of the tree, by node
+ *  - `secrets`   : `GroupSecrets`, sealed to the new member's signed pre-key
+ *  - `signature` : Signature by the committer's identity key
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Welcome {
+    pub group_id: String,
+    pub epoch: u64,
+    pub committer: String,
+    pub leaves: Vec<Option<String>>,
+    pub nodes: Vec<Option<Vec<u8>>>,
+    pub secrets: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Welcome {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let body = (&self.group_id, self.epoch, &self.committer, &self.leaves, &self.nodes, &self.secrets);
+        serde_json::to_vec(&body).unwrap_or_default()
+    }
+
+    /* Checks the signature against the committer's identity key */
+    pub fn verify(&self, committer_pk: &sign::PublicKey) -> bool {
+        verify_signature(&self.signed_bytes(), &self.signature, committer_pk)
+    }
+}
+
+/* The secrets inside a `Welcome` */
+#[derive(Serialize, Deserialize)]
+struct GroupSecrets {
+    joiner_secret: [u8; 32],
+    path_secret: [u8; 32],
+    node: usize,
+}
+
+/* A commit or a welcome, sent pairwise (envelope kind `TreeKem`) */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TreeKemMessage {
+    Commit(Commit),
+    Welcome(Welcome),
+}
+
+impl TreeKemMessage {
+    /* The group the message is about */
+    pub fn group_id(&self) -> &str {
+        match self {
+            TreeKemMessage::Commit(commit) => &commit.group_id,
+            TreeKemMessage::Welcome(welcome) => &welcome.group_id,
+        }
+    }
+
+    /* Encrypts the message from `sender` to `peer` */
+    pub fn seal(&self, sender: &User, peer: &User) -> Option<Envelope> {
+        control::seal(EnvelopeKind::TreeKem, self, sender, peer)
+    }
+
+    /* Decrypts a message addressed to `me`, if the envelope holds one */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::TreeKem, envelope, me)
+    }
+}
+
+/*
+ * Our view of the ratchet tree of a group.
+ *
+ * Fields:
+ *  - `group_id`          : Group the tree belongs to
+ *  - `epoch`             : Current epoch
+ *  - `nodes`             : Public key of each node (`None` = blank)
+ *  - `leaves`            : Member at each leaf (`None` = free)
+ *  - `own_leaf`          : Our leaf index
+ *  - `secrets`           : Secret keys we hold, by node (our direct path)
+ *  - `init_secret`       : Secret chaining this epoch to the next one
+ *  - `encryption_secret` : Secret the messages of this epoch are encrypted with
+ *  - `past`              : Encryption secrets of the last epochs
+ *  - `generation`        : Number of messages we sent in this epoch
+ *  - `seen`              : Messages received (epoch, sender, generation)
+ *  - `logs`              : Step-by-step logs of the last commits
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TreeKem {
+    pub group_id: String,
+    pub epoch: u64,
+    pub nodes: Vec<Option<Vec<u8>>>,
+    pub leaves: Vec<Option<String>>,
+    pub own_leaf: usize,
+    secrets: HashMap<usize, Vec<u8>>,
+    init_secret: [u8; 32],
+    encryption_secret: [u8; 32],
+    #[serde(default)]
+    past: Vec<(u64, [u8; 32])>,
+    #[serde(default)]
+    generation: u32,
+    #[serde(default)]
+    seen: HashSet<(u64, String, u32)>,
+    #[serde(default)]
+    pub logs: Vec<String>,
+}
+
+impl TreeKem {
+    /* Creates a tree with `creator` alone, at epoch 0 */
+    pub fn create(group_id: &str, creator: &User) -> Self {
+        let (leaf_pk, leaf_sk) = node_keypair(&random_secret());
+        let epoch_secret = random_secret();
+        let mut tree = Self {
+            group_id: group_id.to_string(),
+            epoch: 0,
+            nodes: vec![Some(leaf_pk.as_ref().to_vec())],
+            leaves: vec![Some(creator.username().to_string())],
+            own_leaf: 0,
+            secrets: HashMap::from([(0, leaf_sk.as_ref().to_vec())]),
+            init_secret: hmac(&epoch_secret, b"init"),
+            encryption_secret: hmac(&epoch_secret, b"encryption"),
+            past: Vec::new(),
+            generation: 0,
+            seen: HashSet::new(),
+            logs: Vec::new(),
+        };
+        tree.push_log(format!(
+            concat!(
+                "== log (TreeKEM create) ==\n",
+                "Group: {}\nCreator: {} (leaf 0, node 0)\n",
+                "Leaf key: {}\n",
+                "Epoch 0 secret: random\n"
+            ),
+            group_id,
+            creator.username(),
+            hex::encode(leaf_pk.as_ref()),
+        ));
+        tree
+    }
+
+    /* Usernames of the members in the tree */
+    pub fn members(&self) -> impl Iterator<Item = &String> {
+        self.leaves.iter().flatten()
+    }
+
+    /* Whether we hold the secret key of `node` */
+    pub fn knows(&self, node: usize) -> bool {
+        self.secrets.contains_key(&node)
+    }
+
+    fn leaf_of(&self, member: &str) -> Option<usize> {
+        self.leaves.iter().position(|l| l.as_deref() == Some(member))
+    }
+
+    fn push_log(&mut self, log: String) {
+        self.logs.push(log);
+        if self.logs.len() > MAX_LOGS {
+            self.logs.remove(0);
+        }
+    }
+
+    /*
+     * Non-blank nodes covering the subtree of `node`, leaving out the
+     * leaves in `exclude` (members added by the commit being made).
+     */
+    fn resolution(&self, node: usize, exclude: &[usize]) -> Vec<usize> {
+        if self.nodes[node].is_some() && !exclude.contains(&node) {
+            return vec![node];
+        }
+        if level(node) == 0 {
+            return Vec::new();
+        }
+        let mut nodes = self.resolution(left(node), exclude);
+        nodes.extend(self.resolution(right(node), exclude));
+        nodes
+    }
+
+    /* Blanks the direct path of `leaf` (the parents, not the leaf) */
+    fn blank_path(&mut self, leaf: usize) {
+        for node in direct_path(2 * leaf, self.leaves.len()) {
+            self.nodes[node] = None;
+            self.secrets.remove(&node);
+        }
+    }
+
+    /*
+     * Applies the proposals of a commit, removals first, and returns the
+     * nodes of the added leaves.
+     */
+    fn apply_proposals(&mut self, proposals: &[Proposal], log: &mut String) -> Result<Vec<usize>, TreeKemError> {
+        for proposal in proposals {
+            if let Proposal::Remove { member } = proposal {
+                let leaf = self.leaf_of(member).ok_or_else(|| TreeKemError::UnknownMember(member.clone()))?;
+                self.leaves[leaf] = None;
+                self.nodes[2 * leaf] = None;
+                self.secrets.remove(&(2 * leaf));
+                self.blank_path(leaf);
+                log.push_str(&format!("Remove {member}: leaf {leaf} and its direct path blanked\n"));
+            }
+        }
+
+        let mut added = Vec::new();
+        for proposal in proposals {
+            if let Proposal::Add { member, init_key } = proposal {
+                if self.leaf_of(member).is_some() {
+                    return Err(TreeKemError::AlreadyMember(member.clone()));
+                }
+                if box_::PublicKey::from_slice(init_key).is_none() {
+                    return Err(TreeKemError::PathMismatch);
+                }
+                let leaf = match self.leaves.iter().position(Option::is_none) {
+                    Some(free) => free,
+                    None => {
+                        /* Full tree: double its width */
+                        let n = self.leaves.len();
+                        self.leaves.resize(2 * n, None);
+                        self.nodes.resize(4 * n - 1, None);
+                        log.push_str(&format!("Tree extended from {} to {} leaves\n", n, 2 * n));
+                        n
+                    }
+                };
+                self.leaves[leaf] = Some(member.clone());
+                self.nodes[2 * leaf] = Some(init_key.clone());
+                self.blank_path(leaf);
+                added.push(2 * leaf);
+                log.push_str(&format!(
+                    "Add {member}: leaf {leaf} (node {}), leaf key = signed pre-key {}, direct path blanked\n",
+                    2 * leaf,
+                    hex::encode(init_key)
+                ));
+            }
+        }
+        Ok(added)
+    }
+
+    /*
+     * Runs the key schedule for the new epoch and keeps the previous
+     * encryption secret for late messages.
+     */
+    fn key_schedule(&mut self, joiner_secret: &[u8; 32], log: &mut String) {
+        let mut context = b"epoch".to_vec();
+        context.extend_from_slice(self.group_id.as_bytes());
+        context.extend_from_slice(&self.epoch.to_be_bytes());
+        let epoch_secret = hmac(joiner_secret, &context);
+
+        self.past.push((self.epoch - 1, self.encryption_secret));
+        if self.past.len() > MAX_PAST_EPOCHS {
+            self.past.remove(0);
+        }
+        let oldest = self.past.first().map_or(self.epoch, |(epoch, _)| *epoch);
+        self.seen.retain(|(epoch, _, _)| *epoch >= oldest);
+
+        self.init_secret = hmac(&epoch_secret, b"init");
+        self.encryption_secret = hmac(&epoch_secret, b"encryption");
+        self.generation = 0;
+        log.push_str(&format!(
+            concat!(
+                "Joiner secret = HMAC(init secret, commit secret): {}\n",
+                "Epoch {} secret = HMAC(joiner secret, \"epoch\" || group || epoch): {}\n",
+                "Encryption secret = HMAC(epoch secret, \"encryption\"): {}\n"
+            ),
+            hex::encode(joiner_secret),
+            self.epoch,
+            hex::encode(epoch_secret),
+            hex::encode(self.encryption_secret),
+        ));
+    }
+
+    /*
+     * Makes a commit as `me`: applies `proposals` (none for a plain key
+     * update), refreshes our direct path and moves to the next epoch.
+     *
+     * Returns the commit for the existing members and a welcome for each
+     * added one. The tree is left unchanged on error.
+     */
+    pub fn commit(&mut self, proposals: Vec<Proposal>, me: &User) -> Result<(Commit, Vec<(String, Welcome)>), TreeKemError> {
+        let identity_sk = me.identity_sk.as_ref().ok_or(TreeKemError::NoIdentityKey)?;
+        let mut next = self.clone();
+        let mut log = format!(
+            concat!(
+                "== log (TreeKEM commit) ==\n",
+                "Group: {} epoch {} -> {}\n",
+                "Committer: {} (leaf {}, node {})\n"
+            ),
+            self.group_id,
+            self.epoch,
+            self.epoch + 1,
+            me.username(),
+            self.own_leaf,
+            2 * self.own_leaf,
+        );
+        if proposals.is_empty() {
+            log.push_str("Proposals: none (key update)\n");
+        }
+        let added = next.apply_proposals(&proposals, &mut log)?;
+        if next.leaves[next.own_leaf].as_deref() != Some(me.username()) {
+            return Err(TreeKemError::Removed);
+        }
+
+        /* New leaf key, then one path secret per parent */
+        let own = 2 * next.own_leaf;
+        let mut path_secret = random_secret();
+        let (leaf_pk, leaf_sk) = node_keypair(&path_secret);
+        next.nodes[own] = Some(leaf_pk.as_ref().to_vec());
+        next.secrets.insert(own, leaf_sk.as_ref().to_vec());
+        log.push_str(&format!("Leaf node {own}: new key {}\n", hex::encode(leaf_pk.as_ref())));
+
+        let path = direct_path(own, next.leaves.len());
+        let mut path_nodes = Vec::new();
+        let mut path_secrets = Vec::new();
+        let mut child = own;
+        for &node in &path {
+            path_secret = next_path_secret(&path_secret);
+            let (pk, sk) = node_keypair(&path_secret);
+            let copath = sibling(child);
+            let targets = next.resolution(copath, &added);
+            let mut secrets = Vec::new();
+            for &target in &targets {
+                let target_pk = next.nodes[target]
+                    .as_deref()
+                    .and_then(box_::PublicKey::from_slice)
+                    .ok_or(TreeKemError::PathMismatch)?;
+                secrets.push(sealedbox::seal(&path_secret, &target_pk));
+            }
+            log.push_str(&format!(
+                "Node {} (level {}): path secret {} -> key {}\n  sealed to the resolution of copath node {}: {:?}\n",
+                node,
+                level(node),
+                hex::encode(path_secret),
+                hex::encode(pk.as_ref()),
+                copath,
+                targets,
+            ));
+            next.nodes[node] = Some(pk.as_ref().to_vec());
+            next.secrets.insert(node, sk.as_ref().to_vec());
+            path_nodes.push(PathNode {
+                node,
+                public: pk.as_ref().to_vec(),
+                secrets,
+            });
+            path_secrets.push(path_secret);
+            child = node;
+        }
+        let commit_secret = next_path_secret(&path_secret);
+        log.push_str(&format!("Commit secret = HMAC(root path secret, \"path\"): {}\n", hex::encode(commit_secret)));
+
+        let joiner_secret = hmac(&next.init_secret, &commit_secret);
+        next.epoch += 1;
+        next.key_schedule(&joiner_secret, &mut log);
+
+        let mut commit = Commit {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            committer: me.username().to_string(),
+            proposals,
+            leaf_public: leaf_pk.as_ref().to_vec(),
+            path: path_nodes,
+            signature: Vec::new(),
+        };
+        commit.signature = signature_of(&commit.signed_bytes(), identity_sk);
+
+        /* Welcomes: the joiner secret and the path secret of the common ancestor */
+        let mut welcomes = Vec::new();
+        for &node in &added {
+            let Some(ancestor) = path.iter().position(|&p| covers(p, node)) else {
+                continue;
+            };
+            let (Some(member), Some(init_key)) = (
+                next.leaves[node / 2].clone(),
+                next.nodes[node].as_deref().and_then(box_::PublicKey::from_slice),
+            ) else {
+                continue;
+            };
+            let secrets = GroupSecrets {
+                joiner_secret,
+                path_secret: path_secrets[ancestor],
+                node: path[ancestor],
+            };
+            let sealed = sealedbox::seal(&serde_json::to_vec(&secrets).unwrap_or_default(), &init_key);
+            let mut welcome = Welcome {
+                group_id: next.group_id.clone(),
+                epoch: next.epoch,
+                committer: me.username().to_string(),
+                leaves: next.leaves.clone(),
+                nodes: next.nodes.clone(),
+                secrets: sealed,
+                signature: Vec::new(),
+            };
+            welcome.signature = signature_of(&welcome.signed_bytes(), identity_sk);
+            log.push_str(&format!(
+                "Welcome for {member}: joiner secret + path secret of node {} sealed to their signed pre-key\n",
+                path[ancestor]
+            ));
+            welcomes.push((member, welcome));
+        }
+
+        next.push_log(log);
+        *self = next;
+        Ok((commit, welcomes))
+    }
+
+    /*
+     * Applies a commit made by another member (`committer_pk` is its
+     * identity key). The tree is left unchanged on error.
+     */
+    pub fn apply_commit(&mut self, commit: &Commit, committer_pk: &sign::PublicKey) -> Result<(), TreeKemError> {
+        if commit.group_id != self.group_id {
+            return Err(TreeKemError::WrongGroup);
+        }
+        if commit.epoch != self.epoch {
+            return Err(TreeKemError::WrongEpoch {
+                ours: self.epoch,
+                got: commit.epoch,
+            });
+        }
+        if !commit.verify(committer_pk) {
+            return Err(TreeKemError::BadSignature);
+        }
+        let committer_leaf = self
+            .leaf_of(&commit.committer)
+            .ok_or_else(|| TreeKemError::UnknownMember(commit.committer.clone()))?;
+        let me = self.leaves[self.own_leaf].clone().unwrap_or_default();
+
+        let mut next = self.clone();
+        let mut log = format!(
+            concat!(
+                "== log (TreeKEM apply) ==\n",
+                "Group: {} epoch {} -> {}\n",
+                "Committer: {} (leaf {}), signature valid\n",
+                "Receiver: {} (leaf {}, node {})\n"
+            ),
+            self.group_id,
+            self.epoch,
+            self.epoch + 1,
+            commit.committer,
+            committer_leaf,
+            me,
+            self.own_leaf,
+            2 * self.own_leaf,
+        );
+        if commit.proposals.is_empty() {
+            log.push_str("Proposals: none (key update)\n");
+        }
+        let added = next.apply_proposals(&commit.proposals, &mut log)?;
+        if next.leaves[next.own_leaf].as_deref() != Some(me.as_str()) {
+            return Err(TreeKemError::Removed);
+        }
+        if next.leaves[committer_leaf].as_deref() != Some(commit.committer.as_str()) {
+            return Err(TreeKemError::UnknownMember(commit.committer.clone()));
+        }
+
+        let committer_node = 2 * committer_leaf;
+        let own = 2 * next.own_leaf;
+        let path = direct_path(committer_node, next.leaves.len());
+        let valid_key = |key: &[u8]| box_::PublicKey::from_slice(key).is_some();
+        if commit.path.len() != path.len()
+            || commit.path.iter().zip(&path).any(|(p, n)| p.node != *n || !valid_key(&p.public))
+            || !valid_key(&commit.leaf_public)
+        {
+            return Err(TreeKemError::PathMismatch);
+        }
+
+        /* The lowest node of the committer's path above us, and which sealed secret is ours */
+        let index = path.iter().position(|&p| covers(p, own)).ok_or(TreeKemError::PathMismatch)?;
+        let child = if index == 0 { committer_node } else { path[index - 1] };
+        let copath = sibling(child);
+        let targets = next.resolution(copath, &added);
+        let (slot, holder) = targets
+            .iter()
+            .enumerate()
+            .find(|(_, t)| next.secrets.contains_key(t))
+            .ok_or(TreeKemError::NoPathSecret)?;
+        let sealed = commit.path[index].secrets.get(slot).ok_or(TreeKemError::NoPathSecret)?;
+        let holder_pk = next.nodes[*holder]
+            .as_deref()
+            .and_then(box_::PublicKey::from_slice)
+            .ok_or(TreeKemError::PathMismatch)?;
+        let holder_sk = box_::SecretKey::from_slice(&next.secrets[holder]).ok_or(TreeKemError::DecryptionFailed)?;
+        let opened = sealedbox::open(sealed, &holder_pk, &holder_sk).map_err(|_| TreeKemError::DecryptionFailed)?;
+        let mut path_secret = to_secret(&opened).ok_or(TreeKemError::DecryptionFailed)?;
+        log.push_str(&format!(
+            concat!(
+                "Common ancestor with the committer: node {}\n",
+                "Copath node {} has resolution {:?}; opened secret #{} with our key of node {}\n"
+            ),
+            path[index],
+            copath,
+            targets,
+            slot,
+            holder,
+        ));
+
+        /* New public keys along the committer's path; derive ours from the common ancestor up */
+        next.nodes[committer_node] = Some(commit.leaf_public.clone());
+        for (node, path_node) in path.iter().zip(&commit.path) {
+            next.nodes[*node] = Some(path_node.public.clone());
+            next.secrets.remove(node);
+        }
+        for (step, path_node) in commit.path[index..].iter().enumerate() {
+            if step > 0 {
+                path_secret = next_path_secret(&path_secret);
+            }
+            let (pk, sk) = node_keypair(&path_secret);
+            if pk.as_ref() != path_node.public.as_slice() {
+                return Err(TreeKemError::PathMismatch);
+            }
+            next.secrets.insert(path_node.node, sk.as_ref().to_vec());
+            log.push_str(&format!(
+                "Node {} (level {}): path secret {} -> key matches the commit\n",
+                path_node.node,
+                level(path_node.node),
+                hex::encode(path_secret)
+            ));
+        }
+        let commit_secret = next_path_secret(&path_secret);
+        log.push_str(&format!("Commit secret = HMAC(root path secret, \"path\"): {}\n", hex::encode(commit_secret)));
+
+        let joiner_secret = hmac(&next.init_secret, &commit_secret);
+        next.epoch += 1;
+        next.key_schedule(&joiner_secret, &mut log);
+        next.push_log(log);
+        *self = next;
+        Ok(())
+    }
+
+    /*
+     * Joins a group from a welcome addressed to `me`
+     * (`committer_pk` is the identity key of the member who added us).
+     */
+    pub fn join(welcome: &Welcome, me: &User, committer_pk: &sign::PublicKey) -> Result<Self, TreeKemError> {
+        if !welcome.verify(committer_pk) {
+            return Err(TreeKemError::BadSignature);
+        }
+        let leaves = welcome.leaves.len();
+        if welcome.epoch == 0 || !leaves.is_power_of_two() || welcome.nodes.len() != 2 * leaves - 1 {
+            return Err(TreeKemError::PathMismatch);
+        }
+        let own_leaf = welcome
+            .leaves
+            .iter()
+            .position(|l| l.as_deref() == Some(me.username()))
+            .ok_or_else(|| TreeKemError::UnknownMember(me.username().to_string()))?;
+        let own = 2 * own_leaf;
+        if welcome.nodes[own].as_deref() != Some(me.signed_pre_pk.as_ref()) {
+            return Err(TreeKemError::PathMismatch);
+        }
+        let opened = sealedbox::open(&welcome.secrets, &me.signed_pre_pk, &me.signed_pre_sk)
+            .map_err(|_| TreeKemError::DecryptionFailed)?;
+        let secrets: GroupSecrets = serde_json::from_slice(&opened).map_err(|_| TreeKemError::DecryptionFailed)?;
+        if secrets.node >= welcome.nodes.len() || !covers(secrets.node, own) {
+            return Err(TreeKemError::PathMismatch);
+        }
+
+        let mut log = format!(
+            concat!(
+                "== log (TreeKEM welcome) ==\n",
+                "Group: {} epoch {}\n",
+                "Added by {} (signature valid)\n",
+                "Our leaf: {} (node {}), key = our signed pre-key\n",
+                "Opened the group secrets with our signed pre-key\n"
+            ),
+            welcome.group_id,
+            welcome.epoch,
+            welcome.committer,
+            own_leaf,
+            own,
+        );
+
+        /* Derive the keys from the common ancestor with the committer up to the root */
+        let mut known = HashMap::from([(own, me.signed_pre_sk.as_ref().to_vec())]);
+        let mut node = secrets.node;
+        let mut path_secret = secrets.path_secret;
+        loop {
+            let (pk, sk) = node_keypair(&path_secret);
+            if welcome.nodes[node].as_deref() != Some(pk.as_ref()) {
+                return Err(TreeKemError::PathMismatch);
+            }
+            known.insert(node, sk.as_ref().to_vec());
+            log.push_str(&format!(
+                "Node {} (level {}): path secret {} -> key matches the tree\n",
+                node,
+                level(node),
+                hex::encode(path_secret)
+            ));
+            if node == root(leaves) {
+                break;
+            }
+            node = parent(node);
+            path_secret = next_path_secret(&path_secret);
+        }
+
+        let mut tree = Self {
+            group_id: welcome.group_id.clone(),
+            epoch: welcome.epoch,
+            nodes: welcome.nodes.clone(),
+            leaves: welcome.leaves.clone(),
+            own_leaf,
+            secrets: known,
+            init_secret: [0u8; 32],
+            encryption_secret: [0u8; 32],
+            past: Vec::new(),
+            generation: 0,
+            seen: HashSet::new(),
+            logs: Vec::new(),
+        };
+        tree.key_schedule(&secrets.joiner_secret, &mut log);
+        tree.past.clear();
+        tree.push_log(log);
+        Ok(tree)
+    }
+
+    /*
+     * Encrypts `payload` for the current epoch, signed with our identity key.
+     *
+     * Returns the message, the message key and a step-by-step log.
+     */
+    pub fn encrypt(
+        &mut self,
+        message_id: String,
+        payload: &Payload,
+        me: &User,
+    ) -> Result<(GroupMessage, [u8; 32], String), TreeKemError> {
+        let identity_sk = me.identity_sk.as_ref().ok_or(TreeKemError::NoIdentityKey)?;
+        let generation = self.generation;
+        self.generation += 1;
+        let key = message_key(&self.encryption_secret, me.username(), generation);
+        let nonce = secretbox::gen_nonce();
+        let ciphertext = secretbox::seal(payload.encode().as_bytes(), &nonce, &secretbox::Key(key));
+
+        let mut message = GroupMessage {
+            group_id: self.group_id.clone(),
+            message_id,
+            key_id: epoch_key_id(self.epoch),
+            iteration: generation,
+            nonce: nonce.0.to_vec(),
+            ciphertext,
+            signature: Vec::new(),
+        };
+        message.signature = sign::sign_detached(&message.signed_bytes(), identity_sk).as_ref().to_vec();
+
+        let log = format!(
+            concat!(
+                "== log (TreeKEM send) ==\n",
+                "Group: {} epoch {} ({} members)\n",
+                "Message key = HMAC(encryption secret, sender || {}): {}\n",
+                "Nonce: {}\n",
+                "Ciphertext: {}\n",
+                "Signed with the sender identity key\n"
+            ),
+            self.group_id,
+            self.epoch,
+            self.members().count(),
+            generation,
+            hex::encode(key),
+            hex::encode(nonce.0),
+            hex::encode(&message.ciphertext),
+        );
+        Ok((message, key, log))
+    }
+
+    /*
+     * Decrypts a message from `sender` (`sender_pk` is its identity key),
+     * from the current epoch or one of the last ones.
+     */
+    pub fn decrypt(
+        &mut self,
+        sender: &str,
+        message: &GroupMessage,
+        sender_pk: &sign::PublicKey,
+    ) -> Result<(Payload, [u8; 32], String), TreeKemError> {
+        if message.group_id != self.group_id {
+            return Err(TreeKemError::WrongGroup);
+        }
+        let epoch: u64 = message
+            .key_id
+            .strip_prefix("epoch-")
+            .and_then(|e| e.parse().ok())
+            .ok_or(TreeKemError::DecryptionFailed)?;
+        let secret = if epoch == self.epoch {
+            if self.leaf_of(sender).is_none() {
+                return Err(TreeKemError::UnknownMember(sender.to_string()));
+            }
+            self.encryption_secret
+        } else if epoch > self.epoch {
+            return Err(TreeKemError::WrongEpoch {
+                ours: self.epoch,
+                got: epoch,
+            });
+        } else {
+            self.past
+                .iter()
+                .find(|(e, _)| *e == epoch)
+                .map(|(_, s)| *s)
+                .ok_or(TreeKemError::UnknownEpoch(epoch))?
+        };
+        let signature = sign::Signature::try_from(message.signature.as_slice()).map_err(|_| TreeKemError::BadSignature)?;
+        if !sign::verify_detached(&signature, &message.signed_bytes(), sender_pk) {
+            return Err(TreeKemError::BadSignature);
+        }
+        if !self.seen.insert((epoch, sender.to_string(), message.iteration)) {
+            return Err(TreeKemError::Replayed);
+        }
+
+        let key = message_key(&secret, sender, message.iteration);
+        let key_obj = secretbox::Key(key);
+        let nonce = secretbox::Nonce::from_slice(&message.nonce).ok_or(TreeKemError::DecryptionFailed)?;
+        let plaintext = secretbox::open(&message.ciphertext, &nonce, &key_obj)
+            .ok()
+            .and_then(|p| String::from_utf8(p).ok())
+            .ok_or(TreeKemError::DecryptionFailed)?;
+        let payload = Payload::decode(&plaintext).ok_or(TreeKemError::Unreadable)?;
+
+        let log = format!(
+            concat!(
+                "== log (TreeKEM recv) ==\n",
+                "Group: {} epoch {}{}\nSender: {}\n",
+                "Signature by sender identity key: valid\n",
+                "Message key = HMAC(encryption secret, sender || {}): {}\n",
+                "Payload: {} v{}\n",
+                "Plaintext: {}\n"
+            ),
+            self.group_id,
+            epoch,
+            if epoch == self.epoch { "" } else { " (previous epoch)" },
+            sender,
+            message.iteration,
+            hex::encode(key),
+            payload.kind(),
+            payload.version(),
+            payload.encode(),
+        );
+        Ok((payload, key, log))
+    }
+
+    /*
+     * Renders the tree sideways, one node per line in index order
+     * (indented by level): member names at the leaves, a key prefix for
+     * other nodes, `*` where we hold the secret key.
+     */
+    pub fn render(&self) -> String {
+        let mut lines = Vec::new();
+        for (node, public) in self.nodes.iter().enumerate() {
+            let indent = "  ".repeat(level(node) as usize);
+            let key = public.as_deref().map_or("blank".to_string(), |pk| hex::encode(pk.get(..4).unwrap_or(pk)));
+            let owner = if level(node) == 0 {
+                self.leaves[node / 2].as_deref().unwrap_or("(free)").to_string()
+            } else {
+                format!("node {node}")
+            };
+            let mark = if self.knows(node) { " *" } else { "" };
+            lines.push(format!("{indent}{owner} [{key}]{mark}"));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(user: &User) -> Proposal {
+        Proposal::Add {
+            member: user.username().to_string(),
+            init_key: user.signed_pre_pk.as_ref().to_vec(),
+        }
+    }
+
+    /* A group of alice (who created it) with bob and carol, as each of them holds it */
+    fn group() -> ([User; 3], [TreeKem; 3]) {
+        let users = ["alice", "bob", "carol"].map(|name| User::new(name, 1));
+        let mut alice = TreeKem::create("group", &users[0]);
+        let (_, welcomes) = alice.commit(vec![add(&users[1]), add(&users[2])], &users[0]).unwrap();
+        assert_eq!(welcomes.len(), 2);
+        let joined = welcomes.iter().map(|(member, welcome)| {
+            let user = users.iter().find(|u| u.username() == member).unwrap();
+            TreeKem::join(welcome, user, &users[0].identity_pk).unwrap()
+        });
+        let [bob, carol]: [TreeKem; 2] = joined.collect::<Vec<_>>().try_into().unwrap();
+        (users, [alice, bob, carol])
+    }
+
+    fn send(tree: &mut TreeKem, text: &str, me: &User) -> GroupMessage {
+        tree.encrypt(text.to_string(), &Payload::text(text), me).unwrap().0
+    }
+
+    fn read(tree: &mut TreeKem, message: &GroupMessage, sender: &User) -> Result<String, TreeKemError> {
+        tree.decrypt(sender.username(), message, &sender.identity_pk).map(|(payload, _, _)| payload.summary())
+    }
+
+    #[test]
+    fn commits_keep_members_in_step() {
+        let (users, [mut alice, mut bob, mut carol]) = group();
+        let message = send(&mut alice, "hello", &users[0]);
+        assert_eq!(read(&mut bob, &message, &users[0]), Ok("hello".to_string()));
+        assert_eq!(read(&mut carol, &message, &users[0]), Ok("hello".to_string()));
+        assert_eq!(read(&mut carol, &message, &users[0]), Err(TreeKemError::Replayed));
+
+        /* A key update by bob */
+        let (update, _) = bob.commit(Vec::new(), &users[1]).unwrap();
+        alice.apply_commit(&update, &users[1].identity_pk).unwrap();
+        carol.apply_commit(&update, &users[1].identity_pk).unwrap();
+        assert!([&alice, &carol].iter().all(|t| t.epoch == bob.epoch && t.nodes == bob.nodes));
+        let message = send(&mut carol, "after", &users[2]);
+        assert_eq!(read(&mut alice, &message, &users[2]), Ok("after".to_string()));
+        assert_eq!(read(&mut bob, &message, &users[2]), Ok("after".to_string()));
+    }
+
+    #[test]
+    fn removed_member_cannot_follow() {
+        let (users, [mut alice, mut bob, mut carol]) = group();
+        let old = send(&mut alice, "before", &users[0]);
+        let remove = Proposal::Remove {
+            member: "carol".to_string(),
+        };
+        let (commit, _) = alice.commit(vec![remove], &users[0]).unwrap();
+        bob.apply_commit(&commit, &users[0].identity_pk).unwrap();
+        assert_eq!(carol.apply_commit(&commit, &users[0].identity_pk), Err(TreeKemError::Removed));
+
+        let message = send(&mut alice, "secret", &users[0]);
+        assert_eq!(read(&mut bob, &message, &users[0]), Ok("secret".to_string()));
+        assert_eq!(read(&mut carol, &message, &users[0]), Err(TreeKemError::WrongEpoch { ours: 1, got: 2 }));
+        assert_eq!(read(&mut carol, &old, &users[0]), Ok("before".to_string()));
+
+        /* A message from the removed member is refused in the new epoch */
+        let late = send(&mut carol, "still here", &users[2]);
+        assert_eq!(read(&mut bob, &late, &users[2]), Ok("still here".to_string()), "previous epoch");
+        let mut forged = late.clone();
+        forged.key_id = epoch_key_id(bob.epoch);
+        assert_eq!(read(&mut bob, &forged, &users[2]), Err(TreeKemError::UnknownMember("carol".to_string())));
+    }
+
+    #[test]
+    fn invalid_commits_are_refused() {
+        let (users, [_, mut bob, mut carol]) = group();
+        let (commit, _) = bob.commit(Vec::new(), &users[1]).unwrap();
+
+        assert_eq!(carol.apply_commit(&commit, &users[0].identity_pk), Err(TreeKemError::BadSignature), "wrong author");
+        let mut renamed = commit.clone();
+        renamed.committer = "alice".to_string();
+        assert_eq!(carol.apply_commit(&renamed, &users[0].identity_pk), Err(TreeKemError::BadSignature));
+        let mut other_group = commit.clone();
+        other_group.group_id = "other".to_string();
+        assert_eq!(carol.apply_commit(&other_group, &users[1].identity_pk), Err(TreeKemError::WrongGroup));
+
+        carol.apply_commit(&commit, &users[1].identity_pk).unwrap();
+        assert_eq!(
+            carol.apply_commit(&commit, &users[1].identity_pk),
+            Err(TreeKemError::WrongEpoch { ours: 2, got: 1 }),
+            "replayed commit"
+        );
+
+        /* A stranger's commit */
+        let stranger = User::new("mallory", 1);
+        let mut tree = carol.clone();
+        let (mut forged, _) = tree.commit(Vec::new(), &users[2]).unwrap();
+        forged.committer = "mallory".to_string();
+        forged.signature = signature_of(&forged.signed_bytes(), stranger.identity_sk.as_ref().unwrap());
+        assert_eq!(
+            bob.apply_commit(&forged, &stranger.identity_pk),
+            Err(TreeKemError::UnknownMember("mallory".to_string()))
+        );
+    }
+
+    #[test]
+    fn welcome_is_only_for_the_added_member() {
+        let users = ["alice", "bob"].map(|name| User::new(name, 1));
+        let mut alice = TreeKem::create("group", &users[0]);
+        let (_, welcomes) = alice.commit(vec![add(&users[1])], &users[0]).unwrap();
+        let welcome = &welcomes[0].1;
+
+        let impostor = User::new("bob", 1);
+        assert!(TreeKem::join(welcome, &impostor, &users[0].identity_pk).is_err());
+        assert_eq!(TreeKem::join(welcome, &users[1], &users[1].identity_pk).err(), Some(TreeKemError::BadSignature));
+        assert!(TreeKem::join(welcome, &users[1], &users[0].identity_pk).is_ok());
+    }
+}
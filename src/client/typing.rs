@@ -0,0 +1,140 @@
+/*
+ * This module defines typing indicators ("alice is typing…").
+ *
+ * A `TypingSignal` is an ephemeral control message, encrypted end to end
+ * like every other message (see `client::control`). Unlike chat messages:
+ *  - it is never stored in the `Session` nor queued in the `Outbox`
+ *  - it is sent at most once every `TYPING_INTERVAL_MS` per contact
+ *  - it expires on its own after `TYPING_TIMEOUT_MS` without a new signal
+ *
+ * Signals carry their send time, so a stale signal delivered late by
+ * the relay (e.g. after the recipient was offline) is ignored.
+ */
+
+use crate::client::control;
+use crate::client::user::User;
+use crate::net::transport::{Envelope, EnvelopeKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/* Minimum delay between two signals sent to the same contact */
+pub const TYPING_INTERVAL_MS: u64 = 3_000;
+
+/* How long an indicator stays visible without a new signal */
+pub const TYPING_TIMEOUT_MS: u64 = 6_000;
+
+/* "I am typing" signal */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TypingSignal {
+    pub sent_at_ms: u64,
+}
+
+impl TypingSignal {
+    /* Encrypts the signal from `sender` to `peer` (envelope kind `Typing`) */
+    pub fn seal(&self, sender: &User, peer: &User) -> Option<Envelope> {
+        control::seal(EnvelopeKind::Typing, self, sender, peer)
+    }
+
+    /* Decrypts a typing signal addressed to `me`, if the envelope holds one */
+    pub fn open(envelope: &Envelope, me: &User) -> Option<Self> {
+        control::open(EnvelopeKind::Typing, envelope, me)
+    }
+}
+
+/*
+ * In-memory typing state.
+ *
+ * Fields:
+ *  - `last_sent` : When we last signalled each contact (rate limiting)
+ *  - `peers`     : Contacts currently typing, with the time their indicator expires
+ */
+#[derive(Default)]
+pub struct Typing {
+    last_sent: HashMap<String, u64>,
+    peers: HashMap<String, u64>,
+}
+
+impl Typing {
+    /*
+     * Returns `true` if a signal may be sent to `peer` now,
+     * and records it as sent.
+     */
+    pub fn should_send(&mut self, peer: &str, now: u64) -> bool {
+        match self.last_sent.get(peer) {
+            Some(last) if now < last + TYPING_INTERVAL_MS => false,
+            _ => {
+                self.last_sent.insert(peer.to_string(), now);
+                true
+            }
+        }
+    }
+
+    /* Forgets the rate limit for `peer` (after sending a real message) */
+    pub fn reset_sent(&mut self, peer: &str) {
+        self.last_sent.remove(peer);
+    }
+
+    /* Records a signal from `peer`, unless it is already stale */
+    pub fn received(&mut self, peer: &str, signal: &TypingSignal, now: u64) {
+        let expires_at = signal.sent_at_ms.min(now) + TYPING_TIMEOUT_MS;
+        if expires_at > now {
+            self.peers.insert(peer.to_string(), expires_at);
+        }
+    }
+
+    /* Clears the indicator of `peer` (their message arrived) */
+    pub fn stopped(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+
+    /* Drops every indicator that has expired */
+    pub fn expire(&mut self, now: u64) {
+        self.peers.retain(|_, expires_at| *expires_at > now);
+    }
+
+    /* Returns `true` if `peer` is currently typing */
+    pub fn is_typing(&self, peer: &str) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    /* Returns `true` if any indicator is visible (and must be expired) */
+    pub fn any(&self) -> bool {
+        !self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_are_rate_limited() {
+        let mut typing = Typing::default();
+        assert!(typing.should_send("bob", 1_000));
+        assert!(!typing.should_send("bob", 1_000 + TYPING_INTERVAL_MS - 1));
+        assert!(typing.should_send("carol", 1_000));
+        assert!(typing.should_send("bob", 1_000 + TYPING_INTERVAL_MS));
+        typing.reset_sent("bob");
+        assert!(typing.should_send("bob", 1_000 + TYPING_INTERVAL_MS));
+    }
+
+    #[test]
+    fn indicators_expire() {
+        let mut typing = Typing::default();
+        let now = 100_000;
+        typing.received("bob", &TypingSignal { sent_at_ms: now }, now);
+        assert!(typing.is_typing("bob") && typing.any());
+        typing.expire(now + TYPING_TIMEOUT_MS - 1);
+        assert!(typing.is_typing("bob"));
+        typing.expire(now + TYPING_TIMEOUT_MS);
+        assert!(!typing.any());
+
+        /* Delivered late, or clearing on a message */
+        typing.received("bob", &TypingSignal { sent_at_ms: now - TYPING_TIMEOUT_MS }, now);
+        assert!(!typing.is_typing("bob"));
+        typing.received("bob", &TypingSignal { sent_at_ms: u64::MAX }, now);
+        assert!(typing.is_typing("bob"), "a signal from the future counts from now");
+        typing.stopped("bob");
+        assert!(!typing.any());
+    }
+}
@@ -3,12 +3,21 @@
  * in an end-to-end encrypted system inspired by the Signal protocol.
  *
  * Each user has:
- *  - An identity key pair (Ed25519, long-term identity)
+ *  - An identity key pair (Ed25519, long-term identity of the account)
+ *  - A device key pair (Ed25519, identifies this device at login)
  *  - A signed pre-key pair (X25519, signed by the identity key)
+ *  - A Noise static key pair (X25519, derived from the device key), for
+ *    direct links (see `net::p2p`)
  *  - A set of one-time pre-keys (X25519, used for initial sessions)
+ *  - The account's device list, signed by the identity key (see `net::devices`)
+ *
+ * A `User` is the account as seen from one of its devices. Only the
+ * primary device holds the identity secret key; a linked device has
+ * its own device key and pre-key, endorsed by the identity key.
  *
  * It also provides helper methods for:
  *  - Key generation and printing
+ *  - Saving and loading the keys of a device
  *  - Verifying a peer's signed pre-key
  *  - Encrypting and decrypting messages with detailed logs
  *
@@ -17,31 +26,90 @@
  * without full forward secrecy guarantees.
  */
 
+use crate::client::payload::Payload;
+use crate::net::auth::challenge_message;
+use crate::net::devices::{DeviceInfo, DeviceList, PRIMARY_DEVICE};
+use crate::net::directory::{claim_message, valid_username, UsernameClaim};
+use crate::net::noise::Keypair;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::{box_, sign};
 use hex;
+use std::fs;
+
+/* Domain separation tag for the Noise static key derived from a device key */
+const NOISE_KEY_CONTEXT: &[u8] = b"blackipher-noise-static-v1";
 
 /*
  * Represents a user with cryptographic identity and pre-keys.
  *
  * Fields:
  *  - `username`        : Human-readable name of the user
- *  - `identity_pk/sk`  : Long-term Ed25519 identity key pair
- *  - `signed_pre_pk/sk`: Signed pre-key pair (X25519), validated with `identity_sk`
+ *  - `identity_pk/sk`  : Long-term Ed25519 identity key pair (the secret
+ *                        key only on the primary device)
+ *  - `device_id`       : Id of this device within the account
+ *  - `device_pk/sk`    : Ed25519 key pair of this device
+ *  - `signed_pre_pk/sk`: Signed pre-key pair (X25519) of this device, validated with `identity_pk`
  *  - `signed_pre_sig`  : Signature of the signed pre-key, produced by `identity_sk`
  *  - `one_time_prekeys`: Collection of one-time pre-keys (X25519)
+ *  - `devices`         : Latest known device list of the account
  */
 #[derive(Clone)]
 pub struct User {
     pub username: String,
 
     pub identity_pk: sign::PublicKey,
-    pub identity_sk: sign::SecretKey,
+    pub identity_sk: Option<sign::SecretKey>,
+
+    pub device_id: String,
+    pub device_pk: sign::PublicKey,
+    pub device_sk: sign::SecretKey,
 
     pub signed_pre_pk: box_::PublicKey,
     pub signed_pre_sk: box_::SecretKey,
     pub signed_pre_sig: sign::Signature,
 
     pub one_time_prekeys: Vec<(box_::PublicKey, box_::SecretKey)>,
+
+    pub devices: DeviceList,
+}
+
+/*
+ * The public keys of a user, what others need to reach them.
+ *
+ * Fields:
+ *  - `username`       : The user's name
+ *  - `identity_pk`    : Ed25519 identity public key (raw bytes)
+ *  - `device_id`      : The device these keys are for
+ *  - `device_pk`      : Ed25519 public key of that device
+ *  - `signed_pre_pk`  : X25519 signed pre-key of that device
+ *  - `signed_pre_sig` : Identity signature of the signed pre-key
+ *  - `devices`        : The account's device list
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PublicBundle {
+    pub username: String,
+    pub identity_pk: Vec<u8>,
+    pub device_id: String,
+    pub device_pk: Vec<u8>,
+    pub signed_pre_pk: Vec<u8>,
+    pub signed_pre_sig: Vec<u8>,
+    pub devices: DeviceList,
+}
+
+/* The keys of a `User` as stored on disk (hex-encoded) */
+#[derive(Serialize, Deserialize)]
+struct StoredUser {
+    identity_pk: String,
+    identity_sk: Option<String>,
+    device_id: String,
+    device_pk: String,
+    device_sk: String,
+    signed_pre_pk: String,
+    signed_pre_sk: String,
+    signed_pre_sig: String,
+    one_time_prekeys: Vec<(String, String)>,
+    devices: DeviceList,
 }
 
 impl User {
@@ -53,9 +121,11 @@ impl User {
      *  2. Generate a signed pre-key pair (X25519)
      *     - The public part is signed with the identity secret key
      *  3. Generate `num_prekeys` one-time pre-keys (X25519)
+     *  4. Generate the key of the primary device and sign the device list
      */
     pub fn new(username: &str, num_prekeys: usize) -> Self {
         let (id_pk, id_sk) = sign::gen_keypair();
+        let (device_pk, device_sk) = sign::gen_keypair();
 
         let (spk_pk, spk_sk) = box_::gen_keypair();
         let sig = sign::sign_detached(spk_pk.as_ref(), &id_sk);
@@ -65,14 +135,191 @@ impl User {
             ot_prekeys.push(box_::gen_keypair());
         }
 
-        Self {
+        Self::primary(username, (id_pk, id_sk), (device_pk, device_sk), (spk_pk, spk_sk, sig), ot_prekeys)
+    }
+
+    /*
+     * Creates a user whose keys are all derived from `seed`.
+     *
+     * The same seed always gives the same keys, which lets two separate
+     * processes share the demo users (e.g. for peer-to-peer demos).
+     * Anyone who knows the seed knows the secret keys: demo only.
+     */
+    pub fn from_seed(username: &str, seed: &[u8; 32], num_prekeys: usize) -> Self {
+        let derive = |label: &[u8]| {
+            let mut input = seed.to_vec();
+            input.extend_from_slice(label);
+            sha256::hash(&input).0
+        };
+
+        let (id_pk, id_sk) = sign::keypair_from_seed(&sign::Seed(derive(b"identity")));
+        let device = sign::keypair_from_seed(&sign::Seed(derive(b"device-1")));
+
+        let (spk_pk, spk_sk) = box_::keypair_from_seed(&box_::Seed(derive(b"signed-prekey")));
+        let sig = sign::sign_detached(spk_pk.as_ref(), &id_sk);
+
+        let ot_prekeys = (0..num_prekeys)
+            .map(|i| box_::keypair_from_seed(&box_::Seed(derive(format!("one-time-prekey-{i}").as_bytes()))))
+            .collect();
+
+        Self::primary(username, (id_pk, id_sk), device, (spk_pk, spk_sk, sig), ot_prekeys)
+    }
+
+    /*
+     * Assembles the primary device of a new account, whose device list
+     * (version 1) holds only that device.
+     */
+    fn primary(
+        username: &str,
+        (identity_pk, identity_sk): (sign::PublicKey, sign::SecretKey),
+        (device_pk, device_sk): (sign::PublicKey, sign::SecretKey),
+        (signed_pre_pk, signed_pre_sk, signed_pre_sig): (box_::PublicKey, box_::SecretKey, sign::Signature),
+        one_time_prekeys: Vec<(box_::PublicKey, box_::SecretKey)>,
+    ) -> Self {
+        let mut user = Self {
             username: username.to_string(),
-            identity_pk: id_pk,
-            identity_sk: id_sk,
-            signed_pre_pk: spk_pk,
-            signed_pre_sk: spk_sk,
-            signed_pre_sig: sig,
-            one_time_prekeys: ot_prekeys,
+            identity_pk,
+            identity_sk: Some(identity_sk),
+            device_id: PRIMARY_DEVICE.to_string(),
+            device_pk,
+            device_sk,
+            signed_pre_pk,
+            signed_pre_sk,
+            signed_pre_sig,
+            one_time_prekeys,
+            devices: DeviceList {
+                username: username.to_string(),
+                version: 0,
+                devices: Vec::new(),
+                signature: Vec::new(),
+            },
+        };
+        user.add_device(user.device_info());
+        user
+    }
+
+    /*
+     * Creates device `device_id` of the account derived from `seed`
+     * (see `from_seed`), with its own device key and pre-keys.
+     *
+     * The account adds it to its device list and endorses its pre-key;
+     * the returned `User` is the new device, which does not hold the
+     * identity secret key. For the primary device, this is `from_seed`.
+     * Demo only, like `from_seed`.
+     */
+    pub fn from_seed_on_device(username: &str, seed: &[u8; 32], num_prekeys: usize, device_id: &str) -> Self {
+        let mut account = Self::from_seed(username, seed, num_prekeys);
+        if device_id == PRIMARY_DEVICE {
+            return account;
+        }
+        let derive = |label: String| {
+            let mut input = seed.to_vec();
+            input.extend_from_slice(label.as_bytes());
+            sha256::hash(&input).0
+        };
+
+        let (device_pk, device_sk) = sign::keypair_from_seed(&sign::Seed(derive(format!("device-{device_id}"))));
+        let (spk_pk, spk_sk) = box_::keypair_from_seed(&box_::Seed(derive(format!("signed-prekey-{device_id}"))));
+        let ot_prekeys = (0..num_prekeys)
+            .map(|i| box_::keypair_from_seed(&box_::Seed(derive(format!("one-time-prekey-{device_id}-{i}")))))
+            .collect();
+
+        let info = DeviceInfo {
+            device_id: device_id.to_string(),
+            signing_pk: device_pk.as_ref().to_vec(),
+            prekey: spk_pk.as_ref().to_vec(),
+            /* Direct links reach primary devices only */
+            noise_pk: Vec::new(),
+        };
+        let sig = account.add_device(info).expect("the seeded account holds its identity key");
+
+        Self::linked(account.identity_pk, device_id, (device_pk, device_sk), (spk_pk, spk_sk, sig), ot_prekeys, account.devices)
+    }
+
+    /*
+     * Assembles a linked device of the account of `devices`, from its
+     * own keys and the identity signature over its pre-key (see
+     * `client::link` for how a device obtains it).
+     */
+    pub fn linked(
+        identity_pk: sign::PublicKey,
+        device_id: &str,
+        (device_pk, device_sk): (sign::PublicKey, sign::SecretKey),
+        (signed_pre_pk, signed_pre_sk, signed_pre_sig): (box_::PublicKey, box_::SecretKey, sign::Signature),
+        one_time_prekeys: Vec<(box_::PublicKey, box_::SecretKey)>,
+        devices: DeviceList,
+    ) -> Self {
+        Self {
+            username: devices.username.clone(),
+            identity_pk,
+            identity_sk: None,
+            device_id: device_id.to_string(),
+            device_pk,
+            device_sk,
+            signed_pre_pk,
+            signed_pre_sk,
+            signed_pre_sig,
+            one_time_prekeys,
+            devices,
+        }
+    }
+
+    /*
+     * Loads the keys of this device from a JSON file at `path`
+     * (see `save`). Returns `None` if the file is missing or invalid.
+     */
+    pub fn load(path: &str) -> Option<Self> {
+        let data = fs::read_to_string(path).ok()?;
+        let stored: StoredUser = serde_json::from_str(&data).ok()?;
+        let bytes = |value: &str| hex::decode(value).ok();
+        let identity_sk = match &stored.identity_sk {
+            Some(sk) => Some(sign::SecretKey::from_slice(&bytes(sk)?)?),
+            None => None,
+        };
+        let mut one_time_prekeys = Vec::with_capacity(stored.one_time_prekeys.len());
+        for (pk, sk) in &stored.one_time_prekeys {
+            one_time_prekeys.push((box_::PublicKey::from_slice(&bytes(pk)?)?, box_::SecretKey::from_slice(&bytes(sk)?)?));
+        }
+        let mut user = Self {
+            username: stored.devices.username.clone(),
+            identity_pk: sign::PublicKey::from_slice(&bytes(&stored.identity_pk)?)?,
+            identity_sk,
+            device_id: stored.device_id,
+            device_pk: sign::PublicKey::from_slice(&bytes(&stored.device_pk)?)?,
+            device_sk: sign::SecretKey::from_slice(&bytes(&stored.device_sk)?)?,
+            signed_pre_pk: box_::PublicKey::from_slice(&bytes(&stored.signed_pre_pk)?)?,
+            signed_pre_sk: box_::SecretKey::from_slice(&bytes(&stored.signed_pre_sk)?)?,
+            signed_pre_sig: sign::Signature::try_from(bytes(&stored.signed_pre_sig)?.as_slice()).ok()?,
+            one_time_prekeys,
+            devices: stored.devices,
+        };
+        /* A primary device saved before devices listed their Noise key lists it now */
+        if user.identity_sk.is_some() && user.devices.device(&user.device_id) != Some(&user.device_info()) {
+            user.add_device(user.device_info());
+        }
+        Some(user)
+    }
+
+    /* Saves the keys of this device (secret keys included) to a JSON file at `path` */
+    pub fn save(&self, path: &str) {
+        let stored = StoredUser {
+            identity_pk: hex::encode(self.identity_pk.as_ref()),
+            identity_sk: self.identity_sk.as_ref().map(|sk| hex::encode(sk.as_ref())),
+            device_id: self.device_id.clone(),
+            device_pk: hex::encode(self.device_pk.as_ref()),
+            device_sk: hex::encode(self.device_sk.as_ref()),
+            signed_pre_pk: hex::encode(self.signed_pre_pk.as_ref()),
+            signed_pre_sk: hex::encode(self.signed_pre_sk.as_ref()),
+            signed_pre_sig: hex::encode(self.signed_pre_sig.as_ref()),
+            one_time_prekeys: self
+                .one_time_prekeys
+                .iter()
+                .map(|(pk, sk)| (hex::encode(pk.as_ref()), hex::encode(sk.as_ref())))
+                .collect(),
+            devices: self.devices.clone(),
+        };
+        if let Ok(json) = serde_json::to_string_pretty(&stored) {
+            let _ = fs::write(path, json);
         }
     }
 
@@ -85,7 +332,12 @@ impl User {
     pub fn print_keys(&self) {
         println!("User: {}", self.username);
         println!("  Identity Public Key : {}", hex::encode(self.identity_pk.as_ref()));
-        println!("  Identity Secret Key : {}", hex::encode(self.identity_sk.as_ref()));
+        if let Some(identity_sk) = &self.identity_sk {
+            println!("  Identity Secret Key : {}", hex::encode(identity_sk.as_ref()));
+        }
+        println!("  Device              : {}", self.device_id);
+        println!("  Device Public Key   : {}", hex::encode(self.device_pk.as_ref()));
+        println!("  Device Secret Key   : {}", hex::encode(self.device_sk.as_ref()));
         println!("  Signed Pre Public   : {}", hex::encode(self.signed_pre_pk.as_ref()));
         println!("  Signed Pre Secret   : {}", hex::encode(self.signed_pre_sk.as_ref()));
         println!("  Signature on SPK    : {}", hex::encode(self.signed_pre_sig.as_ref()));
@@ -95,6 +347,79 @@ impl User {
         }
     }
 
+    /* The public keys of this user (see `PublicBundle`) */
+    pub fn public_bundle(&self) -> PublicBundle {
+        PublicBundle {
+            username: self.username.clone(),
+            identity_pk: self.identity_pk.as_ref().to_vec(),
+            device_id: self.device_id.clone(),
+            device_pk: self.device_pk.as_ref().to_vec(),
+            signed_pre_pk: self.signed_pre_pk.as_ref().to_vec(),
+            signed_pre_sig: self.signed_pre_sig.as_ref().to_vec(),
+            devices: self.devices.clone(),
+        }
+    }
+
+    /*
+     * A contact known only by their public keys.
+     *
+     * Returns `None` unless the keys are well-formed, the username is
+     * valid, the signed pre-key verifies against the identity key, and
+     * the device list (if any) is the account's and verifies too.
+     *
+     * The contact's secret keys are never known: the secret fields are
+     * filled with unrelated keys, and nothing can be decrypted with them.
+     */
+    pub fn from_bundle(bundle: &PublicBundle) -> Option<Self> {
+        let (_, device_sk) = sign::gen_keypair();
+        let (_, signed_pre_sk) = box_::gen_keypair();
+        let user = Self {
+            username: bundle.username.clone(),
+            identity_pk: sign::PublicKey::from_slice(&bundle.identity_pk)?,
+            identity_sk: None,
+            device_id: bundle.device_id.clone(),
+            device_pk: sign::PublicKey::from_slice(&bundle.device_pk)?,
+            device_sk,
+            signed_pre_pk: box_::PublicKey::from_slice(&bundle.signed_pre_pk)?,
+            signed_pre_sk,
+            signed_pre_sig: sign::Signature::try_from(bundle.signed_pre_sig.as_slice()).ok()?,
+            one_time_prekeys: Vec::new(),
+            devices: bundle.devices.clone(),
+        };
+        let devices_ok = user.devices.devices.is_empty()
+            || (user.devices.username == user.username && user.devices.verify(&user.identity_pk));
+        (valid_username(&user.username) && Self::verify_peer_spk(&user) && devices_ok).then_some(user)
+    }
+
+    /*
+     * A contact found in the directory, from their username claim and
+     * the device list published on the relay.
+     *
+     * The contact is seen as their primary device (or the first device
+     * listed). Its pre-key is endorsed by the device list signature, not
+     * by a signature of its own: `signed_pre_sig` is left blank, and
+     * `verify_peer_spk` checks the device list instead.
+     *
+     * Returns `None` unless the claim verifies and the device list is the
+     * claimed account's, signed by the claimed identity key.
+     */
+    pub fn from_directory(claim: &UsernameClaim, devices: &DeviceList) -> Option<Self> {
+        let identity_pk = claim.identity_key()?;
+        if !claim.verify() || devices.username != claim.username || !devices.verify(&identity_pk) {
+            return None;
+        }
+        let device = devices.device(PRIMARY_DEVICE).or_else(|| devices.devices.first())?;
+        Self::from_bundle(&PublicBundle {
+            username: claim.username.clone(),
+            identity_pk: identity_pk.as_ref().to_vec(),
+            device_id: device.device_id.clone(),
+            device_pk: device.signing_pk.clone(),
+            signed_pre_pk: device.prekey.clone(),
+            signed_pre_sig: vec![0; sign::SIGNATUREBYTES],
+            devices: devices.clone(),
+        })
+    }
+
     /* Returns the username of the user */
     pub fn username(&self) -> &str {
         &self.username
@@ -104,10 +429,136 @@ impl User {
      * Verifies that a peer's signed pre-key is valid.
      *
      * This checks that the peer's `signed_pre_pk` was signed by
-     * their identity secret key using Ed25519.
+     * their identity secret key using Ed25519, either on its own or as
+     * the pre-key of their device in their device list.
      */
     pub fn verify_peer_spk(peer: &User) -> bool {
         sign::verify_detached(&peer.signed_pre_sig, peer.signed_pre_pk.as_ref(), &peer.identity_pk)
+            || (peer.devices.username == peer.username
+                && peer.devices.device(&peer.device_id).and_then(DeviceInfo::prekey) == Some(peer.signed_pre_pk)
+                && peer.devices.verify(&peer.identity_pk))
+    }
+
+    /*
+     * The public keys of this device, as listed in the device list
+     * (with a Noise key on the primary device only).
+     */
+    pub fn device_info(&self) -> DeviceInfo {
+        let noise_pk = if self.device_id == PRIMARY_DEVICE {
+            self.noise_keypair().public.as_ref().to_vec()
+        } else {
+            Vec::new()
+        };
+        DeviceInfo {
+            device_id: self.device_id.clone(),
+            signing_pk: self.device_pk.as_ref().to_vec(),
+            prekey: self.signed_pre_pk.as_ref().to_vec(),
+            noise_pk,
+        }
+    }
+
+    /*
+     * The Noise static key pair this device accepts direct links with.
+     *
+     * It is derived from the device secret key with its own context, so
+     * it is never used by another protocol and needs no storage.
+     */
+    pub fn noise_keypair(&self) -> Keypair {
+        let mut input = NOISE_KEY_CONTEXT.to_vec();
+        input.extend_from_slice(self.device_sk.as_ref());
+        let (public, secret) = box_::keypair_from_seed(&box_::Seed(sha256::hash(&input).0));
+        Keypair { public, secret }
+    }
+
+    /*
+     * The Noise static key a direct link to this user's device must
+     * present: the one listed for it in their device list, if the list
+     * verifies against their identity key.
+     */
+    pub fn noise_key(&self) -> Option<box_::PublicKey> {
+        if self.devices.username != self.username || !self.devices.verify(&self.identity_pk) {
+            return None;
+        }
+        self.devices.device(&self.device_id).and_then(DeviceInfo::noise_key)
+    }
+
+    /*
+     * Adds `device` to the account's device list (replacing a device
+     * with the same id), signs the new version, and returns the identity
+     * signature over the device's pre-key (its `signed_pre_sig`).
+     *
+     * Returns `None` on a linked device, which cannot sign for the account.
+     */
+    pub fn add_device(&mut self, device: DeviceInfo) -> Option<sign::Signature> {
+        let identity_sk = self.identity_sk.as_ref()?;
+        let endorsement = sign::sign_detached(&device.prekey, identity_sk);
+        self.devices.devices.retain(|d| d.device_id != device.device_id);
+        self.devices.devices.push(device);
+        self.devices.version += 1;
+        self.devices.signature = sign::sign_detached(&self.devices.signed_bytes(), identity_sk)
+            .as_ref()
+            .to_vec();
+        Some(endorsement)
+    }
+
+    /*
+     * Removes device `device_id` (e.g. a lost one) from the account's
+     * device list and signs the new version.
+     *
+     * Returns `false` on a linked device, for this device itself, or if
+     * the device is not listed.
+     */
+    pub fn remove_device(&mut self, device_id: &str) -> bool {
+        let Some(identity_sk) = self.identity_sk.as_ref() else {
+            return false;
+        };
+        if device_id == self.device_id || self.devices.device(device_id).is_none() {
+            return false;
+        }
+        self.devices.devices.retain(|d| d.device_id != device_id);
+        self.devices.version += 1;
+        self.devices.signature = sign::sign_detached(&self.devices.signed_bytes(), identity_sk)
+            .as_ref()
+            .to_vec();
+        true
+    }
+
+    /*
+     * Adopts `list` as the account's device list if it is valid for this
+     * account and newer than ours. Returns whether it was adopted.
+     */
+    pub fn update_devices(&mut self, list: DeviceList) -> bool {
+        if list.username != self.username || list.version <= self.devices.version || !list.verify(&self.identity_pk) {
+            return false;
+        }
+        self.devices = list;
+        true
+    }
+
+    /*
+     * Answers a relay login challenge.
+     *
+     * The challenge nonce is bound to the username and the device, and
+     * signed with the device key, proving this device is one of the
+     * account's (the relay checks the signed device list).
+     */
+    pub fn sign_challenge(&self, nonce: &[u8]) -> sign::Signature {
+        sign::sign_detached(&challenge_message(&self.username, &self.device_id, nonce), &self.device_sk)
+    }
+
+    /*
+     * Builds the signed directory claim for this user's username
+     * (see `net::directory`). Only the primary device can.
+     */
+    pub fn username_claim(&self) -> Option<UsernameClaim> {
+        let identity_pk = self.identity_pk.as_ref().to_vec();
+        let identity_sk = self.identity_sk.as_ref()?;
+        let signature = sign::sign_detached(&claim_message(&self.username, &identity_pk), identity_sk);
+        Some(UsernameClaim {
+            username: self.username.clone(),
+            identity_pk,
+            signature: signature.as_ref().to_vec(),
+        })
     }
 
     /*
@@ -118,7 +569,7 @@ impl User {
      *  1. Generate an ephemeral key pair (used only for this message)
      *  2. Perform Diffie-Hellman: DH(ephemeral, peer.SPK)
      *  3. Generate a random nonce
-     *  4. Encrypt the plaintext using the derived shared secret
+     *  4. Encrypt the serialized payload using the derived shared secret
      *  5. Produce a human-readable log with all details
      *
      * Returns:
@@ -130,7 +581,7 @@ impl User {
     pub fn encrypt_message_with_logs(
         &self,
         peer: &User,
-        plaintext: &str,
+        payload: &Payload,
     ) -> (box_::PublicKey, box_::Nonce, Vec<u8>, String) {
         let spk_ok = Self::verify_peer_spk(peer);
 
@@ -138,12 +589,13 @@ impl User {
         let shared = box_::precompute(&peer.signed_pre_pk, &ephemeral_sk);
 
         let nonce = box_::gen_nonce();
-        let ciphertext = box_::seal_precomputed(plaintext.as_bytes(), &nonce, &shared);
+        let ciphertext = box_::seal_precomputed(payload.encode().as_bytes(), &nonce, &shared);
 
         let log = format!(
             concat!(
                 "== log ==\n",
                 "Sender: {}\nReceiver: {}\n",
+                "Payload: {} v{}\n",
                 "Verify(peer.SPK signed by peer.ID) = {}\n",
                 "Ephemeral PK: {}\n",
                 "DH(ephemeral, peer.SPK): precomputed ({} bytes)\n",
@@ -152,6 +604,8 @@ impl User {
             ),
             self.username,
             peer.username,
+            payload.kind(),
+            payload.version(),
             spk_ok,
             hex::encode(ephemeral_pk.as_ref()),
             shared.0.len(),
@@ -162,17 +616,74 @@ impl User {
         (ephemeral_pk, nonce, ciphertext, log)
     }
 
+    /*
+     * Encrypts a message to one device of a peer with logging.
+     *
+     * Same steps as `encrypt_message_with_logs`, with the device's pre-key
+     * instead of the peer's signed pre-key. Step 0 checks instead that
+     * the device is listed in the peer's device list and that the list
+     * was signed by the peer's identity key.
+     *
+     * Returns `None` if the device's pre-key is malformed.
+     */
+    pub fn encrypt_for_device_with_logs(
+        &self,
+        peer: &User,
+        device: &DeviceInfo,
+        payload: &Payload,
+    ) -> Option<(box_::PublicKey, box_::Nonce, Vec<u8>, String)> {
+        let prekey = device.prekey()?;
+        let listed = peer.devices.device(&device.device_id) == Some(device) && peer.devices.verify(&peer.identity_pk);
+
+        let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+        let shared = box_::precompute(&prekey, &ephemeral_sk);
+
+        let nonce = box_::gen_nonce();
+        let ciphertext = box_::seal_precomputed(payload.encode().as_bytes(), &nonce, &shared);
+
+        let log = format!(
+            concat!(
+                "== log ==\n",
+                "Sender: {} (device {})\nReceiver: {} (device {})\n",
+                "Payload: {} v{}\n",
+                "Verify(device in peer.devices v{} signed by peer.ID) = {}\n",
+                "Ephemeral PK: {}\n",
+                "DH(ephemeral, device.prekey): precomputed ({} bytes)\n",
+                "Nonce: {}\n",
+                "Ciphertext: {}\n"
+            ),
+            self.username,
+            self.device_id,
+            peer.username,
+            device.device_id,
+            payload.kind(),
+            payload.version(),
+            peer.devices.version,
+            listed,
+            hex::encode(ephemeral_pk.as_ref()),
+            shared.0.len(),
+            hex::encode(nonce.0),
+            hex::encode(&ciphertext),
+        );
+
+        Some((ephemeral_pk, nonce, ciphertext, log))
+    }
+
     /*
      * Decrypts a message from a peer with logging.
      *
+     * Messages are encrypted to this device's signed pre-key.
+     *
      * Steps:
      *  1. Perform Diffie-Hellman: DH(sender.ephemeral, self.SPK)
      *  2. Decrypt the ciphertext with the derived shared secret
-     *  3. Produce a human-readable log of the process
+     *  3. Read the payload (see `Payload::decode`)
+     *  4. Produce a human-readable log of the process
      *
      * Returns:
-     *  - `Some((plaintext, log))` if decryption succeeds
-     *  - `None` if decryption or UTF-8 decoding fails
+     *  - `Some((payload, log))` if decryption succeeds
+     *  - `None` if decryption or UTF-8 decoding fails, or if the payload
+     *    is not one this client reads
      */
     pub fn decrypt_message_with_logs(
         &self,
@@ -180,29 +691,34 @@ impl User {
         nonce: &box_::Nonce,
         ciphertext: &[u8],
         sender_name: &str,
-    ) -> Option<(String, String)> {
+    ) -> Option<(Payload, String)> {
         let shared = box_::precompute(sender_ephemeral_pk, &self.signed_pre_sk);
 
         let pt = box_::open_precomputed(ciphertext, nonce, &shared).ok()?;
         let plaintext = String::from_utf8(pt).ok()?;
+        let payload = Payload::decode(&plaintext)?;
 
         let log = format!(
             concat!(
                 "== log (recv) ==\n",
-                "Receiver: {}\nSender: {}\n",
+                "Receiver: {} (device {})\nSender: {}\n",
                 "DH(sender.ephemeral, self.SPK): precomputed ({} bytes)\n",
                 "Nonce: {}\n",
                 "Ciphertext: {}\n",
+                "Payload: {} v{}\n",
                 "Plaintext: {}\n"
             ),
             self.username,
+            self.device_id,
             sender_name,
             shared.0.len(),
             hex::encode(nonce.0),
             hex::encode(ciphertext),
+            payload.kind(),
+            payload.version(),
             plaintext
         );
 
-        Some((plaintext, log))
+        Some((payload, log))
     }
 }
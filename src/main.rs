@@ -3,22 +3,134 @@
  *
  * Responsibilities:
  *  - Initialize the sodiumoxide cryptographic library
- *  - Create demo users (the local user and a few contacts)
- *  - Build the contact list
+ *  - Create demo users (the local user, picked with `BLACKIPHER_USER`, and a few contacts)
+ *    and pick this device of the local user with `BLACKIPHER_DEVICE`
+ *  - Or link this device to an account with the code in `BLACKIPHER_LINK`,
+ *    keeping its keys in `device.json` for the next runs
+ *  - Load the contact list from `contacts.json`, or start it with the demo users
+ *  - Pick a transport (in-memory relay, or a TCP/Unix relay from `BLACKIPHER_RELAY`,
+ *    pinned to the relay's Noise key from `BLACKIPHER_RELAY_KEY`); with a relay,
+ *    the transparency log it showed us is remembered in `monitor.json`
  *  - Launch the Iced application with the Elm-style `update` and `view`
  *
  * Note: This setup is for demonstration and testing only.
- * In a real application, contacts would be dynamically managed
- * rather than hardcoded here.
+ * Contacts are managed from the app (and saved to `contacts.json`);
+ * the demo users only seed the list on the first run.
  */
 
 pub mod client; // contains user.rs and contacts.rs
+pub mod net;    // contains transport.rs (network layer)
+pub mod server; // contains relay.rs (store-and-forward relay)
 pub mod ui;     // contains app.rs (UI logic)
 
 use crate::client::contacts::Contacts;
+use crate::client::link::PendingLink;
+use crate::client::transparency::Monitor;
 use crate::client::user::User;
-use crate::ui::app::{update, view, UI};
+use crate::net::devices::{self, PRIMARY_DEVICE};
+use crate::net::link::{self, LinkCode, LinkResponse};
+use crate::net::noise::{self, Keypair};
+use crate::net::transport::{self, MemoryTransport, Transport};
+use crate::server::relay::Relay;
+use crate::ui::app::{subscription, update, view, Message, UI};
 use iced::{application, Theme, Task};
+use std::sync::Arc;
+use std::time::Duration;
+
+/* Keys of a device linked with a code */
+const DEVICE_FILE: &str = "device.json";
+
+/* The contact list, as managed from the app */
+const CONTACTS_FILE: &str = "contacts.json";
+
+/* The relay's log key and the last tree head it showed us */
+const MONITOR_FILE: &str = "monitor.json";
+
+/* The relay's directory and transparency log */
+const DIRECTORY_FILE: &str = "directory.json";
+
+/* How long a new device waits for the primary device to accept it */
+const LINK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/*
+ * Connects to the relay described by `target`:
+ *  - "unix:/path/to/socket" : Unix domain socket
+ *  - "host:port"            : TCP
+ *
+ * `relay_key` is the relay's static Noise key (hex); the connection
+ * fails if the relay presents any other key.
+ */
+async fn connect_relay(target: String, relay_key: Option<String>) -> Result<Arc<dyn Transport>, String> {
+    let relay_key = relay_key
+        .as_deref()
+        .and_then(noise::parse_public_key)
+        .ok_or("BLACKIPHER_RELAY_KEY must hold the relay's public key (hex)")?;
+    #[cfg(unix)]
+    if let Some(path) = target.strip_prefix("unix:") {
+        let transport = transport::connect_unix(path, &relay_key).await.map_err(|e| e.to_string())?;
+        return Ok(Arc::new(transport));
+    }
+    let transport = transport::connect_tcp(&target, &relay_key).await.map_err(|e| e.to_string())?;
+    Ok(Arc::new(transport))
+}
+
+/*
+ * Runs a relay instead of the GUI (`blackipher relay <host:port | unix:/path>`).
+ *
+ * The relay's static Noise key is kept in `relay_key.json` and its public
+ * half is printed so that clients can pin it. The username directory and
+ * its transparency log are kept in `directory.json`, so clients that
+ * remember the log can still check it after a restart.
+ */
+fn run_relay(target: &str) -> std::io::Result<()> {
+    let relay_key = Keypair::load_or_generate("relay_key.json");
+    println!("relay key: {}", hex::encode(relay_key.public.as_ref()));
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let relay = Relay::with_directory_file(DIRECTORY_FILE);
+        #[cfg(unix)]
+        if let Some(path) = target.strip_prefix("unix:") {
+            let _ = std::fs::remove_file(path);
+            println!("relay listening on unix:{path}");
+            return relay.serve_unix(tokio::net::UnixListener::bind(path)?, relay_key).await;
+        }
+        println!("relay listening on {target}");
+        relay.serve_tcp(tokio::net::TcpListener::bind(target).await?, relay_key).await
+    })
+}
+
+/*
+ * Links this device to an account (`BLACKIPHER_LINK=<payload>`), through
+ * the relay at `target`: sends the request for the code shown on the
+ * primary device, and waits for its response (see `client::link`).
+ */
+fn link_device(payload: &str, target: Option<String>, relay_key: Option<String>) -> Result<User, String> {
+    let code = LinkCode::parse(payload).ok_or("BLACKIPHER_LINK must hold a linking payload (blackipher-link:user:code)")?;
+    let target = target.ok_or("linking a device needs a relay (BLACKIPHER_RELAY)")?;
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime.block_on(async {
+        let transport = connect_relay(target, relay_key).await?;
+        let pending = PendingLink::new(code);
+        let request = serde_json::to_vec(&pending.request).map_err(|e| e.to_string())?;
+        transport
+            .link_post(&pending.code.channel(link::REQUESTS), request)
+            .await
+            .map_err(|e| e.to_string())?;
+        println!("waiting for the primary device of {} to accept this device...", pending.code.username);
+
+        let responses = pending.code.channel(link::RESPONSES);
+        let deadline = tokio::time::Instant::now() + LINK_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let messages = transport.link_fetch(&responses).await.map_err(|e| e.to_string())?;
+            if let Some(response) = messages.iter().find_map(|m| serde_json::from_slice::<LinkResponse>(m).ok()) {
+                return pending.complete(&response).map_err(|e| e.to_string());
+            }
+        }
+        Err("the primary device did not answer in time".to_string())
+    })
+}
 
 fn main() -> iced::Result {
     /* Initialize sodiumoxide (required for crypto operations).
@@ -29,31 +141,130 @@ fn main() -> iced::Result {
         std::process::exit(1);
     }
 
-    /* Demo: create the local user */
-    let me = User::new("katpercent", 4);
+    /* `blackipher relay <addr>` serves a relay and never opens a window */
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("relay") {
+        let target = args.get(2).map(String::as_str).unwrap_or("127.0.0.1:7878");
+        if let Err(e) = run_relay(target) {
+            eprintln!("relay failed: {e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    /* Demo: create the three demo users.
+     *
+     * Their keys are derived from their names, so two processes agree on
+     * them (needed to talk peer-to-peer). `BLACKIPHER_USER` picks which
+     * one is the local user (default: katpercent).
+     */
+    let demo_seed = |name: &str| sodiumoxide::crypto::hash::sha256::hash(format!("blackipher-demo:{name}").as_bytes()).0;
+    let demo_user = |name: &str| User::from_seed(name, &demo_seed(name), 4);
+    let local_name = std::env::var("BLACKIPHER_USER").unwrap_or_else(|_| "katpercent".to_string());
+    let mut users: Vec<User> = ["katpercent", "alice", "bob"].into_iter().map(demo_user).collect();
+    let relay_target = std::env::var("BLACKIPHER_RELAY").ok();
+    let relay_key = std::env::var("BLACKIPHER_RELAY_KEY").ok();
 
-    /* Demo: create two additional users */
-    let alice = User::new("alice", 4);
-    let bob = User::new("bob", 4);
+    /* A device linked with a code (`BLACKIPHER_LINK`) generates its own
+     * keys and keeps them in `device.json`, which later runs reuse.
+     *
+     * Otherwise `BLACKIPHER_DEVICE` picks the device of the local user
+     * (default: 1, the primary device). The keys of a demo linked device
+     * are derived from the same seed, and the demo account adds it to
+     * its device list.
+     */
+    let me = if let Ok(payload) = std::env::var("BLACKIPHER_LINK") {
+        match link_device(&payload, relay_target.clone(), relay_key.clone()) {
+            Ok(user) => {
+                println!("linked as device {} of {}", user.device_id, user.username());
+                user.save(DEVICE_FILE);
+                user
+            }
+            Err(e) => {
+                eprintln!("linking failed: {e}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(user) = User::load(DEVICE_FILE) {
+        user
+    } else {
+        let device = std::env::var("BLACKIPHER_DEVICE").unwrap_or_else(|_| PRIMARY_DEVICE.to_string());
+        if !devices::valid_device_id(&device) {
+            eprintln!("BLACKIPHER_DEVICE must be 1 to {} letters or digits", devices::MAX_DEVICE_ID_LEN);
+            std::process::exit(1);
+        }
+        User::from_seed_on_device(&local_name, &demo_seed(&local_name), 4, &device)
+    };
+    users.retain(|u| u.username != me.username);
     // Uncomment for debugging key material:
-    // alice.print_keys();
-    // bob.print_keys();
+    // me.print_keys();
+
+    /* Load the contact list, or start it with the demo users (excluding
+     * the current user).
+     *
+     * Only public keys are saved: a saved contact who is a demo user (same
+     * username and identity key) gets the demo keys back, so that the
+     * messages sent before this device kept a copy of its own can still
+     * be read (see `ui::app`).
+     */
+    let contacts = match Contacts::load(CONTACTS_FILE) {
+        Some(mut contacts) => {
+            for demo in &users {
+                if let Some(saved) = contacts.get_mut(&demo.username) {
+                    if saved.identity_pk == demo.identity_pk {
+                        let devices = saved.devices.clone();
+                        *saved = demo.clone();
+                        saved.devices = devices;
+                    }
+                }
+            }
+            contacts
+        }
+        None => {
+            let mut contacts = Contacts::new();
+            for user in users.iter().cloned() {
+                contacts.add(user).expect("demo usernames are unique");
+            }
+            contacts.save(CONTACTS_FILE);
+            contacts
+        }
+    };
 
-    /* Build the contact list (excluding the current user) */
-    let mut contacts = Contacts::new();
-    contacts.add(alice.clone());
-    contacts.add(bob.clone());
+    /* Pick the transport.
+     *
+     * If `BLACKIPHER_RELAY` is set, connect to that relay once the app is
+     * running; otherwise use an in-process relay (messages stay local).
+     */
 
     /* Launch the Iced application.
      *
-     * - Title   : "BlacKipher Chat"
-     * - Update  : `update` function handles state changes
-     * - View    : `view` function renders the UI
-     * - Theme   : Dark mode
-     * - Startup : Initializes UI with the current user and contacts
+     * - Title        : "BlacKipher Chat"
+     * - Update       : `update` function handles state changes
+     * - View         : `view` function renders the UI
+     * - Subscription : `subscription` delivers incoming envelopes
+     * - Theme        : Dark mode
+     * - Startup      : Initializes UI with the current user and contacts
      */
     application("BlacKipher Chat", update, view)
+        .subscription(subscription)
         .theme(|_ui: &UI| Theme::Dark)
         .centered()
-        .run_with(move || (UI::with_contacts(contacts, me.clone()), Task::none()))
+        .run_with(move || {
+            let ui = UI::with_contacts(contacts, me.clone());
+            match relay_target.clone() {
+                Some(target) => (
+                    ui.with_monitor(Monitor::load(MONITOR_FILE)),
+                    Task::perform(connect_relay(target, relay_key.clone()), Message::TransportConnected),
+                ),
+                None => {
+                    /* The demo contacts "registered" their usernames on the local relay */
+                    let relay = Relay::new();
+                    for claim in users.iter().filter_map(User::username_claim) {
+                        let _ = relay.claim(claim);
+                    }
+                    let local: Arc<dyn Transport> = Arc::new(MemoryTransport::new(relay));
+                    (ui, Task::done(Message::TransportConnected(Ok(local))))
+                }
+            }
+        })
 }
@@ -0,0 +1,39 @@
+/*
+ * This module defines the wire format of the login handshake
+ * between a client and a relay.
+ *
+ * Handshake:
+ *  1. client -> relay : `Hello { username, device }`
+ *  2. relay -> client : `Challenge { nonce }` (random, single use, short-lived)
+ *  3. client -> relay : `Login { username, device, nonce, signature }`
+ *     where `signature = Sign(device_sk, challenge_message(username, device, nonce))`
+ *  4. relay -> client : `Session { token, expires_in }`
+ *
+ * Each device logs in with its own device key, which the relay looks up
+ * in the account's signed device list (see `net::devices`).
+ *
+ * The signed message is domain-separated and bound to the username and
+ * the device, so a signature cannot be reused for another account, another
+ * device or another purpose (e.g. passed off as a signed pre-key signature).
+ */
+
+/* Length of a challenge nonce, in bytes */
+pub const CHALLENGE_LEN: usize = 32;
+
+/* Domain separation tag for login signatures */
+const CHALLENGE_CONTEXT: &[u8] = b"blackipher-login-v2";
+
+/*
+ * Builds the exact byte string a client signs to answer a challenge:
+ * `context || len(username) || username || len(device) || device || nonce`.
+ */
+pub fn challenge_message(username: &str, device: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CHALLENGE_CONTEXT.len() + 8 + username.len() + device.len() + nonce.len());
+    msg.extend_from_slice(CHALLENGE_CONTEXT);
+    msg.extend_from_slice(&(username.len() as u32).to_be_bytes());
+    msg.extend_from_slice(username.as_bytes());
+    msg.extend_from_slice(&(device.len() as u32).to_be_bytes());
+    msg.extend_from_slice(device.as_bytes());
+    msg.extend_from_slice(nonce);
+    msg
+}
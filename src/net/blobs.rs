@@ -0,0 +1,34 @@
+/*
+ * This module defines how blobs are named and moved between a client
+ * and the relay's blob store (see `server::blobs`).
+ *
+ * A blob is an opaque byte string, in practice an encrypted attachment
+ * (see `client::attachments`). It is content-addressed: its id is
+ * `hex(SHA256(blob))`, so the relay can check an upload against its id
+ * and two uploads of the same bytes are stored once.
+ *
+ * Blobs are moved in pieces of at most `BLOB_CHUNK` bytes, so no frame
+ * gets close to `MAX_FRAME_LEN`:
+ *  - `BlobPut { blob_id, offset, data, last }`, in order, the relay
+ *    answering each one with the number of bytes it holds
+ *  - `BlobGet { blob_id, offset }`, answered with the piece at `offset`
+ *    and the total size of the blob
+ */
+
+use sodiumoxide::crypto::hash::sha256;
+
+/* Largest piece of a blob moved in one frame, in bytes */
+pub const BLOB_CHUNK: usize = 64 * 1024;
+
+/* Largest blob the relay stores, in bytes */
+pub const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+
+/* The id of `blob`: its SHA-256, hex-encoded */
+pub fn blob_id(blob: &[u8]) -> String {
+    hex::encode(sha256::hash(blob).0)
+}
+
+/* Checks that `blob_id` looks like a blob id (64 lowercase hex digits) */
+pub fn valid_blob_id(blob_id: &str) -> bool {
+    blob_id.len() == 64 && blob_id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
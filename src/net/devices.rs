@@ -0,0 +1,187 @@
+/*
+ * This module defines the wire format of linked devices.
+ *
+ * An account (a username and its identity key) can be used from several
+ * devices. Each device has its own keys:
+ *  - a device signing key (Ed25519), used to log in to the relay
+ *  - a pre-key (X25519), which messages for that device are encrypted to
+ *  - a Noise static key (X25519), which a direct link to that device is
+ *    pinned to (see `net::p2p`); only primary devices list one
+ *
+ * The account publishes the list of its devices, signed with its
+ * identity key:
+ *
+ *   signature = Sign(identity_sk, context || username || version || devices)
+ *
+ * Anyone holding the account's identity public key can check the list,
+ * so the relay cannot slip in a device of its own. The version only
+ * grows: a list is replaced by a newer one, never by an older one.
+ *
+ * Device `PRIMARY_DEVICE` is the device the account was created on;
+ * envelopes that do not name a device are for it.
+ */
+
+use crate::net::directory::valid_username;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{box_, sign};
+
+/* The device an account was created on */
+pub const PRIMARY_DEVICE: &str = "1";
+
+/* Maximum length of a device id, in bytes */
+pub const MAX_DEVICE_ID_LEN: usize = 16;
+
+/* Domain separation tag for device lists */
+const DEVICE_LIST_CONTEXT: &[u8] = b"blackipher-devices-v1";
+
+/*
+ * Checks that `device_id` is a valid device id:
+ * 1 to `MAX_DEVICE_ID_LEN` ASCII letters or digits.
+ */
+pub fn valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty() && device_id.len() <= MAX_DEVICE_ID_LEN && device_id.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/*
+ * The mailbox of device `device` of `username` on the relay
+ * ("username/device"; no device means the primary one).
+ */
+pub fn mailbox(username: &str, device: &str) -> String {
+    let device = if device.is_empty() { PRIMARY_DEVICE } else { device };
+    format!("{username}/{device}")
+}
+
+/*
+ * The id of the message carried by envelope `envelope_id`.
+ *
+ * The copy of a message sent to a secondary device has the envelope
+ * id "message_id.device", so that each copy is tracked on its own in
+ * the outbox; every device stores the message under its own id.
+ */
+pub fn message_id(envelope_id: &str) -> &str {
+    envelope_id.split('.').next().unwrap_or(envelope_id)
+}
+
+/*
+ * The public keys of one device.
+ *
+ * Fields:
+ *  - `device_id`  : Id of the device, unique within the account
+ *  - `signing_pk` : Ed25519 device key, proves the device at login
+ *  - `prekey`     : X25519 pre-key the device receives messages on
+ *  - `noise_pk`   : X25519 Noise static key of the device's direct links
+ *                   (empty if it accepts none; left out of the JSON then, so
+ *                   lists signed before it existed still verify)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub signing_pk: Vec<u8>,
+    pub prekey: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub noise_pk: Vec<u8>,
+}
+
+impl DeviceInfo {
+    /* The device signing key, if well-formed */
+    pub fn signing_key(&self) -> Option<sign::PublicKey> {
+        sign::PublicKey::from_slice(&self.signing_pk)
+    }
+
+    /* The device pre-key, if well-formed */
+    pub fn prekey(&self) -> Option<box_::PublicKey> {
+        box_::PublicKey::from_slice(&self.prekey)
+    }
+
+    /* The Noise static key of the device's direct links, if it lists a well-formed one */
+    pub fn noise_key(&self) -> Option<box_::PublicKey> {
+        box_::PublicKey::from_slice(&self.noise_pk)
+    }
+}
+
+/*
+ * The devices of an account, signed by its identity key.
+ *
+ * Fields:
+ *  - `username`  : The account
+ *  - `version`   : Grows by one with every change of the list
+ *  - `devices`   : The devices currently linked to the account
+ *  - `signature` : Signature of `signed_bytes()` by the identity key
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeviceList {
+    pub username: String,
+    pub version: u64,
+    pub devices: Vec<DeviceInfo>,
+    pub signature: Vec<u8>,
+}
+
+impl DeviceList {
+    /* The bytes covered by the signature */
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut data = DEVICE_LIST_CONTEXT.to_vec();
+        data.extend_from_slice(&(self.username.len() as u32).to_be_bytes());
+        data.extend_from_slice(self.username.as_bytes());
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.extend_from_slice(&serde_json::to_vec(&self.devices).unwrap_or_default());
+        data
+    }
+
+    /*
+     * Checks the list on its own: valid username and device ids, no
+     * device listed twice, every key well-formed, and a signature made
+     * by `identity_pk`.
+     */
+    pub fn verify(&self, identity_pk: &sign::PublicKey) -> bool {
+        if !valid_username(&self.username) {
+            return false;
+        }
+        for (i, device) in self.devices.iter().enumerate() {
+            if !valid_device_id(&device.device_id)
+                || device.signing_key().is_none()
+                || device.prekey().is_none()
+                || (!device.noise_pk.is_empty() && device.noise_key().is_none())
+                || self.devices[..i].iter().any(|d| d.device_id == device.device_id)
+            {
+                return false;
+            }
+        }
+        let Ok(signature) = sign::Signature::try_from(self.signature.as_slice()) else {
+            return false;
+        };
+        sign::verify_detached(&signature, &self.signed_bytes(), identity_pk)
+    }
+
+    /* The device `device_id`, if listed */
+    pub fn device(&self, device_id: &str) -> Option<&DeviceInfo> {
+        self.devices.iter().find(|d| d.device_id == device_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::user::User;
+
+    #[test]
+    fn lists_signed_without_noise_keys_still_verify() {
+        let alice = User::new("alice", 1);
+        assert_eq!(alice.noise_key(), Some(alice.noise_keypair().public));
+
+        /* A list from before devices had Noise keys */
+        let mut old = User::new("bob", 1);
+        old.devices.devices.clear();
+        old.devices.version = 0;
+        let mut info = old.device_info();
+        info.noise_pk.clear();
+        old.add_device(info);
+        assert!(old.devices.verify(&old.identity_pk));
+        assert!(!old.devices.signed_bytes().windows(8).any(|w| w == b"noise_pk"));
+        assert_eq!(old.noise_key(), None);
+
+        /* The Noise key is covered by the signature */
+        let mut swapped = alice.clone();
+        swapped.devices.devices[0].noise_pk = old.noise_keypair().public.as_ref().to_vec();
+        assert!(!swapped.devices.verify(&swapped.identity_pk));
+        assert_eq!(swapped.noise_key(), None);
+    }
+}
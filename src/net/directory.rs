@@ -0,0 +1,86 @@
+/*
+ * This module defines the wire format of the username directory.
+ *
+ * A username is not taken by simply using it: its owner publishes a
+ * `UsernameClaim`, a statement signed with their identity key:
+ *
+ *   signature = Sign(identity_sk, claim_message(username, identity_pk))
+ *
+ * The relay stores the first valid claim for each username and returns
+ * it on lookup, so clients can check for themselves that the identity
+ * key they got really signed for that name (the relay cannot forge it).
+ *
+ * Usernames are restricted to a small lowercase alphabet, so that two
+ * names that look alike ("Alice" / "alice") cannot both be claimed.
+ */
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign;
+
+/* Maximum length of a username, in bytes */
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/* Domain separation tag for username claims */
+const CLAIM_CONTEXT: &[u8] = b"blackipher-directory-v1";
+
+/*
+ * A username registration, signed by the owner's identity key.
+ *
+ * Fields:
+ *  - `username`    : The claimed name
+ *  - `identity_pk` : The owner's Ed25519 identity public key (raw bytes)
+ *  - `signature`   : Signature of `claim_message(username, identity_pk)`
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UsernameClaim {
+    pub username: String,
+    pub identity_pk: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/*
+ * Checks that `username` may be registered:
+ * 1 to `MAX_USERNAME_LEN` characters among `a-z`, `0-9`, `_`, `-` and `.`.
+ */
+pub fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'_' | b'-' | b'.'))
+}
+
+/*
+ * Builds the exact byte string signed in a claim:
+ * `context || len(username) || username || identity_pk`.
+ */
+pub fn claim_message(username: &str, identity_pk: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CLAIM_CONTEXT.len() + 4 + username.len() + identity_pk.len());
+    msg.extend_from_slice(CLAIM_CONTEXT);
+    msg.extend_from_slice(&(username.len() as u32).to_be_bytes());
+    msg.extend_from_slice(username.as_bytes());
+    msg.extend_from_slice(identity_pk);
+    msg
+}
+
+impl UsernameClaim {
+    /* The claimed identity key, if it is a well-formed Ed25519 key */
+    pub fn identity_key(&self) -> Option<sign::PublicKey> {
+        sign::PublicKey::from_slice(&self.identity_pk)
+    }
+
+    /*
+     * Checks the claim on its own: the username is valid and the
+     * signature was made by the claimed identity key.
+     */
+    pub fn verify(&self) -> bool {
+        let Some(identity_pk) = self.identity_key() else {
+            return false;
+        };
+        let Ok(signature) = sign::Signature::try_from(self.signature.as_slice()) else {
+            return false;
+        };
+        valid_username(&self.username)
+            && sign::verify_detached(&signature, &claim_message(&self.username, &self.identity_pk), &identity_pk)
+    }
+}
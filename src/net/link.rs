@@ -0,0 +1,296 @@
+/*
+ * This module defines the wire format of device linking: how a new
+ * device joins an account, using a one-time code shown by the primary
+ * device (see `client::link`).
+ *
+ * The linking code is 10 random bytes, shown as 16 base32 characters
+ * ("ABCD-EFGH-IJKL-MNOP"). The full payload (what a QR code would hold)
+ * also names the account: "blackipher-link:alice:ABCD-EFGH-IJKL-MNOP".
+ *
+ * Both devices derive from the code:
+ *  - a rendezvous channel on the relay, `hex(SHA256(context || "channel" || direction || code))`,
+ *    which reveals nothing about the code
+ *  - a link key, `SHA256(context || "key" || username || code)`
+ *
+ * Exchange:
+ *  1. new device -> channel "request" : `LinkRequest`
+ *     (its new device key and pre-key, an ephemeral X25519 key, a MAC
+ *     under the link key, and a signature by the new device key)
+ *  2. primary -> channel "response"   : `LinkResponse`
+ *     (an ephemeral X25519 key, and the `LinkGrant` sealed under a key
+ *     derived from the link key and both ephemeral keys)
+ *
+ * Only someone who knows the code can make a request the primary device
+ * accepts, or open its answer; the relay only passes opaque messages on.
+ */
+
+use crate::net::devices::DeviceList;
+use crate::net::directory::valid_username;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes_into;
+
+/* Length of the secret part of a linking code, in bytes */
+pub const CODE_LEN: usize = 10;
+
+/* Prefix of a linking payload */
+pub const LINK_PREFIX: &str = "blackipher-link:";
+
+/* Domain separation tag for device linking */
+pub const LINK_CONTEXT: &[u8] = b"blackipher-link-v1";
+
+/* Channel of the new device's request */
+pub const REQUESTS: &str = "request";
+
+/* Channel of the primary device's response */
+pub const RESPONSES: &str = "response";
+
+/* Base32 alphabet (RFC 4648) */
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/*
+ * A one-time linking code for the account `username`.
+ *
+ * Fields:
+ *  - `username` : The account the new device joins
+ *  - `secret`   : The random part of the code
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkCode {
+    pub username: String,
+    pub secret: [u8; CODE_LEN],
+}
+
+impl LinkCode {
+    /* Draws a fresh code for `username` */
+    pub fn generate(username: &str) -> Self {
+        let mut secret = [0u8; CODE_LEN];
+        randombytes_into(&mut secret);
+        Self {
+            username: username.to_string(),
+            secret,
+        }
+    }
+
+    /* The code as typed by the user, e.g. "ABCD-EFGH-IJKL-MNOP" */
+    pub fn code(&self) -> String {
+        let mut bits = 0u32;
+        let mut pending = 0;
+        let mut chars = Vec::with_capacity(CODE_LEN * 8 / 5);
+        for byte in self.secret {
+            bits = (bits << 8) | byte as u32;
+            pending += 8;
+            while pending >= 5 {
+                pending -= 5;
+                chars.push(BASE32[((bits >> pending) & 31) as usize] as char);
+            }
+        }
+        chars.chunks(4).map(|group| group.iter().collect::<String>()).collect::<Vec<_>>().join("-")
+    }
+
+    /* The full payload, e.g. for a QR code */
+    pub fn payload(&self) -> String {
+        format!("{LINK_PREFIX}{}:{}", self.username, self.code())
+    }
+
+    /*
+     * Parses a payload ("blackipher-link:username:code"). The code is
+     * read case-insensitively, dashes and spaces are ignored.
+     */
+    pub fn parse(payload: &str) -> Option<Self> {
+        let (username, code) = payload.trim().strip_prefix(LINK_PREFIX)?.split_once(':')?;
+        if !valid_username(username) {
+            return None;
+        }
+        let mut secret = Vec::with_capacity(CODE_LEN);
+        let mut bits = 0u32;
+        let mut pending = 0;
+        for c in code.chars().filter(|c| *c != '-' && !c.is_whitespace()) {
+            let value = BASE32.iter().position(|b| *b as char == c.to_ascii_uppercase())? as u32;
+            bits = (bits << 5) | value;
+            pending += 5;
+            if pending >= 8 {
+                pending -= 8;
+                secret.push((bits >> pending) as u8);
+            }
+        }
+        /* Leftover characters would be silently dropped */
+        if pending != 0 {
+            return None;
+        }
+        Some(Self {
+            username: username.to_string(),
+            secret: secret.try_into().ok()?,
+        })
+    }
+
+    /* The relay channel for messages going in `direction` (`REQUESTS` or `RESPONSES`) */
+    pub fn channel(&self, direction: &str) -> String {
+        let mut data = LINK_CONTEXT.to_vec();
+        data.extend_from_slice(b"channel");
+        data.extend_from_slice(direction.as_bytes());
+        data.extend_from_slice(&self.secret);
+        hex::encode(sha256::hash(&data).0)
+    }
+
+    /* The link key, which authenticates both messages */
+    pub fn key(&self) -> [u8; 32] {
+        let mut data = LINK_CONTEXT.to_vec();
+        data.extend_from_slice(b"key");
+        data.extend_from_slice(&(self.username.len() as u32).to_be_bytes());
+        data.extend_from_slice(self.username.as_bytes());
+        data.extend_from_slice(&self.secret);
+        sha256::hash(&data).0
+    }
+}
+
+/* Checks that `channel` looks like a rendezvous channel (64 hex digits) */
+pub fn valid_channel(channel: &str) -> bool {
+    channel.len() == 64 && channel.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/*
+ * The new device's half of the exchange.
+ *
+ * Fields:
+ *  - `signing_pk`   : The new device key (Ed25519)
+ *  - `prekey`       : The new device's pre-key (X25519)
+ *  - `ephemeral_pk` : Ephemeral X25519 key for this exchange
+ *  - `proof`        : Signature of `signed_bytes()` by the new device key
+ *  - `mac`          : HMAC-SHA256 of `signed_bytes()` under the link key
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LinkRequest {
+    pub signing_pk: Vec<u8>,
+    pub prekey: Vec<u8>,
+    pub ephemeral_pk: Vec<u8>,
+    pub proof: Vec<u8>,
+    pub mac: Vec<u8>,
+}
+
+impl LinkRequest {
+    /* The bytes covered by `proof` and `mac`, bound to the account */
+    pub fn signed_bytes(&self, username: &str) -> Vec<u8> {
+        let mut data = LINK_CONTEXT.to_vec();
+        data.extend_from_slice(b"request");
+        data.extend_from_slice(&(username.len() as u32).to_be_bytes());
+        data.extend_from_slice(username.as_bytes());
+        for key in [&self.signing_pk, &self.prekey, &self.ephemeral_pk] {
+            data.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            data.extend_from_slice(key);
+        }
+        data
+    }
+}
+
+/*
+ * The primary device's answer.
+ *
+ * Fields:
+ *  - `ephemeral_pk` : Ephemeral X25519 key of the primary device
+ *  - `nonce`        : Nonce of `sealed`
+ *  - `sealed`       : The `LinkGrant` (JSON), sealed with secretbox
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LinkResponse {
+    pub ephemeral_pk: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub sealed: Vec<u8>,
+}
+
+/*
+ * What the new device receives once the primary device accepted it.
+ *
+ * Fields:
+ *  - `identity_pk` : The account's identity key
+ *  - `device_id`   : Id given to the new device
+ *  - `prekey_sig`  : Identity signature of the new device's pre-key
+ *  - `list`        : The device list, including the new device
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LinkGrant {
+    pub identity_pk: Vec<u8>,
+    pub device_id: String,
+    pub prekey_sig: Vec<u8>,
+    pub list: DeviceList,
+}
+
+/*
+ * The secretbox key sealing the grant:
+ * `SHA256(context || "session" || link_key || DH || request_ephemeral || response_ephemeral)`.
+ */
+pub fn session_key(link_key: &[u8; 32], shared: &[u8], request_ephemeral: &[u8], response_ephemeral: &[u8]) -> [u8; 32] {
+    let mut data = LINK_CONTEXT.to_vec();
+    data.extend_from_slice(b"session");
+    data.extend_from_slice(link_key);
+    data.extend_from_slice(shared);
+    data.extend_from_slice(request_ephemeral);
+    data.extend_from_slice(response_ephemeral);
+    sha256::hash(&data).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(secret: [u8; CODE_LEN]) -> LinkCode {
+        LinkCode {
+            username: "alice".to_string(),
+            secret,
+        }
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        assert_eq!(code([0; CODE_LEN]).code(), "AAAA-AAAA-AAAA-AAAA");
+        assert_eq!(code([0xFF; CODE_LEN]).code(), "7777-7777-7777-7777");
+        /* RFC 4648 test vector: BASE32("foobar") = "MZXW6YTBOI======" */
+        let foobar = code(*b"foobar\0\0\0\0");
+        assert!(foobar.code().replace('-', "").starts_with("MZXW6YTBOI"));
+
+        for _ in 0..100 {
+            let generated = LinkCode::generate("alice");
+            assert_eq!(LinkCode::parse(&generated.payload()), Some(generated.clone()));
+            let typed = format!("{LINK_PREFIX}alice: {} ", generated.code().to_lowercase().replace('-', " "));
+            assert_eq!(LinkCode::parse(&typed), Some(generated));
+        }
+    }
+
+    #[test]
+    fn bad_codes_are_refused() {
+        let valid = "AAAA-AAAA-AAAA-AAAA";
+        assert!(LinkCode::parse(&format!("{LINK_PREFIX}alice:{valid}")).is_some());
+        for payload in [
+            format!("{LINK_PREFIX}alice:AAAA-AAAA-AAAA-AAA"),
+            format!("{LINK_PREFIX}alice:AAAA-AAAA-AAAA-AAAAB"),
+            format!("{LINK_PREFIX}alice:AAAA-AAAA-AAAA-AAAAAA"),
+            format!("{LINK_PREFIX}alice:AAAA-AAAA-AAAA-AAA1"),
+            format!("{LINK_PREFIX}alice:AAAA-AAAA-AAAA-AAA="),
+            format!("{LINK_PREFIX}alice:"),
+            format!("{LINK_PREFIX}alice"),
+            format!("{LINK_PREFIX}Not A User:{valid}"),
+            format!("{LINK_PREFIX}:{valid}"),
+            format!("other-link:alice:{valid}"),
+            valid.to_string(),
+        ] {
+            assert_eq!(LinkCode::parse(&payload), None, "{payload:?}");
+        }
+    }
+
+    #[test]
+    fn derived_values_depend_on_everything() {
+        let first = LinkCode::generate("alice");
+        let other = LinkCode::generate("alice");
+        let bob = LinkCode {
+            username: "bob".to_string(),
+            ..first.clone()
+        };
+        assert_ne!(first.channel(REQUESTS), first.channel(RESPONSES));
+        assert_ne!(first.channel(REQUESTS), other.channel(REQUESTS));
+        assert_eq!(first.channel(REQUESTS), bob.channel(REQUESTS));
+        assert!(valid_channel(&first.channel(REQUESTS)));
+        assert!(!valid_channel("not a channel"));
+        assert_ne!(first.key(), other.key());
+        assert_ne!(first.key(), bob.key());
+    }
+}
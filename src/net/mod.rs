@@ -0,0 +1,9 @@
+pub mod auth;
+pub mod blobs;
+pub mod devices;
+pub mod directory;
+pub mod link;
+pub mod noise;
+pub mod p2p;
+pub mod transparency;
+pub mod transport;
@@ -0,0 +1,501 @@
+/*
+ * This module implements the Noise XX handshake used to protect the
+ * link between a client and a relay:
+ *
+ *     Noise_XX_25519_ChaChaPoly_SHA256
+ *
+ *  -> e
+ *  <- e, ee, s, es
+ *  -> s, se
+ *
+ * Every primitive comes from libsodium (through sodiumoxide):
+ *  - DH     : X25519 (`scalarmult::curve25519`)
+ *  - Cipher : ChaCha20-Poly1305 IETF (`aead::chacha20poly1305_ietf`)
+ *  - Hash   : SHA-256 (`hash::sha256`), HKDF built on `auth::hmacsha256`
+ *
+ * The end-to-end envelope already hides message bodies; this channel also
+ * hides the metadata exchanged with the relay (who logs in, which mailbox
+ * is fetched, envelope ids, timing of individual frames).
+ *
+ * The relay has a long-term static key that clients pin. The initiator
+ * learns the relay's static key in the second handshake message and aborts
+ * if it differs from the pinned one, before sending anything else.
+ *
+ * Note: transport messages are carried by our own 4-byte length framing,
+ * so they are not limited to the 65535 bytes of the Noise specification.
+ */
+
+use crate::net::transport::{read_frame, write_frame};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/* Full protocol name, hashed into the handshake transcript */
+pub const PROTOCOL_NAME: &[u8] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+
+/* Prologue binding the handshake to this application */
+const PROLOGUE: &[u8] = b"blackipher-relay-v1";
+
+const HASHLEN: usize = 32;
+const DHLEN: usize = 32;
+const TAGLEN: usize = 16;
+
+/*
+ * A static X25519 key pair (same key type as `box_`).
+ */
+#[derive(Clone)]
+pub struct Keypair {
+    pub public: box_::PublicKey,
+    pub secret: box_::SecretKey,
+}
+
+/* On-disk form of a `Keypair` (hex strings) */
+#[derive(Serialize, Deserialize)]
+struct StoredKeypair {
+    public: String,
+    secret: String,
+}
+
+impl Keypair {
+    /* Generates a fresh key pair */
+    pub fn generate() -> Self {
+        let (public, secret) = box_::gen_keypair();
+        Self { public, secret }
+    }
+
+    /*
+     * Loads a key pair from a JSON file at `path`.
+     *
+     * If the file does not exist, or cannot be parsed, a new key pair
+     * is generated and written to `path`.
+     */
+    pub fn load_or_generate(path: &str) -> Self {
+        if Path::new(path).exists() {
+            let data = fs::read_to_string(path).unwrap_or_default();
+            if let Ok(stored) = serde_json::from_str::<StoredKeypair>(&data) {
+                let public = hex::decode(&stored.public).ok().and_then(|b| box_::PublicKey::from_slice(&b));
+                let secret = hex::decode(&stored.secret).ok().and_then(|b| box_::SecretKey::from_slice(&b));
+                if let (Some(public), Some(secret)) = (public, secret) {
+                    return Self { public, secret };
+                }
+            }
+        }
+        let keypair = Self::generate();
+        let stored = StoredKeypair {
+            public: hex::encode(keypair.public.as_ref()),
+            secret: hex::encode(&keypair.secret.0),
+        };
+        if let Ok(json) = serde_json::to_string_pretty(&stored) {
+            let _ = fs::write(path, json);
+        }
+        keypair
+    }
+}
+
+/* Parses a hex-encoded X25519 public key (e.g. a pinned relay key) */
+pub fn parse_public_key(hex_key: &str) -> Option<box_::PublicKey> {
+    box_::PublicKey::from_slice(&hex::decode(hex_key.trim()).ok()?)
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("noise: {reason}"))
+}
+
+/* X25519 between a local secret key and a remote public key */
+fn dh(secret: &box_::SecretKey, public: &box_::PublicKey) -> io::Result<[u8; DHLEN]> {
+    let scalar = curve25519::Scalar(secret.0);
+    let point = curve25519::GroupElement(public.0);
+    curve25519::scalarmult(&scalar, &point)
+        .map(|shared| shared.0)
+        .map_err(|_| protocol_error("low-order public key"))
+}
+
+/* HMAC-SHA256 over the concatenation of `parts` */
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; HASHLEN] {
+    let mut state = hmacsha256::State::init(key);
+    for part in parts {
+        state.update(part);
+    }
+    state.finalize().0
+}
+
+/* Noise HKDF with two outputs */
+fn hkdf(chaining_key: &[u8; HASHLEN], input_key_material: &[u8]) -> ([u8; HASHLEN], [u8; HASHLEN]) {
+    let temp_key = hmac(chaining_key, &[input_key_material]);
+    let output1 = hmac(&temp_key, &[&[0x01]]);
+    let output2 = hmac(&temp_key, &[&output1, &[0x02]]);
+    (output1, output2)
+}
+
+/*
+ * A ChaCha20-Poly1305 key with its message counter.
+ *
+ * The 96-bit nonce is 32 zero bits followed by the little-endian counter,
+ * as required by the Noise specification.
+ */
+pub struct CipherState {
+    key: aead::Key,
+    counter: u64,
+}
+
+/* Never print the key, only how far the counter went */
+impl std::fmt::Debug for CipherState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CipherState").field("counter", &self.counter).finish()
+    }
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key: aead::Key(key),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> io::Result<aead::Nonce> {
+        if self.counter == u64::MAX {
+            return Err(protocol_error("nonce exhausted"));
+        }
+        let mut nonce = [0u8; aead::NONCEBYTES];
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        Ok(aead::Nonce(nonce))
+    }
+
+    fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        Ok(aead::seal(plaintext, Some(ad), &nonce, &self.key))
+    }
+
+    fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        aead::open(ciphertext, Some(ad), &nonce, &self.key).map_err(|_| protocol_error("decryption failed"))
+    }
+
+    /* Encrypts one transport message */
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        self.encrypt_with_ad(&[], plaintext)
+    }
+
+    /* Decrypts one transport message (messages must arrive in order) */
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        self.decrypt_with_ad(&[], ciphertext)
+    }
+}
+
+/*
+ * The running transcript hash `h`, chaining key `ck` and,
+ * once a DH has been mixed in, the handshake cipher.
+ */
+struct SymmetricState {
+    ck: [u8; HASHLEN],
+    h: [u8; HASHLEN],
+    cipher: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        /* The protocol name is exactly HASHLEN bytes, so it is used as-is */
+        let mut h = [0u8; HASHLEN];
+        h.copy_from_slice(PROTOCOL_NAME);
+        let mut state = Self {
+            ck: h,
+            h,
+            cipher: None,
+        };
+        state.mix_hash(PROLOGUE);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = sha256::State::new();
+        hasher.update(&self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().0;
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (ck, temp_k) = hkdf(&self.ck, input_key_material);
+        self.ck = ck;
+        self.cipher = Some(CipherState::new(temp_k));
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let ciphertext = match &mut self.cipher {
+            Some(cipher) => cipher.encrypt_with_ad(&self.h, plaintext)?,
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let plaintext = match &mut self.cipher {
+            Some(cipher) => cipher.decrypt_with_ad(&self.h, ciphertext)?,
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /* Derives the two transport ciphers (initiator->responder, responder->initiator) */
+    fn split(&self) -> (CipherState, CipherState) {
+        let (k1, k2) = hkdf(&self.ck, &[]);
+        (CipherState::new(k1), CipherState::new(k2))
+    }
+}
+
+/*
+ * Result of a completed handshake.
+ *
+ * Fields:
+ *  - `send`          : cipher for messages we send
+ *  - `recv`          : cipher for messages we receive
+ *  - `remote_static` : the peer's authenticated static key
+ *  - `handshake_hash`: transcript hash, identical on both sides
+ */
+pub struct Channel {
+    pub send: CipherState,
+    pub recv: CipherState,
+    pub remote_static: box_::PublicKey,
+    pub handshake_hash: [u8; HASHLEN],
+}
+
+fn read_public_key(bytes: &[u8]) -> io::Result<box_::PublicKey> {
+    box_::PublicKey::from_slice(bytes).ok_or_else(|| protocol_error("bad public key"))
+}
+
+/*
+ * Runs the initiator (client) side of the handshake.
+ *
+ * Fails with `PermissionDenied` if the responder's static key
+ * is not `pinned_remote`.
+ */
+pub async fn initiate<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    local_static: &Keypair,
+    pinned_remote: &box_::PublicKey,
+) -> io::Result<Channel>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new();
+    let ephemeral = Keypair::generate();
+
+    /* -> e */
+    let mut message = ephemeral.public.as_ref().to_vec();
+    state.mix_hash(ephemeral.public.as_ref());
+    message.extend(state.encrypt_and_hash(&[])?);
+    write_frame(writer, &message).await?;
+
+    /* <- e, ee, s, es */
+    let message = read_frame(reader).await?;
+    if message.len() < DHLEN + DHLEN + TAGLEN {
+        return Err(protocol_error("message 2 too short"));
+    }
+    let remote_ephemeral = read_public_key(&message[..DHLEN])?;
+    state.mix_hash(remote_ephemeral.as_ref());
+    state.mix_key(&dh(&ephemeral.secret, &remote_ephemeral)?);
+    let remote_static = read_public_key(&state.decrypt_and_hash(&message[DHLEN..DHLEN + DHLEN + TAGLEN])?)?;
+    if remote_static != *pinned_remote {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("noise: relay key mismatch (got {})", hex::encode(remote_static.as_ref())),
+        ));
+    }
+    state.mix_key(&dh(&ephemeral.secret, &remote_static)?);
+    state.decrypt_and_hash(&message[DHLEN + DHLEN + TAGLEN..])?;
+
+    /* -> s, se */
+    let mut message = state.encrypt_and_hash(local_static.public.as_ref())?;
+    state.mix_key(&dh(&local_static.secret, &remote_ephemeral)?);
+    message.extend(state.encrypt_and_hash(&[])?);
+    write_frame(writer, &message).await?;
+
+    let (send, recv) = state.split();
+    Ok(Channel {
+        send,
+        recv,
+        remote_static,
+        handshake_hash: state.h,
+    })
+}
+
+/*
+ * Runs the responder (relay) side of the handshake.
+ */
+pub async fn respond<R, W>(reader: &mut R, writer: &mut W, local_static: &Keypair) -> io::Result<Channel>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut state = SymmetricState::new();
+    let ephemeral = Keypair::generate();
+
+    /* -> e */
+    let message = read_frame(reader).await?;
+    if message.len() < DHLEN {
+        return Err(protocol_error("message 1 too short"));
+    }
+    let remote_ephemeral = read_public_key(&message[..DHLEN])?;
+    state.mix_hash(remote_ephemeral.as_ref());
+    state.decrypt_and_hash(&message[DHLEN..])?;
+
+    /* <- e, ee, s, es */
+    let mut message = ephemeral.public.as_ref().to_vec();
+    state.mix_hash(ephemeral.public.as_ref());
+    state.mix_key(&dh(&ephemeral.secret, &remote_ephemeral)?);
+    message.extend(state.encrypt_and_hash(local_static.public.as_ref())?);
+    state.mix_key(&dh(&local_static.secret, &remote_ephemeral)?);
+    message.extend(state.encrypt_and_hash(&[])?);
+    write_frame(writer, &message).await?;
+
+    /* -> s, se */
+    let message = read_frame(reader).await?;
+    if message.len() < DHLEN + TAGLEN {
+        return Err(protocol_error("message 3 too short"));
+    }
+    let remote_static = read_public_key(&state.decrypt_and_hash(&message[..DHLEN + TAGLEN])?)?;
+    state.mix_key(&dh(&ephemeral.secret, &remote_static)?);
+    state.decrypt_and_hash(&message[DHLEN + TAGLEN..])?;
+
+    let (recv, send) = state.split();
+    Ok(Channel {
+        send,
+        recv,
+        remote_static,
+        handshake_hash: state.h,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    type Half = (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>);
+
+    /* Runs `test` on a fresh runtime */
+    fn run<F: std::future::Future>(test: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(test)
+    }
+
+    /* The two ends of an in-memory pipe */
+    fn pipe() -> (Half, Half) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        (tokio::io::split(client), tokio::io::split(server))
+    }
+
+    /* Runs a full handshake and returns the channels of the client and of the relay */
+    async fn handshake(client: &Keypair, relay: &Keypair, pinned: &box_::PublicKey) -> (io::Result<Channel>, io::Result<Channel>) {
+        let ((mut client_reader, mut client_writer), (mut relay_reader, mut relay_writer)) = pipe();
+        let relay = relay.clone();
+        let responder = tokio::spawn(async move { respond(&mut relay_reader, &mut relay_writer, &relay).await });
+        let initiated = initiate(&mut client_reader, &mut client_writer, client, pinned).await;
+        drop((client_reader, client_writer));
+        (initiated, responder.await.unwrap())
+    }
+
+    #[test]
+    fn handshake_authenticates_both_sides() {
+        run(async {
+            let (client, relay) = (Keypair::generate(), Keypair::generate());
+            let (initiated, responded) = handshake(&client, &relay, &relay.public).await;
+            let (mut client_channel, mut relay_channel) = (initiated.unwrap(), responded.unwrap());
+            assert_eq!(client_channel.remote_static, relay.public);
+            assert_eq!(relay_channel.remote_static, client.public);
+            assert_eq!(client_channel.handshake_hash, relay_channel.handshake_hash);
+
+            let message = client_channel.send.encrypt(b"one").unwrap();
+            assert_eq!(relay_channel.recv.decrypt(&message).unwrap(), b"one");
+            let reply = relay_channel.send.encrypt(b"back").unwrap();
+            assert_eq!(client_channel.recv.decrypt(&reply).unwrap(), b"back");
+        });
+    }
+
+    #[test]
+    fn transport_messages_are_authenticated() {
+        let mut sender = CipherState::new([9; 32]);
+        let receiver = || CipherState::new([9; 32]);
+        let first = sender.encrypt(b"one").unwrap();
+        let second = sender.encrypt(b"two").unwrap();
+
+        let mut tampered = first.clone();
+        tampered[0] ^= 1;
+        assert!(receiver().decrypt(&tampered).is_err(), "tampered message");
+        assert!(receiver().decrypt(&first[..first.len() - 1]).is_err(), "truncated message");
+        assert!(receiver().decrypt(&second).is_err(), "message out of order");
+
+        let mut in_order = receiver();
+        assert_eq!(in_order.decrypt(&first).unwrap(), b"one");
+        assert!(in_order.decrypt(&first).is_err(), "replayed message");
+    }
+
+    #[test]
+    fn wrong_relay_key_is_refused() {
+        run(async {
+            let (client, relay) = (Keypair::generate(), Keypair::generate());
+            let (initiated, responded) = handshake(&client, &relay, &Keypair::generate().public).await;
+            assert_eq!(initiated.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+            assert!(responded.is_err(), "the relay never gets the client's static key");
+        });
+    }
+
+    #[test]
+    fn truncated_handshake_messages_are_refused() {
+        run(async {
+            let relay = Keypair::generate();
+
+            /* Message 2 cut short */
+            let ((mut client_reader, mut client_writer), (mut relay_reader, mut relay_writer)) = pipe();
+            let fake = tokio::spawn(async move {
+                let _ = read_frame(&mut relay_reader).await;
+                write_frame(&mut relay_writer, &[0u8; DHLEN + DHLEN]).await.unwrap();
+            });
+            let initiated = initiate(&mut client_reader, &mut client_writer, &Keypair::generate(), &relay.public).await;
+            assert_eq!(initiated.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+            fake.await.unwrap();
+
+            /* Message 3 cut short */
+            let ((mut client_reader, mut client_writer), (mut relay_reader, mut relay_writer)) = pipe();
+            let responder = tokio::spawn(async move { respond(&mut relay_reader, &mut relay_writer, &relay).await });
+            let ephemeral = Keypair::generate();
+            write_frame(&mut client_writer, ephemeral.public.as_ref()).await.unwrap();
+            let _ = read_frame(&mut client_reader).await.unwrap();
+            write_frame(&mut client_writer, &[0u8; DHLEN]).await.unwrap();
+            assert_eq!(responder.await.unwrap().err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        });
+    }
+
+    #[test]
+    fn forged_handshake_messages_are_refused() {
+        run(async {
+            let relay = Keypair::generate();
+
+            /* A message 3 that is not encrypted under the handshake keys */
+            let ((mut client_reader, mut client_writer), (mut relay_reader, mut relay_writer)) = pipe();
+            let responder = tokio::spawn(async move { respond(&mut relay_reader, &mut relay_writer, &relay).await });
+            let ephemeral = Keypair::generate();
+            write_frame(&mut client_writer, ephemeral.public.as_ref()).await.unwrap();
+            let _ = read_frame(&mut client_reader).await.unwrap();
+            write_frame(&mut client_writer, &[7u8; DHLEN + TAGLEN + TAGLEN]).await.unwrap();
+            let refused = responder.await.unwrap();
+            assert!(refused.err().is_some_and(|e| e.to_string().contains("decryption failed")));
+
+            /* A connection closed in the middle of the handshake */
+            let ((mut client_reader, mut client_writer), (relay_reader, relay_writer)) = pipe();
+            drop((relay_reader, relay_writer));
+            assert!(initiate(&mut client_reader, &mut client_writer, &Keypair::generate(), &Keypair::generate().public).await.is_err());
+        });
+    }
+}
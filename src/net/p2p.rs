@@ -0,0 +1,67 @@
+/*
+ * This module implements direct peer-to-peer mode: two clients talk over
+ * a single TCP connection, with no relay server in between.
+ *
+ * One peer listens, the other connects. To keep the protocol identical to
+ * the relay path, the listening peer hosts a private in-process `Relay`
+ * on its port:
+ *  - the connecting peer uses a normal `TcpTransport` to it
+ *    (Noise XX handshake, device-key login, `Deliver`/`Ack` frames)
+ *  - the listening peer uses a `MemoryTransport` on the same relay
+ *    (same login, same envelopes)
+ *
+ * The Noise static key of the listener is a key of its own, derived from
+ * its device key (`User::noise_keypair`) and listed in its signed device
+ * list, which the connecting peer already holds (and has verified) from
+ * the contact list: it pins that key without any extra key distribution.
+ *
+ * The private relay only lets socket connections act as the contact: the
+ * contact's identity key is registered up front, and claims, device lists
+ * and logins for any other username are refused (see `Relay::private`).
+ * Without that, anyone reaching the port could log in under a free name
+ * and deposit envelopes, which the listener would take for the contact's.
+ */
+
+use crate::client::user::User;
+use crate::net::transport::{self, MemoryTransport, TcpTransport};
+use crate::server::relay::Relay;
+use std::io;
+
+/*
+ * Starts listening on `addr` (e.g. "0.0.0.0:9000") for `peer`.
+ *
+ * Returns the transport the local user should use to talk to `peer`.
+ * The listener keeps running in the background for the rest of the
+ * process lifetime.
+ */
+pub async fn listen(addr: &str, me: &User, peer: &User) -> io::Result<MemoryTransport> {
+    if !User::verify_peer_spk(peer) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer signed pre-key is invalid"));
+    }
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let relay = Relay::private(peer.username(), peer.identity_pk).map_err(|e| io::Error::other(e.to_string()))?;
+    /* So we can write to the contact before they connect (they publish it again then) */
+    let _ = relay.publish_devices(peer.devices.clone());
+
+    let serving = relay.clone();
+    let keypair = me.noise_keypair();
+    tokio::spawn(async move {
+        let _ = serving.serve_tcp(listener, keypair).await;
+    });
+
+    Ok(MemoryTransport::new(relay))
+}
+
+/*
+ * Connects directly to `peer`, listening on `addr`.
+ *
+ * The connection fails if the listener does not own the Noise key
+ * listed for `peer`'s device in their device list.
+ */
+pub async fn connect(addr: &str, peer: &User) -> io::Result<TcpTransport> {
+    let Some(noise_pk) = peer.noise_key() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer lists no direct link key"));
+    };
+    transport::connect_tcp(addr, &noise_pk).await
+}
@@ -0,0 +1,269 @@
+/*
+ * This module defines the key transparency log format: an append-only
+ * Merkle tree (RFC 6962 / RFC 9162 style) whose leaves are the username
+ * claims registered in the directory.
+ *
+ * Hashes:
+ *  - leaf : SHA-256(0x00 || claim_message(username, identity_pk) || signature)
+ *  - node : SHA-256(0x01 || left || right)
+ *  - empty tree : SHA-256("")
+ *
+ * The relay signs each tree head (size + root) with its log key, and
+ * answers every lookup with:
+ *  - an inclusion proof: the claim is leaf `index` of the signed tree
+ *  - a consistency proof: the signed tree extends the one the client
+ *    saw last (nothing was removed or rewritten in between)
+ *
+ * A relay handing different keys to different people must then either
+ * fork its log (caught as soon as the forks are compared) or put both
+ * bindings in it (visible to anyone monitoring the username).
+ *
+ * This module only holds the formats and the proof verification;
+ * the log itself lives in `server::transparency`.
+ */
+
+use crate::net::directory::{claim_message, UsernameClaim};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::sign;
+
+/* A SHA-256 tree hash */
+pub type Hash = [u8; 32];
+
+/* Domain separation tag for tree head signatures */
+const TREE_HEAD_CONTEXT: &[u8] = b"blackipher-log-v1";
+
+/*
+ * A tree head signed by the relay's log key.
+ *
+ * Fields:
+ *  - `size`      : Number of leaves in the tree
+ *  - `root`      : Merkle root of those leaves
+ *  - `signature` : Signature of `tree_head_message(size, root)`
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedTreeHead {
+    pub size: u64,
+    pub root: Hash,
+    pub signature: Vec<u8>,
+}
+
+/*
+ * The relay's answer to a directory lookup.
+ *
+ * Fields:
+ *  - `claim`       : The claim for the username, if registered
+ *  - `index`       : Leaf index of `claim` in the log
+ *  - `inclusion`   : Inclusion proof of `claim` in `head`
+ *  - `head`        : The current signed tree head
+ *  - `consistency` : Consistency proof from the size the client last saw to `head`
+ *  - `log_key`     : The relay's log public key (raw bytes)
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirectoryLookup {
+    pub claim: Option<UsernameClaim>,
+    pub index: u64,
+    pub inclusion: Vec<Hash>,
+    pub head: SignedTreeHead,
+    pub consistency: Vec<Hash>,
+    pub log_key: Vec<u8>,
+}
+
+/* Builds the byte string signed in a tree head: `context || size || root` */
+pub fn tree_head_message(size: u64, root: &Hash) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(TREE_HEAD_CONTEXT.len() + 8 + root.len());
+    msg.extend_from_slice(TREE_HEAD_CONTEXT);
+    msg.extend_from_slice(&size.to_be_bytes());
+    msg.extend_from_slice(root);
+    msg
+}
+
+/* Root of the empty tree */
+pub fn empty_root() -> Hash {
+    sha256::hash(&[]).0
+}
+
+/* Hash of the leaf recording `claim` */
+pub fn leaf_hash(claim: &UsernameClaim) -> Hash {
+    let mut data = vec![0x00];
+    data.extend_from_slice(&claim_message(&claim.username, &claim.identity_pk));
+    data.extend_from_slice(&claim.signature);
+    sha256::hash(&data).0
+}
+
+/* Hash of an inner node */
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut data = Vec::with_capacity(1 + 64);
+    data.push(0x01);
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    sha256::hash(&data).0
+}
+
+impl SignedTreeHead {
+    /* Checks the tree head signature against the log key */
+    pub fn verify(&self, log_key: &sign::PublicKey) -> bool {
+        let Ok(signature) = sign::Signature::try_from(self.signature.as_slice()) else {
+            return false;
+        };
+        sign::verify_detached(&signature, &tree_head_message(self.size, &self.root), log_key)
+    }
+}
+
+/* Right-shifts `fn_` and `sn` until the low bit of `fn_` is set (or `fn_` is 0) */
+fn shift_until_odd(fn_: &mut u64, sn: &mut u64) {
+    while *fn_ & 1 == 0 && *fn_ != 0 {
+        *fn_ >>= 1;
+        *sn >>= 1;
+    }
+}
+
+/*
+ * Verifies that `leaf` is leaf number `index` of the tree of `size`
+ * leaves whose root is `root` (RFC 9162, section 2.1.3.2).
+ */
+pub fn verify_inclusion(leaf: &Hash, index: u64, size: u64, proof: &[Hash], root: &Hash) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut fn_, mut sn) = (index, size - 1);
+    let mut r = *leaf;
+    for p in proof {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            r = node_hash(p, &r);
+            shift_until_odd(&mut fn_, &mut sn);
+        } else {
+            r = node_hash(&r, p);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && r == *root
+}
+
+/*
+ * Verifies that the tree of `new_size` leaves with root `new_root`
+ * extends the tree of `old_size` leaves with root `old_root`
+ * (RFC 9162, section 2.1.4.2).
+ */
+pub fn verify_consistency(old_size: u64, new_size: u64, old_root: &Hash, new_root: &Hash, proof: &[Hash]) -> bool {
+    if old_size > new_size {
+        return false;
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_root == new_root;
+    }
+    if old_size == 0 {
+        /* Every tree extends the empty tree */
+        return proof.is_empty();
+    }
+    if proof.is_empty() {
+        return false;
+    }
+
+    let mut path = proof.to_vec();
+    if old_size.is_power_of_two() {
+        path.insert(0, *old_root);
+    }
+    let (mut fn_, mut sn) = (old_size - 1, new_size - 1);
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    let (mut fr, mut sr) = (path[0], path[0]);
+    for c in &path[1..] {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            shift_until_odd(&mut fn_, &mut sn);
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    fr == *old_root && sr == *new_root && sn == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::transparency::TransparencyLog;
+
+    fn log(size: u8) -> TransparencyLog {
+        let mut log = TransparencyLog::new();
+        for i in 0..size {
+            log.append([i; 32]);
+        }
+        log
+    }
+
+    #[test]
+    fn inclusion_proofs() {
+        for size in 1..=9 {
+            let log = log(size);
+            let head = log.signed_head();
+            for index in 0..size {
+                let proof = log.inclusion_proof(index as u64);
+                assert!(verify_inclusion(&[index; 32], index as u64, head.size, &proof, &head.root));
+            }
+        }
+
+        let log = log(7);
+        let head = log.signed_head();
+        let proof = log.inclusion_proof(2);
+        assert!(!verify_inclusion(&[3; 32], 2, head.size, &proof, &head.root), "another leaf");
+        assert!(!verify_inclusion(&[2; 32], 3, head.size, &proof, &head.root), "another index");
+        let last = log.inclusion_proof(6);
+        assert!(verify_inclusion(&[6; 32], 6, head.size, &last, &head.root));
+        assert!(!verify_inclusion(&[6; 32], 6, head.size + 1, &last, &head.root), "another size");
+        assert!(!verify_inclusion(&[2; 32], 7, head.size, &proof, &head.root), "index out of the tree");
+        assert!(!verify_inclusion(&[2; 32], 2, head.size, &proof[1..], &head.root), "truncated proof");
+        let mut tampered = proof.clone();
+        tampered[0][0] ^= 1;
+        assert!(!verify_inclusion(&[2; 32], 2, head.size, &tampered, &head.root), "tampered proof");
+    }
+
+    #[test]
+    fn consistency_proofs() {
+        for old in 1..=9u8 {
+            let old_root = log(old).signed_head().root;
+            for new in old..=9 {
+                let log = log(new);
+                let proof = log.consistency_proof(old as u64);
+                assert!(verify_consistency(old as u64, new as u64, &old_root, &log.signed_head().root, &proof));
+            }
+        }
+
+        let (old, new) = (log(3).signed_head(), log(6));
+        let proof = new.consistency_proof(3);
+        let root = new.signed_head().root;
+        assert!(verify_consistency(3, 6, &old.root, &root, &proof));
+        assert!(!verify_consistency(4, 6, &old.root, &root, &proof), "another old size");
+        assert!(!verify_consistency(3, 9, &old.root, &root, &proof), "another new size");
+        assert!(!verify_consistency(6, 3, &root, &old.root, &proof), "shrinking tree");
+        assert!(!verify_consistency(3, 6, &log(2).signed_head().root, &root, &proof), "another old tree");
+        assert!(!verify_consistency(3, 6, &old.root, &root, &proof[1..]), "truncated proof");
+
+        /* A rewritten leaf is caught */
+        let mut forked = log(2);
+        forked.append([9; 32]);
+        assert!(!verify_consistency(3, 6, &forked.signed_head().root, &root, &proof));
+    }
+
+    #[test]
+    fn tree_head_signature() {
+        let log = log(4);
+        let mut head = log.signed_head();
+        assert!(head.verify(log.public_key()));
+        assert!(!head.verify(TransparencyLog::new().public_key()), "another log key");
+        head.size += 1;
+        assert!(!head.verify(log.public_key()), "another size");
+    }
+}
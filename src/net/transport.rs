@@ -0,0 +1,798 @@
+/*
+ * This module defines the network layer used by the client to exchange
+ * encrypted envelopes with a relay (or, later, directly with a peer).
+ *
+ * It provides:
+ *  - `Envelope`        : the unit of transfer (an encrypted message + routing data)
+ *  - `Frame`           : the wire protocol spoken between a client and a relay
+ *  - `Transport`       : the async trait every transport implements
+ *  - `MemoryTransport` : an in-process transport backed by a `Relay` (tests, demo)
+ *  - `TcpTransport`    : a transport over a TCP connection to a relay
+ *  - `UnixTransport`   : a transport over a Unix domain socket to a relay
+ *
+ * Frames are serialized as JSON and written with a 4-byte big-endian
+ * length prefix, so the same framing works over any byte stream.
+ * Socket transports first run a Noise XX handshake (see `net::noise`)
+ * against the relay's pinned static key; every frame after that is
+ * encrypted with the resulting channel keys.
+ *
+ * Directory requests (`Claim`, `Lookup`, `FetchDevices`) and link channel
+ * requests (`LinkFetch`) and blob requests (`BlobPut`, `BlobGet`) can be made at any time, even while another task waits in `receive`: replies are
+ * routed to the waiting request, envelopes to `receive`.
+ *
+ * Each device of an account has its own mailbox on the relay
+ * (see `net::devices`): an envelope names the recipient's device,
+ * and the device it was sent from.
+ *
+ * Note: the envelope only carries ciphertext; the relay never sees
+ * plaintext. Routing metadata (sender, recipient, devices) is still visible.
+ */
+
+use crate::client::user::User;
+use crate::net::devices::{self, DeviceList};
+use crate::net::directory::UsernameClaim;
+use crate::net::noise::{self, CipherState, Keypair};
+use crate::net::transparency::DirectoryLookup;
+use crate::server::auth::SessionToken;
+use crate::server::relay::Relay;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::box_;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Poll;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{oneshot, Mutex};
+
+/* Upper bound for a single frame, protects against absurd length prefixes */
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/*
+ * What an envelope carries, so the receiver knows how to handle it
+ * before decrypting. Missing in older envelopes, which are messages.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvelopeKind {
+    #[default]
+    Message,
+    Receipt,
+    Typing,
+    SenderKey,
+    Group,
+    GroupUpdate,
+    TreeKem,
+    Sync,
+    History,
+}
+
+/*
+ * Represents an encrypted message in transit.
+ *
+ * Fields:
+ *  - `id`           : Unique message identifier (hex), shared with `StoredMessage::id`
+ *  - `kind`         : Chat message or control message (e.g. receipt)
+ *  - `sender`       : Username of the sender
+ *  - `sender_device`: Device of the sender (empty: primary device)
+ *  - `recipient`    : Username of the recipient (mailbox owner)
+ *  - `device`       : Device of the recipient (empty: primary device)
+ *  - `ephemeral_pk` : The sender's ephemeral public key (raw bytes)
+ *  - `nonce`        : The nonce used during encryption
+ *  - `ciphertext`   : The encrypted payload
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: String,
+    #[serde(default)]
+    pub kind: EnvelopeKind,
+    pub sender: String,
+    #[serde(default)]
+    pub sender_device: String,
+    pub recipient: String,
+    #[serde(default)]
+    pub device: String,
+    pub ephemeral_pk: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/*
+ * Messages exchanged between a client and a relay.
+ *
+ * - `PublishDevices`: client -> relay, the signed device list of an account
+ *                (see `net::devices`; an older list is ignored)
+ * - `Hello`    : client -> relay, starts a login as device `device` of `username`
+ * - `Challenge`: relay -> client, random nonce to sign (see `net::auth`)
+ * - `Login`    : client -> relay, the challenge signed with the device key
+ * - `Session`  : relay -> client, short-lived session token
+ * - `Subscribe`: client -> relay, "deliver my mailbox to me" (requires a token)
+ * - `Deliver`  : both directions, carries an envelope
+ * - `Delivered`: relay -> client, the envelope `id` was queued for its recipient
+ * - `Ack`      : client -> relay, "envelope `id` has been stored, drop it"
+ * - `Claim`    : client -> relay, registers a signed username claim
+ * - `Claimed`  : relay -> client, outcome of a `Claim` (`error` if refused)
+ * - `Lookup`   : client -> relay, asks for the claim of `username`
+ *                (`known_size`: size of the last tree head the client saw)
+ * - `Found`    : relay -> client, the claim of `username`, if registered,
+ *                with key transparency proofs (see `net::transparency`)
+ * - `FetchDevices`: client -> relay, asks for the device list of `username`
+ * - `Devices`  : relay -> client, the latest device list of `username`, if any
+ *                (unverified: check it against their identity key)
+ * - `LinkPost` : client -> relay, posts a message on a link channel (see `net::link`)
+ * - `LinkFetch`: client -> relay, drains the messages waiting on a link channel
+ * - `LinkMessages`: relay -> client, the messages drained by a `LinkFetch`
+ * - `BlobPut`  : client -> relay, the piece at `offset` of a blob being uploaded
+ *                (see `net::blobs`; `last` completes the upload, login required)
+ * - `BlobStored`: relay -> client, the bytes of the blob the relay holds
+ *                after a `BlobPut` (`error` if refused)
+ * - `BlobGet`  : client -> relay, asks for the piece of a blob at `offset`
+ * - `BlobData` : relay -> client, that piece and the size of the blob
+ *                (`total` is `None` if the relay does not have it)
+ * - `Accepted` : relay -> client, a `PublishDevices`, `Subscribe` or `LinkPost`
+ *                was accepted
+ * - `Expired`  : relay -> client, the session is over and the mailbox is no
+ *                longer pushed: log in and subscribe again
+ * - `Error`    : relay -> client, a request was refused
+ *
+ * The relay answers every request but `Ack` exactly once, in the
+ * order the requests were sent.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    PublishDevices { list: DeviceList },
+    Hello { username: String, device: String },
+    Challenge { nonce: Vec<u8> },
+    Login { username: String, device: String, nonce: Vec<u8>, signature: Vec<u8> },
+    Session { token: String, expires_in: u64 },
+    Subscribe { token: String },
+    Deliver { envelope: Envelope },
+    Delivered { id: String },
+    Ack { id: String },
+    Claim { claim: UsernameClaim },
+    Claimed { username: String, error: Option<String> },
+    Lookup { username: String, known_size: u64 },
+    Found { username: String, lookup: DirectoryLookup },
+    FetchDevices { username: String },
+    Devices { username: String, list: Option<DeviceList> },
+    LinkPost { channel: String, message: Vec<u8> },
+    LinkFetch { channel: String },
+    LinkMessages { channel: String, messages: Vec<Vec<u8>> },
+    BlobPut { blob_id: String, offset: u64, data: Vec<u8>, last: bool },
+    BlobStored { blob_id: String, received: u64, error: Option<String> },
+    BlobGet { blob_id: String, offset: u64 },
+    BlobData { blob_id: String, offset: u64, total: Option<u64>, data: Vec<u8> },
+    Accepted,
+    Expired,
+    Error { reason: String },
+}
+
+/*
+ * The async interface implemented by every transport.
+ *
+ * - `send`       : hands an envelope to the network for delivery to `envelope.recipient`
+ *                  (device `envelope.device`); succeeds once the relay has queued it
+ * - `receive`    : waits for the next envelope addressed to the subscribed device;
+ *                  fails with `TimedOut` once the session expires (`subscribe` again)
+ * - `subscribe`  : publishes `user`'s username claim (primary device only)
+ *                  and device list, logs in as their device
+ *                  (device-key challenge-response) and binds the transport to
+ *                  the device's mailbox; must be called before `receive`
+ * - `acknowledge`: confirms that an envelope was persisted, so it is not redelivered
+ * - `publish_claim`: registers a signed username claim in the directory
+ * - `lookup`     : fetches the directory claim of a username and its proofs
+ *                  (unverified: see `client::transparency::Monitor`)
+ * - `fetch_devices`: fetches the latest device list of a username (unverified)
+ * - `publish_devices`: publishes a new version of our device list, and
+ *                  checks that the relay kept it
+ * - `link_post`  : posts a message on a link channel (no login needed)
+ * - `link_fetch` : drains the messages waiting on a link channel
+ * - `blob_put`   : uploads a piece of a blob, returns the bytes the relay holds
+ * - `blob_get`   : downloads the piece of a blob at an offset, with the
+ *                  size of the blob (`None` if the relay does not have it)
+ *
+ * Envelopes that were received but never acknowledged are delivered
+ * again the next time the mailbox is subscribed.
+ */
+#[async_trait]
+pub trait Transport: Send + Sync + fmt::Debug {
+    async fn send(&self, envelope: Envelope) -> io::Result<()>;
+    async fn receive(&self) -> io::Result<Envelope>;
+    async fn subscribe(&self, user: &User) -> io::Result<()>;
+    async fn acknowledge(&self, id: &str) -> io::Result<()>;
+    async fn publish_claim(&self, claim: &UsernameClaim) -> io::Result<()>;
+    async fn lookup(&self, username: &str, known_size: u64) -> io::Result<DirectoryLookup>;
+    async fn fetch_devices(&self, username: &str) -> io::Result<Option<DeviceList>>;
+    async fn publish_devices(&self, list: &DeviceList) -> io::Result<()>;
+    async fn link_post(&self, channel: &str, message: Vec<u8>) -> io::Result<()>;
+    async fn link_fetch(&self, channel: &str) -> io::Result<Vec<Vec<u8>>>;
+    async fn blob_put(&self, blob_id: &str, offset: u64, data: Vec<u8>, last: bool) -> io::Result<u64>;
+    async fn blob_get(&self, blob_id: &str, offset: u64) -> io::Result<Option<(u64, Vec<u8>)>>;
+}
+
+/*
+ * Writes a single length-prefixed frame to `writer`.
+ */
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+    }
+    writer.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+/*
+ * Reads a single length-prefixed frame from `reader`.
+ *
+ * Returns `UnexpectedEof` if the peer closed the connection.
+ */
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/*
+ * The read half of a connection.
+ *
+ * Once a Noise channel is established, every frame is decrypted
+ * with its receiving cipher before being parsed.
+ */
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    cipher: Option<CipherState>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /* Wraps a reader; `cipher` is `None` for a plaintext link */
+    pub fn new(inner: R, cipher: Option<CipherState>) -> Self {
+        Self { inner, cipher }
+    }
+
+    /* Reads, decrypts and deserializes the next `Frame` */
+    pub async fn recv(&mut self) -> io::Result<Frame> {
+        let mut bytes = read_frame(&mut self.inner).await?;
+        if let Some(cipher) = &mut self.cipher {
+            bytes = cipher.decrypt(&bytes)?;
+        }
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/*
+ * The write half of a connection, encrypting frames once
+ * a Noise channel is established.
+ */
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    inner: W,
+    cipher: Option<CipherState>,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /* Wraps a writer; `cipher` is `None` for a plaintext link */
+    pub fn new(inner: W, cipher: Option<CipherState>) -> Self {
+        Self { inner, cipher }
+    }
+
+    /* Serializes, encrypts and writes a `Frame` */
+    pub async fn send(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut bytes = serde_json::to_vec(frame).map_err(io::Error::other)?;
+        if let Some(cipher) = &mut self.cipher {
+            bytes = cipher.encrypt(&bytes)?;
+        }
+        write_frame(&mut self.inner, &bytes).await
+    }
+}
+
+/*
+ * Runs the client side of the Noise handshake on a fresh connection
+ * and returns the encrypted frame reader/writer pair.
+ *
+ * The client's static key is throwaway: the client proves who it is
+ * with the device-key login that follows, inside the channel.
+ */
+pub async fn secure_client<R, W>(
+    mut reader: R,
+    mut writer: W,
+    relay_key: &box_::PublicKey,
+) -> io::Result<(FrameReader<R>, FrameWriter<W>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let channel = noise::initiate(&mut reader, &mut writer, &Keypair::generate(), relay_key).await?;
+    Ok((
+        FrameReader::new(reader, Some(channel.recv)),
+        FrameWriter::new(writer, Some(channel.send)),
+    ))
+}
+
+/* The error `receive` fails with once the session is over */
+fn expired() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "session expired")
+}
+
+/* Turns a refused request into an `io::Error` */
+fn refused(reason: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason.to_string())
+}
+
+/*
+ * An in-process transport that talks to a `Relay` directly.
+ *
+ * Useful for tests and for the local demo, where every user lives
+ * in the same process and no socket is needed.
+ */
+#[derive(Debug)]
+pub struct MemoryTransport {
+    relay: Relay,
+    session: Mutex<Option<SessionToken>>,
+}
+
+impl MemoryTransport {
+    /* Creates a transport attached to the given relay (not yet subscribed) */
+    pub fn new(relay: Relay) -> Self {
+        Self {
+            relay,
+            session: Mutex::new(None),
+        }
+    }
+
+    /* The session we are logged in with, as long as it lasts */
+    async fn session(&self) -> io::Result<SessionToken> {
+        match self.session.lock().await.clone() {
+            Some(session) if session.expires_at > Instant::now() => Ok(session),
+            Some(_) => Err(expired()),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not subscribed")),
+        }
+    }
+
+    /* The username and device we are logged in as */
+    async fn logged_in(&self) -> io::Result<(String, String)> {
+        let session = self.session().await?;
+        Ok((session.username, session.device))
+    }
+
+    async fn mailbox(&self) -> io::Result<String> {
+        let (username, device) = self.logged_in().await?;
+        Ok(devices::mailbox(&username, &device))
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn send(&self, envelope: Envelope) -> io::Result<()> {
+        /* Same rule as a socket relay: only logged-in devices send, as themselves */
+        let (username, device) = self.logged_in().await?;
+        if envelope.sender != username
+            || devices::mailbox(&username, &envelope.sender_device) != devices::mailbox(&username, &device)
+            || !self.relay.listed(&username, &device)
+        {
+            return Err(refused("sender does not match the logged-in device"));
+        }
+        self.relay.deposit(envelope).map_err(refused)
+    }
+
+    async fn receive(&self) -> io::Result<Envelope> {
+        let session = self.session().await?;
+        let mailbox = devices::mailbox(&session.username, &session.device);
+        tokio::time::timeout_at(session.expires_at.into(), self.relay.fetch(&mailbox))
+            .await
+            .map_err(|_| expired())
+    }
+
+    async fn subscribe(&self, user: &User) -> io::Result<()> {
+        let username = user.username();
+        /* Already claimed is fine: the login below fails if the name is someone else's */
+        if let Some(claim) = user.username_claim() {
+            let _ = self.relay.claim(claim);
+        }
+        self.relay.publish_devices(user.devices.clone()).map_err(refused)?;
+        let nonce = self.relay.challenge(username, &user.device_id).map_err(refused)?;
+        let signature = user.sign_challenge(&nonce);
+        let session = self
+            .relay
+            .login(username, &user.device_id, &nonce, signature.as_ref())
+            .map_err(refused)?;
+        self.relay.subscribe(&session.token).map_err(refused)?;
+        *self.session.lock().await = Some(session);
+        Ok(())
+    }
+
+    async fn acknowledge(&self, id: &str) -> io::Result<()> {
+        let mailbox = self.mailbox().await?;
+        self.relay.acknowledge(&mailbox, id);
+        Ok(())
+    }
+
+    async fn publish_claim(&self, claim: &UsernameClaim) -> io::Result<()> {
+        self.relay.claim(claim.clone()).map_err(refused)
+    }
+
+    async fn lookup(&self, username: &str, known_size: u64) -> io::Result<DirectoryLookup> {
+        Ok(self.relay.lookup(username, known_size))
+    }
+
+    async fn fetch_devices(&self, username: &str) -> io::Result<Option<DeviceList>> {
+        Ok(self.relay.devices(username))
+    }
+
+    async fn publish_devices(&self, list: &DeviceList) -> io::Result<()> {
+        self.relay.publish_devices(list.clone()).map_err(refused)?;
+        match self.relay.devices(&list.username) {
+            Some(kept) if kept == *list => Ok(()),
+            _ => Err(refused("the relay holds a newer device list")),
+        }
+    }
+
+    async fn link_post(&self, channel: &str, message: Vec<u8>) -> io::Result<()> {
+        if self.relay.link_post(channel, message) { Ok(()) } else { Err(refused("link message refused")) }
+    }
+
+    async fn link_fetch(&self, channel: &str) -> io::Result<Vec<Vec<u8>>> {
+        Ok(self.relay.link_take(channel))
+    }
+
+    async fn blob_put(&self, blob_id: &str, offset: u64, data: Vec<u8>, last: bool) -> io::Result<u64> {
+        let (username, device) = self.logged_in().await?;
+        self.relay
+            .blob_put(&devices::mailbox(&username, &device), blob_id, offset, &data, last)
+            .map_err(refused)
+    }
+
+    async fn blob_get(&self, blob_id: &str, offset: u64) -> io::Result<Option<(u64, Vec<u8>)>> {
+        Ok(self.relay.blob_get(blob_id, offset))
+    }
+}
+
+/*
+ * A transport over any split byte stream connected to a relay.
+ *
+ * The read and write halves are locked independently, so a task can
+ * block in `receive` while another one calls `send`.
+ *
+ * Whoever holds the reader routes what it reads: envelopes go to
+ * `inbox` (drained by `receive`), replies (`Error`s included) to the
+ * oldest request in `waiting` (the relay answers requests in order),
+ * and the end of the session sets `expired` (reported by `receive`).
+ */
+#[derive(Debug)]
+pub struct StreamTransport<R, W> {
+    reader: Mutex<FrameReader<R>>,
+    writer: Mutex<FrameWriter<W>>,
+    inbox: std::sync::Mutex<VecDeque<Envelope>>,
+    waiting: std::sync::Mutex<VecDeque<oneshot::Sender<Frame>>>,
+    expired: AtomicBool,
+}
+
+impl<R, W> StreamTransport<R, W> {
+    /* Wraps an already connected (and secured) reader/writer pair */
+    pub fn new(reader: FrameReader<R>, writer: FrameWriter<W>) -> Self {
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            inbox: std::sync::Mutex::new(VecDeque::new()),
+            waiting: std::sync::Mutex::new(VecDeque::new()),
+            expired: AtomicBool::new(false),
+        }
+    }
+
+    /* Hands a frame read by someone else to whoever expects it */
+    fn route(&self, frame: Frame) {
+        match frame {
+            Frame::Deliver { envelope } => self.inbox.lock().unwrap().push_back(envelope),
+            Frame::Expired => self.expired.store(true, Ordering::SeqCst),
+            reply => {
+                if let Some(waiter) = self.waiting.lock().unwrap().pop_front() {
+                    let _ = waiter.send(reply);
+                }
+            }
+        }
+    }
+
+    /* Whether requests sent before the current one still wait for their reply */
+    fn answering_others(&self) -> bool {
+        !self.waiting.lock().unwrap().is_empty()
+    }
+}
+
+/*
+ * Reads the reply to a request sent while holding the writer, which
+ * only comes after the replies to the requests in `waiting` (those are
+ * routed, with envelopes), and returns what `pick` makes of it.
+ *
+ * An `Error` frame from the relay aborts with `PermissionDenied`.
+ */
+async fn expect_frame<R, W, T>(
+    transport: &StreamTransport<R, W>,
+    reader: &mut FrameReader<R>,
+    pick: impl Fn(&Frame) -> Option<T>,
+) -> io::Result<T>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        match reader.recv().await? {
+            frame @ (Frame::Deliver { .. } | Frame::Expired) => transport.route(frame),
+            frame if transport.answering_others() => transport.route(frame),
+            Frame::Error { reason } => return Err(refused(reason)),
+            frame => {
+                return pick(&frame).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected relay reply"));
+            }
+        }
+    }
+}
+
+impl<R, W> StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /*
+     * Sends a request and waits for its reply (an `Error` reply fails
+     * with `PermissionDenied`).
+     *
+     * While the reply has not arrived, either another task is reading
+     * (and will route the reply to us), or the reader is free and we
+     * read, and route, frames ourselves.
+     */
+    async fn request(&self, frame: Frame) -> io::Result<Frame> {
+        let mut reply = {
+            let mut writer = self.writer.lock().await;
+            let (waiter, reply) = oneshot::channel();
+            self.waiting.lock().unwrap().push_back(waiter);
+            writer.send(&frame).await?;
+            reply
+        };
+        loop {
+            let mut reading = Box::pin(self.reader.lock());
+            let ready = std::future::poll_fn(|cx| {
+                if let Poll::Ready(frame) = Pin::new(&mut reply).poll(cx) {
+                    return Poll::Ready(Err(frame));
+                }
+                reading.as_mut().poll(cx).map(Ok)
+            })
+            .await;
+            match ready {
+                Err(frame) => {
+                    return match frame {
+                        Ok(Frame::Error { reason }) => Err(refused(reason)),
+                        Ok(frame) => Ok(frame),
+                        Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")),
+                    };
+                }
+                Ok(mut reader) => {
+                    match reply.try_recv() {
+                        Ok(Frame::Error { reason }) => return Err(refused(reason)),
+                        Ok(frame) => return Ok(frame),
+                        Err(_) => {}
+                    }
+                    let frame = reader.recv().await?;
+                    self.route(frame);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<R, W> Transport for StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin + Send + fmt::Debug,
+    W: AsyncWrite + Unpin + Send + fmt::Debug,
+{
+    async fn send(&self, envelope: Envelope) -> io::Result<()> {
+        match self.request(Frame::Deliver { envelope }).await? {
+            Frame::Delivered { .. } => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected delivery reply")),
+        }
+    }
+
+    async fn receive(&self) -> io::Result<Envelope> {
+        loop {
+            if let Some(envelope) = self.inbox.lock().unwrap().pop_front() {
+                return Ok(envelope);
+            }
+            if self.expired.swap(false, Ordering::SeqCst) {
+                return Err(expired());
+            }
+            let mut reader = self.reader.lock().await;
+            /* Someone else may have read an envelope (or the end of the session) while we waited */
+            if !self.inbox.lock().unwrap().is_empty() || self.expired.load(Ordering::SeqCst) {
+                continue;
+            }
+            match reader.recv().await? {
+                Frame::Deliver { envelope } => return Ok(envelope),
+                Frame::Expired => return Err(expired()),
+                frame => self.route(frame),
+            }
+        }
+    }
+
+    async fn subscribe(&self, user: &User) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        let mut reader = self.reader.lock().await;
+        let username = user.username().to_string();
+        self.expired.store(false, Ordering::SeqCst);
+
+        /*
+         * 1) Make sure the relay knows our identity key: only a signed claim
+         *    binds a username. Already claimed is fine, the login below fails
+         *    if the name is someone else's; linked devices cannot sign one.
+         */
+        if let Some(claim) = user.username_claim() {
+            writer.send(&Frame::Claim { claim }).await?;
+            expect_frame(self, &mut reader, |f| match f {
+                Frame::Claimed { .. } => Some(()),
+                _ => None,
+            })
+            .await?;
+        }
+
+        /* 2) Publish our device list, then ask for a challenge and sign it with the device key */
+        writer.send(&Frame::PublishDevices { list: user.devices.clone() }).await?;
+        expect_frame(self, &mut reader, |f| matches!(f, Frame::Accepted).then_some(())).await?;
+        let hello = Frame::Hello {
+            username: username.clone(),
+            device: user.device_id.clone(),
+        };
+        writer.send(&hello).await?;
+        let nonce = expect_frame(self, &mut reader, |f| match f {
+            Frame::Challenge { nonce } => Some(nonce.clone()),
+            _ => None,
+        })
+        .await?;
+        let login = Frame::Login {
+            username,
+            device: user.device_id.clone(),
+            signature: user.sign_challenge(&nonce).as_ref().to_vec(),
+            nonce,
+        };
+        writer.send(&login).await?;
+
+        /* 3) Use the session token to open the mailbox */
+        let token = expect_frame(self, &mut reader, |f| match f {
+            Frame::Session { token, .. } => Some(token.clone()),
+            _ => None,
+        })
+        .await?;
+        writer.send(&Frame::Subscribe { token }).await?;
+        expect_frame(self, &mut reader, |f| matches!(f, Frame::Accepted).then_some(())).await
+    }
+
+    async fn acknowledge(&self, id: &str) -> io::Result<()> {
+        let frame = Frame::Ack { id: id.to_string() };
+        self.writer.lock().await.send(&frame).await
+    }
+
+    async fn publish_claim(&self, claim: &UsernameClaim) -> io::Result<()> {
+        match self.request(Frame::Claim { claim: claim.clone() }).await? {
+            Frame::Claimed { error: None, .. } => Ok(()),
+            Frame::Claimed { error: Some(reason), .. } => Err(refused(reason)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected directory reply")),
+        }
+    }
+
+    async fn lookup(&self, username: &str, known_size: u64) -> io::Result<DirectoryLookup> {
+        let frame = Frame::Lookup {
+            username: username.to_string(),
+            known_size,
+        };
+        match self.request(frame).await? {
+            Frame::Found { lookup, .. } => Ok(lookup),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected directory reply")),
+        }
+    }
+
+    async fn fetch_devices(&self, username: &str) -> io::Result<Option<DeviceList>> {
+        let frame = Frame::FetchDevices {
+            username: username.to_string(),
+        };
+        match self.request(frame).await? {
+            Frame::Devices { list, .. } => Ok(list),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected directory reply")),
+        }
+    }
+
+    /* The relay accepts an older list without keeping it, so read it back to know whether it was kept */
+    async fn publish_devices(&self, list: &DeviceList) -> io::Result<()> {
+        match self.request(Frame::PublishDevices { list: list.clone() }).await? {
+            Frame::Accepted => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected device list reply")),
+        }
+        match self.fetch_devices(&list.username).await? {
+            Some(kept) if kept == *list => Ok(()),
+            _ => Err(refused("the relay holds a newer device list")),
+        }
+    }
+
+    async fn link_post(&self, channel: &str, message: Vec<u8>) -> io::Result<()> {
+        let frame = Frame::LinkPost {
+            channel: channel.to_string(),
+            message,
+        };
+        match self.request(frame).await? {
+            Frame::Accepted => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected link reply")),
+        }
+    }
+
+    async fn link_fetch(&self, channel: &str) -> io::Result<Vec<Vec<u8>>> {
+        let frame = Frame::LinkFetch {
+            channel: channel.to_string(),
+        };
+        match self.request(frame).await? {
+            Frame::LinkMessages { messages, .. } => Ok(messages),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected link reply")),
+        }
+    }
+
+    async fn blob_put(&self, blob_id: &str, offset: u64, data: Vec<u8>, last: bool) -> io::Result<u64> {
+        let frame = Frame::BlobPut {
+            blob_id: blob_id.to_string(),
+            offset,
+            data,
+            last,
+        };
+        match self.request(frame).await? {
+            Frame::BlobStored { error: None, received, .. } => Ok(received),
+            Frame::BlobStored { error: Some(reason), .. } => Err(refused(reason)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected blob reply")),
+        }
+    }
+
+    async fn blob_get(&self, blob_id: &str, offset: u64) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let frame = Frame::BlobGet {
+            blob_id: blob_id.to_string(),
+            offset,
+        };
+        match self.request(frame).await? {
+            Frame::BlobData { total, data, .. } => Ok(total.map(|total| (total, data))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected blob reply")),
+        }
+    }
+}
+
+/* A transport over a TCP connection */
+pub type TcpTransport =
+    StreamTransport<tokio::net::tcp::OwnedReadHalf, tokio::net::tcp::OwnedWriteHalf>;
+
+/*
+ * Connects to a relay listening on `addr` (e.g. "127.0.0.1:7878")
+ * whose static Noise key must be `relay_key`.
+ */
+pub async fn connect_tcp(addr: &str, relay_key: &box_::PublicKey) -> io::Result<TcpTransport> {
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let (reader, writer) = secure_client(reader, writer, relay_key).await?;
+    Ok(StreamTransport::new(reader, writer))
+}
+
+/* A transport over a Unix domain socket */
+#[cfg(unix)]
+pub type UnixTransport =
+    StreamTransport<tokio::net::unix::OwnedReadHalf, tokio::net::unix::OwnedWriteHalf>;
+
+/* Connects to a relay listening on the Unix socket at `path` (pinned to `relay_key`) */
+#[cfg(unix)]
+pub async fn connect_unix(path: &str, relay_key: &box_::PublicKey) -> io::Result<UnixTransport> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    let (reader, writer) = stream.into_split();
+    let (reader, writer) = secure_client(reader, writer, relay_key).await?;
+    Ok(StreamTransport::new(reader, writer))
+}
@@ -0,0 +1,378 @@
+/*
+ * This module defines the `Authenticator`, the relay-side half of the
+ * device-key challenge-response login (see `net::auth`).
+ *
+ * It keeps:
+ *  - the identity public key registered for each username
+ *  - the latest device list published for each username (see `net::devices`)
+ *  - the challenges currently outstanding (single use, expire after
+ *    `CHALLENGE_TTL`, at most one per device: a new one replaces it)
+ *  - the session tokens handed out (expire after `SESSION_TTL`)
+ *
+ * Devices log in with their own device key: a device can only log in
+ * while it is listed in its account's latest device list, and its
+ * sessions end as soon as a list without it is published.
+ *
+ * A challenge is removed as soon as a response for it is checked, whether
+ * the signature is valid or not, so a captured response cannot be replayed.
+ */
+
+use crate::net::auth::{challenge_message, CHALLENGE_LEN};
+use crate::net::devices::DeviceList;
+use sodiumoxide::crypto::sign;
+use sodiumoxide::randombytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/* How long a challenge may be answered */
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(30);
+
+/* How long a session token stays valid */
+pub const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/* Reasons a registration or login can be refused */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /* The username is already bound to another identity key */
+    AlreadyRegistered,
+    /* The identity key is not a valid Ed25519 public key */
+    InvalidKey,
+    /* No identity key is registered for the username */
+    UnknownUser,
+    /* The device is not in the account's device list */
+    UnknownDevice,
+    /* The device list is malformed or not signed by the account's identity key */
+    BadDeviceList,
+    /* Another device list was already published with the same version */
+    DeviceListConflict,
+    /* The challenge was never issued, already used, or issued to someone else */
+    UnknownChallenge,
+    /* The challenge was answered too late */
+    ExpiredChallenge,
+    /* The signature does not verify against the registered identity key */
+    BadSignature,
+    /* The session token is unknown or has expired */
+    InvalidToken,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            AuthError::AlreadyRegistered => "username already registered with another key",
+            AuthError::InvalidKey => "malformed identity key",
+            AuthError::UnknownUser => "unknown user",
+            AuthError::UnknownDevice => "unknown device",
+            AuthError::BadDeviceList => "invalid device list",
+            AuthError::DeviceListConflict => "another device list has the same version",
+            AuthError::UnknownChallenge => "unknown or replayed challenge",
+            AuthError::ExpiredChallenge => "challenge expired",
+            AuthError::BadSignature => "bad signature",
+            AuthError::InvalidToken => "invalid or expired session token",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/*
+ * A short-lived token proving that its holder logged in as `username`
+ * from device `device`.
+ */
+#[derive(Debug, Clone)]
+pub struct SessionToken {
+    pub token: String,
+    pub username: String,
+    pub device: String,
+    pub expires_at: Instant,
+}
+
+/* An outstanding challenge */
+#[derive(Debug)]
+struct PendingChallenge {
+    username: String,
+    device: String,
+    issued_at: Instant,
+}
+
+/*
+ * Relay-side registry of identities, challenges and sessions.
+ */
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    identities: HashMap<String, sign::PublicKey>,
+    devices: HashMap<String, DeviceList>,
+    challenges: HashMap<Vec<u8>, PendingChallenge>,
+    sessions: HashMap<String, SessionToken>,
+}
+
+impl Authenticator {
+    /* Creates an authenticator with no registered users */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+     * Binds `username` to `identity_pk`.
+     *
+     * Registering the same key twice is accepted; registering a
+     * different key for a taken username is refused.
+     */
+    pub fn register(&mut self, username: &str, identity_pk: sign::PublicKey) -> Result<(), AuthError> {
+        match self.identities.get(username) {
+            Some(existing) if *existing != identity_pk => Err(AuthError::AlreadyRegistered),
+            Some(_) => Ok(()),
+            None => {
+                self.identities.insert(username.to_string(), identity_pk);
+                Ok(())
+            }
+        }
+    }
+
+    /* Returns the identity key registered for `username`, if any */
+    pub fn identity(&self, username: &str) -> Option<&sign::PublicKey> {
+        self.identities.get(username)
+    }
+
+    /*
+     * Stores the device list of `list.username`, if it is signed by
+     * their registered identity key and newer than the stored one.
+     *
+     * Returns `Ok(false)` if the stored list is newer (or the same),
+     * so a device that was offline cannot roll the list back. Sessions
+     * of devices missing from the new list are closed.
+     */
+    pub fn publish_devices(&mut self, list: DeviceList) -> Result<bool, AuthError> {
+        let identity_pk = self.identities.get(&list.username).ok_or(AuthError::UnknownUser)?;
+        if !list.verify(identity_pk) {
+            return Err(AuthError::BadDeviceList);
+        }
+        match self.devices.get(&list.username) {
+            Some(current) if current.version == list.version && *current != list => {
+                return Err(AuthError::DeviceListConflict);
+            }
+            Some(current) if current.version >= list.version => return Ok(false),
+            _ => {}
+        }
+        self.sessions
+            .retain(|_, s| s.username != list.username || list.device(&s.device).is_some());
+        self.devices.insert(list.username.clone(), list);
+        Ok(true)
+    }
+
+    /* Returns the latest device list published for `username`, if any */
+    pub fn devices(&self, username: &str) -> Option<&DeviceList> {
+        self.devices.get(username)
+    }
+
+    /*
+     * Issues a fresh random challenge for device `device` of `username`,
+     * replacing the one it may still have outstanding.
+     *
+     * Expired challenges are purged on the way.
+     */
+    pub fn issue_challenge(&mut self, username: &str, device: &str) -> Result<Vec<u8>, AuthError> {
+        if !self.identities.contains_key(username) {
+            return Err(AuthError::UnknownUser);
+        }
+        if self.devices.get(username).and_then(|list| list.device(device)).is_none() {
+            return Err(AuthError::UnknownDevice);
+        }
+        self.challenges.retain(|_, c| {
+            c.issued_at.elapsed() <= CHALLENGE_TTL && (c.username != username || c.device != device)
+        });
+
+        let nonce = randombytes::randombytes(CHALLENGE_LEN);
+        self.challenges.insert(
+            nonce.clone(),
+            PendingChallenge {
+                username: username.to_string(),
+                device: device.to_string(),
+                issued_at: Instant::now(),
+            },
+        );
+        Ok(nonce)
+    }
+
+    /*
+     * Checks a signed challenge and, if valid, opens a session.
+     *
+     * Steps:
+     *  1. Consume the challenge (it can never be answered twice)
+     *  2. Check it was issued to this username and device, and is not expired
+     *  3. Verify the signature with the device key from the device list
+     *  4. Create a random session token valid for `SESSION_TTL`
+     */
+    pub fn verify_response(
+        &mut self,
+        username: &str,
+        device: &str,
+        nonce: &[u8],
+        signature: &[u8],
+    ) -> Result<SessionToken, AuthError> {
+        let pending = self.challenges.remove(nonce).ok_or(AuthError::UnknownChallenge)?;
+        if pending.username != username || pending.device != device {
+            return Err(AuthError::UnknownChallenge);
+        }
+        if pending.issued_at.elapsed() > CHALLENGE_TTL {
+            return Err(AuthError::ExpiredChallenge);
+        }
+
+        let device_pk = self
+            .devices
+            .get(username)
+            .and_then(|list| list.device(device))
+            .and_then(|d| d.signing_key())
+            .ok_or(AuthError::UnknownDevice)?;
+        let signature = sign::Signature::try_from(signature).map_err(|_| AuthError::BadSignature)?;
+        if !sign::verify_detached(&signature, &challenge_message(username, device, nonce), &device_pk) {
+            return Err(AuthError::BadSignature);
+        }
+
+        self.sessions.retain(|_, s| s.expires_at > Instant::now());
+        let session = SessionToken {
+            token: hex::encode(randombytes::randombytes(32)),
+            username: username.to_string(),
+            device: device.to_string(),
+            expires_at: Instant::now() + SESSION_TTL,
+        };
+        self.sessions.insert(session.token.clone(), session.clone());
+        Ok(session)
+    }
+
+    /*
+     * Resolves a session token to its session (username and device).
+     *
+     * Expired tokens are removed and rejected.
+     */
+    pub fn check_token(&mut self, token: &str) -> Result<SessionToken, AuthError> {
+        match self.sessions.get(token) {
+            Some(session) if session.expires_at > Instant::now() => Ok(session.clone()),
+            Some(_) => {
+                self.sessions.remove(token);
+                Err(AuthError::InvalidToken)
+            }
+            None => Err(AuthError::InvalidToken),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::user::User;
+    use crate::net::devices::PRIMARY_DEVICE;
+
+    /* An authenticator knowing `user`'s account and device list */
+    fn authenticator(user: &User) -> Authenticator {
+        let mut auth = Authenticator::new();
+        auth.register(user.username(), user.identity_pk).unwrap();
+        auth.publish_devices(user.devices.clone()).unwrap();
+        auth
+    }
+
+    /* `since` ago */
+    fn ago(since: Duration) -> Instant {
+        Instant::now().checked_sub(since).unwrap()
+    }
+
+    fn login(auth: &mut Authenticator, user: &User) -> Result<SessionToken, AuthError> {
+        let nonce = auth.issue_challenge(user.username(), &user.device_id)?;
+        let signature = user.sign_challenge(&nonce);
+        auth.verify_response(user.username(), &user.device_id, &nonce, signature.as_ref())
+    }
+
+    #[test]
+    fn challenges_are_single_use() {
+        let alice = User::new("alice", 1);
+        let mut auth = authenticator(&alice);
+        let nonce = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+        let signature = alice.sign_challenge(&nonce);
+        assert!(auth.verify_response("alice", PRIMARY_DEVICE, &nonce, signature.as_ref()).is_ok());
+        assert_eq!(
+            auth.verify_response("alice", PRIMARY_DEVICE, &nonce, signature.as_ref()).err(),
+            Some(AuthError::UnknownChallenge)
+        );
+
+        /* A wrong answer uses the challenge up too */
+        let nonce = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+        assert_eq!(
+            auth.verify_response("alice", PRIMARY_DEVICE, &nonce, &[0; 64]).err(),
+            Some(AuthError::BadSignature)
+        );
+        let signature = alice.sign_challenge(&nonce);
+        assert_eq!(
+            auth.verify_response("alice", PRIMARY_DEVICE, &nonce, signature.as_ref()).err(),
+            Some(AuthError::UnknownChallenge)
+        );
+    }
+
+    #[test]
+    fn a_new_challenge_replaces_the_last() {
+        let alice = User::new("alice", 1);
+        let mut auth = authenticator(&alice);
+        let first = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+        let second = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+        assert_eq!(auth.challenges.len(), 1);
+        let signature = alice.sign_challenge(&first);
+        assert_eq!(
+            auth.verify_response("alice", PRIMARY_DEVICE, &first, signature.as_ref()).err(),
+            Some(AuthError::UnknownChallenge)
+        );
+        let signature = alice.sign_challenge(&second);
+        assert!(auth.verify_response("alice", PRIMARY_DEVICE, &second, signature.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn expired_challenges_are_refused() {
+        let alice = User::new("alice", 1);
+        let mut auth = authenticator(&alice);
+        let nonce = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+        auth.challenges.get_mut(&nonce).unwrap().issued_at = ago(CHALLENGE_TTL + Duration::from_secs(1));
+        let signature = alice.sign_challenge(&nonce);
+        assert_eq!(
+            auth.verify_response("alice", PRIMARY_DEVICE, &nonce, signature.as_ref()).err(),
+            Some(AuthError::ExpiredChallenge)
+        );
+    }
+
+    #[test]
+    fn only_the_challenged_device_can_answer() {
+        let seed = [7; 32];
+        let phone = User::from_seed_on_device("alice", &seed, 1, "2");
+        let mut auth = authenticator(&phone);
+        let nonce = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+
+        /* Signed by the phone, for the primary device */
+        let signature = sign::sign_detached(&challenge_message("alice", PRIMARY_DEVICE, &nonce), &phone.device_sk);
+        assert_eq!(
+            auth.verify_response("alice", PRIMARY_DEVICE, &nonce, signature.as_ref()).err(),
+            Some(AuthError::BadSignature)
+        );
+
+        /* Answered as the phone, though issued to the primary device */
+        let nonce = auth.issue_challenge("alice", PRIMARY_DEVICE).unwrap();
+        let signature = phone.sign_challenge(&nonce);
+        assert_eq!(
+            auth.verify_response("alice", "2", &nonce, signature.as_ref()).err(),
+            Some(AuthError::UnknownChallenge)
+        );
+        assert!(login(&mut auth, &phone).is_ok());
+        assert!(login(&mut auth, &User::from_seed("alice", &seed, 1)).is_ok());
+        assert_eq!(auth.issue_challenge("alice", "3").err(), Some(AuthError::UnknownDevice));
+    }
+
+    #[test]
+    fn expired_sessions_are_refused() {
+        let alice = User::new("alice", 1);
+        let mut auth = authenticator(&alice);
+        let session = login(&mut auth, &alice).unwrap();
+        assert_eq!(auth.check_token(&session.token).unwrap().username, "alice");
+
+        auth.sessions.get_mut(&session.token).unwrap().expires_at = ago(Duration::from_secs(1));
+        assert_eq!(auth.check_token(&session.token).err(), Some(AuthError::InvalidToken));
+        assert!(auth.sessions.is_empty());
+        assert_eq!(auth.check_token("unknown").err(), Some(AuthError::InvalidToken));
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * This module defines the `BlobStore`, the relay-side storage of
+ * content-addressed blobs (see `net::blobs`).
+ *
+ * Rules:
+ *  - a blob is uploaded in order, piece by piece, by a logged-in device
+ *  - it is only stored once its last piece arrived and its SHA-256
+ *    matches its id; a mismatching upload is dropped
+ *  - a blob already stored is not uploaded again (the first piece is
+ *    answered with its full size)
+ *  - a device has at most `MAX_UPLOADS` uploads in progress, and no
+ *    blob is larger than `MAX_BLOB_SIZE`
+ *  - the blobs a device uploaded, and its uploads in progress, hold at
+ *    most `MAX_STORED_BYTES` together
+ *  - a blob is deleted `BLOB_TTL` after it was last uploaded, and an
+ *    upload left without a new piece for `UPLOAD_TTL` is dropped
+ *
+ * Anyone who knows a blob id can download the blob: it is encrypted,
+ * and its key only travels in end-to-end encrypted messages.
+ */
+
+use crate::net::blobs::{self, BLOB_CHUNK, MAX_BLOB_SIZE};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/* Number of uploads a device can have in progress */
+const MAX_UPLOADS: usize = 4;
+
+/* Bytes a device can have stored and in progress at once */
+pub const MAX_STORED_BYTES: usize = 4 * MAX_BLOB_SIZE;
+
+/* How long a blob is kept after its last upload */
+pub const BLOB_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/* How long an upload in progress waits for its next piece */
+pub const UPLOAD_TTL: Duration = Duration::from_secs(10 * 60);
+
+/* Reasons an upload can be refused */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobError {
+    /* The blob id is not a SHA-256 in hex */
+    InvalidId,
+    /* The blob, or the piece, is too large */
+    TooLarge,
+    /* The piece does not start where the upload stopped */
+    OutOfOrder,
+    /* The device has too many uploads in progress */
+    TooManyUploads,
+    /* The device would hold more than `MAX_STORED_BYTES` */
+    QuotaExceeded,
+    /* The uploaded bytes do not hash to the blob id */
+    DigestMismatch,
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            BlobError::InvalidId => "invalid blob id",
+            BlobError::TooLarge => "blob too large",
+            BlobError::OutOfOrder => "blob piece out of order",
+            BlobError::TooManyUploads => "too many uploads in progress",
+            BlobError::QuotaExceeded => "storage quota exceeded",
+            BlobError::DigestMismatch => "blob does not match its id",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+/*
+ * A stored blob.
+ *
+ * Fields:
+ *  - `data`     : The (encrypted) bytes
+ *  - `uploader` : Who uploaded it first, charged for it against the quota
+ *  - `expires`  : When it is deleted (pushed back by each new upload)
+ */
+#[derive(Debug)]
+struct Blob {
+    data: Vec<u8>,
+    uploader: String,
+    expires: Instant,
+}
+
+/* An upload in progress, and when it is dropped unless a piece arrives */
+#[derive(Debug)]
+struct Upload {
+    data: Vec<u8>,
+    expires: Instant,
+}
+
+/*
+ * Stored blobs, and uploads in progress.
+ *
+ * Uploads are keyed by uploader (its mailbox name, see `net::devices`)
+ * and blob id, so two devices uploading the same blob do not interfere.
+ */
+#[derive(Debug, Default)]
+pub struct BlobStore {
+    blobs: HashMap<String, Blob>,
+    uploads: HashMap<(String, String), Upload>,
+}
+
+impl BlobStore {
+    /*
+     * Appends `data`, found at `offset` in blob `blob_id`, to the upload
+     * of `uploader`; `last` completes the upload.
+     *
+     * Returns the number of bytes of the blob the relay now holds.
+     */
+    pub fn put(&mut self, uploader: &str, blob_id: &str, offset: u64, data: &[u8], last: bool) -> Result<u64, BlobError> {
+        let now = Instant::now();
+        self.expire(now);
+        if !blobs::valid_blob_id(blob_id) {
+            return Err(BlobError::InvalidId);
+        }
+        let key = (uploader.to_string(), blob_id.to_string());
+        if let Some(blob) = self.blobs.get_mut(blob_id) {
+            self.uploads.remove(&key);
+            blob.expires = now + BLOB_TTL;
+            return Ok(blob.data.len() as u64);
+        }
+        if data.len() > BLOB_CHUNK {
+            return Err(BlobError::TooLarge);
+        }
+        if offset == 0 && !self.uploads.contains_key(&key) {
+            let pending = self.uploads.keys().filter(|(owner, _)| owner == uploader).count();
+            if pending >= MAX_UPLOADS {
+                return Err(BlobError::TooManyUploads);
+            }
+        }
+        if self.held_by(uploader) + data.len() > MAX_STORED_BYTES {
+            return Err(BlobError::QuotaExceeded);
+        }
+        if offset == 0 {
+            self.uploads.insert(key.clone(), Upload { data: Vec::new(), expires: now });
+        }
+
+        let upload = self.uploads.get_mut(&key).ok_or(BlobError::OutOfOrder)?;
+        if upload.data.len() as u64 != offset {
+            return Err(BlobError::OutOfOrder);
+        }
+        if upload.data.len() + data.len() > MAX_BLOB_SIZE {
+            self.uploads.remove(&key);
+            return Err(BlobError::TooLarge);
+        }
+        upload.data.extend_from_slice(data);
+        upload.expires = now + UPLOAD_TTL;
+        if !last {
+            return Ok(upload.data.len() as u64);
+        }
+
+        let blob = self.uploads.remove(&key).map(|upload| upload.data).unwrap_or_default();
+        if blobs::blob_id(&blob) != blob_id {
+            return Err(BlobError::DigestMismatch);
+        }
+        let size = blob.len() as u64;
+        self.blobs.insert(
+            blob_id.to_string(),
+            Blob {
+                data: blob,
+                uploader: uploader.to_string(),
+                expires: now + BLOB_TTL,
+            },
+        );
+        Ok(size)
+    }
+
+    /*
+     * The piece of blob `blob_id` starting at `offset` (at most
+     * `BLOB_CHUNK` bytes) and the size of the blob, if it is stored.
+     */
+    pub fn get(&self, blob_id: &str, offset: u64) -> Option<(u64, Vec<u8>)> {
+        let blob = &self.blobs.get(blob_id).filter(|blob| blob.expires > Instant::now())?.data;
+        let start = (offset as usize).min(blob.len());
+        let end = (start + BLOB_CHUNK).min(blob.len());
+        Some((blob.len() as u64, blob[start..end].to_vec()))
+    }
+
+    /* Bytes stored for `uploader`, blobs and uploads in progress */
+    fn held_by(&self, uploader: &str) -> usize {
+        let stored: usize = self.blobs.values().filter(|b| b.uploader == uploader).map(|b| b.data.len()).sum();
+        let pending: usize = self
+            .uploads
+            .iter()
+            .filter(|((owner, _), _)| owner == uploader)
+            .map(|(_, upload)| upload.data.len())
+            .sum();
+        stored + pending
+    }
+
+    /* Drops the blobs and uploads whose time is up at `now` */
+    fn expire(&mut self, now: Instant) {
+        self.blobs.retain(|_, blob| blob.expires > now);
+        self.uploads.retain(|_, upload| upload.expires > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Uploads `data` whole, piece by piece, as `uploader` */
+    fn upload(store: &mut BlobStore, uploader: &str, data: &[u8]) -> Result<u64, BlobError> {
+        let id = blobs::blob_id(data);
+        let pieces: Vec<&[u8]> = data.chunks(BLOB_CHUNK).collect();
+        let mut received = 0;
+        for (i, piece) in pieces.iter().enumerate() {
+            received = store.put(uploader, &id, (i * BLOB_CHUNK) as u64, piece, i + 1 == pieces.len())?;
+        }
+        Ok(received)
+    }
+
+    #[test]
+    fn blob_is_stored_once_complete() {
+        let mut store = BlobStore::default();
+        let data = vec![7u8; BLOB_CHUNK + 10];
+        assert_eq!(upload(&mut store, "alice/primary", &data), Ok(data.len() as u64));
+        let (total, piece) = store.get(&blobs::blob_id(&data), BLOB_CHUNK as u64).unwrap();
+        assert_eq!((total, piece.len()), (data.len() as u64, 10));
+    }
+
+    #[test]
+    fn mismatching_or_out_of_order_uploads_are_refused() {
+        let mut store = BlobStore::default();
+        let id = blobs::blob_id(b"expected");
+        assert_eq!(store.put("alice/primary", &id, 0, b"something else", true), Err(BlobError::DigestMismatch));
+        assert!(store.get(&id, 0).is_none());
+        assert_eq!(store.put("alice/primary", &id, 5, b"expected", true), Err(BlobError::OutOfOrder));
+        assert_eq!(store.put("alice/primary", "not an id", 0, b"x", true), Err(BlobError::InvalidId));
+    }
+
+    #[test]
+    fn uploader_quota_is_enforced() {
+        let mut store = BlobStore::default();
+        /* Charged for without being checked: only their size matters here */
+        for i in 0..MAX_STORED_BYTES / MAX_BLOB_SIZE {
+            let blob = Blob {
+                data: vec![0; MAX_BLOB_SIZE],
+                uploader: "alice/primary".to_string(),
+                expires: Instant::now() + BLOB_TTL,
+            };
+            store.blobs.insert(blobs::blob_id(&[i as u8]), blob);
+        }
+        assert_eq!(upload(&mut store, "alice/primary", b"one more"), Err(BlobError::QuotaExceeded));
+        /* Someone else still can */
+        assert!(upload(&mut store, "bob/primary", b"one more").is_ok());
+    }
+
+    #[test]
+    fn blobs_and_abandoned_uploads_expire() {
+        let mut store = BlobStore::default();
+        upload(&mut store, "alice/primary", b"kept for a while").unwrap();
+        let id = blobs::blob_id(b"never finished");
+        store.put("alice/primary", &id, 0, b"never", false).unwrap();
+
+        store.expire(Instant::now() + UPLOAD_TTL);
+        assert!(store.get(&blobs::blob_id(b"kept for a while"), 0).is_some());
+        assert_eq!(store.put("alice/primary", &id, 5, b" finished", true), Err(BlobError::OutOfOrder));
+
+        store.expire(Instant::now() + BLOB_TTL);
+        assert!(store.get(&blobs::blob_id(b"kept for a while"), 0).is_none());
+        assert_eq!(store.held_by("alice/primary"), 0);
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * This module defines the `Directory`, the relay-side registry of
+ * signed username claims (see `net::directory`).
+ *
+ * Rules:
+ *  - a claim is only accepted if its signature verifies
+ *  - a username can be claimed once: any later claim for it is refused,
+ *    even an identical one (the owner can look it up instead)
+ *  - an identity key can own a single username, so one key cannot
+ *    squat many names
+ *
+ * Every accepted claim is appended to the key transparency log
+ * (see `server::transparency`), and lookups come with proofs from it.
+ *
+ * A directory can be saved to a JSON file (its claims in log order and
+ * the log key) and loaded back: the log is rebuilt leaf by leaf, so it
+ * keeps its tree heads across restarts of the relay.
+ */
+
+use crate::net::directory::{valid_username, UsernameClaim};
+use crate::net::transparency::{leaf_hash, DirectoryLookup};
+use crate::server::transparency::TransparencyLog;
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+/* Reasons a claim can be refused */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectoryError {
+    /* The username does not follow the naming rules */
+    InvalidUsername,
+    /* The claim is not signed by the identity key it contains */
+    BadSignature,
+    /* The username was already claimed */
+    Taken,
+    /* The identity key already owns another username */
+    KeyInUse,
+}
+
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            DirectoryError::InvalidUsername => "invalid username",
+            DirectoryError::BadSignature => "claim signature does not verify",
+            DirectoryError::Taken => "username already claimed",
+            DirectoryError::KeyInUse => "identity key already owns another username",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for DirectoryError {}
+
+/*
+ * Registered claims, indexed by username (with their leaf index in
+ * the log) and by identity key.
+ */
+#[derive(Debug, Default)]
+pub struct Directory {
+    claims: HashMap<String, (UsernameClaim, u64)>,
+    owners: HashMap<Vec<u8>, String>,
+    log: TransparencyLog,
+}
+
+/*
+ * A directory as saved to disk.
+ *
+ * Fields:
+ *  - `log_public` : Log public key (hex)
+ *  - `log_secret` : Log secret key (hex)
+ *  - `claims`     : Registered claims, in log order
+ */
+#[derive(Serialize, Deserialize)]
+struct StoredDirectory {
+    log_public: String,
+    log_secret: String,
+    claims: Vec<UsernameClaim>,
+}
+
+impl Directory {
+    /* Creates an empty directory */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+     * Loads a directory saved at `path`, registering its claims again
+     * in log order. Returns `None` if the file is missing or invalid.
+     */
+    pub fn load(path: &str) -> Option<Self> {
+        let data = fs::read_to_string(path).ok()?;
+        let stored: StoredDirectory = serde_json::from_str(&data).ok()?;
+        let public = sign::PublicKey::from_slice(&hex::decode(&stored.log_public).ok()?)?;
+        let secret = sign::SecretKey::from_slice(&hex::decode(&stored.log_secret).ok()?)?;
+        let mut directory = Self {
+            log: TransparencyLog::with_key(public, secret),
+            ..Self::default()
+        };
+        for claim in stored.claims {
+            directory.register(claim).ok()?;
+        }
+        Some(directory)
+    }
+
+    /* Saves the directory to a JSON file at `path` */
+    pub fn save(&self, path: &str) {
+        let stored = StoredDirectory {
+            log_public: hex::encode(self.log.public_key().as_ref()),
+            log_secret: hex::encode(self.log.secret_key().0),
+            claims: self.claims().cloned().collect(),
+        };
+        if let Ok(json) = serde_json::to_string_pretty(&stored) {
+            let _ = fs::write(path, json);
+        }
+    }
+
+    /* The registered claims, in log order */
+    pub fn claims(&self) -> impl Iterator<Item = &UsernameClaim> {
+        let mut claims: Vec<&(UsernameClaim, u64)> = self.claims.values().collect();
+        claims.sort_by_key(|(_, index)| *index);
+        claims.into_iter().map(|(claim, _)| claim)
+    }
+
+    /* Checks whether `claim` would be accepted, without storing it */
+    pub fn check(&self, claim: &UsernameClaim) -> Result<(), DirectoryError> {
+        if !valid_username(&claim.username) {
+            return Err(DirectoryError::InvalidUsername);
+        }
+        if !claim.verify() {
+            return Err(DirectoryError::BadSignature);
+        }
+        if self.claims.contains_key(&claim.username) {
+            return Err(DirectoryError::Taken);
+        }
+        if self.owners.contains_key(&claim.identity_pk) {
+            return Err(DirectoryError::KeyInUse);
+        }
+        Ok(())
+    }
+
+    /*
+     * Stores `claim` if it is valid and conflicts with no other claim,
+     * and appends it to the transparency log.
+     */
+    pub fn register(&mut self, claim: UsernameClaim) -> Result<(), DirectoryError> {
+        self.check(&claim)?;
+        let index = self.log.append(leaf_hash(&claim));
+        self.owners.insert(claim.identity_pk.clone(), claim.username.clone());
+        self.claims.insert(claim.username.clone(), (claim, index));
+        Ok(())
+    }
+
+    /* Returns the claim registered for `username`, if any */
+    pub fn claim(&self, username: &str) -> Option<&UsernameClaim> {
+        self.claims.get(username).map(|(claim, _)| claim)
+    }
+
+    /*
+     * Answers a lookup for `username` from a client that last saw
+     * the log at `known_size`: the claim, if any, with its inclusion
+     * proof, the signed tree head and a consistency proof.
+     */
+    pub fn lookup(&self, username: &str, known_size: u64) -> DirectoryLookup {
+        let (claim, index, inclusion) = match self.claims.get(username) {
+            Some((claim, index)) => (Some(claim.clone()), *index, self.log.inclusion_proof(*index)),
+            None => (None, 0, Vec::new()),
+        };
+        DirectoryLookup {
+            claim,
+            index,
+            inclusion,
+            head: self.log.signed_head(),
+            consistency: self.log.consistency_proof(known_size),
+            log_key: self.log.public_key().as_ref().to_vec(),
+        }
+    }
+}
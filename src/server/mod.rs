@@ -0,0 +1,5 @@
+pub mod auth;
+pub mod blobs;
+pub mod directory;
+pub mod relay;
+pub mod transparency;
@@ -0,0 +1,820 @@
+/*
+ * This module defines the `Relay`, a minimal store-and-forward server
+ * that holds encrypted envelopes until their recipient fetches them.
+ *
+ * Each device of each account owns a mailbox ("username/device",
+ * see `net::devices`) with two queues:
+ *  - `queued`    : envelopes waiting to be handed out
+ *  - `in_flight` : envelopes handed out but not yet acknowledged
+ *
+ * Unacknowledged envelopes are re-queued when the mailbox is subscribed
+ * again, so a client that crashes before persisting a message gets it back.
+ * Only devices listed in a published device list have a mailbox, and a
+ * mailbox holds at most `MAX_MAILBOX_ENVELOPES` envelopes and
+ * `MAX_MAILBOX_BYTES` of ciphertext (queued and in flight together).
+ *
+ * Opening a mailbox requires a session token obtained through the
+ * device-key challenge-response login (see `server::auth`), and a
+ * connection may only send envelopes as the user and device it logged
+ * in as. Accounts publish their signed device lists to the relay, which
+ * hands them out to anyone who asks. Once a device is removed from its
+ * account's list, its mailbox is dropped, nothing is queued for it and
+ * it can no longer send.
+ *
+ * Devices being linked meet on rendezvous channels (see `net::link`):
+ * small queues of opaque messages, named by a hash of the linking code,
+ * that anyone can post to or drain without logging in. At most
+ * `MAX_LINK_CHANNELS` are open at once, each for `LINK_TTL` after it
+ * was opened, like the linking code it serves.
+ *
+ * Encrypted attachments are kept in a content-addressed blob store
+ * (see `server::blobs`): logged-in devices upload them, and anyone
+ * holding a blob id can download it.
+ *
+ * The relay also hosts the username directory (see `server::directory`):
+ * a claimed username is bound to its identity key for logins too. A
+ * relay created with `with_directory_file` keeps the directory on disk.
+ *
+ * The relay of a direct link (`Relay::private`, see `net::p2p`) only
+ * lets socket connections act as the one contact it was opened for.
+ *
+ * The same `Relay` backs the in-memory transport and can be served
+ * over TCP or Unix domain sockets with `serve_tcp` / `serve_unix`.
+ * Socket connections start with a Noise XX handshake in which the
+ * relay proves ownership of its static key (see `net::noise`).
+ */
+
+use crate::net::devices::{self, DeviceList, PRIMARY_DEVICE};
+use crate::net::directory::UsernameClaim;
+use crate::net::link;
+use crate::net::noise::{self, Keypair};
+use crate::net::transparency::DirectoryLookup;
+use crate::net::transport::{Envelope, Frame, FrameReader, FrameWriter};
+use crate::server::auth::{AuthError, Authenticator, SessionToken};
+use crate::server::blobs::{BlobError, BlobStore};
+use crate::server::directory::{Directory, DirectoryError};
+use sodiumoxide::crypto::sign;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Notify;
+
+/* Maximum size of a message posted on a link channel, in bytes */
+const MAX_LINK_MESSAGE: usize = 16 * 1024;
+
+/* Maximum number of messages waiting on a link channel */
+const MAX_LINK_QUEUE: usize = 4;
+
+/* Maximum number of link channels open at once */
+const MAX_LINK_CHANNELS: usize = 1024;
+
+/* How long a link channel stays open (as long as a linking code is shown) */
+pub const LINK_TTL: Duration = Duration::from_secs(10 * 60);
+
+/* Maximum number of envelopes in a mailbox, queued and in flight */
+pub const MAX_MAILBOX_ENVELOPES: usize = 1000;
+
+/* Maximum ciphertext bytes in a mailbox, queued and in flight */
+pub const MAX_MAILBOX_BYTES: usize = 64 * 1024 * 1024;
+
+/* Reasons an envelope can be refused */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DepositError {
+    /* The recipient published no device list, or it does not list the device */
+    UnknownDevice,
+    /* The recipient device's mailbox is full */
+    MailboxFull,
+}
+
+impl fmt::Display for DepositError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            DepositError::UnknownDevice => "unknown recipient device",
+            DepositError::MailboxFull => "recipient mailbox full",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for DepositError {}
+
+/* Why a direct link refuses to act for another account than its peer's */
+const NOT_SERVED: &str = "this link only serves its peer";
+
+/* A single device's mailbox */
+#[derive(Debug, Default)]
+struct Mailbox {
+    queued: VecDeque<Envelope>,
+    in_flight: Vec<Envelope>,
+    notify: Arc<Notify>,
+}
+
+impl Mailbox {
+    /* Whether `envelope` still fits, under `MAX_MAILBOX_ENVELOPES` and `MAX_MAILBOX_BYTES` */
+    fn fits(&self, envelope: &Envelope) -> bool {
+        let held = self.queued.iter().chain(&self.in_flight);
+        let bytes: usize = held.clone().map(|e| e.ciphertext.len()).sum();
+        held.count() < MAX_MAILBOX_ENVELOPES && bytes + envelope.ciphertext.len() <= MAX_MAILBOX_BYTES
+    }
+}
+
+/* A link channel: its messages, and when it closes */
+#[derive(Debug)]
+struct LinkChannel {
+    messages: VecDeque<Vec<u8>>,
+    expires: Instant,
+}
+
+/*
+ * A cheaply cloneable handle to the relay state.
+ *
+ * All clones share the same mailboxes.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Relay {
+    mailboxes: Arc<Mutex<HashMap<String, Mailbox>>>,
+    auth: Arc<Mutex<Authenticator>>,
+    directory: Arc<Mutex<Directory>>,
+    links: Arc<Mutex<HashMap<String, LinkChannel>>>,
+    blobs: Arc<Mutex<BlobStore>>,
+    directory_file: Option<Arc<str>>,
+    remote_only: Option<Arc<str>>,
+}
+
+impl Relay {
+    /* Creates a relay with no mailboxes */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+     * Creates a relay whose directory is loaded from, and saved to after
+     * every accepted claim, the JSON file at `path`. The usernames it
+     * holds are bound for logins again.
+     */
+    pub fn with_directory_file(path: &str) -> Self {
+        let relay = Self {
+            directory_file: Some(path.into()),
+            ..Self::default()
+        };
+        if let Some(directory) = Directory::load(path) {
+            let mut auth = relay.auth.lock().unwrap();
+            for claim in directory.claims() {
+                if let Some(identity_pk) = claim.identity_key() {
+                    let _ = auth.register(&claim.username, identity_pk);
+                }
+            }
+            drop(auth);
+            *relay.directory.lock().unwrap() = directory;
+        }
+        relay
+    }
+
+    /*
+     * Creates the relay of a direct link with `peer`, whose username is
+     * bound to `identity_pk` up front.
+     *
+     * Connections over a socket may only claim, publish a device list
+     * for, or log in as `peer`; the local user reaches the relay in
+     * memory, where nothing is restricted.
+     */
+    pub fn private(peer: &str, identity_pk: sign::PublicKey) -> Result<Self, AuthError> {
+        let relay = Self {
+            remote_only: Some(peer.into()),
+            ..Self::default()
+        };
+        relay.register(peer, identity_pk)?;
+        Ok(relay)
+    }
+
+    /* Whether a socket connection may act as `username` */
+    fn serves_remotely(&self, username: &str) -> bool {
+        self.remote_only.as_deref().is_none_or(|peer| peer == username)
+    }
+
+    /*
+     * Stores an envelope in the mailbox of its recipient's device and
+     * wakes up a pending `fetch`, if any.
+     *
+     * Refused for a device missing from the recipient's published device
+     * list (e.g. a revoked one, or any device of an account that published
+     * none), and when the device's mailbox is full.
+     */
+    pub fn deposit(&self, envelope: Envelope) -> Result<(), DepositError> {
+        if !self.listed(&envelope.recipient, &envelope.device) {
+            return Err(DepositError::UnknownDevice);
+        }
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.entry(devices::mailbox(&envelope.recipient, &envelope.device)).or_default();
+        if !mailbox.fits(&envelope) {
+            return Err(DepositError::MailboxFull);
+        }
+        mailbox.queued.push_back(envelope);
+        mailbox.notify.notify_one();
+        Ok(())
+    }
+
+    /*
+     * Binds `username` to an identity key (see `Authenticator::register`).
+     *
+     * Not reachable from the network: there, only a signed claim binds a
+     * username (see `claim`). Used for keys the relay's owner already trusts.
+     */
+    pub fn register(&self, username: &str, identity_pk: sign::PublicKey) -> Result<(), AuthError> {
+        self.auth.lock().unwrap().register(username, identity_pk)
+    }
+
+    /*
+     * Registers a username claim in the directory.
+     *
+     * The claim is refused if the username is already bound to another
+     * identity key for logins; once accepted, it binds it for logins too.
+     */
+    pub fn claim(&self, claim: UsernameClaim) -> Result<(), DirectoryError> {
+        let mut auth = self.auth.lock().unwrap();
+        let mut directory = self.directory.lock().unwrap();
+        let identity_pk = claim.identity_key().filter(|_| claim.verify()).ok_or(DirectoryError::BadSignature)?;
+        if auth.identity(&claim.username).is_some_and(|pk| *pk != identity_pk) {
+            return Err(DirectoryError::Taken);
+        }
+        let username = claim.username.clone();
+        directory.register(claim)?;
+        if let Some(path) = &self.directory_file {
+            directory.save(path);
+        }
+        auth.register(&username, identity_pk).map_err(|_| DirectoryError::Taken)
+    }
+
+    /* Looks `username` up in the directory, with transparency proofs */
+    pub fn lookup(&self, username: &str, known_size: u64) -> DirectoryLookup {
+        self.directory.lock().unwrap().lookup(username, known_size)
+    }
+
+    /*
+     * Stores a device list (see `Authenticator::publish_devices`) and
+     * drops the mailboxes of the devices it no longer lists.
+     */
+    pub fn publish_devices(&self, list: DeviceList) -> Result<bool, AuthError> {
+        let listed: Vec<String> = list.devices.iter().map(|d| devices::mailbox(&list.username, &d.device_id)).collect();
+        let prefix = format!("{}/", list.username);
+        let stored = self.auth.lock().unwrap().publish_devices(list)?;
+        if stored {
+            self.mailboxes
+                .lock()
+                .unwrap()
+                .retain(|name, _| !name.starts_with(&prefix) || listed.contains(name));
+        }
+        Ok(stored)
+    }
+
+    /*
+     * Whether device `device` of `username` is in the account's device
+     * list (never for an account that published none).
+     */
+    pub fn listed(&self, username: &str, device: &str) -> bool {
+        let device = if device.is_empty() { PRIMARY_DEVICE } else { device };
+        self.auth
+            .lock()
+            .unwrap()
+            .devices(username)
+            .is_some_and(|list| list.device(device).is_some())
+    }
+
+    /* The latest device list published for `username`, if any */
+    pub fn devices(&self, username: &str) -> Option<DeviceList> {
+        self.auth.lock().unwrap().devices(username).cloned()
+    }
+
+    /*
+     * Posts `message` on link channel `channel`, opening it if needed.
+     * Refused if the channel name or the message is malformed, if the
+     * channel is full, or if `MAX_LINK_CHANNELS` are already open.
+     *
+     * Channels older than `LINK_TTL` are closed on the way.
+     */
+    pub fn link_post(&self, channel: &str, message: Vec<u8>) -> bool {
+        if !link::valid_channel(channel) || message.len() > MAX_LINK_MESSAGE {
+            return false;
+        }
+        let mut links = self.links.lock().unwrap();
+        let now = Instant::now();
+        links.retain(|_, c| c.expires > now);
+        if !links.contains_key(channel) && links.len() >= MAX_LINK_CHANNELS {
+            return false;
+        }
+        let channel = links.entry(channel.to_string()).or_insert_with(|| LinkChannel {
+            messages: VecDeque::new(),
+            expires: now + LINK_TTL,
+        });
+        if channel.messages.len() >= MAX_LINK_QUEUE {
+            return false;
+        }
+        channel.messages.push_back(message);
+        true
+    }
+
+    /* Drains the messages waiting on link channel `channel` (none once it closed) */
+    pub fn link_take(&self, channel: &str) -> Vec<Vec<u8>> {
+        let mut links = self.links.lock().unwrap();
+        match links.get_mut(channel) {
+            Some(c) if c.expires > Instant::now() => c.messages.drain(..).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /* Appends a piece to an upload of device `uploader` (see `BlobStore::put`) */
+    pub fn blob_put(&self, uploader: &str, blob_id: &str, offset: u64, data: &[u8], last: bool) -> Result<u64, BlobError> {
+        self.blobs.lock().unwrap().put(uploader, blob_id, offset, data, last)
+    }
+
+    /* A piece of a stored blob and its size (see `BlobStore::get`) */
+    pub fn blob_get(&self, blob_id: &str, offset: u64) -> Option<(u64, Vec<u8>)> {
+        self.blobs.lock().unwrap().get(blob_id, offset)
+    }
+
+    /* Issues a login challenge for device `device` of `username` */
+    pub fn challenge(&self, username: &str, device: &str) -> Result<Vec<u8>, AuthError> {
+        self.auth.lock().unwrap().issue_challenge(username, device)
+    }
+
+    /* Checks a signed challenge and returns a session token */
+    pub fn login(&self, username: &str, device: &str, nonce: &[u8], signature: &[u8]) -> Result<SessionToken, AuthError> {
+        self.auth.lock().unwrap().verify_response(username, device, nonce, signature)
+    }
+
+    /*
+     * Opens the mailbox of the device owning `token` and returns its name,
+     * with the time its session ends.
+     *
+     * Envelopes left in flight by a previous subscription are queued again
+     * (in their original order, ahead of newer envelopes).
+     */
+    pub fn subscribe(&self, token: &str) -> Result<(String, Instant), AuthError> {
+        let session = self.auth.lock().unwrap().check_token(token)?;
+        let name = devices::mailbox(&session.username, &session.device);
+        self.open_mailbox(&name);
+        Ok((name, session.expires_at))
+    }
+
+    fn open_mailbox(&self, name: &str) {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.entry(name.to_string()).or_default();
+        for envelope in mailbox.in_flight.drain(..).rev() {
+            mailbox.queued.push_front(envelope);
+        }
+        if !mailbox.queued.is_empty() {
+            mailbox.notify.notify_one();
+        }
+    }
+
+    /*
+     * Waits until an envelope is available in mailbox `name` and returns it.
+     *
+     * The envelope stays in flight until `acknowledge` is called.
+     */
+    pub async fn fetch(&self, name: &str) -> Envelope {
+        loop {
+            let notify = {
+                let mut mailboxes = self.mailboxes.lock().unwrap();
+                let mailbox = mailboxes.entry(name.to_string()).or_default();
+                if let Some(envelope) = mailbox.queued.pop_front() {
+                    mailbox.in_flight.push(envelope.clone());
+                    return envelope;
+                }
+                mailbox.notify.clone()
+            };
+            notify.notified().await;
+        }
+    }
+
+    /* Drops an in-flight envelope of mailbox `name` once the recipient has stored it */
+    pub fn acknowledge(&self, name: &str, id: &str) {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        if let Some(mailbox) = mailboxes.get_mut(name) {
+            mailbox.in_flight.retain(|e| e.id != id);
+        }
+    }
+
+    /*
+     * Serves a single client connection until it is closed.
+     *
+     * The connection is first secured with a Noise handshake using the
+     * relay's static key `relay_key`. The client must then log in (`Hello` / `Login`) before it can send,
+     * and present its session token in `Subscribe` to open its mailbox.
+     * Both only last until the session expires: sending, uploading and
+     * acknowledging are then refused, and the mailbox stops being pushed
+     * with an `Expired` frame, until the client logs in again.
+     * Directory requests (`Claim`, `Lookup`, `FetchDevices`) need no login,
+     * nor do `PublishDevices` (the list is signed by the account) and
+     * link channels (`LinkPost`, `LinkFetch`) and blob downloads (`BlobGet`);
+     * blob uploads (`BlobPut`) do.
+     * Once subscribed, a background task pushes the mailbox to it as
+     * `Deliver` frames.
+     *
+     * Every request but `Ack` gets exactly one reply, in order: its own
+     * (`Delivered` for a `Deliver`, `Accepted` for `PublishDevices`,
+     * `Subscribe` and `LinkPost`, …) or `Error` if it was refused.
+     */
+    pub async fn serve_connection<R, W>(&self, mut reader: R, mut writer: W, relay_key: &Keypair) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let channel = noise::respond(&mut reader, &mut writer, relay_key).await?;
+        let mut reader = FrameReader::new(reader, Some(channel.recv));
+        let writer = Arc::new(tokio::sync::Mutex::new(FrameWriter::new(writer, Some(channel.send))));
+        let mut logged_in: Option<SessionToken> = None;
+        let mut mailbox: Option<(String, Instant)> = None;
+        let mut pusher: Option<tokio::task::JoinHandle<()>> = None;
+
+        let result = loop {
+            let frame = match reader.recv().await {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let reply = match frame {
+                Frame::PublishDevices { list } if !self.serves_remotely(&list.username) => Some(Frame::Error {
+                    reason: NOT_SERVED.to_string(),
+                }),
+                Frame::Hello { username, .. } | Frame::Login { username, .. } if !self.serves_remotely(&username) => {
+                    Some(Frame::Error { reason: NOT_SERVED.to_string() })
+                }
+                Frame::Claim { claim } if !self.serves_remotely(&claim.username) => Some(Frame::Claimed {
+                    username: claim.username,
+                    error: Some(NOT_SERVED.to_string()),
+                }),
+                Frame::PublishDevices { list } => Some(match self.publish_devices(list) {
+                    Ok(_) => Frame::Accepted,
+                    Err(e) => Frame::Error { reason: e.to_string() },
+                }),
+                Frame::Hello { username, device } => Some(match self.challenge(&username, &device) {
+                    Ok(nonce) => Frame::Challenge { nonce },
+                    Err(e) => Frame::Error { reason: e.to_string() },
+                }),
+                Frame::Login { username, device, nonce, signature } => {
+                    Some(match self.login(&username, &device, &nonce, &signature) {
+                        Ok(session) => {
+                            let reply = Frame::Session {
+                                token: session.token.clone(),
+                                expires_in: session.expires_at.saturating_duration_since(Instant::now()).as_secs(),
+                            };
+                            logged_in = Some(session);
+                            reply
+                        }
+                        Err(e) => Frame::Error { reason: e.to_string() },
+                    })
+                }
+                Frame::Subscribe { token } => match self.subscribe(&token) {
+                    Ok((name, expires_at)) => {
+                        if let Some(task) = pusher.take() {
+                            task.abort();
+                        }
+                        let relay = self.clone();
+                        let writer = writer.clone();
+                        let owner = name.clone();
+                        pusher = Some(tokio::spawn(async move {
+                            loop {
+                                /* Nothing is taken from the mailbox once the session is over */
+                                let frame = match tokio::time::timeout_at(expires_at.into(), relay.fetch(&owner)).await {
+                                    Ok(envelope) => Frame::Deliver { envelope },
+                                    Err(_) => Frame::Expired,
+                                };
+                                let expired = matches!(frame, Frame::Expired);
+                                if writer.lock().await.send(&frame).await.is_err() || expired {
+                                    break;
+                                }
+                            }
+                        }));
+                        mailbox = Some((name, expires_at));
+                        Some(Frame::Accepted)
+                    }
+                    Err(e) => Some(Frame::Error { reason: e.to_string() }),
+                },
+                Frame::Deliver { envelope } => {
+                    let sent_from = devices::mailbox(&envelope.sender, &envelope.sender_device);
+                    let id = envelope.id.clone();
+                    let refused = if live(&logged_in).map(|s| devices::mailbox(&s.username, &s.device)) != Some(sent_from)
+                        || !self.listed(&envelope.sender, &envelope.sender_device)
+                    {
+                        Some("sender does not match the logged-in device".to_string())
+                    } else {
+                        self.deposit(envelope).err().map(|e| e.to_string())
+                    };
+                    Some(match refused {
+                        None => Frame::Delivered { id },
+                        Some(reason) => Frame::Error { reason },
+                    })
+                }
+                Frame::Ack { id } => {
+                    if let Some((name, _)) = mailbox.as_ref().filter(|(_, expires_at)| *expires_at > Instant::now()) {
+                        self.acknowledge(name, &id);
+                    }
+                    None
+                }
+                Frame::Claim { claim } => Some(Frame::Claimed {
+                    username: claim.username.clone(),
+                    error: self.claim(claim).err().map(|e| e.to_string()),
+                }),
+                Frame::Lookup { username, known_size } => Some(Frame::Found {
+                    lookup: self.lookup(&username, known_size),
+                    username,
+                }),
+                Frame::FetchDevices { username } => Some(Frame::Devices {
+                    list: self.devices(&username),
+                    username,
+                }),
+                Frame::LinkPost { channel, message } => Some(match self.link_post(&channel, message) {
+                    true => Frame::Accepted,
+                    false => Frame::Error {
+                        reason: "link message refused".to_string(),
+                    },
+                }),
+                Frame::LinkFetch { channel } => Some(Frame::LinkMessages {
+                    messages: self.link_take(&channel),
+                    channel,
+                }),
+                Frame::BlobPut { blob_id, offset, data, last } => {
+                    let stored = match live(&logged_in) {
+                        Some(session) => self
+                            .blob_put(&devices::mailbox(&session.username, &session.device), &blob_id, offset, &data, last)
+                            .map_err(|e| e.to_string()),
+                        None => Err("blob uploads need a login".to_string()),
+                    };
+                    Some(match stored {
+                        Ok(received) => Frame::BlobStored { blob_id, received, error: None },
+                        Err(reason) => Frame::BlobStored { blob_id, received: 0, error: Some(reason) },
+                    })
+                }
+                Frame::BlobGet { blob_id, offset } => {
+                    let found = self.blob_get(&blob_id, offset);
+                    Some(Frame::BlobData {
+                        blob_id,
+                        offset,
+                        total: found.as_ref().map(|(total, _)| *total),
+                        data: found.map(|(_, data)| data).unwrap_or_default(),
+                    })
+                }
+                /* Relay-to-client frames are not accepted from clients */
+                Frame::Challenge { .. }
+                | Frame::Session { .. }
+                | Frame::Claimed { .. }
+                | Frame::Found { .. }
+                | Frame::Devices { .. }
+                | Frame::LinkMessages { .. }
+                | Frame::BlobStored { .. }
+                | Frame::BlobData { .. }
+                | Frame::Delivered { .. }
+                | Frame::Accepted
+                | Frame::Expired
+                | Frame::Error { .. } => None,
+            };
+
+            if let Some(reply) = reply {
+                if let Err(e) = writer.lock().await.send(&reply).await {
+                    break Err(e);
+                }
+            }
+        };
+
+        if let Some(task) = pusher {
+            task.abort();
+        }
+        result
+    }
+
+    /* Accepts TCP clients forever, serving each one on its own task */
+    pub async fn serve_tcp(&self, listener: tokio::net::TcpListener, relay_key: Keypair) -> io::Result<()> {
+        let relay_key = Arc::new(relay_key);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let relay = self.clone();
+            let relay_key = relay_key.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = stream.into_split();
+                let _ = relay.serve_connection(reader, writer, &relay_key).await;
+            });
+        }
+    }
+
+    /* Accepts Unix domain socket clients forever */
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, listener: tokio::net::UnixListener, relay_key: Keypair) -> io::Result<()> {
+        let relay_key = Arc::new(relay_key);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let relay = self.clone();
+            let relay_key = relay_key.clone();
+            tokio::spawn(async move {
+                let (reader, writer) = stream.into_split();
+                let _ = relay.serve_connection(reader, writer, &relay_key).await;
+            });
+        }
+    }
+}
+
+/* The session a connection logged in with, unless it has expired */
+fn live(session: &Option<SessionToken>) -> Option<&SessionToken> {
+    session.as_ref().filter(|s| s.expires_at > Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::user::User;
+    use crate::net::transport::{secure_client, StreamTransport, Transport};
+
+    /* Runs `test` on a fresh runtime */
+    fn run<F: std::future::Future>(test: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(test)
+    }
+
+    /* Serves a new connection to `relay` over an in-memory pipe */
+    async fn connect(relay: &Relay) -> impl Transport {
+        let relay_key = Keypair::generate();
+        let relay_pk = relay_key.public;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let serving = relay.clone();
+        tokio::spawn(async move {
+            let _ = serving.serve_connection(server_reader, server_writer, &relay_key).await;
+        });
+        let (reader, writer) = tokio::io::split(client);
+        let (reader, writer) = secure_client(reader, writer, &relay_pk).await.unwrap();
+        StreamTransport::new(reader, writer)
+    }
+
+    #[test]
+    fn subscribe_claims_the_username() {
+        run(async {
+            let relay = Relay::new();
+            let alice = User::new("alice", 1);
+            connect(&relay).await.subscribe(&alice).await.unwrap();
+            assert_eq!(relay.lookup("alice", 0).claim, alice.username_claim());
+        });
+    }
+
+    #[test]
+    fn squatted_username_cannot_log_in() {
+        run(async {
+            let relay = Relay::new();
+            let alice = User::new("alice", 1);
+            connect(&relay).await.subscribe(&alice).await.unwrap();
+
+            /* Someone else's keys under the same name */
+            let mallory = User::new("alice", 1);
+            assert!(relay.claim(mallory.username_claim().unwrap()).is_err());
+            assert!(connect(&relay).await.subscribe(&mallory).await.is_err());
+        });
+    }
+
+    /* An empty envelope from `from` to the primary device of `to` */
+    fn envelope(id: &str, from: &User, to: &str) -> Envelope {
+        Envelope {
+            id: id.to_string(),
+            sender: from.username().to_string(),
+            sender_device: from.device_id.clone(),
+            recipient: to.to_string(),
+            device: PRIMARY_DEVICE.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delivery_is_confirmed() {
+        run(async {
+            let relay = Relay::new();
+            let alice = User::new("alice", 1);
+            let bob = User::new("bob", 1);
+            let alice_link = connect(&relay).await;
+            let bob_link = connect(&relay).await;
+            alice_link.subscribe(&alice).await.unwrap();
+            bob_link.subscribe(&bob).await.unwrap();
+
+            alice_link.send(envelope("1", &alice, "bob")).await.unwrap();
+            assert_eq!(bob_link.receive().await.unwrap().id, "1");
+        });
+    }
+
+    #[test]
+    fn refused_delivery_fails() {
+        run(async {
+            let relay = Relay::new();
+            let alice = User::new("alice", 1);
+            let bob = User::new("bob", 1);
+            let link = connect(&relay).await;
+            connect(&relay).await.subscribe(&bob).await.unwrap();
+
+            /* Not logged in yet */
+            assert!(link.send(envelope("1", &alice, "bob")).await.is_err());
+            link.subscribe(&alice).await.unwrap();
+            /* Not as ourselves */
+            assert!(link.send(envelope("2", &bob, "alice")).await.is_err());
+            /* To a device missing from bob's list */
+            let mut unlisted = envelope("3", &alice, "bob");
+            unlisted.device = "phone".to_string();
+            assert!(link.send(unlisted).await.is_err());
+            /* The connection still works afterwards */
+            link.link_post(&"ab".repeat(16), vec![1]).await.ok();
+            assert!(link.link_post("not a channel", vec![1]).await.is_err());
+        });
+    }
+
+    #[test]
+    fn expired_session_is_not_live() {
+        let session = |expires_at| {
+            Some(SessionToken {
+                token: "token".to_string(),
+                username: "alice".to_string(),
+                device: PRIMARY_DEVICE.to_string(),
+                expires_at,
+            })
+        };
+        let now = Instant::now();
+        assert!(live(&session(now + std::time::Duration::from_secs(60))).is_some());
+        assert!(live(&session(now)).is_none());
+        assert!(live(&None).is_none());
+    }
+
+    #[test]
+    fn direct_links_only_serve_their_peer() {
+        run(async {
+            let bob = User::new("bob", 1);
+            let relay = Relay::private("bob", bob.identity_pk).unwrap();
+
+            /* A stranger cannot take a free name over the socket */
+            let mallory = User::new("mallory", 1);
+            assert!(connect(&relay).await.subscribe(&mallory).await.is_err());
+            assert!(relay.lookup("mallory", 0).claim.is_none());
+            assert!(relay.devices("mallory").is_none());
+
+            /* Nor bob's, without bob's keys */
+            assert!(connect(&relay).await.subscribe(&User::new("bob", 1)).await.is_err());
+
+            let link = connect(&relay).await;
+            link.subscribe(&bob).await.unwrap();
+            assert!(link.send(envelope("1", &mallory, "bob")).await.is_err());
+        });
+    }
+
+    #[test]
+    fn only_listed_devices_get_mail() {
+        let relay = Relay::new();
+        let alice = User::new("alice", 1);
+        let bob = User::new("bob", 1);
+        assert_eq!(relay.deposit(envelope("1", &alice, "bob")), Err(DepositError::UnknownDevice));
+
+        relay.claim(bob.username_claim().unwrap()).unwrap();
+        relay.publish_devices(bob.devices.clone()).unwrap();
+        for i in 0..MAX_MAILBOX_ENVELOPES {
+            relay.deposit(envelope(&i.to_string(), &alice, "bob")).unwrap();
+        }
+        assert_eq!(relay.deposit(envelope("full", &alice, "bob")), Err(DepositError::MailboxFull));
+
+        let carol = User::new("carol", 1);
+        relay.claim(carol.username_claim().unwrap()).unwrap();
+        relay.publish_devices(carol.devices.clone()).unwrap();
+        let mut large = envelope("large", &alice, "carol");
+        large.ciphertext = vec![0; MAX_MAILBOX_BYTES / 2];
+        relay.deposit(large.clone()).unwrap();
+        relay.deposit(large.clone()).unwrap();
+        assert_eq!(relay.deposit(envelope("small", &alice, "carol")), Ok(()));
+        large.ciphertext.push(0);
+        assert_eq!(relay.deposit(large), Err(DepositError::MailboxFull));
+    }
+
+    #[test]
+    fn link_channels_are_bounded() {
+        let relay = Relay::new();
+        let channel = |i: usize| format!("{i:064x}");
+        for i in 0..MAX_LINK_CHANNELS {
+            assert!(relay.link_post(&channel(i), vec![1]));
+        }
+        assert!(!relay.link_post(&channel(MAX_LINK_CHANNELS), vec![1]), "too many channels");
+        for _ in 1..MAX_LINK_QUEUE {
+            assert!(relay.link_post(&channel(0), vec![2]));
+        }
+        assert!(!relay.link_post(&channel(0), vec![3]), "channel full");
+        assert_eq!(relay.link_take(&channel(0)).len(), MAX_LINK_QUEUE);
+        assert!(relay.link_take(&channel(0)).is_empty());
+
+        /* Once expired, a channel gives nothing and frees its place */
+        let expired = Instant::now() - std::time::Duration::from_secs(1);
+        relay.links.lock().unwrap().get_mut(&channel(1)).unwrap().expires = expired;
+        assert!(relay.link_take(&channel(1)).is_empty());
+        assert!(relay.link_post(&channel(MAX_LINK_CHANNELS), vec![1]));
+        assert!(!relay.link_post(&channel(MAX_LINK_CHANNELS + 1), vec![1]));
+    }
+
+    #[test]
+    fn unsigned_claim_is_refused() {
+        let relay = Relay::new();
+        let mut claim = User::new("alice", 1).username_claim().unwrap();
+        claim.identity_pk = User::new("mallory", 1).identity_pk.as_ref().to_vec();
+        assert_eq!(relay.claim(claim), Err(DirectoryError::BadSignature));
+        assert!(relay.lookup("alice", 0).claim.is_none());
+    }
+}
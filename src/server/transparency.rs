@@ -0,0 +1,152 @@
+/*
+ * This module defines the `TransparencyLog`, the relay-side append-only
+ * Merkle tree of username claims (formats in `net::transparency`).
+ *
+ * Leaves are never removed or modified. Tree heads are signed with the
+ * log key, generated when the log is created. The log is kept in memory;
+ * a relay that saves its directory (see `server::directory`) rebuilds it
+ * under the same key after a restart.
+ *
+ * Roots and proofs are recomputed from the leaves on demand
+ * (RFC 6962, section 2.1), which is plenty for a demo-sized directory.
+ */
+
+use crate::net::transparency::{empty_root, node_hash, tree_head_message, Hash, SignedTreeHead};
+use sodiumoxide::crypto::sign;
+
+/* Append-only Merkle log with its signing key */
+#[derive(Debug)]
+pub struct TransparencyLog {
+    leaves: Vec<Hash>,
+    public: sign::PublicKey,
+    secret: sign::SecretKey,
+}
+
+impl Default for TransparencyLog {
+    fn default() -> Self {
+        let (public, secret) = sign::gen_keypair();
+        Self {
+            leaves: Vec::new(),
+            public,
+            secret,
+        }
+    }
+}
+
+/* Largest power of two strictly smaller than `n` (n >= 2) */
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/* Merkle tree hash of `leaves` */
+fn root_of(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => empty_root(),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root_of(&leaves[..k]), &root_of(&leaves[k..]))
+        }
+    }
+}
+
+/* Audit path of leaf `m` in the tree of `leaves` */
+fn path(m: usize, leaves: &[Hash]) -> Vec<Hash> {
+    let n = leaves.len();
+    if n <= 1 {
+        return Vec::new();
+    }
+    let k = split(n);
+    let (mut proof, sibling) = if m < k {
+        (path(m, &leaves[..k]), root_of(&leaves[k..]))
+    } else {
+        (path(m - k, &leaves[k..]), root_of(&leaves[..k]))
+    };
+    proof.push(sibling);
+    proof
+}
+
+/* Consistency proof between the first `m` leaves and all of `leaves` */
+fn subproof(m: usize, leaves: &[Hash], complete: bool) -> Vec<Hash> {
+    let n = leaves.len();
+    if m == n {
+        return if complete { Vec::new() } else { vec![root_of(leaves)] };
+    }
+    let k = split(n);
+    let (mut proof, sibling) = if m <= k {
+        (subproof(m, &leaves[..k], complete), root_of(&leaves[k..]))
+    } else {
+        (subproof(m - k, &leaves[k..], false), root_of(&leaves[..k]))
+    };
+    proof.push(sibling);
+    proof
+}
+
+impl TransparencyLog {
+    /* Creates an empty log with a fresh log key */
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Creates an empty log signing with an existing log key */
+    pub fn with_key(public: sign::PublicKey, secret: sign::SecretKey) -> Self {
+        Self {
+            leaves: Vec::new(),
+            public,
+            secret,
+        }
+    }
+
+    /* The secret log key, to save it */
+    pub fn secret_key(&self) -> &sign::SecretKey {
+        &self.secret
+    }
+
+    /* The public key tree heads are signed with */
+    pub fn public_key(&self) -> &sign::PublicKey {
+        &self.public
+    }
+
+    /* Number of leaves */
+    pub fn size(&self) -> u64 {
+        self.leaves.len() as u64
+    }
+
+    /* Appends a leaf and returns its index */
+    pub fn append(&mut self, leaf: Hash) -> u64 {
+        self.leaves.push(leaf);
+        self.size() - 1
+    }
+
+    /* Signs the current tree head */
+    pub fn signed_head(&self) -> SignedTreeHead {
+        let root = root_of(&self.leaves);
+        let signature = sign::sign_detached(&tree_head_message(self.size(), &root), &self.secret);
+        SignedTreeHead {
+            size: self.size(),
+            root,
+            signature: signature.as_ref().to_vec(),
+        }
+    }
+
+    /* Inclusion proof of leaf `index` in the current tree */
+    pub fn inclusion_proof(&self, index: u64) -> Vec<Hash> {
+        if index >= self.size() {
+            return Vec::new();
+        }
+        path(index as usize, &self.leaves)
+    }
+
+    /*
+     * Consistency proof from the tree of `old_size` leaves to the current tree.
+     *
+     * Empty if there is nothing to prove (or nothing that can be proven,
+     * when `old_size` is larger than the log: the client will notice).
+     */
+    pub fn consistency_proof(&self, old_size: u64) -> Vec<Hash> {
+        if old_size == 0 || old_size >= self.size() {
+            return Vec::new();
+        }
+        subproof(old_size as usize, &self.leaves, true)
+    }
+}
@@ -10,128 +10,2258 @@
  * The UI also integrates the cryptographic layer:
  * messages are encrypted before being stored in a session
  * and decrypted before being displayed in the chat window.
+ *
+ * Networking goes through a `Transport` (the relay, or a direct peer-to-peer
+ * link for contacts reached by host:port): outgoing envelopes are queued
+ * in the `Outbox` and sent with a `Task` (retried on a timer while they
+ * fail), and incoming ones are delivered by `subscription()` as
+ * `Message::EnvelopeReceived`.
+ *
+ * Group conversations use Sender Keys (see `client::groups`): a group
+ * message is encrypted once and fanned out to every member through
+ * the outbox. Members and admins change through signed updates
+ * (see `client::membership`), shown in the group info panel. Groups
+ * created in TreeKEM mode use a ratchet tree instead (see
+ * `client::treekem`): membership changes are followed by a commit from
+ * the first admin, and the panel shows the tree and the commit logs.
+ *
+ * Once connected, the client publishes its signed username claim and
+ * checks the directory claim of every contact (see `net::directory`),
+ * each lookup being verified against the key transparency log
+ * (see `client::transparency`).
+ *
+ * The client runs as one device of its account (see `client::devices`):
+ * one-to-one messages are encrypted for every device of the contact and
+ * synced to our other devices, whose signed device lists are fetched
+ * from the relay once connected, and again whenever an envelope comes
+ * from a device we do not know yet. On the primary device, the left
+ * column shows a one-time code to link a new device (see `client::link`)
+ * and lets the user revoke a linked device, or send it the history of
+ * the one-to-one conversations (see `client::history`).
+ *
+ * Files are attached by path: each one is encrypted under its own key
+ * and uploaded to the relay's blob store, and only its reference goes
+ * in the message (see `client::attachments`). Received attachments are
+ * downloaded, checked and saved into `downloads/` on demand.
+ *
+ * Contacts are managed from the contacts panel: added from their public
+ * bundle (pasted) or from the directory, given a local nickname, removed
+ * or blocked. The list is saved to `contacts.json` after every change.
+ *
+ * The send and receive logs are not shown in the chat: clicking a
+ * message opens the protocol inspector, one collapsible row per step
+ * (see `ui::inspector`), or its key agreement drawn on a canvas, whose
+ * ratchet steps can be played over the whole conversation (see
+ * `ui::visualizer`). Its panel is built in `inspect`.
+ */
+
+mod inspect;
+
+use crate::client::attachments::{self, Attachment};
+use crate::client::cards::ContactCard;
+use crate::client::contacts::{self, Contacts};
+use crate::client::devices::{self, SyncedMessage};
+use crate::client::edits::{self, Revisions, SentTimes};
+use crate::client::groups::{self, Group, GroupError, GroupMessage, SenderKeyDistribution};
+use crate::client::history::{self, HistoryMessage, IncomingTransfer};
+use crate::client::link;
+use crate::client::membership::{Applied, GroupOp, GroupUpdate, GroupUpdates};
+use crate::client::outbox::{self, DeliveryState, Outbox, OutboxEntry};
+use crate::client::payload::Payload;
+use crate::client::reactions::{self, Reactions};
+use crate::client::replies::{self, Quote};
+use crate::client::receipts::{Receipt, ReceiptKind};
+use crate::client::sessions::{new_message_id, Session, StoredMessage};
+use crate::client::settings::Settings;
+use crate::client::transparency::{Monitor, TransparencyError};
+use crate::client::treekem::{Proposal, TreeKem, TreeKemError, TreeKemMessage};
+use crate::client::typing::{Typing, TypingSignal};
+use crate::client::user::{PublicBundle, User};
+use crate::net::devices::{self as device_list, DeviceList, PRIMARY_DEVICE};
+use crate::net::directory::{self, UsernameClaim};
+use crate::net::link::{self as link_code, LinkCode, LinkRequest, LinkResponse};
+use crate::net::p2p;
+use crate::net::transport::{Envelope, EnvelopeKind, Transport};
+use crate::ui::inspector::{Inspector, Step};
+use inspect::{diagram_of, inspector_panel, inspector_steps, ratchet_label};
+use crate::ui::markdown;
+use crate::ui::visualizer::{self, Visualizer};
+use iced::clipboard;
+use iced::widget::checkbox;
+use iced::border::{Border, Radius};
+use iced::futures::SinkExt;
+use iced::widget::{button, column, container, mouse_area, row, scrollable, text, text_input, Column, Row};
+use iced::{color, Alignment, Background, Color, Element, Length, Subscription, Task, Theme};
+use sodiumoxide::crypto::{box_, sign};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+ * Holds all application state required by the GUI.
+ *
+ * Fields:
+ *  - `input_value`      : Current text inside the message input field
+ *  - `contacts`         : Contact list (other users)
+ *  - `selected_contact` : Currently selected conversation (contact name or group id)
+ *  - `current_user`     : The active user of this client
+ *  - `session`          : Persistent conversations, stored on disk
+ *  - `outbox`           : Outgoing envelopes and their delivery state, stored on disk
+ *  - `settings`         : Privacy preferences of the current user, stored on disk
+ *  - `typing`           : Typing indicators (in memory only, never persisted)
+ *  - `transport`        : Network transport, if connected
+ *  - `transport_status` : Last transport error, shown under the input field
+ *  - `direct_input`     : host:port typed for a direct (peer-to-peer) link
+ *  - `direct`           : Direct links per contact, used instead of `transport`
+ *  - `direct_status`    : Human-readable state of each direct link
+ *  - `directory`        : Outcome of the directory check of each contact
+ *  - `monitor`          : Key transparency monitor, verifies every directory lookup
+ *  - `transparency_warning`: Set when the relay's log diverged, shown in red
+ *  - `group_name_input` : Name typed for a new group
+ *  - `group_members_input`: Comma-separated members typed for a new group
+ *  - `group_treekem`    : Whether the new group uses TreeKEM instead of Sender Keys
+ *  - `group_rename_input`: New name typed in the group info panel
+ *  - `group_invite_input`: Username typed to invite in the group info panel
+ *  - `held`             : Group messages and sender keys waiting for their
+ *                         sender's key or their group (not acknowledged),
+ *                         with when they were first held
+ *  - `arrived_on`       : The transport each envelope not acknowledged yet
+ *                         arrived on (relay or direct link), to acknowledge it there
+ *  - `link_offer`       : Linking code shown on the primary device, and when it expires
+ *  - `device_status`    : Outcome of the last device operation (link, revoke, history)
+ *  - `transfers`        : History transfers being received, by id
+ *  - `attach_input`     : Path of the file typed to attach
+ *  - `attach_status`    : Outcome of the last attachment upload
+ *  - `downloads`        : State of each attachment download, by blob id
+ *  - `editing`          : Id of our message being edited (its new text is in the input)
+ *  - `edit_window_input`: Edit window typed in the settings, in minutes
+ *  - `reacting`         : Id of the message whose reaction picker is open
+ *  - `replying`         : The message the next one replies to
+ *  - `highlighted`      : Id of the quoted message last jumped to
+ *  - `raw`              : Ids of the messages shown as written instead of formatted
+ *  - `share_input`      : Username typed to share as a contact card
+ *  - `card_status`      : Outcome of the last contact card shared or imported
+ *  - `contacts_panel`   : Whether the contacts panel is open
+ *  - `contact_input`    : Username or public bundle typed in the contacts panel
+ *  - `contact_status`   : Outcome of the last change in the contacts panel
+ *  - `renaming`         : Contact whose nickname is being edited, and the nickname typed
+ *  - `inspector`        : What the protocol inspector shows (see `ui::inspector`)
+ *  - `decrypted`        : The messages of each conversation opened so far, decrypted
+ *                         (`None` where unreadable), as of its `Session::changes`
+ */
+pub struct UI {
+    input_value: String,
+    pub contacts: Contacts,
+    selected_contact: Option<String>,
+    pub current_user: User,
+    pub session: Session,
+    pub outbox: Outbox,
+    pub settings: Settings,
+    typing: Typing,
+    pub transport: Option<Arc<dyn Transport>>,
+    transport_status: Option<String>,
+    direct_input: String,
+    direct: HashMap<String, Arc<dyn Transport>>,
+    direct_status: HashMap<String, String>,
+    directory: HashMap<String, ClaimStatus>,
+    monitor: Arc<Monitor>,
+    transparency_warning: Option<String>,
+    group_name_input: String,
+    group_members_input: String,
+    group_treekem: bool,
+    group_rename_input: String,
+    group_invite_input: String,
+    held: Vec<(u64, Envelope)>,
+    arrived_on: HashMap<String, Arc<dyn Transport>>,
+    link_offer: Option<(LinkCode, u64)>,
+    device_status: Option<String>,
+    transfers: HashMap<String, PendingTransfer>,
+    attach_input: String,
+    attach_status: Option<String>,
+    downloads: HashMap<String, String>,
+    editing: Option<String>,
+    edit_window_input: String,
+    reacting: Option<String>,
+    replying: Option<Quote>,
+    highlighted: Option<String>,
+    raw: HashSet<String>,
+    share_input: String,
+    card_status: Option<String>,
+    contacts_panel: bool,
+    contact_input: String,
+    contact_status: Option<String>,
+    renaming: Option<(String, String)>,
+    inspector: Inspector,
+    decrypted: HashMap<String, (u64, Vec<Option<Opened>>)>,
+}
+
+/* Id of the scrollable holding the messages of the open conversation */
+const MESSAGES_SCROLL: &str = "messages";
+
+/* The contact list, as managed from the contacts panel */
+const CONTACTS_FILE: &str = "contacts.json";
+
+/* Directory where downloaded attachments are saved */
+const DOWNLOAD_DIR: &str = "downloads";
+
+/* Number of history transfers received at the same time */
+const MAX_TRANSFERS: usize = 4;
+
+/* How long a linking code is valid, in milliseconds */
+const LINK_CODE_TTL_MS: u64 = 10 * 60 * 1000;
+
+/* How long an envelope is held waiting for its group or sender key, in milliseconds */
+const HELD_TTL_MS: u64 = 60 * 60 * 1000;
+
+/* How many envelopes are held at most, from one sender and in total */
+const MAX_HELD_PER_SENDER: usize = 200;
+const MAX_HELD: usize = 1000;
+
+/*
+ * A history transfer being received.
+ *
+ * Fields:
+ *  - `from`      : The sending device
+ *  - `transfer`  : The offer and chunks received so far
+ *  - `envelopes` : Ids of the envelopes that carried them, acknowledged only
+ *                  once the transfer is opened or rejected, so that a restart
+ *                  in the middle of it gets them again
+ */
+struct PendingTransfer {
+    from: String,
+    transfer: IncomingTransfer,
+    envelopes: Vec<String>,
+}
+
+/* Outcome of checking a contact against the username directory */
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClaimStatus {
+    /* The directory claim (kept, to share in contact cards) binds the username to the contact's identity key */
+    Verified(UsernameClaim),
+    /* Nobody claimed the username (yet) */
+    Unregistered,
+    /* The claim is invalid, or for another key; or the lookup failed */
+    Rejected(String),
+}
+
+impl UI {
+    /*
+     * Creates a new `UI` state initialized with the provided contacts
+     * and current user. It automatically attempts to load any existing
+     * session data from `session.json`, queued messages from `outbox.json`
+     * and preferences from `settings.json`.
+     */
+    pub fn with_contacts(contacts: Contacts, current_user: User) -> Self {
+        let session = Session::load("session.json");
+        let outbox = Outbox::load("outbox.json");
+        let settings = Settings::load("settings.json");
+        let edit_window_input = (settings.edit_window / 60).to_string();
+        Self {
+            input_value: String::new(),
+            contacts,
+            selected_contact: None,
+            current_user,
+            session,
+            outbox,
+            settings,
+            typing: Typing::default(),
+            transport: None,
+            transport_status: None,
+            direct_input: String::new(),
+            direct: HashMap::new(),
+            direct_status: HashMap::new(),
+            directory: HashMap::new(),
+            monitor: Arc::new(Monitor::new()),
+            transparency_warning: None,
+            group_name_input: String::new(),
+            group_members_input: String::new(),
+            group_treekem: false,
+            group_rename_input: String::new(),
+            group_invite_input: String::new(),
+            held: Vec::new(),
+            arrived_on: HashMap::new(),
+            link_offer: None,
+            device_status: None,
+            transfers: HashMap::new(),
+            attach_input: String::new(),
+            attach_status: None,
+            downloads: HashMap::new(),
+            editing: None,
+            edit_window_input,
+            reacting: None,
+            replying: None,
+            highlighted: None,
+            raw: HashSet::new(),
+            share_input: String::new(),
+            card_status: None,
+            contacts_panel: false,
+            contact_input: String::new(),
+            contact_status: None,
+            renaming: None,
+            inspector: Inspector::default(),
+            decrypted: HashMap::new(),
+        }
+    }
+
+    /* Attaches a transport used to send and receive envelopes */
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /* Replaces the transparency monitor (e.g. with one that remembers the log across runs) */
+    pub fn with_monitor(mut self, monitor: Monitor) -> Self {
+        self.monitor = Arc::new(monitor);
+        self
+    }
+
+    /* The transport to reach `peer`: their direct link if any, else the relay */
+    fn transport_for(&self, peer: &str) -> Option<Arc<dyn Transport>> {
+        self.direct.get(peer).cloned().or_else(|| self.transport.clone())
+    }
+}
+
+/*
+ * Represents all possible messages/events that can occur in the UI.
+ *
+ * - `InputChanged`: Fired when the user types in the input field (drives typing indicators)
+ * - `Send`        : Fired when the user presses "Enter" or clicks "Send"
+ * - `SelectContact`: Fired when the user selects a contact from the list
+ * - `TransportConnected`: A transport finished connecting (or failed to)
+ * - `TransportFinished`: An acknowledge operation completed
+ * - `EnvelopeArrived`: A transport (relay or direct link) delivered an envelope for us
+ * - `EnvelopeReceived`: An envelope to process (arrived, or held until it could be)
+ * - `OutboxSent`   : A send attempt for an outbox entry completed
+ * - `RetryMessage` : The user asked to retry a failed message
+ * - `Tick`         : Periodic timer driving outbox retries, typing and held envelope expiry
+ * - `ToggleReadReceipts`: The user changed the read receipts privacy setting
+ * - `ToggleTypingIndicators`: The user changed the typing indicators setting
+ * - `DirectAddressChanged`: The user edits the host:port of a direct link
+ * - `ConnectDirect` / `ListenDirect`: Open a direct link with the selected contact
+ * - `DirectConnected`: A direct link was established (or failed) for a contact
+ * - `ClaimPublished`: Our username claim was registered in the directory
+ *                    and found in the log (or not)
+ * - `ClaimFound`   : The verified directory lookup of a contact completed
+ * - `GroupNameChanged` / `GroupMembersChanged` / `ToggleGroupTreeKem`: The user edits the new group form
+ * - `CreateGroup`  : Create a group and distribute our sender key to its members
+ * - `GroupRenameChanged` / `GroupInviteChanged`: The user edits the group info panel
+ * - `GroupAction`  : Sign and apply a change to the selected group, and send it to its members
+ * - `TreeKemUpdate`: Refresh our keys in the ratchet tree of the selected group (TreeKEM mode)
+ * - `DevicesFetched`: The device list of an account (a contact's or ours) was fetched
+ * - `LinkDevice`   : Show a one-time code to link a new device (or withdraw it)
+ * - `LinkPolled`   : The link requests waiting on the relay were fetched
+ * - `RevokeDevice` : Remove a (lost) device from our device list
+ * - `DevicesPublished`: Our new device list was published (or not)
+ * - `SendHistory`  : Send our one-to-one history to another of our devices
+ * - `AttachPathChanged`: The user edits the path of the file to attach
+ * - `Attach`       : Encrypt and upload the file, then send its reference
+ * - `AttachmentUploaded`: The upload of an attachment for a conversation completed
+ * - `DownloadAttachment`: Download, check and save an attachment of a conversation
+ * - `AttachmentDownloaded`: A download completed (the path it was saved to)
+ * - `EditMessage`  : Start editing one of our messages (id, current text)
+ * - `CancelEdit`   : Stop editing, without sending
+ * - `DeleteMessage`: Delete one of our messages for everyone
+ * - `EditWindowChanged`: The user edits the edit window setting (minutes)
+ * - `React`        : React to a message (id, emoji), or withdraw our reaction (`true`)
+ * - `ToggleReactionPicker`: Open (or close) the reaction picker of a message
+ * - `ReplyTo`      : Quote a message in the next one sent
+ * - `CancelReply`  : Send the next message without the quote
+ * - `HighlightQuoted`: Scroll to the original of a quote and highlight it (or stop highlighting it)
+ * - `ToggleRaw`    : Show a message as written, or formatted again
+ * - `ShareInputChanged`: The user edits the username to share
+ * - `ShareCard`    : Send the contact card of that user (or ours) in the conversation
+ * - `ImportCard`   : Add the contact introduced by a card to our contacts
+ * - `ToggleContactsPanel`: Open (or close) the contacts panel
+ * - `ContactInputChanged`: The user edits the username or bundle in the contacts panel
+ * - `AddContactBundle`: Add the contact whose public bundle (JSON) was pasted
+ * - `FetchContact` : Look the typed username up in the directory, with its device list
+ * - `ContactFetched`: The lookup and the device list of a new contact arrived
+ * - `ContactChecked`: The directory lookup of a contact being added from a
+ *                    bundle or a card (`true`) completed
+ * - `CopyBundle`   : Copy our public bundle to the clipboard, to give to a contact
+ * - `RenameContact`: Start editing the nickname of a contact
+ * - `NicknameChanged`: The user edits that nickname
+ * - `SaveNickname` : Keep the nickname (an empty one clears it)
+ * - `RemoveContact`: Remove a contact (their conversation stays)
+ * - `BlockContact` : Block a username (`true`) or unblock it
+ * - `Inspect`      : Open a message in the protocol inspector (or close it)
+ * - `ToggleStep`   : Expand (or collapse) a row of the inspector
+ * - `CopyText`     : Copy a value of the inspector to the clipboard
+ * - `ToggleDiagram`: Switch the inspector between its rows and the key agreement diagram
+ * - `PlayRatchet`  : Play the ratchet steps of the conversation from the start (or stop)
+ * - `RatchetFrame` : Timer advancing the ratchet animation
+ */
+#[derive(Debug, Clone)]
+pub enum Message {
+    InputChanged(String),
+    Send,
+    SelectContact(String),
+    TransportConnected(Result<Arc<dyn Transport>, String>),
+    TransportFinished(Result<(), String>),
+    EnvelopeArrived(Arc<dyn Transport>, Envelope),
+    EnvelopeReceived(Envelope),
+    OutboxSent(String, Result<(), String>),
+    RetryMessage(String),
+    Tick,
+    ToggleReadReceipts(bool),
+    ToggleTypingIndicators(bool),
+    DirectAddressChanged(String),
+    ConnectDirect,
+    ListenDirect,
+    DirectConnected(String, Result<Arc<dyn Transport>, String>),
+    ClaimPublished(Result<(), TransparencyError>),
+    ClaimFound(String, Result<Option<UsernameClaim>, TransparencyError>),
+    GroupNameChanged(String),
+    GroupMembersChanged(String),
+    ToggleGroupTreeKem(bool),
+    CreateGroup,
+    GroupRenameChanged(String),
+    GroupInviteChanged(String),
+    GroupAction(GroupOp),
+    TreeKemUpdate,
+    DevicesFetched(String, Result<Option<DeviceList>, String>),
+    LinkDevice,
+    LinkPolled(Result<Vec<Vec<u8>>, String>),
+    RevokeDevice(String),
+    DevicesPublished(Result<(), String>),
+    SendHistory(String),
+    AttachPathChanged(String),
+    Attach,
+    AttachmentUploaded(String, Result<Attachment, String>),
+    DownloadAttachment(String, Attachment),
+    AttachmentDownloaded(String, Result<String, String>),
+    EditMessage(String, String),
+    CancelEdit,
+    DeleteMessage(String),
+    EditWindowChanged(String),
+    React(String, String, bool),
+    ToggleReactionPicker(String),
+    ReplyTo(Quote),
+    CancelReply,
+    HighlightQuoted(String),
+    ToggleRaw(String),
+    ShareInputChanged(String),
+    ShareCard,
+    ImportCard(ContactCard),
+    ToggleContactsPanel,
+    ContactInputChanged(String),
+    AddContactBundle,
+    FetchContact,
+    ContactFetched(String, Result<Option<UsernameClaim>, TransparencyError>, Result<Option<DeviceList>, String>),
+    ContactChecked(PublicBundle, bool, Result<Option<UsernameClaim>, TransparencyError>),
+    CopyBundle,
+    RenameContact(String),
+    NicknameChanged(String),
+    SaveNickname,
+    RemoveContact(String),
+    BlockContact(String, bool),
+    Inspect(String),
+    ToggleStep(usize),
+    CopyText(String),
+    ToggleDiagram,
+    PlayRatchet,
+    RatchetFrame,
+}
+
+/*
+ * The update function (Elm-style).
+ *
+ * It applies state transitions based on the received message:
+ *  - Handles text input updates, signalling "typing" to the contact
+ *  - Encrypts and persists messages on "Send"
+ *  - Changes the active conversation on "SelectContact"
+ *  - Stores and acknowledges envelopes delivered by the transport,
+ *    answering with delivery/read receipts
+ *  - Applies receipts to the delivery state of our own messages
+ *
+ * Then it decrypts what changed in the selected conversation, for `view`.
+ */
+pub fn update(ui: &mut UI, message: Message) -> Task<Message> {
+    let task = handle(ui, message);
+    refresh_decrypted(ui);
+    task
+}
+
+/* Applies `message` to the state (see `update`) */
+fn handle(ui: &mut UI, message: Message) -> Task<Message> {
+    match message {
+        Message::InputChanged(value) => {
+            ui.input_value = value;
+            return send_typing(ui);
+        }
+        Message::Send => {
+            if let Some(name) = ui.selected_contact.clone() {
+                let text = ui.input_value.trim().to_string();
+                if text.is_empty() {
+                    return Task::none();
+                }
+                let payload = match ui.editing.clone() {
+                    Some(target) => {
+                        let Some(times) = change_times(ui, &name, &target) else {
+                            ui.editing = None;
+                            ui.transport_status = Some("this message can no longer be edited".to_string());
+                            return Task::none();
+                        };
+                        Payload::Edit {
+                            target,
+                            text,
+                            times: Some(times),
+                        }
+                    }
+                    None => Payload::Text {
+                        text,
+                        quote: ui.replying.clone(),
+                    },
+                };
+                let sent = if groups::is_group(&name) { send_group(ui, &name, &payload) } else { send_direct(ui, &name, &payload) };
+                if let Some(task) = sent {
+                    // Reset input field (the next keystroke signals typing again)
+                    ui.input_value.clear();
+                    ui.editing = None;
+                    ui.replying = None;
+                    ui.typing.reset_sent(&name);
+                    return task;
+                }
+            }
+        }
+        Message::SelectContact(name) => {
+            ui.selected_contact = Some(name.clone());
+            ui.inspector.close();
+            ui.editing = None;
+            ui.replying = None;
+            ui.highlighted = None;
+
+            // Opening the conversation reads everything received so far
+            let read = ui.session.mark_read(&name, ui.current_user.username());
+            if !read.is_empty() {
+                ui.session.save("session.json");
+                // (read receipts are only sent in one-to-one conversations)
+                if ui.settings.send_read_receipts && !groups::is_group(&name) {
+                    queue_receipt(ui, &name, None, ReceiptKind::Read, read);
+                    return flush_outbox(ui);
+                }
+            }
+        }
+        Message::TransportConnected(Ok(transport)) => {
+            ui.transport = Some(transport.clone());
+            ui.transport_status = None;
+            let mut accounts: Vec<String> = ui.contacts.users.iter().map(|c| c.username().to_string()).collect();
+            accounts.push(ui.current_user.username().to_string());
+            let fetches: Vec<Task<Message>> = accounts.into_iter().map(|name| fetch_devices(ui, name)).collect();
+            return Task::batch([flush_outbox(ui), check_directory(ui, transport)].into_iter().chain(fetches));
+        }
+        Message::TransportConnected(Err(e)) | Message::TransportFinished(Err(e)) => {
+            ui.transport_status = Some(e);
+        }
+        Message::TransportFinished(Ok(())) => {}
+        Message::EnvelopeArrived(transport, envelope) => {
+            ui.arrived_on.insert(envelope.id.clone(), transport);
+            return update(ui, Message::EnvelopeReceived(envelope));
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::Receipt => {
+            // Receipts only update our outbox, and only for messages sent to their author
+            if let Some(receipt) = Receipt::open(&envelope, &ui.current_user) {
+                for id in &receipt.message_ids {
+                    let ours = ui.outbox.entry(id).is_some_and(|e| e.envelope.recipient == envelope.sender);
+                    if ours {
+                        match receipt.kind {
+                            ReceiptKind::Delivered => ui.outbox.mark_delivered(id),
+                            ReceiptKind::Read => ui.outbox.mark_read(id),
+                        }
+                    }
+                }
+                ui.outbox.save("outbox.json");
+            }
+            return acknowledge(ui, envelope.id);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::Sync => {
+            return receive_synced(ui, envelope);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::History => {
+            return receive_history(ui, envelope);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::SenderKey => {
+            return receive_sender_key(ui, envelope);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::TreeKem => {
+            return receive_treekem(ui, envelope);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::GroupUpdate => {
+            let mut tasks = vec![acknowledge(ui, envelope.id.clone())];
+            if let Some(updates) = GroupUpdates::open(&envelope, &ui.current_user) {
+                tasks.push(receive_group_updates(ui, &envelope.sender, updates));
+            }
+            return Task::batch(tasks);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::Group => {
+            return receive_group(ui, envelope);
+        }
+        Message::EnvelopeReceived(envelope)
+            if matches!(envelope.kind, EnvelopeKind::Message | EnvelopeKind::Typing)
+                && ui.contacts.is_blocked(&envelope.sender) =>
+        {
+            // Messages and typing signals of blocked users are dropped, unread
+            return acknowledge(ui, envelope.id);
+        }
+        Message::EnvelopeReceived(envelope) if envelope.kind == EnvelopeKind::Typing => {
+            if ui.settings.typing_indicators {
+                if let Some(signal) = TypingSignal::open(&envelope, &ui.current_user) {
+                    ui.typing.received(&envelope.sender, &signal, outbox::now_ms());
+                }
+            }
+            return acknowledge(ui, envelope.id);
+        }
+        Message::EnvelopeReceived(envelope) => {
+            // A message this client cannot open or read (a newer kind or version) is dropped, not stored
+            let readable = box_::PublicKey::from_slice(&envelope.ephemeral_pk)
+                .zip(box_::Nonce::from_slice(&envelope.nonce))
+                .and_then(|(epk, nonce)| {
+                    let reader = &ui.current_user;
+                    reader.decrypt_message_with_logs(&epk, &nonce, &envelope.ciphertext, &envelope.sender)
+                });
+            if readable.is_none() {
+                return acknowledge(ui, envelope.id);
+            }
+            let log = format!(
+                concat!(
+                    "== log (transport) ==\n",
+                    "Envelope: {}\n",
+                    "From: {}\nTo: {}\n",
+                    "Ciphertext: {} bytes\n"
+                ),
+                envelope.id,
+                envelope.sender,
+                envelope.recipient,
+                envelope.ciphertext.len(),
+            );
+            let id = envelope.id.clone();
+            let sender = envelope.sender.clone();
+            let sender_device = envelope.sender_device.clone();
+            let viewing = ui.selected_contact.as_deref() == Some(sender.as_str());
+            ui.typing.stopped(&sender);
+            let inserted = ui.session.insert_message(
+                &sender,
+                StoredMessage {
+                    id: device_list::message_id(&envelope.id).to_string(),
+                    sender: envelope.sender,
+                    ciphertext: envelope.ciphertext,
+                    ephemeral_pk: envelope.ephemeral_pk,
+                    nonce: envelope.nonce,
+                    log,
+                    read: viewing,
+                    message_key: Vec::new(),
+                    sent_at: outbox::now_ms(),
+                },
+            );
+            ui.session.save("session.json");
+
+            // Tell the sending device it arrived (and every device of the sender it was read, if the chat is open)
+            if inserted {
+                queue_receipt(ui, &sender, Some(&sender_device), ReceiptKind::Delivered, vec![id.clone()]);
+                if viewing && ui.settings.send_read_receipts {
+                    let message_id = device_list::message_id(&id).to_string();
+                    queue_receipt(ui, &sender, None, ReceiptKind::Read, vec![message_id]);
+                }
+            }
+
+            // Only acknowledge once the message is safely on disk
+            let refresh = refresh_unknown_device(ui, &sender, &sender_device);
+            return Task::batch([acknowledge(ui, id), flush_outbox(ui), refresh]);
+        }
+        Message::OutboxSent(id, result) => {
+            match result {
+                Ok(()) => ui.outbox.mark_sent(&id),
+                Err(e) => {
+                    ui.outbox.mark_failed_attempt(&id, e.clone(), outbox::now_ms());
+                    ui.transport_status = Some(e);
+                }
+            }
+            ui.outbox.save("outbox.json");
+        }
+        Message::RetryMessage(id) => {
+            ui.outbox.retry(&id);
+            ui.outbox.save("outbox.json");
+            return flush_outbox(ui);
+        }
+        Message::Tick => {
+            ui.typing.expire(outbox::now_ms());
+            let expired = drop_held(ui, outbox::now_ms());
+            return Task::batch([flush_outbox(ui), poll_link(ui), expired]);
+        }
+        Message::ToggleReadReceipts(enabled) => {
+            ui.settings.send_read_receipts = enabled;
+            ui.settings.save("settings.json");
+        }
+        Message::EditWindowChanged(value) => {
+            if let Ok(minutes) = value.trim().parse::<u64>() {
+                ui.settings.edit_window = minutes.saturating_mul(60);
+                ui.settings.save("settings.json");
+            }
+            ui.edit_window_input = value;
+        }
+        Message::ToggleTypingIndicators(enabled) => {
+            ui.settings.typing_indicators = enabled;
+            ui.settings.save("settings.json");
+            ui.typing = Typing::default();
+        }
+        Message::DirectAddressChanged(value) => ui.direct_input = value,
+        Message::ConnectDirect | Message::ListenDirect => {
+            let listen = matches!(message, Message::ListenDirect);
+            let addr = ui.direct_input.trim().to_string();
+            let Some(name) = ui.selected_contact.clone() else {
+                return Task::none();
+            };
+            let Some(peer) = ui.contacts.get(&name).cloned() else {
+                return Task::none();
+            };
+            if addr.is_empty() {
+                return Task::none();
+            }
+            let me = ui.current_user.clone();
+            let label = if listen { format!("listening on {addr}") } else { format!("connecting to {addr}") };
+            ui.direct_status.insert(name.clone(), label);
+            return Task::perform(
+                async move {
+                    let transport: Arc<dyn Transport> = if listen {
+                        Arc::new(p2p::listen(&addr, &me, &peer).await.map_err(|e| e.to_string())?)
+                    } else {
+                        Arc::new(p2p::connect(&addr, &peer).await.map_err(|e| e.to_string())?)
+                    };
+                    Ok(transport)
+                },
+                move |result| Message::DirectConnected(name.clone(), result),
+            );
+        }
+        Message::DirectConnected(name, Ok(transport)) => {
+            ui.direct.insert(name.clone(), transport);
+            if let Some(status) = ui.direct_status.get_mut(&name) {
+                if let Some(addr) = status.strip_prefix("connecting to ") {
+                    *status = format!("connected to {addr}");
+                }
+            }
+            return flush_outbox(ui);
+        }
+        Message::DirectConnected(name, Err(e)) => {
+            ui.direct_status.insert(name, format!("direct link failed: {e}"));
+        }
+        Message::ClaimPublished(Ok(())) => {}
+        Message::ClaimPublished(Err(e)) => {
+            note_divergence(ui, &e);
+            ui.transport_status = Some(format!("directory: {e}"));
+        }
+        Message::GroupNameChanged(value) => ui.group_name_input = value,
+        Message::GroupMembersChanged(value) => ui.group_members_input = value,
+        Message::ToggleGroupTreeKem(value) => ui.group_treekem = value,
+        Message::CreateGroup => {
+            let name = ui.group_name_input.trim().to_string();
+            let members: Vec<String> = ui
+                .group_members_input
+                .split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| ui.contacts.get(m).is_some())
+                .collect();
+            if name.is_empty() || members.is_empty() {
+                return Task::none();
+            }
+            let treekem = ui.group_treekem;
+            let (mut group, update) = match Group::create(groups::new_group_id(), &name, members, treekem, &ui.current_user) {
+                Ok(created) => created,
+                Err(e) => {
+                    ui.transport_status = Some(format!("group: {e}"));
+                    return Task::none();
+                }
+            };
+            let id = group.id.clone();
+            let others: Vec<String> = group.others(ui.current_user.username()).cloned().collect();
+            if treekem {
+                group.tree = Some(TreeKem::create(&id, &ui.current_user));
+            }
+            ui.session.groups.insert(id.clone(), group);
+            ui.session.save("session.json");
+            ui.group_name_input.clear();
+            ui.group_members_input.clear();
+            ui.selected_contact = Some(id.clone());
+            send_group_updates(ui, &others, vec![update]);
+            if treekem {
+                /* One commit adds everyone to the tree */
+                commit_tree(ui, &id, &others, &[]);
+            } else {
+                distribute_sender_key(ui, &id);
+            }
+            return flush_outbox(ui);
+        }
+        Message::DevicesFetched(name, Ok(Some(list))) => {
+            let account = if name == ui.current_user.username() {
+                Some(&mut ui.current_user)
+            } else {
+                ui.contacts.get_mut(&name)
+            };
+            if let Some(account) = account {
+                /* An older list is ignored; one the account did not sign is reported */
+                if !list.verify(&account.identity_pk) {
+                    ui.transport_status = Some(format!("the relay sent a device list of {name} it did not sign"));
+                } else {
+                    account.update_devices(list);
+                }
+            }
+            if ui.current_user.devices.device(&ui.current_user.device_id).is_none() {
+                ui.transport_status = Some("this device was removed from the account".to_string());
+            }
+        }
+        Message::LinkDevice => {
+            if ui.link_offer.take().is_none() {
+                let code = LinkCode::generate(ui.current_user.username());
+                ui.link_offer = Some((code, outbox::now_ms() + LINK_CODE_TTL_MS));
+            }
+            ui.device_status = None;
+        }
+        Message::LinkPolled(Ok(messages)) => {
+            let Some((code, _)) = ui.link_offer.clone() else {
+                return Task::none();
+            };
+            for message in messages {
+                let Ok(request) = serde_json::from_slice::<LinkRequest>(&message) else {
+                    continue;
+                };
+                match link::accept(&mut ui.current_user, &code, &request) {
+                    Ok((response, device)) => {
+                        ui.link_offer = None;
+                        ui.device_status = Some(format!("device {} linked", device.device_id));
+                        return publish_devices(ui, Some((code, response)));
+                    }
+                    Err(e) => ui.device_status = Some(e.to_string()),
+                }
+            }
+        }
+        Message::LinkPolled(Err(e)) => ui.device_status = Some(format!("linking: {e}")),
+        Message::RevokeDevice(device) => {
+            if ui.current_user.remove_device(&device) {
+                ui.device_status = Some(format!("device {device} revoked"));
+                return publish_devices(ui, None);
+            }
+        }
+        Message::SendHistory(device_id) => {
+            let Some(device) = ui.current_user.devices.device(&device_id).cloned() else {
+                return Task::none();
+            };
+            let records = history::export(&ui.current_user, &ui.contacts, &ui.session);
+            let Some((offer, chunks)) = history::seal(&records) else {
+                return Task::none();
+            };
+            let count = chunks.len();
+            let messages = std::iter::once(HistoryMessage::Offer(offer)).chain(chunks.into_iter().map(HistoryMessage::Chunk));
+            for envelope in messages.filter_map(|m| m.seal(&ui.current_user, &device)) {
+                ui.outbox.enqueue(envelope);
+            }
+            ui.outbox.save("outbox.json");
+            ui.device_status = Some(format!("sending {} messages to device {device_id} ({count} chunks)", records.len()));
+            return flush_outbox(ui);
+        }
+        Message::AttachPathChanged(value) => ui.attach_input = value,
+        Message::Attach => {
+            let path = ui.attach_input.trim().to_string();
+            let Some(name) = ui.selected_contact.clone() else {
+                return Task::none();
+            };
+            if path.is_empty() {
+                return Task::none();
+            }
+            let Some(transport) = ui.transport_for(&name) else {
+                ui.attach_status = Some("attachments need a connection".to_string());
+                return Task::none();
+            };
+            ui.attach_status = Some(format!("uploading {path}…"));
+            return Task::perform(
+                async move {
+                    let data = std::fs::read(&path).map_err(|e| format!("{path}: {e}"))?;
+                    let file_name = Path::new(&path)
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    let (attachment, blob) = attachments::encrypt(&file_name, &data).map_err(|e| e.to_string())?;
+                    attachments::upload(transport.as_ref(), &attachment.blob_id, &blob)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    Ok(attachment)
+                },
+                move |result| Message::AttachmentUploaded(name.clone(), result),
+            );
+        }
+        Message::AttachmentUploaded(name, Ok(attachment)) => {
+            let payload = Payload::Attachment(attachment);
+            let sent = if groups::is_group(&name) { send_group(ui, &name, &payload) } else { send_direct(ui, &name, &payload) };
+            if let Some(task) = sent {
+                ui.attach_input.clear();
+                ui.attach_status = None;
+                return task;
+            }
+        }
+        Message::AttachmentUploaded(_, Err(e)) => ui.attach_status = Some(format!("attachment: {e}")),
+        Message::DownloadAttachment(name, attachment) => {
+            let Some(transport) = ui.transport_for(&name) else {
+                return Task::none();
+            };
+            let blob_id = attachment.blob_id.clone();
+            ui.downloads.insert(blob_id.clone(), "downloading…".to_string());
+            return Task::perform(
+                async move {
+                    let data = attachments::download(transport.as_ref(), &attachment).await?;
+                    let path = attachments::save(DOWNLOAD_DIR, &attachment, &data).map_err(|e| e.to_string())?;
+                    Ok(path.display().to_string())
+                },
+                move |result| Message::AttachmentDownloaded(blob_id.clone(), result),
+            );
+        }
+        Message::AttachmentDownloaded(blob_id, result) => {
+            let status = match result {
+                Ok(path) => format!("✓ saved to {path}"),
+                Err(e) => format!("✗ {e}"),
+            };
+            ui.downloads.insert(blob_id, status);
+        }
+        Message::EditMessage(id, current) => {
+            ui.editing = Some(id);
+            ui.input_value = current;
+        }
+        Message::CancelEdit => {
+            ui.editing = None;
+            ui.input_value.clear();
+        }
+        Message::ReplyTo(quote) => {
+            ui.editing = None;
+            ui.replying = Some(quote);
+        }
+        Message::CancelReply => ui.replying = None,
+        Message::ShareInputChanged(value) => ui.share_input = value,
+        Message::ShareCard => {
+            let Some(name) = ui.selected_contact.clone() else {
+                return Task::none();
+            };
+            let who = ui.share_input.trim().to_string();
+            let card = match card_of(ui, &who) {
+                Ok(card) => card,
+                Err(e) => {
+                    ui.card_status = Some(e);
+                    return Task::none();
+                }
+            };
+            let payload = Payload::ContactCard(card);
+            let sent = if groups::is_group(&name) { send_group(ui, &name, &payload) } else { send_direct(ui, &name, &payload) };
+            if let Some(task) = sent {
+                ui.share_input.clear();
+                ui.card_status = None;
+                return task;
+            }
+        }
+        Message::ImportCard(card) => {
+            let username = card.username().to_string();
+            match card.contact().and_then(|contact| add_contact(ui, contact, true)) {
+                Ok(task) => return task,
+                Err(e) => ui.card_status = Some(format!("contact card of {username}: {e}")),
+            }
+        }
+        Message::Inspect(id) => {
+            ui.inspector.toggle(id);
+            ui.contacts_panel = false;
+        }
+        Message::ToggleStep(index) => ui.inspector.toggle_step(index),
+        Message::CopyText(value) => return clipboard::write(value),
+        Message::ToggleDiagram => ui.inspector.toggle_diagram(),
+        Message::PlayRatchet => ui.inspector.toggle_ratchet(),
+        Message::RatchetFrame => {
+            let steps = ui.selected_contact.as_ref().and_then(|name| ui.session.get_messages(name)).map_or(0, Vec::len);
+            ui.inspector.ratchet_frame(steps);
+        }
+        Message::ToggleContactsPanel => {
+            ui.contacts_panel = !ui.contacts_panel;
+            ui.renaming = None;
+        }
+        Message::ContactInputChanged(value) => ui.contact_input = value,
+        Message::AddContactBundle => {
+            let added = serde_json::from_str::<PublicBundle>(ui.contact_input.trim())
+                .ok()
+                .and_then(|bundle| User::from_bundle(&bundle))
+                .ok_or(contacts::ContactError::InvalidBundle);
+            match added.and_then(|contact| add_contact(ui, contact, false)) {
+                Ok(task) => {
+                    ui.contact_input.clear();
+                    return task;
+                }
+                Err(e) => ui.contact_status = Some(format!("bundle: {e}")),
+            }
+        }
+        Message::FetchContact => {
+            let name = ui.contact_input.trim().to_string();
+            if !directory::valid_username(&name) {
+                ui.contact_status = Some("type a username, or paste a bundle".to_string());
+                return Task::none();
+            }
+            let Some(transport) = ui.transport.clone() else {
+                ui.contact_status = Some("not connected to a relay".to_string());
+                return Task::none();
+            };
+            let monitor = ui.monitor.clone();
+            ui.contact_status = Some(format!("looking {name} up…"));
+            return Task::perform(
+                async move {
+                    let claim = monitor.lookup(transport.as_ref(), &name).await;
+                    let devices = transport.fetch_devices(&name).await.map_err(|e| e.to_string());
+                    (name, claim, devices)
+                },
+                |(name, claim, devices)| Message::ContactFetched(name, claim, devices),
+            );
+        }
+        Message::ContactFetched(name, claim, devices) => {
+            let claim = match claim {
+                Ok(Some(claim)) => claim,
+                Ok(None) => {
+                    ui.contact_status = Some(format!("{name} is not registered in the directory"));
+                    return Task::none();
+                }
+                Err(e) => {
+                    note_divergence(ui, &e);
+                    ui.contact_status = Some(format!("{name}: {e}"));
+                    return Task::none();
+                }
+            };
+            let list = match devices {
+                Ok(Some(list)) => list,
+                Ok(None) => {
+                    ui.contact_status = Some(format!("{name} has published no devices"));
+                    return Task::none();
+                }
+                Err(e) => {
+                    ui.contact_status = Some(format!("device list of {name}: {e}"));
+                    return Task::none();
+                }
+            };
+            let added = User::from_directory(&claim, &list).ok_or(contacts::ContactError::InvalidBundle);
+            match added.and_then(|contact| store_contact(ui, contact, claim)) {
+                Ok(task) => {
+                    ui.contact_input.clear();
+                    ui.contact_status = Some(format!("added {name} from the directory"));
+                    return task;
+                }
+                Err(e) => ui.contact_status = Some(format!("{name}: {e}")),
+            }
+        }
+        Message::ContactChecked(bundle, from_card, result) => {
+            let name = bundle.username.clone();
+            let added = match result {
+                Ok(Some(claim)) => User::from_bundle(&bundle)
+                    .ok_or(contacts::ContactError::InvalidBundle)
+                    .and_then(|contact| store_contact(ui, contact, claim))
+                    .map_err(|e| e.to_string()),
+                Ok(None) => Err("not registered in the directory, so their key cannot be checked".to_string()),
+                Err(e) => {
+                    note_divergence(ui, &e);
+                    Err(e.to_string())
+                }
+            };
+            let (status, task) = match added {
+                Ok(task) => (format!("added {name} (key checked against the directory)"), task),
+                Err(e) => (format!("{name} not added: {e}"), Task::none()),
+            };
+            if from_card {
+                ui.card_status = Some(status);
+            } else {
+                ui.contact_status = Some(status);
+            }
+            return task;
+        }
+        Message::CopyBundle => {
+            let Ok(json) = serde_json::to_string(&ui.current_user.public_bundle()) else {
+                return Task::none();
+            };
+            ui.contact_status = Some("our public bundle was copied to the clipboard".to_string());
+            return clipboard::write(json);
+        }
+        Message::RenameContact(name) => {
+            let nickname = ui.contacts.nickname(&name).unwrap_or_default().to_string();
+            ui.renaming = Some((name, nickname));
+        }
+        Message::NicknameChanged(value) => {
+            if let Some((_, nickname)) = ui.renaming.as_mut() {
+                *nickname = value;
+            }
+        }
+        Message::SaveNickname => {
+            let Some((name, nickname)) = ui.renaming.take() else {
+                return Task::none();
+            };
+            match ui.contacts.set_nickname(&name, &nickname) {
+                Ok(()) => {
+                    ui.contacts.save(CONTACTS_FILE);
+                    ui.contact_status = None;
+                }
+                Err(e) => {
+                    ui.contact_status = Some(format!("{name}: {e}"));
+                    ui.renaming = Some((name, nickname));
+                }
+            }
+        }
+        Message::RemoveContact(name) => {
+            ui.contacts.remove(&name);
+            ui.contacts.save(CONTACTS_FILE);
+            ui.directory.remove(&name);
+            if ui.selected_contact.as_deref() == Some(name.as_str()) {
+                ui.selected_contact = None;
+            }
+            ui.contact_status = Some(format!("removed {name} (the conversation is kept)"));
+        }
+        Message::BlockContact(name, block) => {
+            let name = name.trim().to_string();
+            if !directory::valid_username(&name) || name == ui.current_user.username() {
+                ui.contact_status = Some("type the username to block".to_string());
+                return Task::none();
+            }
+            if block {
+                ui.contacts.block(&name);
+                ui.typing.stopped(&name);
+                ui.contact_input.clear();
+            } else {
+                ui.contacts.unblock(&name);
+            }
+            ui.contacts.save(CONTACTS_FILE);
+            ui.contact_status = None;
+        }
+        Message::ToggleRaw(id) => {
+            if !ui.raw.remove(&id) {
+                ui.raw.insert(id);
+            }
+        }
+        Message::HighlightQuoted(id) => {
+            if ui.highlighted.as_deref() == Some(id.as_str()) {
+                ui.highlighted = None;
+                return Task::none();
+            }
+            let Some(y) = ui.selected_contact.as_deref().and_then(|name| row_offset(ui, name, &id)) else {
+                return Task::none();
+            };
+            ui.highlighted = Some(id);
+            return scrollable::snap_to(scrollable::Id::new(MESSAGES_SCROLL), scrollable::RelativeOffset { x: 0.0, y });
+        }
+        Message::ToggleReactionPicker(id) => {
+            ui.reacting = if ui.reacting.as_deref() == Some(id.as_str()) { None } else { Some(id) };
+        }
+        Message::React(target, emoji, remove) => {
+            ui.reacting = None;
+            let Some(name) = ui.selected_contact.clone() else {
+                return Task::none();
+            };
+            if !reactions::valid_reaction(&emoji) {
+                return Task::none();
+            }
+            let payload = Payload::Reaction { target, emoji, remove };
+            let sent = if groups::is_group(&name) { send_group(ui, &name, &payload) } else { send_direct(ui, &name, &payload) };
+            if let Some(task) = sent {
+                return task;
+            }
+        }
+        Message::DeleteMessage(id) => {
+            let Some(name) = ui.selected_contact.clone() else {
+                return Task::none();
+            };
+            let Some(times) = change_times(ui, &name, &id) else {
+                ui.transport_status = Some("this message can no longer be deleted".to_string());
+                return Task::none();
+            };
+            if ui.editing.as_deref() == Some(id.as_str()) {
+                ui.editing = None;
+                ui.input_value.clear();
+            }
+            let payload = Payload::Delete {
+                target: id,
+                times: Some(times),
+            };
+            let sent = if groups::is_group(&name) { send_group(ui, &name, &payload) } else { send_direct(ui, &name, &payload) };
+            if let Some(task) = sent {
+                return task;
+            }
+        }
+        Message::DevicesPublished(result) => {
+            if let Err(e) = result {
+                ui.transport_status = Some(format!("device list: {e}"));
+            }
+        }
+        Message::DevicesFetched(_, Ok(None)) => {}
+        Message::DevicesFetched(name, Err(e)) => {
+            ui.transport_status = Some(format!("device list of {name}: {e}"));
+        }
+        Message::TreeKemUpdate => {
+            if let Some(id) = ui.selected_contact.clone().filter(|id| groups::is_group(id)) {
+                commit_tree(ui, &id, &[], &[]);
+                return flush_outbox(ui);
+            }
+        }
+        Message::GroupRenameChanged(value) => ui.group_rename_input = value,
+        Message::GroupInviteChanged(value) => ui.group_invite_input = value,
+        Message::GroupAction(op) => {
+            if let Some(id) = ui.selected_contact.clone().filter(|id| groups::is_group(id)) {
+                return change_group(ui, &id, op);
+            }
+        }
+        Message::ClaimFound(name, result) => {
+            let status = match (result, ui.contacts.get(&name)) {
+                (Err(e), _) => {
+                    note_divergence(ui, &e);
+                    ClaimStatus::Rejected(e.to_string())
+                }
+                (Ok(None), _) => ClaimStatus::Unregistered,
+                (Ok(Some(_)), None) => return Task::none(),
+                (Ok(Some(claim)), Some(contact)) => match contacts::check_claim(contact, &claim) {
+                    Ok(()) => ClaimStatus::Verified(claim),
+                    Err(e) => ClaimStatus::Rejected(e.to_string()),
+                },
+            };
+            ui.directory.insert(name, status);
+        }
+    }
+    Task::none()
+}
+
+/*
+ * Publishes our username claim on `transport`, then looks up the claim
+ * of every contact, all through the transparency monitor.
+ *
+ * We also look ourselves up: the log must hold a claim for our identity
+ * key, and not another key for our username. A refused publication is
+ * fine as long as that holds (e.g. the claim was published during a
+ * previous run). Linked devices cannot sign a claim: they only check.
+ */
+fn check_directory(ui: &UI, transport: Arc<dyn Transport>) -> Task<Message> {
+    let claim = ui.current_user.username_claim();
+    let username = ui.current_user.username().to_string();
+    let identity_pk = ui.current_user.identity_pk;
+    let monitor = ui.monitor.clone();
+    let publisher = transport.clone();
+    let publish = Task::perform(
+        async move {
+            let published = match &claim {
+                Some(claim) => publisher.publish_claim(claim).await.map_err(|e| e.to_string()),
+                None => Err("our claim is missing from the directory (publish it from the primary device)".to_string()),
+            };
+            match monitor.lookup(publisher.as_ref(), &username).await? {
+                Some(found) if found.identity_key() == Some(identity_pk) => Ok(()),
+                Some(_) => Err(TransparencyError::Transport(
+                    "the directory holds another key for our username".to_string(),
+                )),
+                None => Err(TransparencyError::Transport(match published {
+                    Err(e) => e,
+                    Ok(()) => "our claim is missing from the directory".to_string(),
+                })),
+            }
+        },
+        Message::ClaimPublished,
+    );
+    let lookups = ui
+        .contacts
+        .users
+        .iter()
+        .map(|contact| lookup_claim(ui, transport.clone(), contact.username().to_string()));
+    Task::batch(std::iter::once(publish).chain(lookups))
+}
+
+/* Looks the claim of contact `name` up in the directory, through the transparency monitor */
+fn lookup_claim(ui: &UI, transport: Arc<dyn Transport>, name: String) -> Task<Message> {
+    let monitor = ui.monitor.clone();
+    Task::perform(
+        async move {
+            let result = monitor.lookup(transport.as_ref(), &name).await;
+            (name, result)
+        },
+        |(name, result)| Message::ClaimFound(name, result),
+    )
+}
+
+/*
+ * Adds `contact` (from a bundle, or a contact card if `from_card`).
+ *
+ * When connected, their claim is looked up in the directory first, and
+ * they are only added if it binds their username to the same identity
+ * key (see `Message::ContactChecked`). Offline, they are added right away
+ * and checked once connected: sending to them is refused if the check
+ * fails (see `send_direct`).
+ */
+fn add_contact(ui: &mut UI, contact: User, from_card: bool) -> Result<Task<Message>, contacts::ContactError> {
+    if contact.username == ui.current_user.username || ui.contacts.get(&contact.username).is_some() {
+        return Err(contacts::ContactError::Duplicate);
+    }
+    let name = contact.username.clone();
+    let status = if from_card { &mut ui.card_status } else { &mut ui.contact_status };
+    let Some(transport) = ui.transport.clone() else {
+        ui.contacts.add(contact)?;
+        ui.contacts.save(CONTACTS_FILE);
+        *status = Some(format!("added {name} (offline: their key is checked once connected)"));
+        return Ok(Task::none());
+    };
+    *status = Some(format!("checking {name} in the directory…"));
+    let monitor = ui.monitor.clone();
+    let bundle = contact.public_bundle();
+    Ok(Task::perform(
+        async move { monitor.lookup(transport.as_ref(), &name).await },
+        move |result| Message::ContactChecked(bundle.clone(), from_card, result),
+    ))
+}
+
+/*
+ * Adds `contact` after checking that the directory `claim` binds their
+ * username to their identity key, saves the list, then fetches their
+ * device list (if connected).
+ */
+fn store_contact(ui: &mut UI, contact: User, claim: UsernameClaim) -> Result<Task<Message>, contacts::ContactError> {
+    let name = contact.username.clone();
+    ui.contacts.add_verified(contact, &claim)?;
+    ui.contacts.save(CONTACTS_FILE);
+    ui.directory.insert(name.clone(), ClaimStatus::Verified(claim));
+    Ok(fetch_devices(ui, name))
+}
+
+/* Raises the transparency warning if `error` shows a diverging log */
+fn note_divergence(ui: &mut UI, error: &TransparencyError) {
+    if error.is_divergence() {
+        ui.transparency_warning = Some(format!(
+            "⚠ Key transparency: the relay's log diverged from what we saw before ({error}). \
+             It may be showing different keys to different people."
+        ));
+    }
+}
+
+/*
+ * The contact card of `who`: ours, or a contact's with the claim the
+ * directory returned for them (demo contacts can sign their own).
+ */
+fn card_of(ui: &UI, who: &str) -> Result<ContactCard, String> {
+    if who == ui.current_user.username() {
+        let claim = ui.current_user.username_claim().ok_or("only the primary device can sign our card")?;
+        return Ok(ContactCard::new(&ui.current_user, claim));
+    }
+    let contact = ui.contacts.get(who).ok_or_else(|| format!("{who} is not a contact"))?;
+    let claim = match ui.directory.get(who) {
+        Some(ClaimStatus::Verified(claim)) => Some(claim.clone()),
+        _ => contact.username_claim(),
+    };
+    let claim = claim.ok_or_else(|| format!("no verified directory claim for {who}"))?;
+    Ok(ContactCard::new(contact, claim))
+}
+
+/*
+ * If we can still edit or delete our message `id` of conversation `name`,
+ * the times a request made now carries (see `client::edits`).
+ */
+fn change_times(ui: &UI, name: &str, id: &str) -> Option<SentTimes> {
+    let now = outbox::now_ms();
+    let window_ms = ui.settings.edit_window * 1000;
+    let message = ui.session.get_messages(name)?.iter().find(|m| m.id == id)?;
+    edits::can_change(message, ui.current_user.username(), now, window_ms).then_some(SentTimes {
+        sent_at: now,
+        original_sent_at: message.sent_at,
+    })
+}
+
+/*
+ * Encrypts `payload` for contact `name`, stores it and queues it for
+ * every device of the contact and our other devices.
+ *
+ * Returns `None` if `name` is not a contact, or if the directory holds
+ * another key for them (the reason is shown).
+ */
+fn send_direct(ui: &mut UI, name: &str, payload: &Payload) -> Option<Task<Message>> {
+    if let Some(ClaimStatus::Rejected(e)) = ui.directory.get(name) {
+        ui.transport_status = Some(format!("not sent: the directory does not confirm the key of {name} ({e})"));
+        return None;
+    }
+    let recipient = ui.contacts.get(name)?;
+
+    // Encrypt the message (produces ciphertext + logs)
+    let (epk, nonce, ciphertext, send_log) = ui.current_user.encrypt_message_with_logs(recipient, payload);
+
+    // Store a copy encrypted to ourselves under the recipient's conversation
+    // (the contact's secret keys are not ours to read it back)
+    let (own_epk, own_nonce, own_ciphertext, _) = ui.current_user.encrypt_message_with_logs(&ui.current_user, payload);
+    let id = ui.session.add_message(name, ui.current_user.username(), own_ciphertext, own_epk, own_nonce, send_log);
+    ui.session.save("session.json");
+
+    // Copies for the contact's other devices and ours
+    let copies = devices::fan_out(&ui.current_user, recipient, &id, payload);
+    let device = recipient.device_id.clone();
+
+    // Queue the envelopes; they are sent now if a transport is up
+    ui.outbox.enqueue(Envelope {
+        id,
+        kind: EnvelopeKind::Message,
+        sender: ui.current_user.username().to_string(),
+        sender_device: ui.current_user.device_id.clone(),
+        recipient: name.to_string(),
+        device,
+        ephemeral_pk: epk.as_ref().to_vec(),
+        nonce: nonce.0.to_vec(),
+        ciphertext,
+    });
+    for copy in copies {
+        ui.outbox.enqueue(copy);
+    }
+    ui.outbox.save("outbox.json");
+    Some(flush_outbox(ui))
+}
+
+/*
+ * Encrypts `payload` once with our sender key for group `group_id`, stores it,
+ * and queues one envelope per other member.
+ *
+ * Returns `None` if it cannot be sent (the reason is shown).
+ */
+fn send_group(ui: &mut UI, group_id: &str, payload: &Payload) -> Option<Task<Message>> {
+    /* Our sender key is the primary device's: a linked device would start a rival chain */
+    if ui.current_user.identity_sk.is_none() {
+        ui.transport_status = Some("groups can only be used from the primary device".to_string());
+        return None;
+    }
+    let me = ui.current_user.username().to_string();
+    let group = ui.session.groups.get_mut(group_id)?;
+    if !group.is_member(&me) {
+        ui.transport_status = Some("you are no longer a member of this group".to_string());
+        return None;
+    }
+    let (message, message_key, log) = if group.uses_treekem() {
+        let Some(tree) = group.tree.as_mut() else {
+            ui.transport_status = Some("waiting for the TreeKEM welcome of this group".to_string());
+            return None;
+        };
+        match tree.encrypt(new_message_id(), payload, &ui.current_user) {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                ui.transport_status = Some(format!("TreeKEM: {e}"));
+                return None;
+            }
+        }
+    } else {
+        match group.encrypt(new_message_id(), payload) {
+            Ok(encrypted) => encrypted,
+            Err(e) => {
+                ui.transport_status = Some(format!("group: {e}"));
+                return None;
+            }
+        }
+    };
+    let envelopes: Vec<Envelope> = group
+        .others(&me)
+        .filter_map(|member| message.envelope(&ui.current_user, member))
+        .collect();
+    ui.session.insert_message(
+        group_id,
+        StoredMessage {
+            id: message.message_id.clone(),
+            sender: me,
+            ciphertext: message.ciphertext,
+            ephemeral_pk: Vec::new(),
+            nonce: message.nonce,
+            log,
+            read: true,
+            message_key: message_key.to_vec(),
+            sent_at: outbox::now_ms(),
+        },
+    );
+    ui.session.save("session.json");
+    for envelope in envelopes {
+        ui.outbox.enqueue(envelope);
+    }
+    ui.outbox.save("outbox.json");
+    Some(flush_outbox(ui))
+}
+
+/* Queues our sender key for group `group_id` to every other member */
+fn distribute_sender_key(ui: &mut UI, group_id: &str) {
+    let Some(group) = ui.session.groups.get(group_id) else {
+        return;
+    };
+    let others: Vec<String> = group.others(ui.current_user.username()).cloned().collect();
+    send_sender_key(ui, group_id, &others);
+}
+
+/* Queues our sender key for group `group_id` to `members` (pairwise encrypted) */
+fn send_sender_key(ui: &mut UI, group_id: &str, members: &[String]) {
+    let Some(group) = ui.session.groups.get(group_id) else {
+        return;
+    };
+    let distribution = group.distribution();
+    for member in members {
+        if let Some(contact) = ui.contacts.get(member) {
+            if let Some(envelope) = distribution.seal(&ui.current_user, contact) {
+                ui.outbox.enqueue(envelope);
+            }
+        }
+    }
+    ui.outbox.save("outbox.json");
+}
+
+/* Queues signed group `updates` to each of `members` (pairwise encrypted) */
+fn send_group_updates(ui: &mut UI, members: &[String], updates: Vec<GroupUpdate>) {
+    let updates = GroupUpdates { updates };
+    for member in members {
+        if let Some(contact) = ui.contacts.get(member) {
+            if let Some(envelope) = updates.seal(&ui.current_user, contact) {
+                ui.outbox.enqueue(envelope);
+            }
+        }
+    }
+    ui.outbox.save("outbox.json");
+}
+
+/*
+ * Signs `op` as the next update of group `group_id`, applies it and
+ * sends it to the members: the update alone to those who were already
+ * in the group (including a removed one, so they know), the whole
+ * history to the newly invited ones.
+ */
+fn change_group(ui: &mut UI, group_id: &str, op: GroupOp) -> Task<Message> {
+    match &op {
+        GroupOp::Rename { name } if name.is_empty() => return Task::none(),
+        GroupOp::Invite { member } if ui.contacts.get(member).is_none() => {
+            ui.transport_status = Some(format!("group: {member} is not a contact"));
+            return Task::none();
+        }
+        _ => {}
+    }
+    let me = ui.current_user.username().to_string();
+    let identity_pk = ui.current_user.identity_pk;
+    let Some(group) = ui.session.groups.get_mut(group_id) else {
+        return Task::none();
+    };
+    let before: Vec<String> = group.others(&me).cloned().collect();
+    let update = match group.propose(op, &ui.current_user) {
+        Ok(update) => update,
+        Err(e) => {
+            ui.transport_status = Some(format!("group: {e}"));
+            return Task::none();
+        }
+    };
+    let (applied, errors) = group.receive(vec![update.clone()], |_| Some(identity_pk));
+    if let Some(e) = errors.first() {
+        ui.transport_status = Some(format!("group: {e}"));
+        return Task::none();
+    }
+    let history = group.history.clone();
+    ui.session.save("session.json");
+    ui.group_rename_input.clear();
+    ui.group_invite_input.clear();
+
+    send_group_updates(ui, &before, vec![update]);
+    send_group_updates(ui, &applied.added, history);
+    after_membership_change(ui, group_id, &applied, false);
+    flush_outbox(ui)
+}
+
+/*
+ * Makes a TreeKEM commit for group `group_id`, adding and removing the
+ * given members (a plain key update if both are empty). The commit goes
+ * to the members already in the tree, a welcome to each added member.
+ */
+fn commit_tree(ui: &mut UI, group_id: &str, added: &[String], removed: &[String]) {
+    let Some(tree) = ui.session.groups.get_mut(group_id).and_then(|g| g.tree.as_mut()) else {
+        return;
+    };
+    let mut proposals: Vec<Proposal> = removed
+        .iter()
+        .filter(|m| tree.members().any(|t| t == *m))
+        .map(|m| Proposal::Remove { member: m.clone() })
+        .collect();
+    for member in added.iter().filter(|m| !tree.members().any(|t| t == *m)) {
+        match ui.contacts.get(member) {
+            Some(contact) => proposals.push(Proposal::Add {
+                member: member.clone(),
+                init_key: contact.signed_pre_pk.as_ref().to_vec(),
+            }),
+            None => ui.transport_status = Some(format!("TreeKEM: {member} is not a contact, not added")),
+        }
+    }
+    if proposals.is_empty() && !(added.is_empty() && removed.is_empty()) {
+        return;
+    }
+
+    let before: Vec<String> = tree.members().cloned().collect();
+    let (commit, welcomes) = match tree.commit(proposals, &ui.current_user) {
+        Ok(result) => result,
+        Err(e) => {
+            ui.transport_status = Some(format!("TreeKEM commit failed: {e}"));
+            return;
+        }
+    };
+    let me = ui.current_user.username();
+    let message = TreeKemMessage::Commit(commit);
+    for member in before.iter().filter(|m| *m != me && !removed.contains(m)) {
+        if let Some(envelope) = ui.contacts.get(member).and_then(|c| message.seal(&ui.current_user, c)) {
+            ui.outbox.enqueue(envelope);
+        }
+    }
+    for (member, welcome) in welcomes {
+        let message = TreeKemMessage::Welcome(welcome);
+        if let Some(envelope) = ui.contacts.get(&member).and_then(|c| message.seal(&ui.current_user, c)) {
+            ui.outbox.enqueue(envelope);
+        }
+    }
+    ui.session.save("session.json");
+    ui.outbox.save("outbox.json");
+}
+
+/*
+ * Handles a TreeKEM commit or welcome.
+ *
+ * Messages for a group we do not know yet, or for a later epoch than
+ * ours, are held (not acknowledged) until they can be processed.
+ */
+fn receive_treekem(ui: &mut UI, envelope: Envelope) -> Task<Message> {
+    let Some(message) = TreeKemMessage::open(&envelope, &ui.current_user) else {
+        return acknowledge(ui, envelope.id);
+    };
+    let identity_of = |name: &str| {
+        if name == ui.current_user.username() {
+            Some(ui.current_user.identity_pk)
+        } else {
+            ui.contacts.get(name).map(|c| c.identity_pk)
+        }
+    };
+    let Some(group) = ui.session.groups.get(message.group_id()) else {
+        if groups::is_group(message.group_id()) {
+            return hold(ui, envelope);
+        }
+        return acknowledge(ui, envelope.id);
+    };
+    let group_id = group.id.clone();
+
+    let result = match &message {
+        TreeKemMessage::Welcome(welcome) => {
+            let newer = group.tree.as_ref().is_none_or(|t| welcome.epoch > t.epoch);
+            match identity_of(&welcome.committer) {
+                Some(pk) if newer && group.is_member(&welcome.committer) => {
+                    TreeKem::join(welcome, &ui.current_user, &pk).map(Some)
+                }
+                _ => Ok(None),
+            }
+        }
+        TreeKemMessage::Commit(commit) => {
+            let Some(mut tree) = group.tree.clone() else {
+                if !group.is_member(ui.current_user.username()) {
+                    return acknowledge(ui, envelope.id);
+                }
+                return hold(ui, envelope);
+            };
+            if commit.epoch > tree.epoch {
+                return hold(ui, envelope);
+            }
+            match identity_of(&commit.committer) {
+                Some(pk) if group.is_member(&commit.committer) => tree.apply_commit(commit, &pk).map(|()| Some(tree)),
+                _ => Err(TreeKemError::UnknownMember(commit.committer.clone())),
+            }
+        }
+    };
+
+    let Some(group) = ui.session.groups.get_mut(&group_id) else {
+        return Task::none();
+    };
+    match result {
+        Ok(Some(tree)) => group.tree = Some(tree),
+        Ok(None) => {}
+        Err(TreeKemError::Removed) => group.tree = None,
+        Err(e) => ui.transport_status = Some(format!("TreeKEM message from {} refused: {e}", envelope.sender)),
+    }
+    ui.session.save("session.json");
+    Task::batch([acknowledge(ui, envelope.id), retry_held(ui)])
+}
+
+/*
+ * Keeps sender keys in line with the members, once updates were applied:
+ *  - someone left: we rotate our key and send the new one to those who
+ *    remain, so the former member cannot read what we send next
+ *  - someone joined: they get our current key
+ *  - we just joined: everyone gets our key
+ *
+ * In TreeKEM mode, the designated committer commits the changes to the
+ * tree instead (see `Group::committer`).
+ */
+fn after_membership_change(ui: &mut UI, group_id: &str, applied: &Applied, joined: bool) {
+    let me = ui.current_user.username().to_string();
+    let Some(group) = ui.session.groups.get_mut(group_id) else {
+        return;
+    };
+    if !group.is_member(&me) {
+        return;
+    }
+    if group.uses_treekem() {
+        if group.committer() == Some(&me) {
+            commit_tree(ui, group_id, &applied.added, &applied.removed);
+        }
+        return;
+    }
+    if !applied.removed.is_empty() {
+        group.rotate_sender_key();
+        ui.session.save("session.json");
+        distribute_sender_key(ui, group_id);
+    } else if joined {
+        distribute_sender_key(ui, group_id);
+    } else if !applied.added.is_empty() {
+        send_sender_key(ui, group_id, &applied.added);
+    }
+}
+
+/*
+ * Applies group updates received from `sender`.
+ *
+ * Updates for an unknown group must include its creation: we were
+ * invited, and check the whole history before joining. Envelopes held
+ * while waiting for the group are then processed.
+ */
+fn receive_group_updates(ui: &mut UI, sender: &str, updates: GroupUpdates) -> Task<Message> {
+    let me = ui.current_user.username().to_string();
+    let Some(group_id) = updates.updates.first().map(|u| u.group_id.clone()) else {
+        return Task::none();
+    };
+    if !groups::is_group(&group_id) {
+        return Task::none();
+    }
+    let mut identities: HashMap<String, sign::PublicKey> =
+        ui.contacts.users.iter().map(|c| (c.username().to_string(), c.identity_pk)).collect();
+    identities.insert(me.clone(), ui.current_user.identity_pk);
+
+    let joined = !ui.session.groups.contains_key(&group_id);
+    if joined {
+        let Some(create) = updates.updates.iter().find(|u| u.version == 0) else {
+            return Task::none();
+        };
+        let Some(author_pk) = identities.get(&create.author) else {
+            return Task::none();
+        };
+        match Group::from_create(create, author_pk) {
+            Ok(group) => ui.session.groups.insert(group_id.clone(), group),
+            Err(e) => {
+                ui.transport_status = Some(format!("group invitation from {sender} refused: {e}"));
+                return Task::none();
+            }
+        };
+    }
+    let Some(group) = ui.session.groups.get_mut(&group_id) else {
+        return Task::none();
+    };
+    let (applied, errors) = group.receive(updates.updates, |name| identities.get(name).copied());
+    if let Some(e) = errors.first() {
+        ui.transport_status = Some(format!("group update from {sender} refused: {e}"));
+    }
+    if joined && !group.is_member(&me) {
+        ui.session.groups.remove(&group_id);
+        return Task::none();
+    }
+    ui.session.save("session.json");
+    after_membership_change(ui, &group_id, &applied, joined);
+    Task::batch([retry_held(ui), flush_outbox(ui)])
+}
+
+/*
+ * Handles a sender key distribution. Distributions for a group we do
+ * not know yet are held (not acknowledged) until its updates arrive.
+ */
+fn receive_sender_key(ui: &mut UI, envelope: Envelope) -> Task<Message> {
+    let Some(distribution) = SenderKeyDistribution::open(&envelope, &ui.current_user) else {
+        return acknowledge(ui, envelope.id);
+    };
+    if groups::is_group(&distribution.group_id) && !ui.session.groups.contains_key(&distribution.group_id) {
+        return hold(ui, envelope);
+    }
+    let ack = acknowledge(ui, envelope.id.clone());
+    Task::batch([ack, accept_sender_key(ui, &envelope.sender, distribution)])
+}
+
+/*
+ * Stores a sender key received from `sender` for a known group, then
+ * processes the group messages held while waiting for it.
+ */
+fn accept_sender_key(ui: &mut UI, sender: &str, distribution: SenderKeyDistribution) -> Task<Message> {
+    let Some(group) = ui.session.groups.get_mut(&distribution.group_id) else {
+        return Task::none();
+    };
+    if !group.accept_distribution(sender, distribution.key) {
+        return Task::none();
+    }
+    ui.session.save("session.json");
+    retry_held(ui)
+}
+
+/* Holds an envelope until it can be processed (see `drop_held`) */
+fn hold(ui: &mut UI, envelope: Envelope) -> Task<Message> {
+    let now = outbox::now_ms();
+    ui.held.push((now, envelope));
+    drop_held(ui, now)
+}
+
+/*
+ * Drops the held envelopes waiting for longer than `HELD_TTL_MS`, then
+ * the oldest ones of a sender holding more than `MAX_HELD_PER_SENDER`,
+ * then the oldest ones beyond `MAX_HELD`. Dropped envelopes are
+ * acknowledged, so the relay stops redelivering them.
+ */
+fn drop_held(ui: &mut UI, now: u64) -> Task<Message> {
+    ui.held.sort_by_key(|(since, _)| *since);
+    let mut kept: Vec<(u64, Envelope)> = Vec::new();
+    let mut dropped: Vec<Envelope> = Vec::new();
+    let mut per_sender: HashMap<String, usize> = HashMap::new();
+    /* Newest first, so the oldest are the ones over the limits */
+    for (since, envelope) in std::mem::take(&mut ui.held).into_iter().rev() {
+        let count = per_sender.entry(envelope.sender.clone()).or_default();
+        *count += 1;
+        if now.saturating_sub(since) > HELD_TTL_MS || *count > MAX_HELD_PER_SENDER || kept.len() >= MAX_HELD {
+            dropped.push(envelope);
+        } else {
+            kept.push((since, envelope));
+        }
+    }
+    kept.reverse();
+    ui.held = kept;
+    if !dropped.is_empty() {
+        ui.transport_status = Some(format!("{} group messages waiting too long were dropped", dropped.len()));
+    }
+    let acks: Vec<Task<Message>> = dropped
+        .into_iter()
+        .map(|envelope| acknowledge(ui, envelope.id))
+        .collect();
+    Task::batch(acks)
+}
+
+/* Processes the held envelopes again (those still waiting are held again, since when they were first held) */
+fn retry_held(ui: &mut UI) -> Task<Message> {
+    let held = std::mem::take(&mut ui.held);
+    let first_held: HashMap<String, u64> = held.iter().map(|(since, e)| (e.id.clone(), *since)).collect();
+    let retried: Vec<Task<Message>> = held
+        .into_iter()
+        .map(|(_, envelope)| match envelope.kind {
+            EnvelopeKind::SenderKey => receive_sender_key(ui, envelope),
+            EnvelopeKind::TreeKem => receive_treekem(ui, envelope),
+            _ => receive_group(ui, envelope),
+        })
+        .collect();
+    for (since, envelope) in ui.held.iter_mut() {
+        if let Some(first) = first_held.get(&envelope.id) {
+            *since = (*since).min(*first);
+        }
+    }
+    let expired = drop_held(ui, outbox::now_ms());
+    Task::batch(retried.into_iter().chain([expired, flush_outbox(ui)]))
+}
+
+/*
+ * Decrypts and stores a group message.
+ *
+ * If the sender's key has not arrived yet, the envelope is held (and not
+ * acknowledged) until it does. Invalid messages are dropped, and so,
+ * quietly, are payloads this client does not read.
+ */
+fn receive_group(ui: &mut UI, envelope: Envelope) -> Task<Message> {
+    let Some(message) = GroupMessage::from_envelope(&envelope) else {
+        return acknowledge(ui, envelope.id);
+    };
+    let sender_pk = ui.contacts.get(&envelope.sender).map(|c| c.identity_pk);
+    let Some(group) = ui.session.groups.get_mut(&message.group_id) else {
+        return hold(ui, envelope);
+    };
+    let decrypted = if group.uses_treekem() {
+        /* TreeKEM: wait for our welcome, or for the commit starting the message's epoch */
+        let member = group.is_member(ui.current_user.username());
+        match (group.tree.as_mut(), sender_pk) {
+            (None, _) if member => {
+                return hold(ui, envelope);
+            }
+            (Some(tree), Some(pk)) => match tree.decrypt(&envelope.sender, &message, &pk) {
+                Err(TreeKemError::WrongEpoch { ours, got }) if got > ours => {
+                    return hold(ui, envelope);
+                }
+                Err(TreeKemError::Unreadable) => {
+                    return acknowledge(ui, envelope.id);
+                }
+                result => result.map_err(|e| e.to_string()),
+            },
+            _ => Err(TreeKemError::UnknownMember(envelope.sender.clone()).to_string()),
+        }
+    } else {
+        match group.decrypt(&envelope.sender, &message) {
+            Err(GroupError::MissingSenderKey) => {
+                return hold(ui, envelope);
+            }
+            Err(GroupError::Unreadable) => {
+                return acknowledge(ui, envelope.id);
+            }
+            result => result.map_err(|e| e.to_string()),
+        }
+    };
+    let (_plaintext, message_key, log) = match decrypted {
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            ui.transport_status = Some(format!("group message from {} dropped: {e}", envelope.sender));
+            return acknowledge(ui, envelope.id);
+        }
+    };
+    let viewing = ui.selected_contact.as_deref() == Some(message.group_id.as_str());
+    let inserted = ui.session.insert_message(
+        &message.group_id,
+        StoredMessage {
+            id: message.message_id,
+            sender: envelope.sender.clone(),
+            ciphertext: message.ciphertext,
+            ephemeral_pk: Vec::new(),
+            nonce: message.nonce,
+            log,
+            read: viewing,
+            message_key: message_key.to_vec(),
+            sent_at: outbox::now_ms(),
+        },
+    );
+    ui.session.save("session.json");
+    if inserted {
+        queue_receipt(ui, &envelope.sender, Some(&envelope.sender_device), ReceiptKind::Delivered, vec![envelope.id.clone()]);
+    }
+    Task::batch([acknowledge(ui, envelope.id), flush_outbox(ui)])
+}
+
+/*
+ * Encrypts a receipt for device `device` of `peer` (every device if `None`)
+ * and queues it in the outbox, so it benefits from the same retries as
+ * normal messages.
+ */
+fn queue_receipt(ui: &mut UI, peer: &str, device: Option<&str>, kind: ReceiptKind, message_ids: Vec<String>) {
+    let Some(contact) = ui.contacts.get(peer) else {
+        return;
+    };
+    let mut targets = devices::devices_of(contact);
+    if let Some(device) = device {
+        targets.retain(|d| device_list::mailbox(peer, &d.device_id) == device_list::mailbox(peer, device));
+    }
+    let receipt = Receipt { kind, message_ids };
+    let envelopes = devices::seal_for_devices(EnvelopeKind::Receipt, &receipt, &ui.current_user, contact, &targets);
+    for envelope in envelopes {
+        ui.outbox.enqueue(envelope);
+    }
+    ui.outbox.save("outbox.json");
+}
+
+/*
+ * Sends a typing signal to every device of the selected contact, if
+ * enabled and not rate-limited. The signal goes straight to the
+ * transport: it is ephemeral, so it is neither stored nor retried.
+ */
+fn send_typing(ui: &mut UI) -> Task<Message> {
+    if !ui.settings.typing_indicators || ui.input_value.trim().is_empty() {
+        return Task::none();
+    }
+    let Some(name) = ui.selected_contact.clone() else {
+        return Task::none();
+    };
+    let Some(transport) = ui.transport_for(&name) else {
+        return Task::none();
+    };
+    let Some(contact) = ui.contacts.get(&name) else {
+        return Task::none();
+    };
+    let now = outbox::now_ms();
+    if !ui.typing.should_send(&name, now) {
+        return Task::none();
+    }
+    let signal = TypingSignal { sent_at_ms: now };
+    let targets = devices::devices_of(contact);
+    let envelopes = devices::seal_for_devices(EnvelopeKind::Typing, &signal, &ui.current_user, contact, &targets);
+    Task::batch(envelopes.into_iter().map(|envelope| {
+        let transport = transport.clone();
+        Task::perform(
+            async move { transport.send(envelope).await.map_err(|e| e.to_string()) },
+            Message::TransportFinished,
+        )
+    }))
+}
+
+/* Fetches the device list of account `name` from the relay */
+fn fetch_devices(ui: &UI, name: String) -> Task<Message> {
+    let Some(transport) = ui.transport.clone() else {
+        return Task::none();
+    };
+    Task::perform(
+        async move {
+            let result = transport.fetch_devices(&name).await.map_err(|e| e.to_string());
+            (name, result)
+        },
+        |(name, result)| Message::DevicesFetched(name, result),
+    )
+}
+
+/*
+ * Fetches the link requests waiting for our linking code, if one is
+ * shown; the code is withdrawn once it has expired.
  */
+fn poll_link(ui: &mut UI) -> Task<Message> {
+    let Some((code, expires_at)) = &ui.link_offer else {
+        return Task::none();
+    };
+    if outbox::now_ms() >= *expires_at {
+        ui.link_offer = None;
+        ui.device_status = Some("the linking code expired".to_string());
+        return Task::none();
+    }
+    let Some(transport) = ui.transport.clone() else {
+        return Task::none();
+    };
+    let channel = code.channel(link_code::REQUESTS);
+    Task::perform(
+        async move { transport.link_fetch(&channel).await.map_err(|e| e.to_string()) },
+        Message::LinkPolled,
+    )
+}
 
-use crate::client::contacts::Contacts;
-use crate::client::sessions::Session;
-use crate::client::user::User;
-use iced::border::{Border, Radius};
-use iced::widget::{button, column, container, row, scrollable, text, text_input, Column, Row};
-use iced::{color, Alignment, Background, Color, Element, Length, Theme};
-use sodiumoxide::crypto::box_;
+/*
+ * Publishes our device list, then, for a device we just linked, posts
+ * the response to its request: the new device logs in as soon as it
+ * has it, so the relay must already list it.
+ */
+fn publish_devices(ui: &UI, linked: Option<(LinkCode, LinkResponse)>) -> Task<Message> {
+    let Some(transport) = ui.transport.clone() else {
+        return Task::done(Message::DevicesPublished(Err("not connected".to_string())));
+    };
+    let list = ui.current_user.devices.clone();
+    Task::perform(
+        async move {
+            transport.publish_devices(&list).await?;
+            if let Some((code, response)) = linked {
+                let response = serde_json::to_vec(&response).map_err(std::io::Error::other)?;
+                transport.link_post(&code.channel(link_code::RESPONSES), response).await?;
+            }
+            Ok(())
+        },
+        |result: std::io::Result<()>| Message::DevicesPublished(result.map_err(|e| e.to_string())),
+    )
+}
 
 /*
- * Holds all application state required by the GUI.
- *
- * Fields:
- *  - `input_value`      : Current text inside the message input field
- *  - `contacts`         : Contact list (other users)
- *  - `selected_contact` : Currently selected contact for conversation
- *  - `current_user`     : The active user of this client
- *  - `session`          : Persistent conversations, stored on disk
+ * Fetches the device list of `account` again if `device` is not in the
+ * list we have: it was linked since we last fetched it.
  */
-pub struct UI {
-    input_value: String,
-    pub contacts: Contacts,
-    selected_contact: Option<String>,
-    pub current_user: User,
-    pub session: Session,
+fn refresh_unknown_device(ui: &UI, account: &str, device: &str) -> Task<Message> {
+    let device = if device.is_empty() { PRIMARY_DEVICE } else { device };
+    let known = if account == ui.current_user.username() {
+        Some(&ui.current_user)
+    } else {
+        ui.contacts.get(account)
+    };
+    match known {
+        Some(user) if user.devices.device(device).is_none() => fetch_devices(ui, account.to_string()),
+        _ => Task::none(),
+    }
 }
 
-impl UI {
-    /*
-     * Creates a new `UI` state initialized with the provided contacts
-     * and current user. It automatically attempts to load any existing
-     * session data from `session.json`.
-     */
-    pub fn with_contacts(contacts: Contacts, current_user: User) -> Self {
-        let session = Session::load("session.json");
-        Self {
-            input_value: String::new(),
-            contacts,
-            selected_contact: None,
-            current_user,
-            session,
+/*
+ * Stores a message we sent from another of our devices in its
+ * conversation, re-encrypted like a message sent from this one.
+ *
+ * Only our own account can sync messages to us; anything else is
+ * acknowledged and dropped.
+ */
+fn receive_synced(ui: &mut UI, envelope: Envelope) -> Task<Message> {
+    let me = ui.current_user.username().to_string();
+    let refresh = refresh_unknown_device(ui, &me, &envelope.sender_device);
+    let synced = Some(&envelope)
+        .filter(|e| e.sender == me)
+        .and_then(|e| SyncedMessage::open(e, &ui.current_user));
+    if let Some(synced) = synced {
+        if ui.contacts.get(&synced.peer).is_some() {
+            let (epk, nonce, ciphertext, log) = ui.current_user.encrypt_message_with_logs(&ui.current_user, &synced.payload);
+            ui.session.insert_message(
+                &synced.peer,
+                StoredMessage {
+                    id: synced.message_id,
+                    sender: me.clone(),
+                    ciphertext,
+                    ephemeral_pk: epk.as_ref().to_vec(),
+                    nonce: nonce.0.to_vec(),
+                    log: format!("== log (sync) ==\nSent from our device {}\n{log}", envelope.sender_device),
+                    read: true,
+                    message_key: Vec::new(),
+                    sent_at: outbox::now_ms(),
+                },
+            );
+            ui.session.save("session.json");
         }
     }
+    Task::batch([acknowledge(ui, envelope.id), refresh])
 }
 
 /*
- * Represents all possible messages/events that can occur in the UI.
+ * Handles a piece of a history transfer from another of our devices;
+ * once the transfer is complete, checks it and merges it into our
+ * session (see `client::history`). Its envelopes are acknowledged only
+ * then, whether it was merged or rejected.
+ */
+fn receive_history(ui: &mut UI, envelope: Envelope) -> Task<Message> {
+    let me = ui.current_user.username().to_string();
+    let message = Some(&envelope)
+        .filter(|e| e.sender == me && e.sender_device != ui.current_user.device_id)
+        .and_then(|e| HistoryMessage::open(e, &ui.current_user));
+    let Some(message) = message else {
+        return acknowledge(ui, envelope.id);
+    };
+    let id = message.transfer_id().to_string();
+    if !ui.transfers.contains_key(&id) && ui.transfers.len() >= MAX_TRANSFERS {
+        return acknowledge(ui, envelope.id);
+    }
+    let pending = ui.transfers.entry(id.clone()).or_insert_with(|| PendingTransfer {
+        from: envelope.sender_device.clone(),
+        transfer: IncomingTransfer::default(),
+        envelopes: Vec::new(),
+    });
+    pending.transfer.add(message);
+    if !pending.envelopes.contains(&envelope.id) {
+        pending.envelopes.push(envelope.id);
+    }
+    if !pending.transfer.is_complete() {
+        return Task::none();
+    }
+    let Some(PendingTransfer { from, transfer, envelopes }) = ui.transfers.remove(&id) else {
+        return Task::none();
+    };
+    ui.device_status = Some(match transfer.open() {
+        Ok(records) => {
+            let total = records.len();
+            let added = history::merge(&mut ui.session, &ui.current_user, &ui.contacts, records, &from);
+            ui.session.save("session.json");
+            format!("history from device {from}: {added} new of {total} messages")
+        }
+        Err(e) => format!("history from device {from}: {e}"),
+    });
+    Task::batch(envelopes.into_iter().map(|id| acknowledge(ui, id)))
+}
+
+/*
+ * Acknowledges envelope `id` to the transport it arrived on: an envelope
+ * the relay delivered stays in its mailbox until the relay gets the ack,
+ * even if a direct link with its sender is open now.
+ */
+fn acknowledge(ui: &mut UI, id: String) -> Task<Message> {
+    let Some(transport) = ui.arrived_on.remove(&id) else {
+        return Task::none();
+    };
+    Task::perform(
+        async move { transport.acknowledge(&id).await.map_err(|e| e.to_string()) },
+        Message::TransportFinished,
+    )
+}
+
+/*
+ * Sends every outbox entry that is due, one `Task` per envelope,
+ * each through the transport of its recipient.
  *
- * - `InputChanged`: Fired when the user types in the input field
- * - `Send`        : Fired when the user presses "Enter" or clicks "Send"
- * - `SelectContact`: Fired when the user selects a contact from the list
+ * Entries whose recipient cannot be reached (no transport) stay
+ * `Pending` and go out once a transport is connected. A direct link
+ * reaches the contact's primary device only; copies for their other
+ * devices go through the relay.
  */
-#[derive(Debug, Clone)]
-pub enum Message {
-    InputChanged(String),
-    Send,
-    SelectContact(String),
+fn flush_outbox(ui: &mut UI) -> Task<Message> {
+    let to_primary = |e: &Envelope| device_list::mailbox(&e.recipient, &e.device) == device_list::mailbox(&e.recipient, PRIMARY_DEVICE);
+    let has_direct = |e: &Envelope| to_primary(e) && ui.direct.contains_key(&e.recipient);
+    let has_relay = ui.transport.is_some();
+    let due = ui.outbox.take_due(outbox::now_ms(), |e| has_relay || has_direct(e));
+    Task::batch(due.into_iter().filter_map(|envelope| {
+        let transport = if to_primary(&envelope) { ui.transport_for(&envelope.recipient)? } else { ui.transport.clone()? };
+        let id = envelope.id.clone();
+        Some(Task::perform(
+            async move { transport.send(envelope).await.map_err(|e| e.to_string()) },
+            move |result| Message::OutboxSent(id.clone(), result),
+        ))
+    }))
 }
 
 /*
- * The update function (Elm-style).
+ * The subscription function (Elm-style).
  *
- * It applies state transitions based on the received message:
- *  - Handles text input updates
- *  - Encrypts and persists messages on "Send"
- *  - Changes the active conversation on "SelectContact"
+ * While a transport is attached, it logs in as the current device, subscribes
+ * to their mailbox and turns every incoming envelope into `Message::EnvelopeReceived`.
+ * The subscription is keyed by the transport instance, so attaching a
+ * new transport restarts it.
+ *
+ * While messages are waiting in the outbox, typing indicators are
+ * shown, envelopes are held or a linking code is waiting for a new
+ * device, a one-second timer emits `Message::Tick` to retry / expire / poll them.
  */
-pub fn update(ui: &mut UI, message: Message) {
-    match message {
-        Message::InputChanged(value) => ui.input_value = value,
-        Message::Send => {
-            if let Some(name) = &ui.selected_contact {
-                let text = ui.input_value.trim();
-                if text.is_empty() {
+pub fn subscription(ui: &UI) -> Subscription<Message> {
+    let pending = ui.outbox.entries.iter().any(|e| e.state == DeliveryState::Pending);
+    let retries = if pending || ui.typing.any() || ui.link_offer.is_some() || !ui.held.is_empty() {
+        iced::time::every(Duration::from_secs(1)).map(|_| Message::Tick)
+    } else {
+        Subscription::none()
+    };
+    let ratchet = if ui.inspector.ratchet.is_some() {
+        iced::time::every(visualizer::FRAME).map(|_| Message::RatchetFrame)
+    } else {
+        Subscription::none()
+    };
+    let links = ui.transport.iter().chain(ui.direct.values());
+    let incoming = links.map(|transport| incoming(&ui.current_user, transport.clone()));
+    Subscription::batch([retries, ratchet].into_iter().chain(incoming))
+}
+
+/* Delivers envelopes from one transport (relay or direct link) as messages */
+fn incoming(user: &User, transport: Arc<dyn Transport>) -> Subscription<Message> {
+    let user = user.clone();
+    let id = (user.username.clone(), Arc::as_ptr(&transport) as *const () as usize);
+
+    Subscription::run_with_id(
+        id,
+        iced::stream::channel(32, move |mut output| async move {
+            /* Sessions are short-lived: log in again each time ours expires */
+            loop {
+                if let Err(e) = transport.subscribe(&user).await {
+                    let _ = output.send(Message::TransportFinished(Err(e.to_string()))).await;
                     return;
                 }
-                if let Some(recipient) = ui.contacts.get(name) {
-                    // Encrypt the message (produces ciphertext + logs)
-                    let (epk, nonce, ciphertext, send_log) =
-                        ui.current_user.encrypt_message_with_logs(recipient, text);
-
-                    // Store it persistently under the recipient's conversation
-                    ui.session.add_message(
-                        name,
-                        ciphertext.clone(),
-                        epk,
-                        nonce,
-                        send_log,
-                    );
-                    ui.session.save("session.json");
-
-                    // Reset input field
-                    ui.input_value.clear();
+                loop {
+                    let message = match transport.receive().await {
+                        Ok(envelope) => Message::EnvelopeArrived(transport.clone(), envelope),
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break,
+                        Err(e) => {
+                            let _ = output.send(Message::TransportFinished(Err(e.to_string()))).await;
+                            return;
+                        }
+                    };
+                    if output.send(message).await.is_err() {
+                        return;
+                    }
                 }
             }
-        }
-        Message::SelectContact(name) => {
-            ui.selected_contact = Some(name);
-        }
-    }
+        }),
+    )
 }
 
 /*
  * The view function (Elm-style).
  *
  * It renders the interface based on the current `UI` state:
- *  - Left column  : list of contacts
+ *  - Left column  : list of contacts and groups
  *  - Right column : chat history (decrypted) + input field
+ *  - Group info   : for a group, its members, admin actions and history
  *
  * For each stored message:
  *  - Plaintext is shown in white
  *  - Encryption/decryption logs are displayed in semi-transparent gray
  */
-pub fn view(ui: &UI) -> Element<Message> {
+pub fn view(ui: &UI) -> Element<'_, Message> {
     /* Build the left column (contact list) */
     let mut contacts_col: Column<Message> = column![].spacing(10);
 
     for u in &ui.contacts.users {
         let name = u.username();
         let selected = ui.selected_contact.as_deref() == Some(name);
-        let label = if selected { format!("> {}", name) } else { name.to_string() };
+        let shown = ui.contacts.display_name(name);
+        let mut label = if selected { format!("> {}", shown) } else { shown };
+        if ui.contacts.is_blocked(name) {
+            label.push_str(" ⛔");
+        }
+        match ui.directory.get(name) {
+            Some(ClaimStatus::Verified(_)) => label.push_str(" ✓"),
+            Some(ClaimStatus::Rejected(_)) => label.push_str(" ⚠"),
+            Some(ClaimStatus::Unregistered) | None => {}
+        }
 
         let contact_btn = button(text(label))
             .width(Length::Fill)
@@ -149,6 +2279,127 @@ pub fn view(ui: &UI) -> Element<Message> {
         contacts_col = contacts_col.push(contact_btn);
     }
 
+    contacts_col = contacts_col.push(
+        button(text(if ui.contacts_panel { "Close contacts" } else { "Manage contacts" }).size(12))
+            .on_press(Message::ToggleContactsPanel),
+    );
+
+    /* Groups, then the form creating a new one */
+    let mut group_list: Vec<&Group> = ui.session.groups.values().collect();
+    group_list.sort_by(|a, b| a.name.cmp(&b.name));
+    for group in group_list {
+        let selected = ui.selected_contact.as_deref() == Some(group.id.as_str());
+        let label = format!("{}# {} ({})", if selected { "> " } else { "" }, group.name, group.members.len());
+        contacts_col = contacts_col.push(
+            button(text(label))
+                .width(Length::Fill)
+                .on_press(Message::SelectContact(group.id.clone()))
+                .style(|_theme: &Theme, _status| iced::widget::button::Style {
+                    background: Some(Background::Color(color!(0x1E1E2E))),
+                    text_color: Color::WHITE,
+                    border: Border {
+                        radius: Radius::from(5.0),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+        );
+    }
+    /* Groups live on the primary device (updates are signed with the identity key) */
+    if ui.current_user.identity_sk.is_some() {
+        contacts_col = contacts_col.push(
+            column![
+                text_input("New group name", &ui.group_name_input)
+                    .on_input(Message::GroupNameChanged)
+                    .size(12),
+                text_input("Members (alice, bob)", &ui.group_members_input)
+                    .on_input(Message::GroupMembersChanged)
+                    .on_submit(Message::CreateGroup)
+                    .size(12),
+                checkbox("TreeKEM mode", ui.group_treekem)
+                    .on_toggle(Message::ToggleGroupTreeKem)
+                    .size(12)
+                    .text_size(11),
+                button(text("Create group").size(12)).on_press(Message::CreateGroup),
+            ]
+            .spacing(4),
+        );
+    } else {
+        contacts_col = contacts_col.push(
+            text("Groups are only available on the primary device")
+                .size(11)
+                .color(color!(0x888888)),
+        );
+    }
+
+    /* Privacy settings, below the contacts */
+    contacts_col = contacts_col.push(
+        checkbox("Send read receipts", ui.settings.send_read_receipts)
+            .on_toggle(Message::ToggleReadReceipts)
+            .size(14)
+            .text_size(12),
+    );
+    contacts_col = contacts_col.push(
+        checkbox("Typing indicators", ui.settings.typing_indicators)
+            .on_toggle(Message::ToggleTypingIndicators)
+            .size(14)
+            .text_size(12),
+    );
+    contacts_col = contacts_col.push(
+        row![
+            text("Edit window (min)").size(12),
+            text_input("15", &ui.edit_window_input)
+                .on_input(Message::EditWindowChanged)
+                .size(12)
+                .width(Length::Fixed(50.0)),
+        ]
+        .spacing(6)
+        .align_y(Alignment::Center),
+    );
+    contacts_col = contacts_col.push(
+        text("Edits are timed by the sender's clock; one-to-one, the sender is who the relay says it is")
+            .size(11)
+            .color(color!(0x888888)),
+    );
+
+    /* This device of the account; the primary device links and revokes the others */
+    let device_count = devices::devices_of(&ui.current_user).len();
+    let primary = ui.current_user.identity_sk.is_some();
+    let role = if primary { "primary" } else { "linked" };
+    contacts_col = contacts_col.push(
+        text(format!("device {} of {} ({})", ui.current_user.device_id, device_count, role))
+            .size(12)
+            .color(color!(0x888888)),
+    );
+    if primary {
+        for device in devices::other_devices(&ui.current_user) {
+            contacts_col = contacts_col.push(
+                column![
+                    text(format!("device {}", device.device_id)).size(12),
+                    row![
+                        button(text("Send history").size(11))
+                            .padding([2, 6])
+                            .on_press(Message::SendHistory(device.device_id.clone())),
+                        button(text("Revoke").size(11))
+                            .padding([2, 6])
+                            .on_press(Message::RevokeDevice(device.device_id.clone())),
+                    ]
+                    .spacing(4),
+                ]
+                .spacing(2),
+            );
+        }
+        let label = if ui.link_offer.is_some() { "Cancel linking" } else { "Link a device" };
+        contacts_col = contacts_col.push(button(text(label).size(12)).on_press(Message::LinkDevice));
+        if let Some((code, _)) = &ui.link_offer {
+            contacts_col = contacts_col.push(text(code.code()).size(16).color(Color::WHITE));
+            contacts_col = contacts_col.push(text(code.payload()).size(10).color(color!(0x888888)));
+        }
+    }
+    if let Some(status) = &ui.device_status {
+        contacts_col = contacts_col.push(text(status).size(11).color(color!(0x888888)));
+    }
+
     let contacts_list = container(contacts_col)
         .width(Length::Fixed(180.0))
         .height(Length::Fill)
@@ -160,63 +2411,150 @@ pub fn view(ui: &UI) -> Element<Message> {
 
     /* Build the right column (messages + input) */
     let mut messages_col = column![].spacing(8).padding(10);
+    let mut inspected: Option<(String, Vec<Step>)> = None;
+    let mut visual: Option<Visualizer> = None;
 
     if let Some(name) = &ui.selected_contact {
-        if let Some(messages) = ui.session.get_messages(name) {
-            for stored in messages {
-                if let (Some(epk_bytes), Some(nonce_bytes)) = (
-                    box_::PublicKey::from_slice(&stored.ephemeral_pk),
-                    box_::Nonce::from_slice(&stored.nonce),
-                ) {
-                    if let Some(user) = ui.contacts.get(name) {
-                        if let Some((plaintext, recv_log)) = user.decrypt_message_with_logs(
-                            &epk_bytes,
-                            &nonce_bytes,
-                            &stored.ciphertext,
-                            ui.current_user.username(),
-                        ) {
-                            /* 1) Show decrypted plaintext message */
-                            messages_col = messages_col.push(
-                                text(format!(
-                                    "{} → {}: {}",
-                                    ui.current_user.username(),
-                                    name,
-                                    plaintext
-                                ))
-                                .color(Color::WHITE),
-                            );
-
-                            /* 2) Show sender log (encryption details) */
-                            messages_col = messages_col.push(
-                                text(&stored.log)
-                                    .size(12)
-                                    .color(Color {
-                                        r: 0.7,
-                                        g: 0.7,
-                                        b: 0.7,
-                                        a: 0.6,
-                                    }),
-                            );
-
-                            /* 3) Show receiver log (decryption details) */
-                            messages_col = messages_col.push(
-                                text(recv_log)
-                                    .size(12)
-                                    .color(Color {
-                                        r: 0.7,
-                                        g: 0.7,
-                                        b: 0.7,
-                                        a: 0.6,
-                                    }),
-                            );
-                        }
-                    }
+        let me = ui.current_user.username();
+        let window_ms = ui.settings.edit_window * 1000;
+        let now = outbox::now_ms();
+        let shown = shown_conversation(ui, name);
+        let pairs: Vec<(&StoredMessage, &Payload)> = shown.iter().map(|m| (m.stored, m.payload)).collect();
+        let revisions = Revisions::apply(&pairs, window_ms);
+        let reactions = Reactions::apply(&pairs);
+        for message in &shown {
+            let stored = message.stored;
+
+            /* Reactions show under the message they react to */
+            if matches!(message.payload, Payload::Reaction { .. }) {
+                continue;
+            }
+
+            /* Edits and deletes show in the message they change, unless refused */
+            if matches!(message.payload, Payload::Edit { .. } | Payload::Delete { .. }) {
+                if let Some(reason) = revisions.refused(&stored.id) {
+                    messages_col = messages_col.push(
+                        text(format!("{}: {} ignored ({reason})", message.label, message.payload.kind()))
+                            .size(12)
+                            .color(color!(0x888888)),
+                    );
+                }
+                continue;
+            }
+            if revisions.is_deleted(&stored.id) {
+                messages_col = messages_col.push(text(format!("{}: 🗑 message deleted", message.label)).color(color!(0x888888)));
+                continue;
+            }
+
+            /* 1) Show decrypted plaintext message (its current text, if edited),
+             *    below the message it quotes */
+            if ui.highlighted.as_deref() == Some(stored.id.as_str()) {
+                messages_col = messages_col.push(text("▼ quoted message").size(11).color(color!(0xE5C07B)));
+            }
+            if let Payload::Text { quote: Some(quote), .. } = &message.payload {
+                messages_col = messages_col.push(quote_block(quote, message.label, &shown, &revisions));
+            }
+            let versions = revisions.versions(&stored.id);
+            let payload = match (versions.and_then(|v| v.last()), &message.payload) {
+                (Some(current), Payload::Text { quote, .. }) => Payload::Text {
+                    text: current.clone(),
+                    quote: quote.clone(),
+                },
+                _ => message.payload.clone(),
+            };
+            /* Clicking a message opens it in the protocol inspector */
+            let raw = ui.raw.contains(&stored.id);
+            let inspecting = ui.inspector.is_open(&stored.id);
+            if inspecting && !ui.inspector.diagram {
+                inspected = Some((stored.id.clone(), inspector_steps(ui, name, message)));
+            }
+            let mut content = mouse_area(message_line(ui, name, message.author, message.label.to_string(), &payload, raw));
+            if !stored.id.is_empty() {
+                content = content.on_press(Message::Inspect(stored.id.clone()));
+            }
+            let mut line = row![content].spacing(10).align_y(Alignment::Center);
+            if inspecting {
+                line = line.push(text("◀ inspecting").size(11).color(color!(0xE5C07B)));
+            }
+            if let Payload::Text { text: body, .. } = &payload {
+                if !stored.id.is_empty() && markdown::has_formatting(&markdown::parse(body)) {
+                    line = line.push(
+                        button(text(if raw { "Formatted" } else { "Raw" }).size(12))
+                            .padding([2, 8])
+                            .on_press(Message::ToggleRaw(stored.id.clone())),
+                    );
+                }
+            }
+            if edits::can_change(stored, me, now, window_ms) {
+                if let Payload::Text { text: current, .. } = &payload {
+                    line = line.push(
+                        button(text("Edit").size(12))
+                            .padding([2, 8])
+                            .on_press(Message::EditMessage(stored.id.clone(), current.clone())),
+                    );
+                }
+                line = line.push(
+                    button(text("Delete").size(12))
+                        .padding([2, 8])
+                        .on_press(Message::DeleteMessage(stored.id.clone())),
+                );
+            }
+            if !stored.id.is_empty() {
+                let author = if stored.sender.is_empty() { me } else { stored.sender.as_str() };
+                line = line.push(
+                    button(text("Reply").size(12))
+                        .padding([2, 8])
+                        .on_press(Message::ReplyTo(Quote::of(&stored.id, author, &payload))),
+                );
+                line = line.push(
+                    button(text("☺+").size(12))
+                        .padding([2, 8])
+                        .on_press(Message::ToggleReactionPicker(stored.id.clone())),
+                );
+            }
+            messages_col = messages_col.push(line);
+            if let Some(chips) = reaction_row(ui, &reactions, &stored.id) {
+                messages_col = messages_col.push(chips);
+            }
+            if let Some(versions) = versions {
+                let earlier: Vec<String> = versions[..versions.len() - 1].iter().map(|v| format!("\"{v}\"")).collect();
+                messages_col = messages_col.push(
+                    text(format!("edited · earlier: {}", earlier.join(" → ")))
+                        .size(11)
+                        .color(color!(0x888888)),
+                );
+            }
+
+            /* 1b) Delivery state of our own messages */
+            if message.outgoing {
+                if groups::is_group(name) {
+                    messages_col = messages_col.push(group_delivery_status(ui, &stored.id));
+                } else if let Some(entry) = ui.outbox.entry(&stored.id) {
+                    messages_col = messages_col.push(delivery_status(entry));
                 }
             }
         }
+
+        /* The diagram of the inspected message, or of the current ratchet step while it plays */
+        let index = match ui.inspector.ratchet {
+            Some(playback) => Some(playback.step.min(shown.len().saturating_sub(1))),
+            None => shown.iter().position(|m| ui.inspector.is_open(&m.stored.id)),
+        };
+        if let (true, Some(id), Some(index)) = (ui.inspector.diagram, &ui.inspector.open, index) {
+            if let Some(message) = shown.get(index) {
+                visual = Some(Visualizer {
+                    diagram: diagram_of(ui, name, message),
+                    timeline: shown.iter().map(|m| ratchet_label(name, m)).collect(),
+                    current: index,
+                    progress: ui.inspector.ratchet.map_or(1.0, |playback| playback.progress),
+                });
+                inspected = Some((id.clone(), Vec::new()));
+            }
+        }
     }
 
     let messages_scroll = scrollable(messages_col)
+        .id(scrollable::Id::new(MESSAGES_SCROLL))
         .height(Length::FillPortion(8))
         .width(Length::Fill);
 
@@ -240,12 +2578,672 @@ pub fn view(ui: &UI) -> Element<Message> {
             ..Default::default()
         });
 
-    let input_row: Row<Message> = row![input, send_button]
+    let mut input_row: Row<Message> = row![input, send_button]
+        .spacing(10)
+        .align_y(Alignment::Center);
+    if ui.editing.is_some() {
+        input_row = input_row.push(button(text("Cancel edit").size(12)).on_press(Message::CancelEdit));
+    }
+    let mut reply_row = row![].spacing(10).align_y(Alignment::Center);
+    if let Some(quote) = ui.replying.as_ref().filter(|_| ui.editing.is_none()) {
+        reply_row = reply_row.push(
+            text(format!("↪ replying to {}: {}", quote.author, quote.excerpt))
+                .size(12)
+                .color(color!(0x888888)),
+        );
+        reply_row = reply_row.push(button(text("Cancel").size(12)).padding([2, 8]).on_press(Message::CancelReply));
+    }
+
+    /* Chat header: contact name + typing indicator + directory check + direct link controls
+     * (for a group: its name and members) */
+    let mut header = column![].spacing(2);
+    if let Some(group) = ui.selected_contact.as_ref().and_then(|id| ui.session.groups.get(id)) {
+        header = header.push(text(format!("# {}", group.name)).size(18).color(Color::WHITE));
+        let keys = if group.uses_treekem() {
+            match &group.tree {
+                Some(tree) => format!("TreeKEM epoch {}", tree.epoch),
+                None => "TreeKEM: waiting for a welcome".to_string(),
+            }
+        } else {
+            let received = group.others(ui.current_user.username()).filter(|m| group.peers.contains_key(*m)).count();
+            format!("sender keys received: {}/{}", received, group.members.len() - 1)
+        };
+        header = header.push(
+            text(format!("{} · {}", group.members.join(", "), keys))
+                .size(12)
+                .color(color!(0x888888)),
+        );
+    } else if let Some(name) = &ui.selected_contact {
+        header = header.push(text(ui.contacts.display_name(name)).size(18).color(Color::WHITE));
+        if ui.contacts.is_blocked(name) {
+            header = header.push(
+                text("⛔ blocked: their messages are dropped")
+                    .size(12)
+                    .color(color!(0xE06C75)),
+            );
+        }
+        if ui.typing.is_typing(name) {
+            header = header.push(
+                text(format!("{} is typing…", ui.contacts.display_name(name)))
+                    .size(12)
+                    .color(color!(0x98C379)),
+            );
+        }
+        let (claim_label, claim_tint) = match ui.directory.get(name) {
+            Some(ClaimStatus::Verified(_)) => {
+                ("✓ username verified in the directory and its transparency log".to_string(), color!(0x98C379))
+            }
+            Some(ClaimStatus::Unregistered) => ("username not registered in the directory".to_string(), color!(0xE5C07B)),
+            Some(ClaimStatus::Rejected(e)) => (format!("⚠ directory: {e}"), color!(0xE06C75)),
+            None => ("directory not checked".to_string(), color!(0x888888)),
+        };
+        header = header.push(text(claim_label).size(12).color(claim_tint));
+        if let Some(contact) = ui.contacts.get(name) {
+            let ids: Vec<String> = devices::devices_of(contact).into_iter().map(|d| d.device_id).collect();
+            header = header.push(
+                text(format!("devices: {} (list v{})", ids.join(", "), contact.devices.version))
+                    .size(12)
+                    .color(color!(0x888888)),
+            );
+        }
+
+        let direct_row = row![
+            text_input("host:port (direct, no relay)", &ui.direct_input)
+                .on_input(Message::DirectAddressChanged)
+                .size(12)
+                .width(Length::Fixed(220.0)),
+            button(text("Connect").size(12)).on_press(Message::ConnectDirect),
+            button(text("Listen").size(12)).on_press(Message::ListenDirect),
+            text(ui.direct_status.get(name).map(String::as_str).unwrap_or("via relay"))
+                .size(12)
+                .color(Color {
+                    r: 0.7,
+                    g: 0.7,
+                    b: 0.7,
+                    a: 0.8,
+                }),
+        ]
+        .spacing(8)
+        .align_y(Alignment::Center);
+        header = header.push(direct_row);
+    }
+
+    /* Contact card row: username to share + share button */
+    let mut share_row: Row<Message> = row![
+        text_input("Contact to share (username)", &ui.share_input)
+            .on_input(Message::ShareInputChanged)
+            .on_submit(Message::ShareCard)
+            .size(12)
+            .width(Length::Fixed(220.0)),
+        button(text("Share card").size(12)).on_press(Message::ShareCard),
+    ]
+    .spacing(10)
+    .align_y(Alignment::Center);
+    if let Some(status) = &ui.card_status {
+        share_row = share_row.push(text(status).size(12).color(color!(0x888888)));
+    }
+
+    /* Attachment row: path of a file + attach button */
+    let mut attach_row: Row<Message> = row![
+        text_input("File to attach (path)", &ui.attach_input)
+            .on_input(Message::AttachPathChanged)
+            .on_submit(Message::Attach)
+            .size(12)
+            .width(Length::Fill),
+        button(text("Attach").size(12)).on_press(Message::Attach),
+    ]
+    .spacing(10)
+    .align_y(Alignment::Center);
+    if let Some(status) = &ui.attach_status {
+        attach_row = attach_row.push(text(status).size(12).color(color!(0x888888)));
+    }
+
+    let mut chat_col = column![header, messages_scroll, reply_row, input_row, attach_row, share_row].spacing(10).padding(10);
+
+    /* Transport errors are shown below the input row */
+    if let Some(status) = &ui.transport_status {
+        chat_col = chat_col.push(text(status).size(12).color(color!(0xE06C75)));
+    }
+    if let Some(warning) = &ui.transparency_warning {
+        chat_col = chat_col.push(text(warning).size(14).color(color!(0xE06C75)));
+    }
+
+    /* Final layout: left = contacts, right = chat (and the group info panel, or the contacts panel) */
+    let mut layout = row![contacts_list, chat_col];
+    if ui.contacts_panel {
+        layout = layout.push(contacts_panel(ui));
+    } else if let Some((id, steps)) = inspected {
+        layout = layout.push(inspector_panel(ui, id, steps, visual));
+    } else if let Some(group) = ui.selected_contact.as_ref().and_then(|id| ui.session.groups.get(id)) {
+        layout = layout.push(group_panel(ui, group));
+    }
+    layout.into()
+}
+
+/*
+ * Renders the quote above a reply: the quoted message as stored here
+ * (pressing it scrolls to it and highlights it), or, if it is not, the
+ * excerpt the sender (`replier`) quoted, with the author they named; that
+ * name is only their say. A quoted message that was deleted stays hidden.
+ */
+fn quote_block<'a>(quote: &Quote, replier: &str, shown: &[Shown], revisions: &Revisions) -> Element<'a, Message> {
+    let dim = color!(0x888888);
+    let Some(original) = shown.iter().find(|m| !quote.id.is_empty() && m.stored.id == quote.id) else {
+        return text(format!(
+            "↪ {} (according to {replier}): {} (original not available)",
+            quote.author, quote.excerpt
+        ))
+            .size(12)
+            .color(dim)
+            .into();
+    };
+    if revisions.is_deleted(&quote.id) {
+        return text("↪ the quoted message was deleted").size(12).color(dim).into();
+    }
+    let current = match revisions.versions(&quote.id).and_then(|v| v.last()) {
+        Some(current) => replies::excerpt(current),
+        None => replies::excerpt(&original.payload.summary()),
+    };
+    button(text(format!("↪ {}: {}", original.label, current)).size(12))
+        .padding([2, 8])
+        .style(button::text)
+        .on_press(Message::HighlightQuoted(quote.id.clone()))
+        .into()
+}
+
+/*
+ * Renders the reactions to message `id`, one button per emoji with its
+ * count (highlighted if we reacted with it; pressing it toggles ours),
+ * followed by the reaction picker if it is open.
+ */
+fn reaction_row<'a>(ui: &UI, reactions: &Reactions, id: &str) -> Option<Element<'a, Message>> {
+    let me = ui.current_user.username();
+    let summary = reactions.summary(id, me);
+    let picking = ui.reacting.as_deref() == Some(id);
+    if summary.is_empty() && !picking {
+        return None;
+    }
+    let mut chips = row![].spacing(4).align_y(Alignment::Center);
+    for (emoji, count, mine) in summary {
+        let style = if mine { button::primary } else { button::secondary };
+        chips = chips.push(
+            button(text(format!("{emoji} {count}")).size(12))
+                .padding([1, 6])
+                .style(style)
+                .on_press(Message::React(id.to_string(), emoji, mine)),
+        );
+    }
+    if picking {
+        chips = chips.push(text("·").size(12).color(color!(0x888888)));
+        for emoji in reactions::QUICK_REACTIONS {
+            let mine = reactions.has_reacted(id, emoji, me);
+            chips = chips.push(
+                button(text(*emoji).size(12))
+                    .padding([1, 6])
+                    .style(button::text)
+                    .on_press(Message::React(id.to_string(), emoji.to_string(), mine)),
+            );
+        }
+    }
+    Some(chips.into())
+}
+
+/*
+ * Where message `id` sits in conversation `name`, as a relative scroll
+ * offset: its rank among the rows the chat shows (reactions, and edits
+ * and deletes that were applied, have none). Rows differ in height, so
+ * this lands close to the message; its highlight marks it.
+ */
+fn row_offset(ui: &UI, name: &str, id: &str) -> Option<f32> {
+    let shown = shown_conversation(ui, name);
+    let pairs: Vec<(&StoredMessage, &Payload)> = shown.iter().map(|m| (m.stored, m.payload)).collect();
+    let revisions = Revisions::apply(&pairs, ui.settings.edit_window * 1000);
+    let rows: Vec<&str> = shown
+        .iter()
+        .filter(|m| match m.payload {
+            Payload::Reaction { .. } => false,
+            Payload::Edit { .. } | Payload::Delete { .. } => revisions.refused(&m.stored.id).is_some(),
+            _ => true,
+        })
+        .map(|m| m.stored.id.as_str())
+        .collect();
+    let index = rows.iter().position(|row| *row == id)?;
+    Some(if rows.len() > 1 { index as f32 / (rows.len() - 1) as f32 } else { 0.0 })
+}
+
+/*
+ * A stored message, decrypted for display.
+ *
+ * Fields:
+ *  - `author`   : Who wrote it
+ *  - `label`    : Its author (and recipient, in a one-to-one conversation)
+ *  - `payload`  : What it carries
+ *  - `recv_log` : The decryption log (one-to-one messages)
+ *  - `outgoing` : Whether we sent it
+ */
+struct Opened {
+    author: String,
+    label: String,
+    payload: Payload,
+    recv_log: Option<String>,
+    outgoing: bool,
+}
+
+/* A message of the open conversation as shown: as stored, and decrypted (see `Opened`) */
+struct Shown<'a> {
+    stored: &'a StoredMessage,
+    author: &'a str,
+    label: &'a str,
+    payload: &'a Payload,
+    recv_log: Option<&'a str>,
+    outgoing: bool,
+}
+
+/*
+ * Decrypts the messages of the selected conversation, unless they
+ * were since it last changed: rendering (e.g. every frame of the
+ * ratchet animation) then reads them from `UI::decrypted`.
+ */
+fn refresh_decrypted(ui: &mut UI) {
+    let Some(name) = ui.selected_contact.clone() else {
+        return;
+    };
+    let changes = ui.session.changes(&name);
+    if ui.decrypted.get(&name).is_some_and(|(seen, _)| *seen == changes) {
+        return;
+    }
+    let messages = ui.session.get_messages(&name).map(Vec::as_slice).unwrap_or_default();
+    let opened = messages.iter().map(|stored| open_message(ui, &name, stored)).collect();
+    ui.decrypted.insert(name, (changes, opened));
+}
+
+/* Decrypts message `stored` of conversation `name`, if this device can read it */
+fn open_message(ui: &UI, name: &str, stored: &StoredMessage) -> Option<Opened> {
+    let me = ui.current_user.username();
+
+    /* Group messages are decrypted with their stored sender-key message key */
+    if groups::is_group(name) {
+        let payload = groups::open_stored(&stored.message_key, &stored.nonce, &stored.ciphertext)?;
+        return Some(Opened {
+            author: stored.sender.clone(),
+            label: stored.sender.clone(),
+            payload,
+            recv_log: None,
+            outgoing: stored.sender == me,
+        });
+    }
+
+    /* Messages without a sender predate the transport and are outgoing */
+    let incoming = !stored.sender.is_empty() && stored.sender != me;
+
+    /* Incoming messages and our copies of outgoing ones are encrypted to us;
+     * older outgoing ones were stored as sent, readable with the contact's
+     * keys (demo users carry their secrets) */
+    let (author, target) = if incoming { (name, me) } else { (me, name) };
+    let epk = box_::PublicKey::from_slice(&stored.ephemeral_pk)?;
+    let nonce = box_::Nonce::from_slice(&stored.nonce)?;
+    let contact = ui.contacts.get(name).filter(|_| !incoming);
+    let (payload, recv_log) = std::iter::once(&ui.current_user)
+        .chain(contact)
+        .find_map(|reader| reader.decrypt_message_with_logs(&epk, &nonce, &stored.ciphertext, author))?;
+    Some(Opened {
+        author: author.to_string(),
+        label: format!("{} → {}", author, target),
+        payload,
+        recv_log: Some(recv_log),
+        outgoing: !incoming,
+    })
+}
+
+/* The messages of conversation `name` that this device can read, in order, as decrypted last */
+fn shown_conversation<'a>(ui: &'a UI, name: &str) -> Vec<Shown<'a>> {
+    let me = ui.current_user.username();
+    let Some(messages) = ui.session.get_messages(name) else {
+        return Vec::new();
+    };
+    let Some((_, opened)) = ui.decrypted.get(name).filter(|(seen, _)| *seen == ui.session.changes(name)) else {
+        return Vec::new();
+    };
+    messages
+        .iter()
+        .zip(opened)
+        .filter_map(|(stored, opened)| {
+            /* Messages of blocked users stay stored, but hidden (including those from before the block) */
+            if stored.sender != me && ui.contacts.is_blocked(&stored.sender) {
+                return None;
+            }
+            let opened = opened.as_ref()?;
+            Some(Shown {
+                stored,
+                author: &opened.author,
+                label: &opened.label,
+                payload: &opened.payload,
+                recv_log: opened.recv_log.as_deref(),
+                outgoing: opened.outgoing,
+            })
+        })
+        .collect()
+}
+
+/*
+ * Renders a message of conversation `name` by `author`, `label` naming
+ * its author.
+ *
+ * Text is rendered as Markdown (see `ui::markdown`), or as written if
+ * `raw`. An attachment shows its file name and size, a button to
+ * download it, and the state of its download. A contact card says who
+ * vouches for it, with a button to import it. Any other payload is
+ * shown dimmed, as its summary.
+ */
+fn message_line<'a>(ui: &'a UI, name: &str, author: &str, label: String, payload: &Payload, raw: bool) -> Element<'a, Message> {
+    let attachment = match payload {
+        Payload::Attachment(attachment) => attachment.clone(),
+        Payload::ContactCard(card) => return card_line(ui, author, label, card),
+        Payload::Text { text: body, .. } if raw => return text(format!("{label}: {body}")).color(Color::WHITE).into(),
+        Payload::Text { text: body, .. } => {
+            return row![text(format!("{label}:")).color(Color::WHITE), markdown::view(body)]
+                .spacing(6)
+                .into();
+        }
+        other => return text(format!("{label}: {}", other.summary())).color(color!(0x888888)).into(),
+    };
+    let mut line = row![
+        text(format!("{label}: 📎 {} ({})", attachment.name, file_size(attachment.size))).color(Color::WHITE),
+    ]
+    .spacing(10)
+    .align_y(Alignment::Center);
+    if let Some(status) = ui.downloads.get(&attachment.blob_id) {
+        line = line.push(text(status).size(12).color(color!(0x888888)));
+    }
+    line.push(
+        button(text("Download").size(12))
+            .padding([2, 8])
+            .on_press(Message::DownloadAttachment(name.to_string(), attachment)),
+    )
+    .into()
+}
+
+/*
+ * Renders a contact card: the contact it introduces, a note that only
+ * `author` vouches for it, and a button to import it if it is new.
+ */
+fn card_line<'a>(ui: &UI, author: &str, label: String, card: &ContactCard) -> Element<'a, Message> {
+    let username = card.username().to_string();
+    let mut line = row![text(format!("{label}: 📇 contact card of {username}")).color(Color::WHITE)]
         .spacing(10)
         .align_y(Alignment::Center);
+    let note = if author == ui.current_user.username() {
+        "shared by you".to_string()
+    } else {
+        format!("vouched for by {author}, not verified by you")
+    };
+    line = line.push(text(note).size(12).color(color!(0xE5C07B)));
+    if username == ui.current_user.username() {
+        line = line.push(text("(you)").size(12).color(color!(0x888888)));
+    } else if ui.contacts.get(&username).is_some() {
+        line = line.push(text("already a contact").size(12).color(color!(0x888888)));
+    } else {
+        line = line.push(
+            button(text("Import").size(12))
+                .padding([2, 8])
+                .on_press(Message::ImportCard(card.clone())),
+        );
+    }
+    line.into()
+}
+
+/* A file size for display, e.g. "12.3 KiB" */
+fn file_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/*
+ * Renders the contacts panel: the form adding a contact (from a pasted
+ * bundle or the directory) or blocking a username, then each contact
+ * with its nickname and actions, then the blocked usernames.
+ */
+fn contacts_panel(ui: &UI) -> Element<'_, Message> {
+    let dim = color!(0x888888);
+    let mut panel = column![
+        text("Contacts").size(16).color(Color::WHITE),
+        text_input("Username, or a public bundle (JSON)", &ui.contact_input)
+            .on_input(Message::ContactInputChanged)
+            .on_submit(Message::FetchContact)
+            .size(12),
+        row![
+            button(text("Fetch").size(12)).on_press(Message::FetchContact),
+            button(text("Add bundle").size(12)).on_press(Message::AddContactBundle),
+            button(text("Block").size(12)).on_press(Message::BlockContact(ui.contact_input.clone(), true)),
+        ]
+        .spacing(4),
+        button(text("Copy my bundle").size(12)).on_press(Message::CopyBundle),
+    ]
+    .spacing(6)
+    .padding(10);
+    if let Some(status) = &ui.contact_status {
+        panel = panel.push(text(status).size(12).color(dim));
+    }
+
+    for contact in &ui.contacts.users {
+        let name = contact.username();
+        let blocked = ui.contacts.is_blocked(name);
+        panel = panel.push(text(ui.contacts.display_name(name)).size(14).color(Color::WHITE));
+        match &ui.renaming {
+            Some((renaming, nickname)) if renaming == name => {
+                panel = panel.push(
+                    row![
+                        text_input("Nickname (empty: none)", nickname)
+                            .on_input(Message::NicknameChanged)
+                            .on_submit(Message::SaveNickname)
+                            .size(12),
+                        button(text("Save").size(12)).on_press(Message::SaveNickname),
+                    ]
+                    .spacing(4),
+                );
+            }
+            _ => {
+                panel = panel.push(
+                    row![
+                        button(text("Nickname").size(11)).padding([2, 6]).on_press(Message::RenameContact(name.to_string())),
+                        button(text(if blocked { "Unblock" } else { "Block" }).size(11))
+                            .padding([2, 6])
+                            .on_press(Message::BlockContact(name.to_string(), !blocked)),
+                        button(text("Remove").size(11)).padding([2, 6]).on_press(Message::RemoveContact(name.to_string())),
+                    ]
+                    .spacing(4),
+                );
+            }
+        }
+    }
+
+    /* Blocked usernames that are not contacts (e.g. group members) */
+    let others: Vec<&str> = ui.contacts.blocked().filter(|name| ui.contacts.get(name).is_none()).collect();
+    if !others.is_empty() {
+        panel = panel.push(text("Blocked").size(14).color(Color::WHITE));
+        for name in others {
+            panel = panel.push(
+                row![
+                    text(name).size(12).color(dim),
+                    button(text("Unblock").size(11)).padding([2, 6]).on_press(Message::BlockContact(name.to_string(), false)),
+                ]
+                .spacing(6)
+                .align_y(Alignment::Center),
+            );
+        }
+    }
+
+    container(scrollable(panel))
+        .width(Length::Fixed(240.0))
+        .height(Length::Fill)
+        .style(|_theme: &Theme| iced::widget::container::Style {
+            background: Some(Background::Color(color!(0x181824))),
+            text_color: Some(Color::WHITE),
+            ..Default::default()
+        })
+        .into()
+}
+
+/*
+ * Renders the group info panel: name, members (with admin actions),
+ * invitation, leave button and the signed history of the group.
+ */
+fn group_panel<'a>(ui: &'a UI, group: &'a Group) -> Element<'a, Message> {
+    let me = ui.current_user.username();
+    let admin = group.is_admin(me);
+    let dim = Color {
+        r: 0.7,
+        g: 0.7,
+        b: 0.7,
+        a: 0.8,
+    };
+    let mut panel = column![text("Group info").size(16).color(Color::WHITE)].spacing(6).padding(10);
+
+    if admin {
+        panel = panel.push(
+            row![
+                text_input(&group.name, &ui.group_rename_input)
+                    .on_input(Message::GroupRenameChanged)
+                    .size(12),
+                button(text("Rename").size(12)).on_press(Message::GroupAction(GroupOp::Rename {
+                    name: ui.group_rename_input.trim().to_string(),
+                })),
+            ]
+            .spacing(4)
+            .align_y(Alignment::Center),
+        );
+    }
+
+    /* Members; admins can remove them or make them admin */
+    panel = panel.push(text(format!("Members ({})", group.members.len())).size(14).color(Color::WHITE));
+    for member in &group.members {
+        let label = if group.is_admin(member) { format!("{member} (admin)") } else { member.clone() };
+        let mut member_row = row![text(label).size(12).color(Color::WHITE).width(Length::Fill)]
+            .spacing(4)
+            .align_y(Alignment::Center);
+        if admin && member != me {
+            member_row = member_row.push(button(text("Remove").size(11)).padding([2, 6]).on_press(
+                Message::GroupAction(GroupOp::Remove {
+                    member: member.clone(),
+                }),
+            ));
+            if !group.is_admin(member) {
+                member_row = member_row.push(button(text("Make admin").size(11)).padding([2, 6]).on_press(
+                    Message::GroupAction(GroupOp::Promote {
+                        member: member.clone(),
+                    }),
+                ));
+            }
+        }
+        panel = panel.push(member_row);
+    }
+
+    if admin {
+        panel = panel.push(
+            row![
+                text_input("Invite (username)", &ui.group_invite_input)
+                    .on_input(Message::GroupInviteChanged)
+                    .size(12),
+                button(text("Invite").size(12)).on_press(Message::GroupAction(GroupOp::Invite {
+                    member: ui.group_invite_input.trim().to_string(),
+                })),
+            ]
+            .spacing(4)
+            .align_y(Alignment::Center),
+        );
+    }
+    if group.is_member(me) {
+        panel = panel.push(button(text("Leave group").size(12)).on_press(Message::GroupAction(GroupOp::Leave)));
+    } else {
+        panel = panel.push(text("You are no longer a member").size(12).color(color!(0xE06C75)));
+    }
+
+    /* Ratchet tree and commit logs (TreeKEM mode) */
+    if let Some(tree) = &group.tree {
+        panel = panel.push(
+            text(format!("TreeKEM epoch {} (leaf {})", tree.epoch, tree.own_leaf))
+                .size(14)
+                .color(Color::WHITE),
+        );
+        panel = panel.push(text(tree.render()).size(11).color(Color::WHITE));
+        if group.is_member(me) {
+            panel = panel.push(button(text("Update my keys").size(12)).on_press(Message::TreeKemUpdate));
+        }
+        for log in tree.logs.iter().rev().take(3) {
+            panel = panel.push(text(log).size(10).color(dim));
+        }
+    }
+
+    /* Signed history, oldest first */
+    panel = panel.push(text(format!("History (version {})", group.version)).size(14).color(Color::WHITE));
+    for update in &group.history {
+        panel = panel.push(text(format!("v{} {} {}", update.version, update.author, update.op)).size(11).color(dim));
+    }
+
+    container(scrollable(panel))
+        .width(Length::Fixed(240.0))
+        .height(Length::Fill)
+        .style(|_theme: &Theme| iced::widget::container::Style {
+            background: Some(Background::Color(color!(0x181824))),
+            text_color: Some(Color::WHITE),
+            ..Default::default()
+        })
+        .into()
+}
+
+/*
+ * Renders the delivery state of an outgoing group message,
+ * summed over its copies (one per member).
+ */
+fn group_delivery_status<'a>(ui: &UI, message_id: &str) -> Element<'a, Message> {
+    let prefix = format!("{message_id}.");
+    let copies: Vec<&OutboxEntry> = ui.outbox.entries.iter().filter(|e| e.envelope.id.starts_with(&prefix)).collect();
+    let count = |states: &[DeliveryState]| copies.iter().filter(|e| states.contains(&e.state)).count();
+    let (label, tint) = if count(&[DeliveryState::Failed]) > 0 {
+        (format!("✗ failed for {}/{}", count(&[DeliveryState::Failed]), copies.len()), color!(0xE06C75))
+    } else if count(&[DeliveryState::Delivered, DeliveryState::Read]) > 0 {
+        let delivered = count(&[DeliveryState::Delivered, DeliveryState::Read]);
+        (format!("✓✓ delivered to {}/{}", delivered, copies.len()), color!(0x98C379))
+    } else if count(&[DeliveryState::Sent]) == copies.len() && !copies.is_empty() {
+        ("✓ sent".to_string(), color!(0x98C379))
+    } else {
+        ("pending".to_string(), color!(0xE5C07B))
+    };
+    text(label).size(12).color(tint).into()
+}
 
-    let chat_col = column![messages_scroll, input_row].spacing(10).padding(10);
+/*
+ * Renders the delivery state of an outgoing message.
+ *
+ * Failed messages show the last error and a "Retry" button.
+ */
+fn delivery_status(entry: &OutboxEntry) -> Element<'_, Message> {
+    let (label, tint) = match entry.state {
+        DeliveryState::Pending if entry.attempts > 0 => {
+            (format!("pending (retry {}/{})", entry.attempts, outbox::MAX_ATTEMPTS), color!(0xE5C07B))
+        }
+        DeliveryState::Pending => ("pending".to_string(), color!(0xE5C07B)),
+        DeliveryState::Sent => ("✓ sent".to_string(), color!(0x98C379)),
+        DeliveryState::Delivered => ("✓✓ delivered".to_string(), color!(0x98C379)),
+        DeliveryState::Read => ("✓✓ read".to_string(), color!(0x61AFEF)),
+        DeliveryState::Failed => (
+            format!("✗ failed: {}", entry.last_error.as_deref().unwrap_or("unknown error")),
+            color!(0xE06C75),
+        ),
+    };
 
-    /* Final layout: left = contacts, right = chat */
-    row![contacts_list, chat_col].into()
+    let mut status_row = row![text(label).size(12).color(tint)]
+        .spacing(10)
+        .align_y(Alignment::Center);
+    if entry.state == DeliveryState::Failed {
+        status_row = status_row.push(
+            button(text("Retry").size(12))
+                .padding([2, 8])
+                .on_press(Message::RetryMessage(entry.envelope.id.clone())),
+        );
+    }
+    status_row.into()
 }
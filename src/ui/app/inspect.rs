@@ -0,0 +1,255 @@
+/*
+ * The protocol inspector of the chat (see `ui::inspector` for its rows
+ * and state, `ui::visualizer` for its diagram): what it shows of a
+ * message of the open conversation, and its panel.
+ */
+
+use super::{ClaimStatus, Message, Shown, UI};
+use crate::client::groups;
+use crate::client::user::User;
+use crate::ui::inspector::{self, Step};
+use crate::ui::visualizer::{self, Diagram, DirectKeys, GroupKeys, Visualizer};
+use iced::widget::{button, column, container, row, scrollable, text};
+use iced::{color, Alignment, Background, Color, Element, Font, Length, Theme};
+use sodiumoxide::crypto::box_;
+
+/*
+ * The protocol steps of `message`, of conversation `name`, for the
+ * inspector: the message as stored on this device, the keys involved
+ * and what was verified, then its send and receive logs.
+ */
+pub(super) fn inspector_steps(ui: &UI, name: &str, message: &Shown) -> Vec<Step> {
+    let stored = message.stored;
+    let section = "stored copy";
+    let mut steps = vec![
+        Step::text(section, "Message id", &stored.id),
+        Step::text(section, "Author", message.author),
+        Step::text(section, "Payload", &format!("{} v{}", message.payload.kind(), message.payload.version())),
+    ];
+    if groups::is_group(name) {
+        steps.push(Step::text(
+            section,
+            "Message key",
+            &format!("{} bytes, from the sender key chain (not shown)", stored.message_key.len()),
+        ));
+    } else {
+        steps.push(Step::bytes(section, "Ephemeral PK", &stored.ephemeral_pk));
+    }
+    steps.push(Step::bytes(section, "Nonce", &stored.nonce));
+    steps.push(Step::bytes(section, "Ciphertext (MAC, then encrypted payload)", &stored.ciphertext));
+
+    /* One-to-one: the Diffie-Hellman computation and the keys of the contact */
+    if let Some(contact) = ui.contacts.get(name).filter(|_| !groups::is_group(name)) {
+        let me = &ui.current_user;
+        let section = "keys";
+        steps.push(Step::bytes(section, &format!("{}.SPK (reader)", me.username), me.signed_pre_pk.as_ref()));
+        steps.push(Step::text(
+            section,
+            &format!("DH(ephemeral, {}.SPK)", me.username),
+            &format!(
+                "inputs: ephemeral {} ({} bytes) + {}.SPK {} ({} bytes) → shared key ({} bytes, not shown)",
+                inspector::key_id(&stored.ephemeral_pk),
+                stored.ephemeral_pk.len(),
+                me.username,
+                inspector::key_id(me.signed_pre_pk.as_ref()),
+                box_::PUBLICKEYBYTES,
+                box_::PRECOMPUTEDKEYBYTES,
+            ),
+        ));
+        steps.push(Step::bytes(section, &format!("{}.ID", contact.username), contact.identity_pk.as_ref()));
+        steps.push(Step::bytes(section, &format!("{}.SPK", contact.username), contact.signed_pre_pk.as_ref()));
+        for device in &contact.devices.devices {
+            steps.push(Step::bytes(
+                section,
+                &format!("{} device {} prekey", contact.username, device.device_id),
+                &device.prekey,
+            ));
+        }
+
+        let section = "verification";
+        steps.push(Step::text(section, "Decrypt(MAC checked by Poly1305)", "true"));
+        steps.push(Step::text(
+            section,
+            &format!("Verify({0}.SPK signed by {0}.ID)", contact.username),
+            &User::verify_peer_spk(contact).to_string(),
+        ));
+        if !contact.devices.devices.is_empty() {
+            steps.push(Step::text(
+                section,
+                &format!("Verify({0}.devices v{1} signed by {0}.ID)", contact.username, contact.devices.version),
+                &contact.devices.verify(&contact.identity_pk).to_string(),
+            ));
+        }
+        let claim = match ui.directory.get(name) {
+            Some(ClaimStatus::Verified(_)) => "true".to_string(),
+            Some(ClaimStatus::Unregistered) => "not registered".to_string(),
+            Some(ClaimStatus::Rejected(e)) => format!("false ({e})"),
+            None => "not checked".to_string(),
+        };
+        steps.push(Step::text(section, "Directory claim (transparency log)", &claim));
+    }
+
+    steps.extend(inspector::parse_log(&stored.log));
+    if let Some(recv_log) = message.recv_log {
+        steps.extend(inspector::parse_log(recv_log));
+    }
+    steps
+}
+
+/*
+ * The key agreement of `message`, of conversation `name`, for the
+ * visualizer: for a one-to-one message, the keys of both sides and the
+ * ephemeral key it was encrypted with; for a group message, the step of
+ * the key chain it used. Keys missing from this device are left unknown.
+ */
+pub(super) fn diagram_of(ui: &UI, name: &str, message: &Shown) -> Diagram {
+    let stored = message.stored;
+    let steps = inspector::parse_log(&stored.log);
+    let logged = |label: &str| steps.iter().find(|s| s.label.starts_with(label));
+    let id = |key: &[u8]| Some(inspector::key_id(key));
+
+    if let Some(group) = ui.session.groups.get(name) {
+        return Diagram::group(GroupKeys {
+            author: message.author.to_string(),
+            treekem: group.uses_treekem(),
+            step: ratchet_label(name, message),
+            chain_key: logged("Chain key").map(|s| inspector::key_id(&hex::decode(&s.copy).unwrap_or_default())),
+            message_key: id(&stored.message_key),
+            next_chain_key: logged("Next chain key").map(|s| inspector::key_id(&hex::decode(&s.copy).unwrap_or_default())),
+        });
+    }
+
+    let me = &ui.current_user;
+    let contact = ui.contacts.get(name);
+    if message.outgoing {
+        /* The stored copy is encrypted to us: the log has the key the contact's device got */
+        let ephemeral = logged("Ephemeral PK").and_then(|s| hex::decode(&s.copy).ok()).unwrap_or_else(|| stored.ephemeral_pk.clone());
+        let device = logged("Receiver").and_then(|s| s.detail.split("(device ").nth(1)).map(|d| d.trim_end_matches(')'));
+        let prekey = contact.map(|c| match device.and_then(|d| c.devices.device(d)) {
+            Some(device) => device.prekey.clone(),
+            None => c.signed_pre_pk.as_ref().to_vec(),
+        });
+        Diagram::direct(DirectKeys {
+            sender: me.username.clone(),
+            receiver: name.to_string(),
+            sender_identity: id(me.identity_pk.as_ref()),
+            ephemeral: id(&ephemeral),
+            receiver_identity: contact.map(|c| inspector::key_id(c.identity_pk.as_ref())),
+            receiver_prekey: prekey.map(|p| inspector::key_id(&p)),
+            receiver_one_time: None,
+        })
+    } else {
+        Diagram::direct(DirectKeys {
+            sender: name.to_string(),
+            receiver: me.username.clone(),
+            sender_identity: contact.map(|c| inspector::key_id(c.identity_pk.as_ref())),
+            ephemeral: id(&stored.ephemeral_pk),
+            receiver_identity: id(me.identity_pk.as_ref()),
+            receiver_prekey: id(me.signed_pre_pk.as_ref()),
+            receiver_one_time: me.one_time_prekeys.first().map(|(pk, _)| inspector::key_id(pk.as_ref())),
+        })
+    }
+}
+
+/*
+ * A short label of the ratchet step of `message`: the id of its
+ * ephemeral key, or its place in the group's key chain.
+ */
+pub(super) fn ratchet_label(name: &str, message: &Shown) -> String {
+    let stored = message.stored;
+    if !groups::is_group(name) {
+        return format!("EK {}", inspector::key_id(&stored.ephemeral_pk));
+    }
+    let steps = inspector::parse_log(&stored.log);
+    let chain = steps.iter().find(|s| s.label == "Sender key").and_then(|s| {
+        let at = s.detail.find("iteration ")?;
+        s.detail[at..].split(" (").next().map(str::to_string)
+    });
+    let schedule = steps.iter().find_map(|s| {
+        let n = s.label.strip_prefix("Message key = HMAC(encryption secret, sender || ")?;
+        Some(format!("generation {}", n.trim_end_matches(')')))
+    });
+    chain.or(schedule).unwrap_or_else(|| "message".to_string())
+}
+
+/*
+ * Renders the protocol inspector of message `id`: its steps grouped by
+ * section, one row each. A row shows the step and its value on one line
+ * (verification results in green or red); expanding it shows the whole
+ * value, e.g. a hex dump. Each row can be copied, or all of them.
+ *
+ * In diagram mode, `visual` is drawn instead, with the button playing
+ * the ratchet.
+ */
+pub(super) fn inspector_panel<'a>(ui: &UI, id: String, steps: Vec<Step>, visual: Option<Visualizer>) -> Element<'a, Message> {
+    let dim = color!(0x888888);
+    let all: Vec<String> = steps.iter().map(|s| format!("[{}] {}: {}", s.section, s.label, s.copy)).collect();
+    let mut header = row![text("Protocol inspector").size(16).color(Color::WHITE)]
+        .spacing(6)
+        .align_y(Alignment::Center);
+    if !ui.inspector.diagram {
+        header = header.push(button(text("Copy all").size(11)).padding([2, 6]).on_press(Message::CopyText(all.join("\n"))));
+    }
+    header = header.push(
+        button(text(if ui.inspector.diagram { "Steps" } else { "Diagram" }).size(11))
+            .padding([2, 6])
+            .on_press(Message::ToggleDiagram),
+    );
+    header = header.push(button(text("Close").size(11)).padding([2, 6]).on_press(Message::Inspect(id)));
+    let mut panel = column![header].spacing(4).padding(10);
+
+    /* The diagram: the key agreement of the message, and the ratchet over the conversation */
+    if let Some(visual) = visual {
+        panel = panel.push(visualizer::view(visual));
+        panel = panel.push(
+            text("solid: computed · dashed: the other DHs of full X3DH, not computed by this prototype")
+                .size(11)
+                .color(dim),
+        );
+        panel = panel.push(
+            button(text(if ui.inspector.ratchet.is_some() { "Stop" } else { "Play the ratchet" }).size(12))
+                .padding([2, 8])
+                .on_press(Message::PlayRatchet),
+        );
+    }
+    let expanded = &ui.inspector.expanded;
+
+    let mut section = None;
+    for (index, step) in steps.into_iter().enumerate() {
+        if section.as_ref() != Some(&step.section) {
+            panel = panel.push(text(step.section.clone()).size(13).color(color!(0xE5C07B)));
+            section = Some(step.section.clone());
+        }
+        let open = expanded.contains(&index);
+        let toggle = button(text(if open { "▾" } else { "▸" }).size(11))
+            .padding([0, 4])
+            .style(button::text)
+            .on_press_maybe(step.expandable().then_some(Message::ToggleStep(index)));
+        panel = panel.push(
+            row![
+                toggle,
+                text(step.label.clone()).size(12).color(Color::WHITE).width(Length::Fill),
+                button(text("Copy").size(10)).padding([1, 5]).on_press(Message::CopyText(step.copy.clone())),
+            ]
+            .spacing(4)
+            .align_y(Alignment::Center),
+        );
+        let tint = match step.verified() {
+            Some(true) => color!(0x98C379),
+            Some(false) => color!(0xE06C75),
+            None => dim,
+        };
+        let value = if open { step.detail } else { step.summary };
+        panel = panel.push(container(text(value).font(Font::MONOSPACE).size(11).color(tint)).padding([0, 20]));
+    }
+
+    container(scrollable(panel))
+        .width(Length::Fixed(380.0))
+        .height(Length::Fill)
+        .style(|_theme: &Theme| iced::widget::container::Style {
+            background: Some(Background::Color(color!(0x181824))),
+            text_color: Some(Color::WHITE),
+            ..Default::default()
+        })
+        .into()
+}
@@ -0,0 +1,225 @@
+/*
+ * This module builds the rows of the protocol inspector: one step of
+ * the protocol per row (a key, a Diffie-Hellman computation, a nonce,
+ * the ciphertext, a verification), grouped in sections by where it
+ * comes from (the stored envelope, the send and receive logs).
+ *
+ * Logs are read line by line: a "== log (…) ==" line starts a section,
+ * and each "Label: value" or "Label = value" line is a step (indented
+ * lines continue the step above). Hex values are decoded: keys get a
+ * short key id, and anything longer is shown as a hex dump.
+ *
+ * `Inspector` is what the inspector shows: the message open in it, its
+ * expanded rows, and whether it draws the key agreement instead (see
+ * `ui::visualizer`), possibly playing the ratchet. Rendering is left to
+ * `ui::app`, which owns the messages a row sends.
+ */
+
+use crate::ui::visualizer::Playback;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::HashSet;
+
+/* Longest value shown on a collapsed row, in characters */
+const SUMMARY_LEN: usize = 48;
+
+/* Bytes per line of a hex dump */
+const DUMP_WIDTH: usize = 16;
+
+/* Size of the keys given a key id (X25519 and Ed25519 public keys) */
+const KEY_LEN: usize = 32;
+
+/*
+ * The state of the inspector.
+ *
+ * Fields:
+ *  - `open`     : Id of the message open in the inspector
+ *  - `expanded` : Rows shown in full
+ *  - `diagram`  : Whether it draws the key agreement instead of its rows
+ *  - `ratchet`  : While the ratchet plays over the conversation, where it is
+ */
+#[derive(Debug, Default)]
+pub struct Inspector {
+    pub open: Option<String>,
+    pub expanded: HashSet<usize>,
+    pub diagram: bool,
+    pub ratchet: Option<Playback>,
+}
+
+impl Inspector {
+    /* Opens message `id`, or closes it if it is the one open */
+    pub fn toggle(&mut self, id: String) {
+        self.open = if self.open.as_deref() == Some(id.as_str()) { None } else { Some(id) };
+        self.expanded.clear();
+        self.ratchet = None;
+    }
+
+    /* Closes the inspector, e.g. when another conversation is selected */
+    pub fn close(&mut self) {
+        self.open = None;
+        self.ratchet = None;
+    }
+
+    /* Whether message `id` is the one open */
+    pub fn is_open(&self, id: &str) -> bool {
+        !id.is_empty() && self.open.as_deref() == Some(id)
+    }
+
+    /* Expands row `index`, or collapses it */
+    pub fn toggle_step(&mut self, index: usize) {
+        if !self.expanded.remove(&index) {
+            self.expanded.insert(index);
+        }
+    }
+
+    /* Switches between the rows and the diagram */
+    pub fn toggle_diagram(&mut self) {
+        self.diagram = !self.diagram;
+        self.ratchet = None;
+    }
+
+    /* Plays the ratchet from the first step, or stops it */
+    pub fn toggle_ratchet(&mut self) {
+        self.ratchet = if self.ratchet.is_some() { None } else { Some(Playback::default()) };
+    }
+
+    /* Advances the ratchet by one frame, over a conversation of `steps` messages */
+    pub fn ratchet_frame(&mut self, steps: usize) {
+        if self.ratchet.as_mut().is_some_and(|playback| !playback.advance(steps)) {
+            self.ratchet = None;
+        }
+    }
+}
+
+/*
+ * One row of the inspector.
+ *
+ * Fields:
+ *  - `section` : Where the step comes from (e.g. "log (recv)")
+ *  - `label`   : The step (e.g. "DH(sender.ephemeral, self.SPK)")
+ *  - `summary` : Its value on one line, shown when the row is collapsed
+ *  - `detail`  : Its full value (e.g. a hex dump), shown when expanded
+ *  - `copy`    : What the copy button puts on the clipboard (hex, not the dump)
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub section: String,
+    pub label: String,
+    pub summary: String,
+    pub detail: String,
+    pub copy: String,
+}
+
+impl Step {
+    /* A step whose value is text */
+    pub fn text(section: &str, label: &str, value: &str) -> Self {
+        Self {
+            section: section.to_string(),
+            label: label.to_string(),
+            summary: shorten(value),
+            detail: value.to_string(),
+            copy: value.to_string(),
+        }
+    }
+
+    /* A step whose value is bytes: a key (with its key id), or a hex dump */
+    pub fn bytes(section: &str, label: &str, bytes: &[u8]) -> Self {
+        let hex = hex::encode(bytes);
+        let (summary, detail) = if bytes.len() == KEY_LEN {
+            (format!("id {} · {}", key_id(bytes), shorten(&hex)), hex.clone())
+        } else {
+            (format!("{} bytes · {}", bytes.len(), shorten(&hex)), hex_dump(bytes))
+        };
+        Self {
+            section: section.to_string(),
+            label: label.to_string(),
+            summary,
+            detail,
+            copy: hex,
+        }
+    }
+
+    /* Whether expanding the row shows more than its summary */
+    pub fn expandable(&self) -> bool {
+        self.detail != self.summary
+    }
+
+    /* The result of a verification step, if it is one ("true" or "false") */
+    pub fn verified(&self) -> Option<bool> {
+        match self.detail.as_str() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/* A short id of a public key: the start of its SHA-256, e.g. "1a2b3c4d" */
+pub fn key_id(key: &[u8]) -> String {
+    hex::encode(&sha256::hash(key).0[..4])
+}
+
+/* `bytes` as a hex dump: an offset, then `DUMP_WIDTH` bytes per line */
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(DUMP_WIDTH)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            format!("{:04x}  {}", i * DUMP_WIDTH, hex.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/* Splits a log into steps, one per "Label: value" (or "Label = value") line */
+pub fn parse_log(log: &str) -> Vec<Step> {
+    let mut steps: Vec<Step> = Vec::new();
+    let mut section = "log".to_string();
+    for line in log.lines() {
+        if let Some(title) = line.trim().strip_prefix("== ").and_then(|l| l.strip_suffix(" ==")) {
+            section = title.to_string();
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        /* Indented lines carry on the step above */
+        if line.starts_with(char::is_whitespace) {
+            if let Some(last) = steps.last_mut().filter(|s| s.section == section) {
+                let value = format!("{}\n{}", last.detail, line.trim_end());
+                *last = Step::text(&section, &last.label, &value);
+                continue;
+            }
+        }
+        /* "Label: value" first: a label may hold " = " ("Message key = HMAC(…): …") */
+        let split = [": ", " = "]
+            .into_iter()
+            .find_map(|separator| line.find(separator).map(|at| (at, separator.len())));
+        let step = match split {
+            Some((at, len)) => value_step(&section, line[..at].trim(), line[at + len..].trim()),
+            None => Step::text(&section, "", line.trim()),
+        };
+        steps.push(step);
+    }
+    steps
+}
+
+/* A step of a log: hex values become bytes, anything else stays text */
+fn value_step(section: &str, label: &str, value: &str) -> Step {
+    let hex = value.len() >= 2 * DUMP_WIDTH && value.len() % 2 == 0 && value.chars().all(|c| c.is_ascii_hexdigit());
+    match hex::decode(value) {
+        Ok(bytes) if hex => Step::bytes(section, label, &bytes),
+        _ => Step::text(section, label, value),
+    }
+}
+
+/* `value` on one line, cut at `SUMMARY_LEN` characters */
+fn shorten(value: &str) -> String {
+    let line = value.lines().next().unwrap_or_default();
+    let cut = line.chars().count() > SUMMARY_LEN || value.contains('\n');
+    let mut short: String = line.chars().take(SUMMARY_LEN).collect();
+    if cut {
+        short.push('…');
+    }
+    short
+}
@@ -0,0 +1,357 @@
+/*
+ * This module renders the text of chat messages as a small, safe
+ * subset of Markdown:
+ *  - `**bold**`, `*italic*` (or `_italic_`) and `` `inline code` ``
+ *  - code blocks, between lines of three backticks
+ *  - bullet (`-`, `*`, `+`) and numbered (`1.`) list items
+ *  - links, `[label](url)`: the label is shown with its address next
+ *    to it, and nothing is ever fetched or opened (images are shown as
+ *    links too)
+ *
+ * Anything else, HTML included, is shown as written. A backslash
+ * escapes a punctuation character.
+ *
+ * Parsing is linear in the length of the message: where each closing
+ * delimiter comes next is computed once per paragraph (see `Closers`),
+ * not searched for at every opening one.
+ */
+
+use iced::font::{Style, Weight};
+use iced::widget::text::Span;
+use iced::widget::{column, container, rich_text, row, span, text};
+use iced::{color, Background, Color, Element, Font};
+
+/*
+ * A run of text with one style.
+ *
+ * Fields:
+ *  - `text`   : The text
+ *  - `bold`   : Inside `**`
+ *  - `italic` : Inside `*` or `_`
+ *  - `code`   : Inside backticks
+ *  - `link`   : The address, for the label of a link
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Fragment {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub code: bool,
+    pub link: Option<String>,
+}
+
+/*
+ * A block of a message.
+ *
+ *  - `Paragraph` : Lines of text (line breaks are kept)
+ *  - `Item`      : A list item, with its marker ("•" or "3.")
+ *  - `Code`      : A code block, shown as written
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block {
+    Paragraph(Vec<Fragment>),
+    Item(String, Vec<Fragment>),
+    Code(String),
+}
+
+/* The delimiters that `Closers` can find */
+const DELIMITERS: [&str; 6] = ["`", "**", "*", "_", "]", ")"];
+
+/*
+ * For each position of a paragraph, where the next occurrence of each
+ * of the `DELIMITERS` starts (filled in one backward pass).
+ */
+struct Closers {
+    next: Vec<[Option<usize>; DELIMITERS.len()]>,
+}
+
+impl Closers {
+    fn new(chars: &[char]) -> Self {
+        let patterns: Vec<Vec<char>> = DELIMITERS.iter().map(|d| d.chars().collect()).collect();
+        let mut next = vec![[None; DELIMITERS.len()]; chars.len() + 1];
+        for i in (0..chars.len()).rev() {
+            next[i] = next[i + 1];
+            for (k, pattern) in patterns.iter().enumerate() {
+                if chars[i..].starts_with(pattern) {
+                    next[i][k] = Some(i);
+                }
+            }
+        }
+        Self { next }
+    }
+
+    /* Index of the next `delimiter` (one of `DELIMITERS`) from `from` */
+    fn find(&self, from: usize, delimiter: &str) -> Option<usize> {
+        let k = DELIMITERS.iter().position(|d| *d == delimiter)?;
+        self.next.get(from)?[k]
+    }
+}
+
+/* Splits `source` into blocks */
+pub fn parse(source: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+    let flush = |paragraph: &mut Vec<&str>, blocks: &mut Vec<Block>| {
+        if !paragraph.is_empty() {
+            blocks.push(Block::Paragraph(inlines(&paragraph.join("\n"))));
+            paragraph.clear();
+        }
+    };
+
+    for line in source.lines() {
+        if line.trim_start().starts_with("```") {
+            match code.take() {
+                Some(lines) => blocks.push(Block::Code(lines.join("\n"))),
+                None => {
+                    flush(&mut paragraph, &mut blocks);
+                    code = Some(Vec::new());
+                }
+            }
+            continue;
+        }
+        if let Some(lines) = code.as_mut() {
+            lines.push(line);
+            continue;
+        }
+        if line.trim().is_empty() {
+            flush(&mut paragraph, &mut blocks);
+            continue;
+        }
+        if let Some((marker, rest)) = list_item(line) {
+            flush(&mut paragraph, &mut blocks);
+            blocks.push(Block::Item(marker, inlines(rest)));
+            continue;
+        }
+        paragraph.push(line);
+    }
+    /* An unclosed code block runs to the end of the message */
+    if let Some(lines) = code {
+        blocks.push(Block::Code(lines.join("\n")));
+    }
+    flush(&mut paragraph, &mut blocks);
+    blocks
+}
+
+/* Whether `blocks` render differently from their source (anything but plain paragraphs) */
+pub fn has_formatting(blocks: &[Block]) -> bool {
+    blocks.iter().any(|block| match block {
+        Block::Paragraph(fragments) => fragments
+            .iter()
+            .any(|f| f.bold || f.italic || f.code || f.link.is_some()),
+        _ => true,
+    })
+}
+
+/* The marker and the text of a list item line */
+fn list_item(line: &str) -> Option<(String, &str)> {
+    let line = line.trim_start();
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(rest) = line.strip_prefix(bullet) {
+            return Some(("•".to_string(), rest));
+        }
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = line[digits..].strip_prefix(". ")?;
+    (1..=9).contains(&digits).then(|| (line[..digits + 1].to_string(), rest))
+}
+
+/* Splits a paragraph into styled fragments */
+pub fn inlines(source: &str) -> Vec<Fragment> {
+    let chars: Vec<char> = source.chars().collect();
+    let closers = Closers::new(&chars);
+    let mut fragments = Vec::new();
+    let mut style = Fragment::default();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            '\\' if next.is_some_and(|n| n.is_ascii_punctuation()) => {
+                style.text.extend(next);
+                i += 2;
+            }
+            '`' => match closers.find(i + 1, "`") {
+                Some(end) => {
+                    push(&mut fragments, &mut style);
+                    fragments.push(Fragment {
+                        text: chars[i + 1..end].iter().collect(),
+                        code: true,
+                        ..Fragment::default()
+                    });
+                    i = end + 1;
+                }
+                None => {
+                    style.text.push(c);
+                    i += 1;
+                }
+            },
+            '*' if next == Some('*') => {
+                if style.bold || closers.find(i + 2, "**").is_some() {
+                    push(&mut fragments, &mut style);
+                    style.bold = !style.bold;
+                } else {
+                    style.text.push_str("**");
+                }
+                i += 2;
+            }
+            '*' | '_' => {
+                let before = i.checked_sub(1).map(|b| chars[b]);
+                let toggles = if style.italic {
+                    /* `_` only closes at the end of a word (snake_case stays) */
+                    c == '*' || !next.is_some_and(char::is_alphanumeric)
+                } else {
+                    next.is_some_and(|n| !n.is_whitespace())
+                        && (c == '*' || !before.is_some_and(char::is_alphanumeric))
+                        && closers.find(i + 1, if c == '*' { "*" } else { "_" }).is_some()
+                };
+                if toggles {
+                    push(&mut fragments, &mut style);
+                    style.italic = !style.italic;
+                } else {
+                    style.text.push(c);
+                }
+                i += 1;
+            }
+            '[' | '!' => match link(&chars, &closers, if c == '!' { i + 1 } else { i }) {
+                Some((label, url, end)) if c == '[' || next == Some('[') => {
+                    push(&mut fragments, &mut style);
+                    fragments.push(Fragment {
+                        text: label,
+                        link: Some(url),
+                        ..style.clone()
+                    });
+                    i = end;
+                }
+                _ => {
+                    style.text.push(c);
+                    i += 1;
+                }
+            },
+            _ => {
+                style.text.push(c);
+                i += 1;
+            }
+        }
+    }
+    push(&mut fragments, &mut style);
+    fragments
+}
+
+/* Ends the current fragment, keeping its style for the next one */
+fn push(fragments: &mut Vec<Fragment>, current: &mut Fragment) {
+    if !current.text.is_empty() {
+        fragments.push(current.clone());
+        current.text.clear();
+    }
+}
+
+/* A `[label](url)` link at `start`: its label, address and the index after it */
+fn link(chars: &[char], closers: &Closers, start: usize) -> Option<(String, String, usize)> {
+    if chars.get(start) != Some(&'[') {
+        return None;
+    }
+    let close = closers.find(start + 1, "]")?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    let end = closers.find(close + 2, ")")?;
+    let url: String = chars[close + 2..end].iter().collect();
+    if url.is_empty() || url.chars().any(char::is_whitespace) {
+        return None;
+    }
+    Some((chars[start + 1..close].iter().collect(), url, end + 1))
+}
+
+/* Renders Markdown `source` with iced widgets */
+pub fn view<'a, M: Clone + 'static>(source: &str) -> Element<'a, M> {
+    let mut blocks = column![].spacing(4);
+    for block in parse(source) {
+        blocks = match block {
+            Block::Paragraph(fragments) => blocks.push(rich(fragments)),
+            Block::Item(marker, fragments) => {
+                blocks.push(row![text(marker).color(Color::WHITE), rich(fragments)].spacing(6))
+            }
+            Block::Code(code) => blocks.push(
+                container(text(code).font(Font::MONOSPACE).size(13).color(Color::WHITE))
+                    .padding(6)
+                    .style(|_theme| container::Style {
+                        background: Some(Background::Color(color!(0x11111B))),
+                        ..Default::default()
+                    }),
+            ),
+        };
+    }
+    blocks.into()
+}
+
+/* Renders styled fragments as one rich text */
+fn rich<'a, M: Clone + 'static>(fragments: Vec<Fragment>) -> Element<'a, M> {
+    let mut spans: Vec<Span<'a, M, Font>> = Vec::new();
+    for fragment in fragments {
+        let mut font = if fragment.code { Font::MONOSPACE } else { Font::DEFAULT };
+        if fragment.bold {
+            font.weight = Weight::Bold;
+        }
+        if fragment.italic {
+            font.style = Style::Italic;
+        }
+        let mut piece = span(fragment.text.clone()).font(font).color(Color::WHITE);
+        if fragment.code {
+            piece = piece.background(color!(0x313244));
+        }
+        match fragment.link {
+            /* The address is always shown: a label cannot hide where a link goes */
+            Some(url) => {
+                spans.push(piece.underline(true).color(color!(0x61AFEF)));
+                if url != fragment.text {
+                    spans.push(span(format!(" <{url}>")).color(color!(0x888888)));
+                }
+            }
+            None => spans.push(piece),
+        }
+    }
+    rich_text(spans).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styled(text: &str, edit: impl FnOnce(&mut Fragment)) -> Fragment {
+        let mut fragment = Fragment {
+            text: text.to_string(),
+            ..Fragment::default()
+        };
+        edit(&mut fragment);
+        fragment
+    }
+
+    #[test]
+    fn inline_styles() {
+        assert_eq!(
+            inlines("a **b** *c* `d` [e](https://f)"),
+            vec![
+                styled("a ", |_| {}),
+                styled("b", |f| f.bold = true),
+                styled(" ", |_| {}),
+                styled("c", |f| f.italic = true),
+                styled(" ", |_| {}),
+                styled("d", |f| f.code = true),
+                styled(" ", |_| {}),
+                styled("e", |f| f.link = Some("https://f".to_string())),
+            ]
+        );
+        assert_eq!(inlines("snake_case_name"), vec![styled("snake_case_name", |_| {})]);
+        assert_eq!(inlines("[x](a b) \\*y\\*"), vec![styled("[x](a b) *y*", |_| {})]);
+    }
+
+    #[test]
+    fn unclosed_delimiters_stay_text() {
+        let source = format!("{} *a `b", "[x ".repeat(100_000));
+        let fragments = inlines(&source);
+        assert!(!has_formatting(&[Block::Paragraph(fragments.clone())]));
+        assert_eq!(fragments.iter().map(|f| f.text.as_str()).collect::<String>(), source);
+    }
+}
@@ -1 +1,4 @@
 pub mod app;
+pub mod inspector;
+pub mod markdown;
+pub mod visualizer;
@@ -0,0 +1,376 @@
+/*
+ * This module draws the key agreement of a message on an iced canvas:
+ * keys are nodes (identity, signed pre-key, one-time pre-key,
+ * ephemeral, chain and message keys), and each Diffie-Hellman
+ * computation, signature or key derivation is an edge between them.
+ *
+ * A one-to-one message shows the one DH this prototype computes,
+ * DH(ephemeral, SPK), next to the three other DHs of a full X3DH
+ * handshake, which it skips (dashed). A group message shows one step
+ * of its sender key chain, or of the TreeKEM key schedule.
+ *
+ * Over a conversation, every message is one step of the ratchet: a
+ * fresh ephemeral key, or the next key of the chain. `Visualizer`
+ * animates one step (edges are drawn in order, a dot running along the
+ * current one) above a timeline of all the steps of the conversation;
+ * `Playback` plays the steps one after the other.
+ */
+
+use iced::alignment::{Horizontal, Vertical};
+use iced::mouse;
+use iced::widget::canvas::{self, Frame, Geometry, LineDash, Path, Stroke, Text};
+use iced::{color, Color, Element, Length, Point, Rectangle, Renderer, Theme};
+use std::time::Duration;
+
+/* How long one ratchet step is played, and how often it is redrawn */
+pub const STEP: Duration = Duration::from_millis(1500);
+pub const FRAME: Duration = Duration::from_millis(40);
+
+/* Height of the canvas, in pixels */
+const HEIGHT: f32 = 440.0;
+
+/* Height of the diagram, above the timeline */
+const DIAGRAM_HEIGHT: f32 = 340.0;
+
+/* Radius of a key node */
+const NODE_RADIUS: f32 = 16.0;
+
+/* Steps shown at most in the timeline (the last ones) */
+const TIMELINE_STEPS: usize = 12;
+
+/* What a node stands for */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    Identity,
+    SignedPreKey,
+    OneTime,
+    Ephemeral,
+    Chain,
+    Derived,
+    Ciphertext,
+}
+
+/* What an edge stands for; `Skipped` is a step of full X3DH this prototype leaves out */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Dh,
+    Signs,
+    Derives,
+    Skipped,
+}
+
+/*
+ * A key of the diagram.
+ *
+ * Fields:
+ *  - `label`  : What the key is (e.g. "alice.SPK")
+ *  - `key_id` : Its short id (see `inspector::key_id`), if known here
+ *  - `kind`   : What it stands for (its color)
+ *  - `column` : 0 (sender), 1 (derived) or 2 (receiver)
+ *  - `row`    : From the top, 0 to 3
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub label: String,
+    pub key_id: Option<String>,
+    pub kind: KeyKind,
+    pub column: u8,
+    pub row: u8,
+}
+
+/* A computation between two nodes (indices in `Diagram::nodes`), in the order it happens */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+    pub label: String,
+}
+
+/* The key agreement of one message */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagram {
+    pub title: String,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+/*
+ * The keys of a one-to-one message, as key ids (`None` when this
+ * device does not know them).
+ *
+ * Fields:
+ *  - `sender` / `receiver`    : Usernames
+ *  - `sender_identity`        : The sender's identity key
+ *  - `ephemeral`              : The ephemeral key of the message
+ *  - `receiver_identity`      : The receiver's identity key
+ *  - `receiver_prekey`        : The (signed) pre-key the message was encrypted to
+ *  - `receiver_one_time`      : A one-time pre-key of the receiver
+ */
+#[derive(Debug, Clone, Default)]
+pub struct DirectKeys {
+    pub sender: String,
+    pub receiver: String,
+    pub sender_identity: Option<String>,
+    pub ephemeral: Option<String>,
+    pub receiver_identity: Option<String>,
+    pub receiver_prekey: Option<String>,
+    pub receiver_one_time: Option<String>,
+}
+
+/*
+ * The keys of a group message, as key ids (`None` when unknown here).
+ *
+ * Fields:
+ *  - `author`         : Who sent it
+ *  - `treekem`        : Whether the group uses TreeKEM (else Sender Keys)
+ *  - `step`           : Its place in the chain ("iteration 3", "generation 2")
+ *  - `chain_key`      : The chain key it was derived from (sender side only)
+ *  - `message_key`    : Its message key
+ *  - `next_chain_key` : The chain key that replaced it (sender side only)
+ */
+#[derive(Debug, Clone, Default)]
+pub struct GroupKeys {
+    pub author: String,
+    pub treekem: bool,
+    pub step: String,
+    pub chain_key: Option<String>,
+    pub message_key: Option<String>,
+    pub next_chain_key: Option<String>,
+}
+
+impl Diagram {
+    /* Adds a node and returns its index */
+    fn node(&mut self, label: String, key_id: Option<String>, kind: KeyKind, column: u8, row: u8) -> usize {
+        self.nodes.push(Node {
+            label,
+            key_id,
+            kind,
+            column,
+            row,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, kind: EdgeKind, label: &str) {
+        self.edges.push(Edge {
+            from,
+            to,
+            kind,
+            label: label.to_string(),
+        });
+    }
+
+    /* The key agreement of a one-to-one message */
+    pub fn direct(keys: DirectKeys) -> Self {
+        let mut d = Diagram {
+            title: format!("{} → {}", keys.sender, keys.receiver),
+            ..Diagram::default()
+        };
+        let sender_id = d.node(format!("{}.ID", keys.sender), keys.sender_identity, KeyKind::Identity, 0, 0);
+        let ephemeral = d.node("EK".to_string(), keys.ephemeral, KeyKind::Ephemeral, 0, 1);
+        let receiver_id = d.node(format!("{}.ID", keys.receiver), keys.receiver_identity, KeyKind::Identity, 2, 0);
+        let prekey = d.node(format!("{}.SPK", keys.receiver), keys.receiver_prekey, KeyKind::SignedPreKey, 2, 1);
+        let one_time = d.node(format!("{}.OPK", keys.receiver), keys.receiver_one_time, KeyKind::OneTime, 2, 2);
+        let shared = d.node("shared key".to_string(), None, KeyKind::Derived, 1, 3);
+        d.edge(receiver_id, prekey, EdgeKind::Signs, "signs");
+        d.edge(ephemeral, shared, EdgeKind::Dh, "");
+        d.edge(prekey, shared, EdgeKind::Dh, "DH(EK, SPK)");
+        d.edge(sender_id, prekey, EdgeKind::Skipped, "DH1(ID, SPK)");
+        d.edge(ephemeral, receiver_id, EdgeKind::Skipped, "DH2(EK, ID)");
+        d.edge(ephemeral, one_time, EdgeKind::Skipped, "DH4(EK, OPK)");
+        d
+    }
+
+    /* One step of the key chain of a group message */
+    pub fn group(keys: GroupKeys) -> Self {
+        let mut d = Diagram {
+            title: format!("{} · {}", keys.author, keys.step),
+            ..Diagram::default()
+        };
+        let (signer, chain, derive) = if keys.treekem {
+            (format!("{}.ID", keys.author), "encryption secret", "HMAC(secret, sender || n)")
+        } else {
+            (format!("{} signing key", keys.author), "chain key", "HMAC(chain key, 0x01)")
+        };
+        let signer = d.node(signer, None, KeyKind::Identity, 0, 0);
+        let chain = d.node(chain.to_string(), keys.chain_key, KeyKind::Chain, 1, 0);
+        let message_key = d.node("message key".to_string(), keys.message_key, KeyKind::Derived, 1, 2);
+        let ciphertext = d.node("ciphertext".to_string(), None, KeyKind::Ciphertext, 1, 3);
+        d.edge(chain, message_key, EdgeKind::Derives, derive);
+        if !keys.treekem {
+            let next = d.node("next chain key".to_string(), keys.next_chain_key, KeyKind::Chain, 2, 1);
+            d.edge(chain, next, EdgeKind::Derives, "HMAC(chain key, 0x02)");
+        }
+        d.edge(message_key, ciphertext, EdgeKind::Derives, "secretbox");
+        d.edge(signer, ciphertext, EdgeKind::Signs, "signs");
+        d
+    }
+}
+
+/*
+ * The canvas program: the diagram of the current step, drawn up to
+ * `progress` (0 to 1), above the timeline of the conversation.
+ *
+ * Fields:
+ *  - `diagram`  : The key agreement of the current message
+ *  - `timeline` : A short label of every step of the conversation (e.g. the ephemeral key id)
+ *  - `current`  : Index of the current step in `timeline`
+ *  - `progress` : How much of the current step is drawn
+ */
+pub struct Visualizer {
+    pub diagram: Diagram,
+    pub timeline: Vec<String>,
+    pub current: usize,
+    pub progress: f32,
+}
+
+/*
+ * The ratchet playing over a conversation.
+ *
+ * Fields:
+ *  - `step`     : Index of the message whose step is drawn
+ *  - `progress` : How much of it is drawn, from 0 to 1
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Playback {
+    pub step: usize,
+    pub progress: f32,
+}
+
+impl Playback {
+    /*
+     * Advances by one `FRAME` through a conversation of `steps` messages.
+     * Returns `false` once the last step is fully drawn.
+     */
+    pub fn advance(&mut self, steps: usize) -> bool {
+        self.progress += FRAME.as_secs_f32() / STEP.as_secs_f32();
+        if self.progress < 1.0 {
+            return true;
+        }
+        if self.step + 1 >= steps {
+            return false;
+        }
+        self.step += 1;
+        self.progress = 0.0;
+        true
+    }
+}
+
+/* The color of a kind of key */
+fn tint(kind: KeyKind) -> Color {
+    match kind {
+        KeyKind::Identity => color!(0x61AFEF),
+        KeyKind::SignedPreKey => color!(0xC678DD),
+        KeyKind::OneTime => color!(0xD19A66),
+        KeyKind::Ephemeral => color!(0xE5C07B),
+        KeyKind::Chain => color!(0x56B6C2),
+        KeyKind::Derived => color!(0x98C379),
+        KeyKind::Ciphertext => color!(0xABB2BF),
+    }
+}
+
+/* A label drawn at `position` */
+fn label(content: String, position: Point, size: f32, color: Color) -> Text {
+    Text {
+        content,
+        position,
+        color,
+        size: size.into(),
+        horizontal_alignment: Horizontal::Center,
+        vertical_alignment: Vertical::Center,
+        ..Text::default()
+    }
+}
+
+impl<Message> canvas::Program<Message> for Visualizer {
+    type State = ();
+
+    fn draw(&self, _state: &(), renderer: &Renderer, _theme: &Theme, bounds: Rectangle, _cursor: mouse::Cursor) -> Vec<Geometry> {
+        let mut frame = Frame::new(renderer, bounds.size());
+        let dim = color!(0x5C6370);
+        let width = bounds.width;
+        let at = |node: &Node| {
+            let x = width * (0.15 + 0.35 * f32::from(node.column));
+            let y = 50.0 + f32::from(node.row) * (DIAGRAM_HEIGHT - 80.0) / 3.0;
+            Point::new(x, y)
+        };
+
+        frame.fill_text(label(self.diagram.title.clone(), Point::new(width / 2.0, 14.0), 13.0, Color::WHITE));
+
+        /* Edges, in order: those done are drawn, the current one grows with a dot running along it */
+        let count = self.diagram.edges.len().max(1) as f32;
+        for (i, edge) in self.diagram.edges.iter().enumerate() {
+            let start = i as f32 / count;
+            if self.progress <= start {
+                break;
+            }
+            let done = ((self.progress - start) * count).min(1.0);
+            let (Some(from), Some(to)) = (self.diagram.nodes.get(edge.from), self.diagram.nodes.get(edge.to)) else {
+                continue;
+            };
+            let (a, b) = (at(from), at(to));
+            let end = Point::new(a.x + (b.x - a.x) * done, a.y + (b.y - a.y) * done);
+            let (color, dash): (Color, &[f32]) = match edge.kind {
+                EdgeKind::Dh => (color!(0x98C379), &[]),
+                EdgeKind::Signs => (color!(0x61AFEF), &[]),
+                EdgeKind::Derives => (color!(0x56B6C2), &[]),
+                EdgeKind::Skipped => (dim, &[5.0, 5.0]),
+            };
+            let stroke = Stroke {
+                line_dash: LineDash { segments: dash, offset: 0 },
+                ..Stroke::default().with_color(color).with_width(2.0)
+            };
+            frame.stroke(&Path::line(a, end), stroke);
+            if done < 1.0 {
+                frame.fill(&Path::circle(end, 4.0), Color::WHITE);
+            } else if !edge.label.is_empty() {
+                let middle = Point::new((a.x + b.x) / 2.0, (a.y + b.y) / 2.0 - 10.0);
+                frame.fill_text(label(edge.label.clone(), middle, 11.0, color));
+            }
+        }
+
+        /* Nodes, on top of the edges */
+        for node in &self.diagram.nodes {
+            let center = at(node);
+            frame.fill(&Path::circle(center, NODE_RADIUS), tint(node.kind));
+            frame.fill_text(label(node.label.clone(), Point::new(center.x, center.y + NODE_RADIUS + 9.0), 12.0, Color::WHITE));
+            if let Some(id) = &node.key_id {
+                frame.fill_text(label(id.clone(), Point::new(center.x, center.y + NODE_RADIUS + 22.0), 10.0, dim));
+            }
+        }
+
+        /* Timeline of the ratchet: one dot per step, the current one highlighted */
+        let first = self.current.saturating_sub(TIMELINE_STEPS - 1);
+        let shown = &self.timeline[first.min(self.timeline.len())..self.timeline.len().min(first + TIMELINE_STEPS)];
+        let y = DIAGRAM_HEIGHT + 40.0;
+        let gap = width / (TIMELINE_STEPS as f32 + 1.0);
+        frame.fill_text(label("ratchet steps".to_string(), Point::new(width / 2.0, DIAGRAM_HEIGHT + 12.0), 11.0, dim));
+        for (i, step) in shown.iter().enumerate() {
+            let center = Point::new(gap * (i as f32 + 1.0), y);
+            if i > 0 {
+                let previous = Point::new(center.x - gap, y);
+                frame.stroke(&Path::line(previous, center), Stroke::default().with_color(dim).with_width(1.0));
+            }
+            let current = first + i == self.current;
+            let radius = if current { 7.0 } else { 4.0 };
+            frame.fill(&Path::circle(center, radius), if current { color!(0xE5C07B) } else { dim });
+            if current {
+                frame.fill_text(label(step.clone(), Point::new(center.x.clamp(40.0, width - 40.0), y + 20.0), 10.0, Color::WHITE));
+            }
+        }
+        frame.fill_text(label(
+            format!("step {} of {}", self.current + 1, self.timeline.len()),
+            Point::new(width / 2.0, HEIGHT - 12.0),
+            11.0,
+            dim,
+        ));
+
+        vec![frame.into_geometry()]
+    }
+}
+
+/* Renders `visualizer` on a canvas */
+pub fn view<'a, M: 'a>(visualizer: Visualizer) -> Element<'a, M> {
+    canvas::Canvas::new(visualizer).width(Length::Fill).height(Length::Fixed(HEIGHT)).into()
+}